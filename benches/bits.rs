@@ -0,0 +1,119 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use steganogan_rs::compression::Compression;
+use steganogan_rs::ecc::{self, EccScheme};
+use steganogan_rs::{payload, utils};
+
+const DATA_DEPTH: usize = 1;
+const SIZES: [(usize, usize); 2] = [(1920, 1080), (3840, 2160)];
+
+// Runs every benchmark on a single thread and on the default rayon pool to show the speedup.
+fn pools() -> Vec<(String, rayon::ThreadPool)> {
+  let mut threads = vec![1, rayon::current_num_threads()];
+  threads.dedup();
+  threads
+    .into_iter()
+    .map(|n| {
+      let pool = rayon::ThreadPoolBuilder::new().num_threads(n).build().unwrap();
+      (format!("{n} threads"), pool)
+    })
+    .collect()
+}
+
+fn message() -> Vec<u8> {
+  let header = payload::Header {
+    size: (3840, 2160),
+    source_size: None,
+    chunk: None,
+    frame: None,
+    channels: None,
+    compression: Compression::default(),
+    payload_type: payload::PayloadType::Text,
+    ecc: ecc::Ecc::Standard,
+    signature: None,
+    mac: None,
+  };
+  payload::pack(&header, "a fairly long secret message ".repeat(8).as_bytes())
+}
+
+fn bench_encode(c: &mut Criterion) {
+  let data = "lorem ipsum dolor sit amet ".repeat(40_000);
+  let mut group = c.benchmark_group("bytes_to_encoded_bits");
+  group.sample_size(10);
+  for (name, pool) in pools() {
+    group.bench_function(BenchmarkId::from_parameter(name), |b| {
+      pool.install(|| b.iter(|| utils::bytes_to_encoded_bits(data.as_bytes())))
+    });
+  }
+  group.finish();
+}
+
+// Reference Reed-Solomon against the parity table and, with the `simd` feature, the SIMD backend, single threaded.
+fn bench_ecc(c: &mut Criterion) {
+  let data = "lorem ipsum dolor sit amet ".repeat(4_000);
+  let reference = ecc::ReedSolomon::new(5, 25);
+  let table = ecc::ParityTable::new(5, 25);
+  #[allow(unused_mut)]
+  let mut schemes: Vec<(&str, &dyn EccScheme)> = vec![("reference", &reference), ("parity table", &table)];
+  #[cfg(feature = "simd")]
+  schemes.push(("simd", &*ecc::STANDARD));
+  let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+  let mut group = c.benchmark_group("ecc");
+  group.sample_size(10);
+  for (name, scheme) in schemes {
+    group.bench_function(BenchmarkId::from_parameter(name), |b| {
+      pool.install(|| b.iter(|| ecc::encode(scheme, data.as_bytes())))
+    });
+  }
+  group.finish();
+}
+
+fn bench_extract(c: &mut Criterion) {
+  let data = message();
+  let mut group = c.benchmark_group("extract");
+  group.sample_size(10);
+  for (w, h) in SIZES {
+    let bits = payload::tile(&data, DATA_DEPTH, h, w).unwrap();
+    for (name, pool) in pools() {
+      group.bench_function(BenchmarkId::new(format!("{w}x{h}"), name), |b| {
+        pool.install(|| b.iter(|| payload::extract(&bits).unwrap()))
+      });
+    }
+  }
+  group.finish();
+}
+
+fn bench_bits_to_bytes(c: &mut Criterion) {
+  let mut group = c.benchmark_group("bits_to_bytes");
+  group.sample_size(10);
+  for (w, h) in SIZES {
+    let bits = payload::tile(&message(), DATA_DEPTH, h, w).unwrap();
+    for (name, pool) in pools() {
+      group.bench_function(BenchmarkId::new(format!("{w}x{h}"), name), |b| {
+        pool.install(|| b.iter(|| utils::bits_to_bytes(&bits)))
+      });
+    }
+  }
+  group.finish();
+}
+
+fn bench_split_bytes(c: &mut Criterion) {
+  let mut group = c.benchmark_group("split_bytes");
+  group.sample_size(10);
+  for (w, h) in SIZES {
+    let bytes = utils::bits_to_bytes(&payload::tile(&message(), DATA_DEPTH, h, w).unwrap());
+    group.bench_function(BenchmarkId::from_parameter(format!("{w}x{h}")), |b| {
+      b.iter(|| utils::split_bytes(&bytes, &[0; 4]))
+    });
+  }
+  group.finish();
+}
+
+criterion_group!(
+  benches,
+  bench_encode,
+  bench_ecc,
+  bench_extract,
+  bench_bits_to_bytes,
+  bench_split_bytes
+);
+criterion_main!(benches);
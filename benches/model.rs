@@ -0,0 +1,73 @@
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use steganogan_rs::model::decoder::Decoder;
+use steganogan_rs::model::encoder::Encoder;
+use steganogan_rs::weights::ModelConfig;
+
+const DATA_DEPTH: usize = 1;
+const HIDDEN_SIZE: usize = 32;
+const SIZES: [(usize, usize); 2] = [(256, 256), (512, 512)];
+
+// The pretrained layout with and without fused batch norm, and its depthwise-separable variant.
+fn configs() -> [(&'static str, ModelConfig, bool); 3] {
+  let config = ModelConfig {
+    data_depth: DATA_DEPTH,
+    hidden_size: HIDDEN_SIZE,
+    ..Default::default()
+  };
+  let separable = ModelConfig {
+    separable: true,
+    ..config.clone()
+  };
+  [
+    ("full", config.clone(), false),
+    ("fused", config, true),
+    ("separable", separable, false),
+  ]
+}
+
+fn bench_encoder(c: &mut Criterion) {
+  let device = Device::cuda_if_available(0).unwrap();
+  let mut group = c.benchmark_group("Encoder::forward");
+  group.sample_size(10);
+  for (name, config, fuse) in configs() {
+    // Randomly initialized weights, the forward pass costs the same as with trained ones
+    let vb = VarBuilder::from_varmap(&VarMap::new(), DType::F32, &device);
+    let mut encoder = Encoder::from_config(&config, vb).unwrap();
+    if fuse {
+      encoder.fuse().unwrap();
+    }
+    for (w, h) in SIZES {
+      let image = Tensor::randn(0f32, 1f32, (1, 3, h, w), &device).unwrap();
+      let data = Tensor::randn(0f32, 1f32, (1, DATA_DEPTH, h, w), &device).unwrap();
+      group.bench_function(BenchmarkId::new(name, format!("{w}x{h}")), |b| {
+        b.iter(|| encoder.forward(&image, &data).unwrap())
+      });
+    }
+  }
+  group.finish();
+}
+
+fn bench_decoder(c: &mut Criterion) {
+  let device = Device::cuda_if_available(0).unwrap();
+  let mut group = c.benchmark_group("Decoder::forward");
+  group.sample_size(10);
+  for (name, config, fuse) in configs() {
+    let vb = VarBuilder::from_varmap(&VarMap::new(), DType::F32, &device);
+    let mut decoder = Decoder::from_config(&config, vb).unwrap();
+    if fuse {
+      decoder.fuse().unwrap();
+    }
+    for (w, h) in SIZES {
+      let image = Tensor::randn(0f32, 1f32, (1, 3, h, w), &device).unwrap();
+      group.bench_function(BenchmarkId::new(name, format!("{w}x{h}")), |b| {
+        b.iter(|| decoder.forward(&image).unwrap())
+      });
+    }
+  }
+  group.finish();
+}
+
+criterion_group!(benches, bench_encoder, bench_decoder);
+criterion_main!(benches);
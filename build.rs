@@ -0,0 +1,19 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+  println!("cargo:rerun-if-changed=build.rs");
+  println!("cargo:rerun-if-changed=proto/steganogan.proto");
+  #[cfg(feature = "grpc")]
+  {
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    tonic_build::compile_protos("proto/steganogan.proto")?;
+  }
+  #[cfg(feature = "ffi")]
+  {
+    // Into OUT_DIR rather than the source tree, `ffi::tests` checks that include/steganogan.h matches it
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    let out = std::path::PathBuf::from(std::env::var("OUT_DIR")?).join("steganogan.h");
+    cbindgen::generate(std::env::var("CARGO_MANIFEST_DIR")?)?.write_to_file(out);
+  }
+  #[cfg(feature = "node")]
+  napi_build::setup();
+  Ok(())
+}
@@ -0,0 +1,102 @@
+/* Generated by cbindgen from src/ffi.rs, do not edit. */
+
+#ifndef STEGANOGAN_H
+#define STEGANOGAN_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum SteganoStatus {
+  STEGANO_STATUS_OK = 0,
+  STEGANO_STATUS_INVALID_ARGUMENT,
+  STEGANO_STATUS_WEIGHT_LOAD,
+  STEGANO_STATUS_SHAPE_MISMATCH,
+  STEGANO_STATUS_CAPACITY_EXCEEDED,
+  STEGANO_STATUS_DECODE_FAILED,
+  STEGANO_STATUS_UNSUPPORTED_FORMAT,
+  STEGANO_STATUS_OTHER,
+} SteganoStatus;
+
+/**
+ * Opaque handle to a loaded model.
+ */
+typedef struct SteganoCodec SteganoCodec;
+
+/**
+ * Loads a model directory, PyTorch checkpoint or downloaded model by name. Returns NULL on failure.
+ *
+ * # Safety
+ * `model` must be a NUL-terminated string.
+ */
+struct SteganoCodec *steganogan_load(const char *model);
+
+/**
+ * Loads a model from the bytes of its encoder and decoder safetensors, e.g. bundled with an app. Returns NULL on
+ * failure.
+ *
+ * # Safety
+ * The buffers must be valid for their lengths.
+ */
+struct SteganoCodec *steganogan_load_buffers(const uint8_t *encoder,
+                                             size_t encoder_len,
+                                             const uint8_t *decoder,
+                                             size_t decoder_len);
+
+/**
+ * Rejects images with more than `max_pixels` pixels in encode and decode, 0 removes the limit.
+ *
+ * # Safety
+ * `codec` must come from `steganogan_load` or `steganogan_load_buffers`.
+ */
+void steganogan_set_max_pixels(struct SteganoCodec *codec, uint64_t max_pixels);
+
+/**
+ * # Safety
+ * `codec` must come from `steganogan_load` and not be used afterwards.
+ */
+void steganogan_free(struct SteganoCodec *codec);
+
+/**
+ * Hides `data` in the image (any format the crate reads) and writes the stego PNG to `out`, to be released with
+ * `steganogan_free_buffer`.
+ *
+ * # Safety
+ * `codec` must come from `steganogan_load`, the input buffers must be valid for their lengths and the out pointers
+ * must be writable.
+ */
+enum SteganoStatus steganogan_encode(const struct SteganoCodec *codec,
+                                     const uint8_t *image,
+                                     size_t image_len,
+                                     const uint8_t *data,
+                                     size_t data_len,
+                                     uint8_t **out,
+                                     size_t *out_len);
+
+/**
+ * Recovers the message from a stego image and writes it (not NUL-terminated) to `out`, to be released with
+ * `steganogan_free_buffer`.
+ *
+ * # Safety
+ * Same as for `steganogan_encode`.
+ */
+enum SteganoStatus steganogan_decode(const struct SteganoCodec *codec,
+                                     const uint8_t *image,
+                                     size_t image_len,
+                                     uint8_t **out,
+                                     size_t *out_len);
+
+/**
+ * # Safety
+ * `buffer` and `len` must come from `steganogan_encode` or `steganogan_decode`.
+ */
+void steganogan_free_buffer(uint8_t *buffer, size_t len);
+
+/**
+ * Message of the last error on this thread, NULL if there was none. Valid until the next call on the same thread.
+ */
+const char *steganogan_last_error(void);
+
+#endif /* STEGANOGAN_H */
@@ -0,0 +1,134 @@
+use anyhow::Result;
+use image::RgbImage;
+use rand::rngs::StdRng;
+use serde::Serialize;
+
+use crate::attack;
+use crate::compression::Compression;
+use crate::eval::Evaluator;
+use crate::payload::{self, Header, PayloadType};
+use crate::utils::{CHUNK_SIZE, ENCODED_SIZE};
+
+#[derive(Debug, Serialize)]
+pub struct Estimate {
+  pub attack: String,
+  /// Fraction of payload bits decoded wrong, if the attack keeps the image size
+  pub bit_error_rate: Option<f32>,
+  /// Chance of recovering the payload at that bit error rate, see `recovery_probability`
+  pub probability: Option<f64>,
+  /// Whether the payload was actually recovered from the attacked image
+  pub recovered: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Analysis {
+  /// Bytes of the packed payload, after compression
+  pub packed_len: usize,
+  pub blocks: usize,
+  /// Complete copies of the encoded payload the image holds
+  pub copies: usize,
+  pub estimates: Vec<Estimate>,
+}
+
+// Embeds the message into the cover and runs the attack suite on the stego image, estimating from the bit errors of
+// each attack how likely the payload is to survive it.
+pub fn analyze(
+  evaluator: &Evaluator,
+  cover: &RgbImage,
+  message: &[u8],
+  compression: Compression,
+  level: Option<u32>,
+  rng: &mut StdRng,
+) -> Result<Analysis> {
+  let header = Header {
+    size: cover.dimensions(),
+    compression,
+    payload_type: PayloadType::Text,
+    ..Default::default()
+  };
+  let packed = payload::pack_with(&header, message, level, None, None);
+  let (stego, bits) = evaluator.embed(cover, &packed)?;
+  let blocks = packed.len().div_ceil(CHUNK_SIZE);
+  let copies = bits.len() / payload::encoded_len(&packed);
+
+  let mut estimates = Vec::new();
+  let attacks = std::iter::once(None).chain(attack::suite().into_iter().map(Some));
+  for attack in attacks {
+    let attacked = match attack {
+      Some(attack) => attack.apply(&stego, rng)?,
+      None => stego.clone(),
+    };
+    let decoded = evaluator.decode_bits(&attacked)?;
+    let bit_error_rate = (decoded.len() == bits.len())
+      .then(|| decoded.iter().zip(bits.iter()).filter(|(a, b)| a != b).count() as f32 / bits.len() as f32);
+    estimates.push(Estimate {
+      attack: attack.map_or("none".to_string(), |attack| attack.name()),
+      bit_error_rate,
+      probability: bit_error_rate.map(|ber| recovery_probability(ber as f64, copies, blocks)),
+      recovered: payload::extract(&decoded).is_ok_and(|payload| payload.data == message),
+    });
+  }
+  Ok(Analysis {
+    packed_len: packed.len(),
+    blocks,
+    copies,
+    estimates,
+  })
+}
+
+// Probability that all `blocks` Reed-Solomon blocks decode when every bit is the majority vote of `copies` copies,
+// each wrong independently with probability `ber`. Decoding sums the soft outputs of the copies instead of voting,
+// which does at least as well, so this is a conservative estimate.
+pub fn recovery_probability(ber: f64, copies: usize, blocks: usize) -> f64 {
+  if copies == 0 {
+    return 0.;
+  }
+  // A tie is a coin flip
+  let voted: f64 = binomial(copies, ber)
+    .into_iter()
+    .enumerate()
+    .map(|(wrong, p)| match (2 * wrong).cmp(&copies) {
+      std::cmp::Ordering::Greater => p,
+      std::cmp::Ordering::Equal => p / 2.,
+      std::cmp::Ordering::Less => 0.,
+    })
+    .sum();
+  let byte_error = 1. - (1. - voted.min(1.)).powi(8);
+  let correctable = (ENCODED_SIZE - CHUNK_SIZE) / 2;
+  let block: f64 = binomial(ENCODED_SIZE, byte_error)
+    .into_iter()
+    .take(correctable + 1)
+    .sum();
+  block.min(1.).powi(blocks as i32)
+}
+
+// Probabilities of 0..=n successes in n trials, computed in log space so that large n do not underflow.
+fn binomial(n: usize, p: f64) -> Vec<f64> {
+  let p = p.clamp(1e-12, 1. - 1e-12);
+  let odds = (p / (1. - p)).ln();
+  let mut log_p = n as f64 * (1. - p).ln();
+  let mut probabilities = Vec::with_capacity(n + 1);
+  for k in 0..=n {
+    probabilities.push(log_p.exp());
+    log_p += ((n - k) as f64 / (k + 1) as f64).ln() + odds;
+  }
+  probabilities
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_recovery_probability() {
+    assert!((binomial(30, 0.3).iter().sum::<f64>() - 1.).abs() < 1e-9);
+    assert!(recovery_probability(0., 1, 10) > 0.999);
+    assert!(recovery_probability(0.5, 1000, 10) < 1e-6);
+    assert_eq!(recovery_probability(0.01, 0, 10), 0.);
+    // More copies and fewer blocks help
+    let p = recovery_probability(0.2, 3, 20);
+    assert!(p < recovery_probability(0.2, 9, 20));
+    assert!(p < recovery_probability(0.2, 3, 5));
+    assert!(recovery_probability(0.3, 5000, 100) > 0.999);
+  }
+}
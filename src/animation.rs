@@ -0,0 +1,126 @@
+use std::io::Cursor;
+
+use anyhow::Result;
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, Delay, Frame, ImageFormat, RgbImage, RgbaImage};
+
+// Frames of an animated GIF, PNG or WebP, composited to the full canvas.
+pub struct Animation {
+  pub frames: Vec<RgbaImage>,
+  pub delays: Vec<Delay>,
+}
+
+impl Animation {
+  // `None` for still images, including animated formats with a single frame, which take the still image path.
+  pub fn read(bytes: &[u8]) -> Result<Option<Self>> {
+    let frames = match image::guess_format(bytes)? {
+      ImageFormat::Gif => collect(GifDecoder::new(Cursor::new(bytes))?)?,
+      ImageFormat::Png => {
+        let decoder = PngDecoder::new(Cursor::new(bytes))?;
+        if !decoder.is_apng() {
+          return Ok(None);
+        }
+        collect(decoder.apng())?
+      }
+      ImageFormat::WebP => {
+        let decoder = WebPDecoder::new(Cursor::new(bytes))?;
+        if !decoder.has_animation() {
+          return Ok(None);
+        }
+        collect(decoder)?
+      }
+      _ => return Ok(None),
+    };
+    if frames.len() < 2 {
+      return Ok(None);
+    }
+    let delays = frames.iter().map(Frame::delay).collect();
+    let frames = frames.into_iter().map(Frame::into_buffer).collect();
+    Ok(Some(Self { frames, delays }))
+  }
+
+  pub fn rgb_frames(&self) -> Vec<RgbImage> {
+    self
+      .frames
+      .iter()
+      .map(|frame| image::DynamicImage::ImageRgba8(frame.clone()).to_rgb8())
+      .collect()
+  }
+
+  // Replaces the color of every frame, keeping its alpha channel.
+  pub fn with_rgb_frames(&self, frames: &[RgbImage]) -> Self {
+    let frames = self
+      .frames
+      .iter()
+      .zip(frames)
+      .map(|(original, rgb)| {
+        RgbaImage::from_fn(original.width(), original.height(), |x, y| {
+          let [r, g, b] = rgb.get_pixel(x, y).0;
+          image::Rgba([r, g, b, original.get_pixel(x, y)[3]])
+        })
+      })
+      .collect();
+    Self {
+      frames,
+      delays: self.delays.clone(),
+    }
+  }
+
+  // Lossless APNG with every frame replacing the previous one, looping forever. GIF would quantize the colors and
+  // the `image` crate does not write animated WebP.
+  pub fn to_apng(&self) -> Result<Vec<u8>> {
+    let (width, height) = self.frames[0].dimensions();
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(self.frames.len() as u32, 0)?;
+    let mut writer = encoder.write_header()?;
+    writer.set_blend_op(png::BlendOp::Source)?;
+    writer.set_dispose_op(png::DisposeOp::None)?;
+    for (frame, delay) in self.frames.iter().zip(self.delays.iter()) {
+      let (numerator, denominator) = delay.numer_denom_ms();
+      let ms = (numerator / denominator.max(1)).min(u16::MAX as u32) as u16;
+      writer.set_frame_delay(ms, 1000)?;
+      writer.write_image_data(frame.as_raw())?;
+    }
+    writer.finish()?;
+    Ok(out)
+  }
+}
+
+fn collect<'a>(decoder: impl AnimationDecoder<'a>) -> Result<Vec<Frame>> {
+  Ok(decoder.into_frames().collect_frames()?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_apng_roundtrip() -> Result<()> {
+    let frames: Vec<_> = (0..3u8)
+      .map(|i| RgbaImage::from_fn(5, 4, |x, y| image::Rgba([i * 50, x as u8, y as u8, 200 + i])))
+      .collect();
+    let animation = Animation {
+      delays: vec![Delay::from_numer_denom_ms(40, 1); 3],
+      frames,
+    };
+    let rgb: Vec<_> = animation
+      .rgb_frames()
+      .into_iter()
+      .map(|frame| image::imageops::flip_vertical(&frame))
+      .collect();
+    let encoded = animation.with_rgb_frames(&rgb).to_apng()?;
+
+    let decoded = Animation::read(&encoded)?.unwrap();
+    assert_eq!(decoded.frames.len(), 3);
+    assert_eq!(std::time::Duration::from(decoded.delays[1]).as_millis(), 40);
+    assert_eq!(decoded.rgb_frames(), rgb);
+    assert_eq!(decoded.frames[2].get_pixel(1, 1)[3], 202);
+    assert!(Animation::read(&crate::image_io::encode_image(&rgb[0], ImageFormat::Png)?)?.is_none());
+    Ok(())
+  }
+}
@@ -0,0 +1,122 @@
+use std::io::Cursor;
+
+use anyhow::Result;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{self, FilterType};
+use image::RgbImage;
+use rand::rngs::StdRng;
+use serde::Serialize;
+
+use crate::rng;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Attack {
+  Jpeg(u8),
+  Resize(f32),
+  Crop(f32),
+  Brightness(i32),
+  Contrast(f32),
+  Noise(f32),
+  Screenshot,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AttackResult {
+  pub attack: String,
+  pub recovered: bool,
+  /// Fraction of payload bits decoded the same as from the unmodified image, if the size is unchanged
+  pub bit_agreement: Option<f32>,
+}
+
+pub fn suite() -> Vec<Attack> {
+  let mut attacks: Vec<_> = [95, 90, 80, 70, 60, 50].map(Attack::Jpeg).to_vec();
+  attacks.extend([
+    Attack::Resize(0.75),
+    Attack::Resize(0.5),
+    Attack::Crop(0.9),
+    Attack::Crop(0.75),
+    Attack::Brightness(20),
+    Attack::Brightness(-20),
+    Attack::Contrast(20.),
+    Attack::Contrast(-20.),
+    Attack::Noise(2.),
+    Attack::Noise(5.),
+    Attack::Screenshot,
+  ]);
+  attacks
+}
+
+impl Attack {
+  pub fn name(&self) -> String {
+    match self {
+      Attack::Jpeg(quality) => format!("jpeg q={quality}"),
+      Attack::Resize(scale) => format!("resize x{scale}"),
+      Attack::Crop(keep) => format!("crop {}%", keep * 100.),
+      Attack::Brightness(value) => format!("brightness {value:+}"),
+      Attack::Contrast(value) => format!("contrast {value:+}"),
+      Attack::Noise(std) => format!("gaussian noise std={std}"),
+      Attack::Screenshot => "screenshot".to_string(),
+    }
+  }
+
+  pub fn apply(&self, img: &RgbImage, rng: &mut StdRng) -> Result<RgbImage> {
+    let (w, h) = img.dimensions();
+    Ok(match *self {
+      Attack::Jpeg(quality) => jpeg(img, quality)?,
+      Attack::Resize(scale) => rescale(img, scale),
+      Attack::Crop(keep) => {
+        let (cw, ch) = ((w as f32 * keep) as u32, (h as f32 * keep) as u32);
+        imageops::crop_imm(img, (w - cw) / 2, (h - ch) / 2, cw, ch).to_image()
+      }
+      Attack::Brightness(value) => imageops::brighten(img, value),
+      Attack::Contrast(value) => imageops::contrast(img, value),
+      Attack::Noise(std) => {
+        let mut noisy = img.clone();
+        for p in noisy.iter_mut() {
+          *p = (*p as f32 + std * rng::normal(rng)).round().clamp(0., 255.) as u8;
+        }
+        noisy
+      }
+      // Display scaling, slight blur and gamma shift, then a high quality JPEG as most screenshot tools produce.
+      Attack::Screenshot => {
+        let img = imageops::blur(&rescale(img, 0.9), 0.5);
+        jpeg(&imageops::brighten(&img, 4), 90)?
+      }
+    })
+  }
+}
+
+fn rescale(img: &RgbImage, scale: f32) -> RgbImage {
+  let (w, h) = img.dimensions();
+  let small = imageops::resize(
+    img,
+    (w as f32 * scale) as u32,
+    (h as f32 * scale) as u32,
+    FilterType::Triangle,
+  );
+  imageops::resize(&small, w, h, FilterType::Triangle)
+}
+
+fn jpeg(img: &RgbImage, quality: u8) -> Result<RgbImage> {
+  let mut out = Cursor::new(Vec::new());
+  JpegEncoder::new_with_quality(&mut out, quality).encode_image(img)?;
+  Ok(image::load_from_memory(out.get_ref())?.to_rgb8())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_suite() -> Result<()> {
+    let img = RgbImage::from_fn(40, 30, |x, y| image::Rgb([(x * 6) as u8, (y * 8) as u8, 100]));
+    for attack in suite() {
+      let attacked = attack.apply(&img, &mut rng::from_seed(Some(0)))?;
+      match attack {
+        Attack::Crop(_) => assert!(attacked.width() < 40 && attacked.height() < 30),
+        _ => assert_eq!(attacked.dimensions(), img.dimensions()),
+      }
+    }
+    Ok(())
+  }
+}
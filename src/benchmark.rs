@@ -0,0 +1,136 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use image::{ImageFormat, Rgb, RgbImage};
+use rand::Rng;
+use steganogan_rs::codec::{Codec, DecodeOptions, EncodeOptions, StageTimes};
+use steganogan_rs::{image_io, rng, SteganoError};
+
+use crate::BenchArgs;
+
+const SIZES: [(u32, u32); 3] = [(512, 512), (1920, 1080), (3840, 2160)];
+const MESSAGE: &str = "The quick brown fox jumps over the lazy dog. ";
+
+// Time of every step of one operation, summed over the timed iterations.
+#[derive(Debug, Default)]
+struct Times {
+  load: Duration,
+  stages: StageTimes,
+  save: Duration,
+}
+
+impl Times {
+  fn total(&self) -> Duration {
+    self.load + self.stages.preprocess + self.stages.forward + self.stages.postprocess + self.save
+  }
+
+  fn row(&self, size: (u32, u32), operation: &str, iterations: usize) -> String {
+    let ms = |duration: Duration| format!("{:.1}", duration.as_secs_f64() * 1000. / iterations as f64);
+    format!(
+      "{:<11}{operation:<8}{:>8.2}{:>8}{:>12}{:>9}{:>13}{:>8}",
+      format!("{}x{}", size.0, size.1),
+      iterations as f64 / self.total().as_secs_f64(),
+      ms(self.load),
+      ms(self.stages.preprocess),
+      ms(self.stages.forward),
+      ms(self.stages.postprocess),
+      ms(self.save),
+    )
+  }
+}
+
+pub fn run(args: BenchArgs) -> Result<()> {
+  let device = crate::device()?;
+  let start = Instant::now();
+  let codec = Codec::open(&args.model, &device)?;
+  println!(
+    "model loaded in {:.1} ms on {device:?}",
+    start.elapsed().as_secs_f64() * 1000.
+  );
+  println!(
+    "{:<11}{:<8}{:>8}{:>8}{:>12}{:>9}{:>13}{:>8}",
+    "size", "op", "img/s", "load", "preprocess", "forward", "postprocess", "save"
+  );
+
+  let sizes = if args.sizes.is_empty() { &SIZES[..] } else { &args.sizes };
+  let mut rng = rng::from_seed(Some(0));
+  for &size in sizes {
+    let cover = image_io::encode_image(&cover(size, &mut rng), ImageFormat::Png)?;
+    // The first run pays for allocations and kernel compilation
+    measure(&codec, &cover, 1, args.batch_size)?;
+    let (encode, decode) = measure(&codec, &cover, args.iterations, args.batch_size)?;
+    let images = args.iterations * args.batch_size;
+    println!("{}", encode.row(size, "encode", images));
+    println!("{}", decode.row(size, "decode", images));
+  }
+  println!("times are ms per image");
+  Ok(())
+}
+
+// Runs the whole encode (load cover, encode, save PNG) and decode (load stego image, decode) pipelines in memory, on
+// batches of `batch_size` copies of the cover that go through the model together.
+fn measure(codec: &Codec, cover: &[u8], iterations: usize, batch_size: usize) -> Result<(Times, Times)> {
+  let (mut encode, mut decode) = (Times::default(), Times::default());
+  let options = EncodeOptions::default();
+  for _ in 0..iterations {
+    let start = Instant::now();
+    let imgs = (0..batch_size)
+      .map(|_| Ok(image::load_from_memory(cover)?.to_rgb8()))
+      .collect::<Result<Vec<_>>>()?;
+    encode.load += start.elapsed();
+    let items: Vec<_> = imgs.iter().map(|img| (img, MESSAGE.as_bytes(), options)).collect();
+    let stego = codec.encode_images_timed(&items, &mut encode.stages)?;
+    let start = Instant::now();
+    let stego = stego
+      .iter()
+      .map(|stego| image_io::encode_image(stego, ImageFormat::Png))
+      .collect::<Result<Vec<_>>>()?;
+    encode.save += start.elapsed();
+
+    let start = Instant::now();
+    let imgs = stego
+      .iter()
+      .map(|stego| Ok(image::load_from_memory(stego)?.to_rgb8()))
+      .collect::<Result<Vec<_>>>()?;
+    decode.load += start.elapsed();
+    for payload in codec.decode_images_timed(&imgs, &DecodeOptions::default(), &mut decode.stages)? {
+      match payload {
+        Err(err) if !matches!(err.downcast_ref(), Some(SteganoError::DecodeFailed)) => return Err(err),
+        _ => {}
+      }
+    }
+  }
+  Ok((encode, decode))
+}
+
+// Smooth gradient with some noise, closer to a photo than a flat image.
+fn cover((width, height): (u32, u32), rng: &mut impl Rng) -> RgbImage {
+  RgbImage::from_fn(width, height, |x, y| {
+    let noise: u8 = rng.gen_range(0..16);
+    Rgb([
+      (x * 239 / width) as u8 + noise,
+      (y * 239 / height) as u8 + noise,
+      ((x + y) * 239 / (width + height)) as u8 + noise,
+    ])
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use candle_core::Device;
+
+  use super::*;
+
+  #[test]
+  fn test_measure() -> Result<()> {
+    let codec = Codec::open("pretrained", &Device::Cpu)?;
+    let cover = image_io::encode_image(&cover((64, 48), &mut rng::from_seed(Some(0))), ImageFormat::Png)?;
+    let (encode, decode) = measure(&codec, &cover, 2, 1)?;
+    assert!(encode.stages.forward > Duration::ZERO && encode.save > Duration::ZERO);
+    assert!(decode.stages.forward > Duration::ZERO && decode.total() > decode.stages.forward);
+    assert!(encode.row((64, 48), "encode", 2).starts_with("64x48      encode"));
+    let (batched, _) = measure(&codec, &cover, 1, 3)?;
+    assert!(batched.stages.forward > Duration::ZERO);
+    Ok(())
+  }
+}
@@ -0,0 +1,33 @@
+use anyhow::Result;
+
+// The clipboard is accessed by the client, never by the daemon, which may run in another session.
+
+#[cfg(feature = "clipboard")]
+pub fn read() -> Result<String> {
+  Ok(arboard::Clipboard::new()?.get_text()?)
+}
+
+// On Linux the clipboard is served by the process that set it, so this waits until a clipboard manager or another
+// application takes it over.
+#[cfg(feature = "clipboard")]
+pub fn write(text: &str) -> Result<()> {
+  let mut clipboard = arboard::Clipboard::new()?;
+  #[cfg(target_os = "linux")]
+  {
+    use arboard::SetExtLinux;
+    clipboard.set().wait().text(text)?;
+  }
+  #[cfg(not(target_os = "linux"))]
+  clipboard.set_text(text)?;
+  Ok(())
+}
+
+#[cfg(not(feature = "clipboard"))]
+pub fn read() -> Result<String> {
+  anyhow::bail!("Built without clipboard support, enable the clipboard feature")
+}
+
+#[cfg(not(feature = "clipboard"))]
+pub fn write(_text: &str) -> Result<()> {
+  anyhow::bail!("Built without clipboard support, enable the clipboard feature")
+}
@@ -0,0 +1,1354 @@
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{ensure, Context, Result};
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use ed25519_dalek::SigningKey;
+use image::imageops::{self, FilterType};
+use image::RgbImage;
+use rand::Rng;
+
+use crate::color;
+use crate::compression::Compression;
+use crate::ecc::Ecc;
+use crate::error::{self, SteganoError};
+use crate::image_io;
+use crate::mask::{self, Mask};
+use crate::model::decoder::Decoder;
+use crate::model::encoder::Encoder;
+use crate::payload::{self, Candidate, Payload};
+use crate::pool::{PoolKey, TensorPool};
+use crate::rng;
+use crate::stego_key::StegoKey;
+use crate::sync;
+use crate::texture;
+use crate::train;
+use crate::transform::Transform;
+use crate::weights::{self, ModelConfig};
+use crate::zoo;
+
+// Payload tensors kept for reuse by default, a few sizes of covers are common in a batch.
+const POOL_CAPACITY: usize = 4;
+// Copies of the payload the channels picked by `EncodeOptions::variable_rate` hold at least.
+const VARIABLE_RATE_COPIES: usize = 8;
+
+#[derive(Default, Clone, Copy)]
+pub struct EncodeOptions<'a> {
+  /// Resize the cover to this size before encoding
+  pub size: Option<(u32, u32)>,
+  /// Sign the payload, see `Payload::verify`
+  pub sign_key: Option<&'a SigningKey>,
+  /// Scramble the payload bits, the same key is needed to decode
+  pub stego_key: Option<&'a StegoKey>,
+  /// Part of a payload split across several images
+  pub chunk: Option<payload::Chunk>,
+  /// Index of the video frame, stored in the header
+  pub frame: Option<u32>,
+  pub compression: Compression,
+  /// Level of `compression`, its default if not set
+  pub compression_level: Option<u32>,
+  pub payload_type: payload::PayloadType,
+  /// Change no channel of any pixel by more than this many of 255 levels
+  pub max_delta: Option<u8>,
+  /// Only embed where the mask is light, decoding needs the same mask
+  pub mask: Option<&'a Mask>,
+  /// Scale the residual by local texture to this power, see `texture::strength_map`
+  pub adaptive_strength: Option<f32>,
+  /// Scale the luma of the residual by this weight and move its energy into chroma, see `color::shift_to_chroma`
+  pub luma_weight: Option<f32>,
+  /// How the payload fills the image, decoding detects it
+  pub spread: payload::Spread,
+  /// Error correction of the payload, decoding detects it
+  pub ecc: Ecc,
+  /// Encode these bits instead of the message and its header
+  pub null_payload: Option<payload::NullPayload>,
+  /// Lay the payload out in only this many leading data channels and leave the others 0
+  pub channels: Option<usize>,
+  /// Without `channels`, use as few leading data channels as a short payload needs, see `Codec::pack`
+  pub variable_rate: bool,
+  /// Seed of the bits of `NullPayload::Random`, fresh ones if not set
+  pub seed: Option<u64>,
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct DecodeOptions<'a> {
+  /// Key the payload was scrambled with
+  pub stego_key: Option<&'a StegoKey>,
+  /// Mask the payload was embedded with
+  pub mask: Option<&'a Mask>,
+  /// Find the tiles of `Spread::Tiles` or `Spread::RobustTiles` in an image that may have been cropped or shifted
+  pub resync: Option<sync::Tiling>,
+  /// Leading data channels the payload was laid out in, found from the logits and the header if not set
+  pub channels: Option<usize>,
+}
+
+// Time spent in the steps of `Codec::encode_timed` and `Codec::decode_timed`, summed over calls.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StageTimes {
+  /// Resizing, converting the image to a tensor and tiling the payload
+  pub preprocess: Duration,
+  /// The network, including copying its output back to the host, which waits for the device
+  pub forward: Duration,
+  /// Converting the output to an image or extracting the payload
+  pub postprocess: Duration,
+}
+
+// Adds the time since `clock` to `stage` and restarts the clock.
+fn lap(clock: &mut Instant, stage: &mut Duration) {
+  let now = Instant::now();
+  *stage += now - *clock;
+  *clock = now;
+}
+
+// Rounds an encoder output on the host to the stego image of a cover, keeping to the options.
+fn finish(img: &RgbImage, x: &Tensor, options: &EncodeOptions) -> Result<RgbImage> {
+  let mut stego = imageops::crop_imm(&image_io::from_tensor(x)?, 0, 0, img.width(), img.height()).to_image();
+  if let Some(delta) = options.max_delta {
+    // Rounding to 8 bits can add a level on top of the clamped residual
+    for (stego, cover) in stego.iter_mut().zip(img.iter()) {
+      *stego = (*stego).clamp(cover.saturating_sub(delta), cover.saturating_add(delta));
+    }
+  }
+  if let Some(mask) = options.mask {
+    mask.restore(&mut stego, img);
+  }
+  Ok(stego)
+}
+
+// How forward passes fit into the memory limit of a codec, see `Codec::plan`.
+struct Plan {
+  /// Images per forward pass
+  batch: usize,
+  /// Side of the square tiles every image is cut into
+  tile: Option<usize>,
+}
+
+// Runs `forward` over square tiles of the inputs, each with `margin` pixels of context on every side that is cropped
+// from its output, and stitches the outputs back together.
+fn tiled(
+  inputs: &[Tensor],
+  tile: usize,
+  margin: usize,
+  forward: impl Fn(&[Tensor]) -> Result<Tensor>,
+) -> Result<Tensor> {
+  let (_, _, h, w) = inputs[0].dims4()?;
+  let mut rows = Vec::new();
+  for y in (0..h).step_by(tile) {
+    let mut row = Vec::new();
+    for x in (0..w).step_by(tile) {
+      let (y0, x0) = (y.saturating_sub(margin), x.saturating_sub(margin));
+      let (y1, x1) = ((y + tile + margin).min(h), (x + tile + margin).min(w));
+      let crops = inputs
+        .iter()
+        .map(|input| Ok(input.narrow(2, y0, y1 - y0)?.narrow(3, x0, x1 - x0)?))
+        .collect::<Result<Vec<_>>>()?;
+      let output = forward(&crops)?;
+      row.push(
+        output
+          .narrow(2, y - y0, tile.min(h - y))?
+          .narrow(3, x - x0, tile.min(w - x))?,
+      );
+    }
+    rows.push(Tensor::cat(&row, 3)?);
+  }
+  Ok(Tensor::cat(&rows, 2)?)
+}
+
+// Header of a payload encoded with `options` into a stego image of `size`, rescaled from `source_size` if set.
+fn header(size: (u32, u32), source_size: Option<(u32, u32)>, options: &EncodeOptions) -> payload::Header {
+  payload::Header {
+    size,
+    source_size,
+    chunk: options.chunk,
+    frame: options.frame,
+    channels: None,
+    compression: options.compression,
+    payload_type: options.payload_type,
+    ecc: options.ecc,
+    signature: None,
+    mac: None,
+  }
+}
+
+// A (3, h, w) or (1, 3, h, w) image tensor as a batch of one.
+fn single(img: &Tensor) -> Result<Tensor> {
+  let img = match img.rank() {
+    3 => img.unsqueeze(0)?,
+    _ => img.clone(),
+  };
+  ensure!(
+    img.rank() == 4 && img.dim(0)? == 1,
+    "Expected a (3, h, w) or (1, 3, h, w) image tensor, got {:?}",
+    img.dims()
+  );
+  Ok(img)
+}
+
+// Largest area an image may be padded to, relative to its own, to share a forward pass with larger images.
+const BUCKET_SLACK: f64 = 1.25;
+
+// A cover ready for the encoder, see `Codec::prepare`.
+struct Prepared {
+  /// The cover after resizing, with the size of the stego image
+  img: RgbImage,
+  /// Height and width of the cover
+  size: (usize, usize),
+  img_tensor: Tensor,
+  data: Tensor,
+  /// Per-pixel scales of the residual, see `shape_residual`
+  scales: Vec<Tensor>,
+}
+
+// `(height, width)` of an image of the given `(width, height)`, the order of tensor dimensions.
+fn tensor_size((width, height): (u32, u32)) -> (usize, usize) {
+  (height as usize, width as usize)
+}
+
+// Groups the indices of images of the given `(height, width)` into buckets that run through a network together,
+// padded to the largest height and width in the bucket. Same-size images always share a bucket, and an image only
+// joins a bucket while that pads it to at most `BUCKET_SLACK` times its area.
+fn buckets(sizes: &[(usize, usize)]) -> Vec<Vec<usize>> {
+  let mut order: Vec<usize> = (0..sizes.len()).collect();
+  order.sort_by_key(|&i| (sizes[i].0 * sizes[i].1, sizes[i]));
+  // With the smallest area first, that image gets padded the most
+  let mut buckets: Vec<(usize, (usize, usize), Vec<usize>)> = Vec::new();
+  for i in order {
+    let (h, w) = sizes[i];
+    let fits = buckets
+      .iter()
+      .position(|(area, (bh, bw), _)| ((*bh).max(h) * (*bw).max(w)) as f64 <= *area as f64 * BUCKET_SLACK);
+    match fits {
+      Some(b) => {
+        let (_, size, bucket) = &mut buckets[b];
+        *size = (size.0.max(h), size.1.max(w));
+        bucket.push(i);
+      }
+      None => buckets.push((h * w, (h, w), vec![i])),
+    }
+  }
+  buckets.into_iter().map(|(_, _, bucket)| bucket).collect()
+}
+
+// Stacks (1, c, h, w) tensors of different sizes into one batch, padding each to the largest height and width by
+// repeating its last row and column.
+fn pad_batch(tensors: &[&Tensor]) -> Result<Tensor> {
+  let sizes = tensors
+    .iter()
+    .map(|x| Ok((x.dim(2)?, x.dim(3)?)))
+    .collect::<Result<Vec<_>>>()?;
+  let h = sizes.iter().map(|size| size.0).max().context("Empty batch")?;
+  let w = sizes.iter().map(|size| size.1).max().context("Empty batch")?;
+  let padded = tensors
+    .iter()
+    .zip(sizes)
+    .map(|(x, size)| Ok(x.pad_with_same(2, 0, h - size.0)?.pad_with_same(3, 0, w - size.1)?))
+    .collect::<Result<Vec<_>>>()?;
+  Ok(Tensor::cat(&padded, 0)?)
+}
+
+// Post-processing of the encoder residual requested by the options, before it is added to the cover: per-pixel
+// `scales` (mask, adaptive strength), the shift to chroma, then the `max_delta` budget.
+fn shape_residual(mut residual: Tensor, options: &EncodeOptions, scales: &[Tensor]) -> Result<Tensor> {
+  for scale in scales {
+    residual = residual.broadcast_mul(scale)?;
+  }
+  if let Some(weight) = options.luma_weight {
+    residual = color::shift_to_chroma(&residual, weight)?;
+  }
+  if let Some(delta) = options.max_delta {
+    let bound = delta as f64 / 127.5;
+    residual = residual.clamp(-bound, bound)?;
+  }
+  Ok(residual)
+}
+
+// Encoder and decoder of one model, the library entry point for hiding and recovering messages.
+pub struct Codec {
+  config: ModelConfig,
+  device: Device,
+  /// Precision the networks run in, the weights are loaded as f32 and cast
+  dtype: DType,
+  encoder: Encoder,
+  decoder: Decoder,
+  /// Loaded weights of the encoder and decoder, for `fallback`
+  vars: [VarMap; 2],
+  /// Copies of the networks on the CPU, built when the device first runs out of memory
+  fallback: OnceLock<(Encoder, Decoder)>,
+  /// Warnings of the operations so far, see `Codec::take_warnings`
+  warnings: Mutex<Vec<String>>,
+  max_pixels: Option<u64>,
+  /// Device memory forward passes may use, see `Codec::set_max_memory`
+  max_memory: Option<u64>,
+  /// Device memory of the encoder and decoder weights
+  weight_bytes: u64,
+  pool: TensorPool,
+}
+
+impl Codec {
+  pub fn load(model: &Path, device: &Device) -> Result<Self> {
+    let config = weights::model_config(model, "encoder")?;
+    Self::build(config, device, DType::F32, |varmap, component| {
+      Ok(weights::load(varmap, model, component)?)
+    })
+  }
+
+  // Loads a model by path or name like `zoo::resolve`, or the weights built into the binary for `zoo::EMBEDDED`.
+  pub fn open(model: &str, device: &Device) -> Result<Self> {
+    #[cfg(feature = "embedded-weights")]
+    if zoo::is_embedded(model) {
+      return Self::from_buffers(weights::EMBEDDED_ENCODER, weights::EMBEDDED_DECODER, device);
+    }
+    Self::load(&zoo::resolve(model)?, device)
+  }
+
+  // Loads the encoder and decoder from in-memory safetensors, without touching the filesystem.
+  pub fn from_buffers(encoder: &[u8], decoder: &[u8], device: &Device) -> Result<Self> {
+    let config = weights::buffer_config(encoder)?.unwrap_or_default();
+    Self::build(config, device, DType::F32, |varmap, component| {
+      let buffer = if component == "encoder" { encoder } else { decoder };
+      weights::load_buffer(varmap, buffer, component)
+    })
+  }
+
+  pub(crate) fn build(
+    config: ModelConfig,
+    device: &Device,
+    dtype: DType,
+    load: impl Fn(&mut VarMap, &str) -> Result<()>,
+  ) -> Result<Self> {
+    let mut vars = [VarMap::new(), VarMap::new()];
+    let vb = |i: usize| VarBuilder::from_varmap(&vars[i], DType::F32, device);
+    let mut encoder = Encoder::from_config(&config, vb(0))?;
+    let mut decoder = Decoder::from_config(&config, vb(1))?;
+    for (varmap, component) in vars.iter_mut().zip(["encoder", "decoder"]) {
+      load(varmap, component)?;
+    }
+    if dtype != DType::F32 {
+      encoder = Encoder::from_config(&config, train::cast_var_builder(&vars[0], dtype, device)?)?;
+      decoder = Decoder::from_config(&config, train::cast_var_builder(&vars[1], dtype, device)?)?;
+    }
+    let weight_bytes = vars
+      .iter()
+      .flat_map(VarMap::all_vars)
+      .map(|var| (var.elem_count() * dtype.size_in_bytes()) as u64)
+      .sum();
+    // The codec only runs inference, so batch norm is fused once the weights are loaded
+    encoder.fuse()?;
+    decoder.fuse()?;
+    Ok(Self {
+      config,
+      device: device.clone(),
+      dtype,
+      encoder,
+      decoder,
+      vars,
+      fallback: OnceLock::new(),
+      warnings: Mutex::new(Vec::new()),
+      max_pixels: None,
+      max_memory: None,
+      weight_bytes,
+      pool: TensorPool::new(POOL_CAPACITY),
+    })
+  }
+
+  // Limits the image size encode and decode accept, since the networks keep several activations of `hidden_size`
+  // floats per pixel.
+  pub fn set_max_pixels(&mut self, max_pixels: Option<u64>) {
+    self.max_pixels = max_pixels;
+  }
+
+  // Limits the device memory of the weights and forward passes to about `max_memory` bytes. Batches are split into
+  // passes over fewer images and images too large for a pass of their own are processed in tiles, instead of running
+  // out of memory. The use is estimated from the widest layer, see `ModelConfig::activations_per_pixel`.
+  pub fn set_max_memory(&mut self, max_memory: Option<u64>) {
+    self.max_memory = max_memory;
+  }
+
+  // Images per forward pass over images of `(height, width)`, and the side of the tiles an image is cut into when a
+  // pass over it alone would exceed `max_memory`.
+  fn plan(&self, (h, w): (usize, usize)) -> Result<Plan> {
+    let Some(max_memory) = self.max_memory else {
+      return Ok(Plan {
+        batch: usize::MAX,
+        tile: None,
+      });
+    };
+    let per_pixel = (self.config.activations_per_pixel() * self.dtype.size_in_bytes()) as u64;
+    let available = max_memory.saturating_sub(self.weight_bytes) / per_pixel;
+    let pixels = (h * w) as u64;
+    if pixels <= available {
+      let batch = (available / pixels.max(1)) as usize;
+      return Ok(Plan { batch, tile: None });
+    }
+    let (margin, alignment) = self.config.tile_margin();
+    let side = (available as f64).sqrt() as usize;
+    let tile = side.saturating_sub(2 * margin) / alignment * alignment;
+    ensure!(
+      tile > 0,
+      "A memory limit of {:.1} MB is too low for the model, which needs {:.1} MB for tiles of {alignment}x{alignment}",
+      max_memory as f64 / 1e6,
+      (self.weight_bytes + (alignment + 2 * margin).pow(2) as u64 * per_pixel) as f64 / 1e6
+    );
+    Ok(Plan {
+      batch: 1,
+      tile: Some(tile),
+    })
+  }
+
+  // The encoder residual of a batch, in as many forward passes as `max_memory` needs.
+  fn encode_pass(&self, images: &Tensor, data: &Tensor) -> Result<Tensor> {
+    self.planned(&[images, data], |inputs, cpu| {
+      let (encoder, dtype) = match cpu {
+        true => (&self.fallback()?.0, DType::F32),
+        false => (&self.encoder, self.dtype),
+      };
+      let residual = encoder.residual(&inputs[0].to_dtype(dtype)?, &inputs[1].to_dtype(dtype)?)?;
+      Ok(residual.to_dtype(DType::F32)?)
+    })
+  }
+
+  // The decoder logits of a batch, in as many forward passes as `max_memory` needs.
+  fn decode_pass(&self, images: &Tensor) -> Result<Tensor> {
+    self.planned(&[images], |inputs, cpu| {
+      let (decoder, dtype) = match cpu {
+        true => (&self.fallback()?.1, DType::F32),
+        false => (&self.decoder, self.dtype),
+      };
+      Ok(decoder.forward(&inputs[0].to_dtype(dtype)?)?.to_dtype(DType::F32)?)
+    })
+  }
+
+  // Runs `forward` on the inputs on the device, falling back to the CPU with a warning if the device runs out of
+  // memory, so that one large image does not fail a long batch job. `forward` gets whether it runs on the CPU.
+  fn planned(&self, inputs: &[&Tensor], forward: impl Fn(&[Tensor], bool) -> Result<Tensor>) -> Result<Tensor> {
+    match self.passes(inputs, |inputs| forward(inputs, false)) {
+      Err(err) if error::is_out_of_memory(&err) && !self.device.is_cpu() => {
+        let (n, _, h, w) = inputs[0].dims4()?;
+        self.warn(format!("{err:#}, running {n} image(s) of {w}x{h} on the CPU instead"));
+        let inputs = inputs
+          .iter()
+          .map(|input| Ok(input.to_device(&Device::Cpu)?))
+          .collect::<Result<Vec<_>>>()?;
+        Ok(forward(&inputs, true)?.to_device(&self.device)?)
+      }
+      result => result,
+    }
+  }
+
+  // Runs `forward` on the inputs, a batch at a time and in tiles of the images if the plan has them.
+  fn passes(&self, inputs: &[&Tensor], forward: impl Fn(&[Tensor]) -> Result<Tensor>) -> Result<Tensor> {
+    let (n, _, h, w) = inputs[0].dims4()?;
+    let plan = self.plan((h, w))?;
+    let (margin, _) = self.config.tile_margin();
+    let mut outputs = Vec::new();
+    for start in (0..n).step_by(plan.batch.max(1)) {
+      let len = plan.batch.min(n - start);
+      let batch = inputs
+        .iter()
+        .map(|input| Ok(input.narrow(0, start, len)?))
+        .collect::<Result<Vec<_>>>()?;
+      outputs.push(match plan.tile {
+        Some(tile) => tiled(&batch, tile, margin, &forward)?,
+        None => forward(&batch)?,
+      });
+    }
+    Ok(Tensor::cat(&outputs, 0)?)
+  }
+
+  // The networks on the CPU in f32, which has no half precision kernels.
+  fn fallback(&self) -> Result<&(Encoder, Decoder)> {
+    if let Some(networks) = self.fallback.get() {
+      return Ok(networks);
+    }
+    let vb = |i: usize| train::cast_var_builder(&self.vars[i], DType::F32, &Device::Cpu);
+    let mut encoder = Encoder::from_config(&self.config, vb(0)?)?;
+    let mut decoder = Decoder::from_config(&self.config, vb(1)?)?;
+    encoder.fuse()?;
+    decoder.fuse()?;
+    Ok(self.fallback.get_or_init(|| (encoder, decoder)))
+  }
+
+  fn warn(&self, warning: String) {
+    self.warnings.lock().unwrap().push(warning);
+  }
+
+  // Warnings of the operations since the last call, like falling back to the CPU after running out of device memory.
+  pub fn take_warnings(&self) -> Vec<String> {
+    std::mem::take(&mut self.warnings.lock().unwrap())
+  }
+
+  fn check_size(&self, (width, height): (u32, u32)) -> Result<()> {
+    let pixels = width as u64 * height as u64;
+    match self.max_pixels {
+      Some(max) if pixels > max => Err(SteganoError::ImageTooLarge { pixels, max })?,
+      _ => Ok(()),
+    }
+  }
+
+  // Number of payload tensors kept for covers of the same size, see `TensorPool`.
+  pub fn set_pool_capacity(&mut self, capacity: usize) {
+    self.pool = TensorPool::new(capacity);
+  }
+
+  pub fn config(&self) -> &ModelConfig {
+    &self.config
+  }
+
+  // Payload bits available in an image of the given size.
+  pub fn capacity(&self, size: (u32, u32)) -> usize {
+    self.channel_capacity(size, self.config.data_depth)
+  }
+
+  // Payload bits the message takes and those a cover of `size` holds when encoded with `options`, which may rescale
+  // it. Masks are not taken into account.
+  pub fn capacity_for(&self, size: (u32, u32), message: &[u8], options: &EncodeOptions) -> Result<(usize, usize)> {
+    let target = options.size.unwrap_or(size);
+    let header = header(target, options.size.map(|_| size), options);
+    let plane = self.channel_capacity(target, 1);
+    let (packed, channels) = self.pack(header, message, options, plane)?;
+    let available = match options.spread.tiling() {
+      Some(tiling) => sync::capacity(channels, tiling),
+      None => channels * plane,
+    };
+    Ok((payload::encoded_len(&packed), available))
+  }
+
+  // Same as `capacity` for a payload laid out in the leading `channels` data channels only.
+  pub fn channel_capacity(&self, (width, height): (u32, u32), channels: usize) -> usize {
+    channels * width as usize * height as usize
+  }
+
+  // Data channels of the model a payload is laid out in, all of them unless `channels` picks fewer.
+  fn channels(&self, channels: Option<usize>) -> Result<usize> {
+    let depth = self.config.data_depth;
+    let channels = channels.unwrap_or(depth);
+    ensure!(
+      (1..=depth).contains(&channels),
+      "The model has {depth} data channels, {channels} can not be used"
+    );
+    Ok(channels)
+  }
+
+  // Hides the message in the cover, resized to `size` first if given. The stego image has the same size as the
+  // (resized) cover.
+  pub fn encode(&self, cover: &RgbImage, message: &[u8], size: Option<(u32, u32)>) -> Result<RgbImage> {
+    self.encode_with(
+      cover,
+      message,
+      &EncodeOptions {
+        size,
+        ..Default::default()
+      },
+    )
+  }
+
+  pub fn encode_with(&self, cover: &RgbImage, message: &[u8], options: &EncodeOptions) -> Result<RgbImage> {
+    self.encode_timed(cover, message, options, &mut StageTimes::default())
+  }
+
+  pub fn encode_timed(
+    &self,
+    cover: &RgbImage,
+    message: &[u8],
+    options: &EncodeOptions,
+    times: &mut StageTimes,
+  ) -> Result<RgbImage> {
+    let mut stego = self.encode_images_timed(&[(cover, message, *options)], times)?;
+    Ok(stego.remove(0))
+  }
+
+  // Encodes several covers, each with its own message and options. Covers of similar sizes go through the encoder in
+  // a single forward pass, see `buckets`, which is faster on a GPU than one image at a time.
+  pub fn encode_images(&self, items: &[(&RgbImage, &[u8], EncodeOptions)]) -> Result<Vec<RgbImage>> {
+    self.encode_images_timed(items, &mut StageTimes::default())
+  }
+
+  pub fn encode_images_timed(
+    &self,
+    items: &[(&RgbImage, &[u8], EncodeOptions)],
+    times: &mut StageTimes,
+  ) -> Result<Vec<RgbImage>> {
+    let mut clock = Instant::now();
+    let prepared = items
+      .iter()
+      .map(|(cover, message, options)| self.prepare(cover, message, options))
+      .collect::<Result<Vec<_>>>()?;
+    lap(&mut clock, &mut times.preprocess);
+
+    let sizes: Vec<(usize, usize)> = prepared.iter().map(|prepared| prepared.size).collect();
+    let mut outputs: Vec<Option<Tensor>> = vec![None; prepared.len()];
+    for bucket in buckets(&sizes) {
+      let images: Vec<&Tensor> = bucket.iter().map(|&i| &prepared[i].img_tensor).collect();
+      let data: Vec<&Tensor> = bucket.iter().map(|&i| &prepared[i].data).collect();
+      let residual = self.encode_pass(&pad_batch(&images)?, &pad_batch(&data)?)?;
+      for (j, &i) in bucket.iter().enumerate() {
+        let (h, w) = sizes[i];
+        let residual = residual.narrow(0, j, 1)?.narrow(2, 0, h)?.narrow(3, 0, w)?;
+        let residual = shape_residual(residual, &items[i].2, &prepared[i].scales)?;
+        outputs[i] = Some(Encoder::compose(&prepared[i].img_tensor, &residual)?.to_device(&Device::Cpu)?);
+      }
+    }
+    lap(&mut clock, &mut times.forward);
+
+    let stego = prepared
+      .iter()
+      .zip(outputs)
+      .zip(items)
+      .map(|((prepared, x), (_, _, options))| finish(&prepared.img, &x.unwrap(), options))
+      .collect::<Result<Vec<_>>>()?;
+    lap(&mut clock, &mut times.postprocess);
+    Ok(stego)
+  }
+
+  // Resizes the cover and lays out the payload bits and residual scales for the encoder.
+  fn prepare(&self, cover: &RgbImage, message: &[u8], options: &EncodeOptions) -> Result<Prepared> {
+    let img = match options.size {
+      Some((w, h)) => imageops::resize(cover, w, h, FilterType::Lanczos3),
+      None => cover.clone(),
+    };
+    self.check_size(img.dimensions())?;
+    let img_tensor = self
+      .config
+      .preprocess
+      .encoder_input(&image_io::to_tensor(&img, &self.device)?)?;
+
+    let header = header(img.dimensions(), options.size.map(|_| cover.dimensions()), options);
+    let (h, w) = tensor_size(img.dimensions());
+    let mut scales = Vec::new();
+    if let Some(mask) = options.mask {
+      scales.push(mask.tensor(img.dimensions(), &self.device)?);
+    }
+    if let Some(exponent) = options.adaptive_strength {
+      scales.push(texture::strength_map(&img, exponent, &self.device)?);
+    }
+    let positions = options.mask.map(|mask| mask.positions(img.dimensions())).transpose()?;
+    let plane = positions
+      .as_ref()
+      .map_or(h * w, |positions| positions.iter().filter(|&&p| p).count());
+    let (packed, channels) = self.pack(header, message, options, plane)?;
+    let data = self.payload_tensor(packed, channels, options, positions.as_deref(), (h, w))?;
+    Ok(Prepared {
+      img,
+      size: (h, w),
+      img_tensor,
+      data,
+      scales,
+    })
+  }
+
+  // Hides the message in an image tensor of shape (3, h, w) or (1, 3, h, w) with values in [0, 1], the layout of most
+  // vision models, and returns the stego image in the same shape and dtype without rounding it to 8 bits. Resizing,
+  // masks and adaptive strength work on `RgbImage`s only.
+  pub fn encode_tensor(&self, cover: &Tensor, message: &[u8], options: &EncodeOptions) -> Result<Tensor> {
+    let stego = self.encode_batch(&single(cover)?, &[message], options)?;
+    Ok(stego.reshape(cover.shape())?)
+  }
+
+  // Hides a message in every image of an (n, 3, h, w) batch like `encode_tensor`, in a single forward pass.
+  pub fn encode_batch(&self, covers: &Tensor, messages: &[&[u8]], options: &EncodeOptions) -> Result<Tensor> {
+    ensure!(
+      options.size.is_none() && options.mask.is_none() && options.adaptive_strength.is_none(),
+      "Resizing, masks and adaptive strength need an image, see `Codec::encode_with`"
+    );
+    let (pixels, (h, w)) = self.tensor_pixels(covers)?;
+    ensure!(
+      messages.len() == pixels.dim(0)?,
+      "Expected one message per image, got {} for {} images",
+      messages.len(),
+      pixels.dim(0)?
+    );
+    let header = header((w as u32, h as u32), None, options);
+    let data = messages
+      .iter()
+      .map(|message| {
+        let (packed, channels) = self.pack(header.clone(), message, options, h * w)?;
+        self.payload_tensor(packed, channels, options, None, (h, w))
+      })
+      .collect::<Result<Vec<_>>>()?;
+    let img_tensor = self.config.preprocess.encoder_input(&pixels)?;
+    let residual = self.encode_pass(&img_tensor, &Tensor::cat(&data, 0)?)?;
+    let x = Encoder::compose(&img_tensor, &shape_residual(residual, options, &[])?)?;
+    let stego = image_io::to_chw(&x)?.clamp(0., 1.)?;
+    Ok(stego.to_dtype(covers.dtype())?.to_device(covers.device())?)
+  }
+
+  // Same as `decode_with` for an image tensor like those of `encode_tensor`.
+  pub fn decode_tensor(&self, img: &Tensor, options: &DecodeOptions) -> Result<Payload> {
+    let mut payloads = self.decode_batch(&single(img)?, options)?;
+    payloads.pop().context("No image to decode")?
+  }
+
+  // Decodes every image of an (n, 3, h, w) batch in a single forward pass. Images without a readable payload fail
+  // with `SteganoError::DecodeFailed` on their own.
+  pub fn decode_batch(&self, imgs: &Tensor, options: &DecodeOptions) -> Result<Vec<Result<Payload>>> {
+    let (pixels, (h, w)) = self.tensor_pixels(imgs)?;
+    let img_tensor = self.config.preprocess.decoder_input(&pixels)?;
+    let logits = self.decode_pass(&img_tensor)?;
+    let sizes = vec![(w as u32, h as u32); logits.dim(0)?];
+    self.payloads(&logits, &sizes, options)
+  }
+
+  // Decodes several images, running images of similar sizes through the decoder in a single forward pass like
+  // `encode_images`. Images without a readable payload fail with `SteganoError::DecodeFailed` on their own.
+  pub fn decode_images(&self, imgs: &[RgbImage], options: &DecodeOptions) -> Result<Vec<Result<Payload>>> {
+    self.decode_images_timed(imgs, options, &mut StageTimes::default())
+  }
+
+  pub fn decode_images_timed(
+    &self,
+    imgs: &[RgbImage],
+    options: &DecodeOptions,
+    times: &mut StageTimes,
+  ) -> Result<Vec<Result<Payload>>> {
+    let mut clock = Instant::now();
+    let pixels = imgs
+      .iter()
+      .map(|img| {
+        self.check_size(img.dimensions())?;
+        let pixels = image_io::to_tensor(img, &self.device)?;
+        self.config.preprocess.decoder_input(&pixels)
+      })
+      .collect::<Result<Vec<_>>>()?;
+    let sizes: Vec<(usize, usize)> = imgs.iter().map(|img| tensor_size(img.dimensions())).collect();
+    lap(&mut clock, &mut times.preprocess);
+
+    let mut payloads: Vec<Option<Result<Payload>>> = (0..imgs.len()).map(|_| None).collect();
+    for bucket in buckets(&sizes) {
+      let batch: Vec<&Tensor> = bucket.iter().map(|&i| &pixels[i]).collect();
+      let logits = self.decode_pass(&pad_batch(&batch)?)?;
+      lap(&mut clock, &mut times.forward);
+      let bucket_sizes: Vec<(u32, u32)> = bucket.iter().map(|&i| imgs[i].dimensions()).collect();
+      for (&i, payload) in bucket.iter().zip(self.payloads(&logits, &bucket_sizes, options)?) {
+        payloads[i] = Some(payload);
+      }
+      lap(&mut clock, &mut times.postprocess);
+    }
+    Ok(payloads.into_iter().map(Option::unwrap).collect())
+  }
+
+  // Payloads of a batch of decoder logits, each cropped to the size of its image.
+  fn payloads(&self, logits: &Tensor, sizes: &[(u32, u32)], options: &DecodeOptions) -> Result<Vec<Result<Payload>>> {
+    let mut payloads = Vec::new();
+    for (i, &size) in sizes.iter().enumerate() {
+      let (h, w) = tensor_size(size);
+      let mut image_logits = logits
+        .get(i)?
+        .narrow(1, 0, h)?
+        .narrow(2, 0, w)?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+      self.config.center_logits(&mut image_logits);
+      payloads.push(self.extract(&image_logits, size, options));
+    }
+    Ok(payloads)
+  }
+
+  // An (n, 3, h, w) tensor in [0, 1] as pixels in the layout of `image_io::to_tensor` on the device of the codec,
+  // with its height and width.
+  fn tensor_pixels(&self, imgs: &Tensor) -> Result<(Tensor, (usize, usize))> {
+    let (_, channels, h, w) = imgs.dims4()?;
+    ensure!(channels == 3, "Expected RGB images, got {channels} channels");
+    self.check_size((w as u32, h as u32))?;
+    let imgs = imgs.to_device(&self.device)?.to_dtype(DType::F32)?;
+    Ok((image_io::from_chw(&imgs)?, (h, w)))
+  }
+
+  // Header and message packed for the encoder, with the leading data channels to lay them out in. Those are
+  // `options.channels`, or with `options.variable_rate` the fewest whose `plane` payload bits each hold
+  // `VARIABLE_RATE_COPIES` copies, which leaves more of the image as it is for a short message. The header records
+  // either for decoding.
+  fn pack(
+    &self,
+    header: payload::Header,
+    message: &[u8],
+    options: &EncodeOptions,
+    plane: usize,
+  ) -> Result<(Vec<u8>, usize)> {
+    let depth = self.config.data_depth;
+    let pack = |channels: Option<usize>| {
+      let header = payload::Header {
+        channels: channels.map(|channels| channels as u8),
+        ..header.clone()
+      };
+      payload::pack_with(
+        &header,
+        message,
+        options.compression_level,
+        options.sign_key,
+        options.stego_key,
+      )
+    };
+    let channels = match (options.channels, options.variable_rate) {
+      (Some(channels), _) => Some(self.channels(Some(channels))?),
+      (None, true) => {
+        // The recorded count takes the same byte whatever it is
+        let len = VARIABLE_RATE_COPIES * payload::encoded_len(&pack(Some(depth)));
+        let capacity = |channels| match options.spread.tiling() {
+          Some(tiling) => sync::capacity(channels, tiling),
+          None => channels * plane,
+        };
+        Some((1..=depth).find(|&channels| capacity(channels) >= len).unwrap_or(depth))
+      }
+      (None, false) => None,
+    };
+    Ok((pack(channels), channels.unwrap_or(depth)))
+  }
+
+  // Payload bits as a (1, data_depth, h, w) tensor, scrambled with the stego key and scattered to the `positions` a
+  // mask keeps if the options have them, in the leading `depth` channels only.
+  fn payload_tensor(
+    &self,
+    packed: Vec<u8>,
+    depth: usize,
+    options: &EncodeOptions,
+    positions: Option<&[bool]>,
+    (h, w): (usize, usize),
+  ) -> Result<Tensor> {
+    let data = match (options.null_payload, options.stego_key, positions, options.spread) {
+      (Some(payload::NullPayload::Zeros), ..) => Tensor::zeros((1, depth, h, w), DType::F32, &self.device)?,
+      (Some(payload::NullPayload::Random), ..) => {
+        let mut rng = rng::from_seed(options.seed);
+        let bits: Vec<f32> = (0..depth * h * w).map(|_| f32::from(rng.gen::<bool>())).collect();
+        Tensor::from_vec(bits, (1, depth, h, w), &self.device)?
+      }
+      (None, None, None, payload::Spread::Repeat) => {
+        let key = PoolKey {
+          shape: (h, w),
+          content: packed.clone(),
+        };
+        self
+          .pool
+          .get_or_insert_with(key, || Ok(payload::tile_tensor(&packed, depth, h, w, &self.device)?))?
+      }
+      (None, stego_key, positions, spread) => {
+        // Masked payload bits are a single row of the positions the mask keeps
+        let (rows, cols) = match positions {
+          Some(positions) => (1, positions.iter().filter(|&&p| p).count()),
+          None => (h, w),
+        };
+        let mut bits = payload::tile_with(&packed, spread, depth, rows, cols)?;
+        if let Some(key) = stego_key {
+          bits = key.scramble(&bits);
+        }
+        if let Some(positions) = positions {
+          bits = mask::scatter(&bits, positions, depth);
+        }
+        let bits: Vec<f32> = bits.into_iter().map(f32::from).collect();
+        Tensor::from_vec(bits, (1, depth, h, w), &self.device)?
+      }
+    };
+    // The channels the payload leaves out are 0, which the decoder reads as such
+    let unused = self.config.data_depth - depth;
+    if unused == 0 {
+      return Ok(data);
+    }
+    let zeros = Tensor::zeros((1, unused, h, w), data.dtype(), &self.device)?;
+    Ok(Tensor::cat(&[&data, &zeros], 1)?)
+  }
+
+  // Fails with `SteganoError::DecodeFailed` if the image holds no readable payload.
+  pub fn decode(&self, img: &RgbImage) -> Result<Payload> {
+    self.decode_with(img, &DecodeOptions::default())
+  }
+
+  // Same as `decode` for images encoded with a stego key or mask.
+  pub fn decode_with(&self, img: &RgbImage, options: &DecodeOptions) -> Result<Payload> {
+    self.decode_timed(img, options, &mut StageTimes::default())
+  }
+
+  pub fn decode_timed(&self, img: &RgbImage, options: &DecodeOptions, times: &mut StageTimes) -> Result<Payload> {
+    self.decode_frames_timed(std::slice::from_ref(img), options, times)
+  }
+
+  // Decodes the frames of an animation that all carry the same payload. Their logits are summed like those of the
+  // copies within an image, so frames that decode badly on their own still help.
+  pub fn decode_frames(&self, frames: &[RgbImage], options: &DecodeOptions) -> Result<Payload> {
+    self.decode_frames_timed(frames, options, &mut StageTimes::default())
+  }
+
+  fn decode_frames_timed(
+    &self,
+    frames: &[RgbImage],
+    options: &DecodeOptions,
+    times: &mut StageTimes,
+  ) -> Result<Payload> {
+    let mut clock = Instant::now();
+    let (logits, size) = self.raw_logits(frames, &mut clock, times)?;
+    let payload = self.extract(&logits, size, options);
+    lap(&mut clock, &mut times.postprocess);
+    payload
+  }
+
+  // Decodes after undoing each of `Transform::search`, for images that were lightly edited before they were shared.
+  // Of the edits that lead to a payload, the one under which the most Reed-Solomon blocks decode wins.
+  pub fn decode_search(&self, frames: &[RgbImage], options: &DecodeOptions) -> Result<(Transform, Payload)> {
+    let mut best: Option<(f32, Transform, Payload)> = None;
+    for transform in Transform::search() {
+      let frames: Vec<RgbImage> = frames.iter().map(|frame| transform.undo(frame)).collect();
+      let (logits, shape) = match self.logits(&frames, options, &mut Instant::now(), &mut StageTimes::default()) {
+        Ok(logits) => logits,
+        // Nothing to realign under this edit
+        Err(err) if matches!(err.downcast_ref(), Some(SteganoError::DecodeFailed)) => continue,
+        Err(err) => return Err(err),
+      };
+      let Ok(payload) = payload::extract_spread(&logits, shape) else {
+        continue;
+      };
+      let ecc = payload.header.as_ref().map_or(Ecc::Standard, |header| header.ecc);
+      let score = payload::block_success(&logits, ecc);
+      if best.as_ref().is_none_or(|(best, _, _)| score > *best) {
+        best = Some((score, transform, payload));
+      }
+    }
+    let (_, transform, payload) = best.ok_or(SteganoError::DecodeFailed)?;
+    Ok((transform, payload))
+  }
+
+  // Every decoding candidate of an image or of the summed frames of an animation, with diagnostics, instead of only
+  // the best one, see `payload::candidates`.
+  pub fn candidates(&self, frames: &[RgbImage], options: &DecodeOptions) -> Result<Vec<Candidate>> {
+    let (logits, shape) = self.logits(frames, options, &mut Instant::now(), &mut StageTimes::default())?;
+    Ok(payload::candidates(&logits, shape))
+  }
+
+  // Channel bit error rate of the repeated copies in the frames, see `payload::estimate_errors`.
+  pub fn estimate_errors(
+    &self,
+    frames: &[RgbImage],
+    options: &DecodeOptions,
+  ) -> Result<Option<payload::ErrorEstimate>> {
+    let (logits, _) = self.logits(frames, options, &mut Instant::now(), &mut StageTimes::default())?;
+    Ok(payload::estimate_errors(&logits))
+  }
+
+  // Logits with their `(channels, height, width)` layout, a single row with a mask like on encode, or the stream of
+  // one tile with `resync`. Unmasking, unscrambling and realigning the logits counts as postprocessing.
+  fn logits(
+    &self,
+    frames: &[RgbImage],
+    options: &DecodeOptions,
+    clock: &mut Instant,
+    times: &mut StageTimes,
+  ) -> Result<(Vec<f32>, (usize, usize, usize))> {
+    let (logits, size) = self.raw_logits(frames, clock, times)?;
+    let channels = self.used_channels(&logits, options)?;
+    self.realign(&logits, size, channels, options)
+  }
+
+  // Centered decoder logits of all data channels summed over the frames, with the size of the frames.
+  fn raw_logits(
+    &self,
+    frames: &[RgbImage],
+    clock: &mut Instant,
+    times: &mut StageTimes,
+  ) -> Result<(Vec<f32>, (u32, u32))> {
+    let size = frames.first().context("No frames to decode")?.dimensions();
+    ensure!(
+      frames.iter().all(|frame| frame.dimensions() == size),
+      "All frames must have the same size"
+    );
+    self.check_size(size)?;
+    let mut logits: Vec<f32> = Vec::new();
+    for frame in frames {
+      let pixels = image_io::to_tensor(frame, &self.device)?;
+      let img_tensor = self.config.preprocess.decoder_input(&pixels)?;
+      lap(clock, &mut times.preprocess);
+      let mut frame_logits = self.decode_pass(&img_tensor)?.flatten_all()?.to_vec1::<f32>()?;
+      self.config.center_logits(&mut frame_logits);
+      lap(clock, &mut times.forward);
+      match logits.is_empty() {
+        true => logits = frame_logits,
+        false => logits
+          .iter_mut()
+          .zip(frame_logits)
+          .for_each(|(sum, logit)| *sum += logit),
+      }
+    }
+    Ok((logits, size))
+  }
+
+  // Payload of the centered decoder logits of an image of the given size. One laid out in fewer data channels than
+  // the model has is read from those alone, as many as its header records.
+  fn extract(&self, logits: &[f32], size: (u32, u32), options: &DecodeOptions) -> Result<Payload> {
+    let depth = self.config.data_depth;
+    let read = |channels| -> Result<Payload> {
+      let (logits, shape) = self.realign(logits, size, channels, options)?;
+      Ok(payload::extract_spread(&logits, shape)?)
+    };
+    let channels = self.used_channels(logits, options)?;
+    let (payload, channels) = match read(channels) {
+      // A wrong guess, e.g. under a mask that leaves few bits to set in any channel
+      Err(err)
+        if channels < depth
+          && options.channels.is_none()
+          && matches!(err.downcast_ref(), Some(SteganoError::DecodeFailed)) =>
+      {
+        (read(depth)?, depth)
+      }
+      payload => (payload?, channels),
+    };
+    // Copies read from the wrong channels can still decode on their own, the recorded ones give every copy
+    match payload
+      .header
+      .as_ref()
+      .and_then(|header| header.channels)
+      .map(usize::from)
+    {
+      Some(recorded) if recorded != channels && recorded <= depth => Ok(read(recorded).unwrap_or(payload)),
+      _ => Ok(payload),
+    }
+  }
+
+  // `options.channels`, or the data channels before those the decoder reads as 0 throughout, which a payload laid out
+  // in fewer channels than the model has leaves.
+  fn used_channels(&self, logits: &[f32], options: &DecodeOptions) -> Result<usize> {
+    if options.channels.is_some() {
+      return self.channels(options.channels);
+    }
+    let depth = self.config.data_depth;
+    let plane = (logits.len() / depth).max(1);
+    // The coded bits of a payload are far from all 0, even those of a short one repeated
+    let used = logits
+      .chunks(plane)
+      .rposition(|channel| channel.iter().filter(|&&logit| logit > 0.).count() * 100 > channel.len());
+    Ok(used.map_or(depth, |channel| channel + 1))
+  }
+
+  // Unmasks, unscrambles and realigns the logits of the leading `channels` of an image of the given size.
+  fn realign(
+    &self,
+    logits: &[f32],
+    size: (u32, u32),
+    channels: usize,
+    options: &DecodeOptions,
+  ) -> Result<(Vec<f32>, (usize, usize, usize))> {
+    let logits = &logits[..logits.len() / self.config.data_depth * channels];
+    let (logits, shape) = match options.mask {
+      Some(mask) => {
+        let logits = mask::gather(logits, &mask.positions(size)?);
+        let count = logits.len() / channels;
+        (logits, (channels, 1, count))
+      }
+      None => {
+        let (height, width) = tensor_size(size);
+        (logits.to_vec(), (channels, height, width))
+      }
+    };
+    let logits = match options.stego_key {
+      Some(key) => key.unscramble_logits(&logits),
+      None => logits,
+    };
+    if let Some(tiling) = options.resync {
+      let stream = sync::realign(&logits, shape, tiling).ok_or(SteganoError::DecodeFailed)?;
+      let len = stream.len();
+      return Ok((stream, (1, 1, len)));
+    }
+    Ok((logits, shape))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use image::ImageFormat;
+
+  use super::*;
+
+  #[test]
+  fn test_save_round_trip() -> Result<()> {
+    let device = &Device::Cpu;
+    let codec = Codec::load(Path::new("pretrained"), device)?;
+    let cover = RgbImage::from_fn(64, 48, |x, y| {
+      image::Rgb([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8])
+    });
+    let stego = codec.encode(&cover, b"round trip", None)?;
+    let saved = image_io::encode_image(&stego, ImageFormat::Png)?;
+    let loaded = image::load_from_memory(&saved)?.to_rgb8();
+    assert_eq!(loaded, stego);
+
+    // Rounding to 8 bits leaves almost every bit the decoder reads from the encoder output as it is, the pretrained
+    // decoder is close to undecided on the rest
+    let preprocess = codec.config.preprocess;
+    let image = preprocess.encoder_input(&image_io::to_tensor(&cover, device)?)?;
+    let data = payload::tile_tensor(b"round trip", codec.config.data_depth, 48, 64, device)?;
+    let encoded = codec.encoder.forward(&image, &data)?;
+    let bits =
+      |x: &Tensor| -> Result<Vec<u8>> { Ok(codec.decoder.forward(x)?.gt(0.)?.flatten_all()?.to_vec1::<u8>()?) };
+    let expected = bits(&preprocess.encoded_to_decoder(&encoded)?)?;
+    let saved = image_io::encode_image(&image_io::from_tensor(&encoded)?, ImageFormat::Png)?;
+    let loaded = image::load_from_memory(&saved)?.to_rgb8();
+    let decoded = bits(&preprocess.decoder_input(&image_io::to_tensor(&loaded, device)?)?)?;
+    let same = expected.iter().zip(&decoded).filter(|(a, b)| a == b).count();
+    assert!(same as f32 > 0.9 * expected.len() as f32);
+    Ok(())
+  }
+
+  #[test]
+  fn test_tensor_matches_image() -> Result<()> {
+    let device = &Device::Cpu;
+    let codec = Codec::load(Path::new("pretrained"), device)?;
+    // Odd sides, which go through the networks as they are
+    let cover = RgbImage::from_fn(101, 77, |x, y| {
+      image::Rgb([(x * 255 / 101) as u8, (y * 255 / 77) as u8, (x ^ y) as u8])
+    });
+    let stego = codec.encode(&cover, b"tensor", None)?;
+
+    let chw = |img: &RgbImage| -> Result<Tensor> {
+      let x = Tensor::from_vec(img.as_raw().clone(), (77, 101, 3), device)?.permute((2, 0, 1))?;
+      Ok((x.to_dtype(DType::F32)? / 255.)?)
+    };
+    let stego_tensor = codec.encode_tensor(&chw(&cover)?, b"tensor", &EncodeOptions::default())?;
+    assert_eq!(stego_tensor.dims(), [3, 77, 101]);
+    let levels = image_io::quantize(&(stego_tensor.permute((1, 2, 0))? * 255.)?)?;
+    assert_eq!(RgbImage::from_raw(101, 77, levels).unwrap(), stego);
+
+    let decoded = codec.decode_tensor(&chw(&stego)?.unsqueeze(0)?, &DecodeOptions::default())?;
+    assert_eq!(decoded.message, "tensor");
+    assert_eq!(codec.decode(&stego)?.message, "tensor");
+    assert!(codec
+      .decode_tensor(
+        &Tensor::zeros((2, 3, 8, 8), DType::F32, device)?,
+        &DecodeOptions::default()
+      )
+      .is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn test_batches() -> Result<()> {
+    assert_eq!(
+      buckets(&[(48, 64), (480, 640), (50, 64), (64, 48), (48, 64)]),
+      [vec![0, 4, 2], vec![3], vec![1]]
+    );
+
+    let codec = Codec::load(Path::new("pretrained"), &Device::Cpu)?;
+    let cover = |w, h| {
+      RgbImage::from_fn(w, h, |x, y| {
+        image::Rgb([(x * 255 / w) as u8, (y * 255 / h) as u8, (x ^ y) as u8])
+      })
+    };
+    let covers = [cover(96, 72), cover(95, 71), cover(96, 72)];
+    let options = EncodeOptions::default();
+    let items: Vec<_> = covers
+      .iter()
+      .zip([&b"one"[..], b"two", b"three"])
+      .map(|(cover, message)| (cover, message, options))
+      .collect();
+    let stego = codec.encode_images(&items)?;
+    // Same-size covers share a forward pass without padding, which leaves their stego images as they are alone
+    assert_eq!(stego[2], codec.encode(&covers[2], b"three", None)?);
+    assert_eq!(stego[1].dimensions(), (95, 71));
+    let payloads = codec.decode_images(&stego, &DecodeOptions::default())?;
+    for (payload, message) in payloads.into_iter().zip(["one", "two", "three"]) {
+      assert_eq!(payload?.message, message);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_null_payload() -> Result<()> {
+    let codec = Codec::load(Path::new("pretrained"), &Device::Cpu)?;
+    let cover = RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, 128]));
+    let options = |null_payload| EncodeOptions {
+      null_payload: Some(null_payload),
+      seed: Some(1),
+      ..Default::default()
+    };
+    // The message is ignored, so every zero-payload stego image of a cover is the same
+    let zeros = codec.encode_with(&cover, b"one", &options(payload::NullPayload::Zeros))?;
+    assert_eq!(
+      zeros,
+      codec.encode_with(&cover, b"two", &options(payload::NullPayload::Zeros))?
+    );
+    assert_ne!(
+      zeros,
+      codec.encode_with(&cover, b"one", &options(payload::NullPayload::Random))?
+    );
+    // Random bits are reproducible with a seed
+    let random = codec.encode_with(&cover, b"one", &options(payload::NullPayload::Random))?;
+    assert_eq!(
+      random,
+      codec.encode_with(&cover, b"two", &options(payload::NullPayload::Random))?
+    );
+    let reseeded = EncodeOptions {
+      seed: Some(2),
+      ..options(payload::NullPayload::Random)
+    };
+    assert_ne!(random, codec.encode_with(&cover, b"one", &reseeded)?);
+    assert!(codec.decode(&zeros).is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn test_channels() -> Result<()> {
+    let codec = Codec::load(Path::new("pretrained"), &Device::Cpu)?;
+    let depth = codec.config.data_depth;
+    assert!(depth > 2);
+    let cover = RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, 60]));
+    let options = EncodeOptions {
+      channels: Some(2),
+      ..Default::default()
+    };
+    let data = codec.prepare(&cover, b"two data channels", &options)?.data;
+    assert_eq!(data.dims(), [1, depth, 48, 64]);
+    assert_eq!(data.narrow(1, 2, depth - 2)?.sum_all()?.to_scalar::<f32>()?, 0.);
+    let too_many = EncodeOptions {
+      channels: Some(depth + 1),
+      ..Default::default()
+    };
+    assert!(codec.prepare(&cover, b"too many", &too_many).is_err());
+
+    // Decoder logits of a perfect read of the first two channels
+    let header = payload::Header {
+      size: (64, 48),
+      channels: Some(2),
+      ..Default::default()
+    };
+    let bits = payload::tile(&payload::pack(&header, b"two data channels"), 2, 48, 64)?;
+    let mut logits: Vec<f32> = bits.iter().map(|&bit| if bit == 1 { 1. } else { -1. }).collect();
+    logits.resize(depth * 48 * 64, -1.);
+    let decode = DecodeOptions::default();
+    assert_eq!(codec.used_channels(&logits, &decode)?, 2);
+    let payload = codec.extract(&logits, (64, 48), &decode)?;
+    assert_eq!(payload.message, "two data channels");
+    assert_eq!(payload.header.and_then(|header| header.channels), Some(2));
+    let forced = DecodeOptions {
+      channels: Some(2),
+      ..Default::default()
+    };
+    assert_eq!(codec.extract(&logits, (64, 48), &forced)?.message, "two data channels");
+
+    // A short message takes one channel with every copy, a long one all of them
+    let variable = EncodeOptions {
+      variable_rate: true,
+      ..Default::default()
+    };
+    let large = RgbImage::from_fn(160, 120, |x, y| image::Rgb([(x * 2) as u8, (y * 2) as u8, 60]));
+    let data = codec.prepare(&large, b"short", &variable)?.data;
+    assert!(data.narrow(1, 0, 1)?.sum_all()?.to_scalar::<f32>()? > 0.);
+    assert_eq!(data.narrow(1, 1, depth - 1)?.sum_all()?.to_scalar::<f32>()?, 0.);
+    let (packed, channels) = codec.pack(header.clone(), b"short", &variable, 120 * 160)?;
+    assert_eq!(channels, 1);
+    let read = payload::extract(&payload::tile(&packed, 1, 120, 160)?)?;
+    assert_eq!(
+      (read.message.as_str(), read.header.and_then(|header| header.channels)),
+      ("short", Some(1))
+    );
+    let long: Vec<u8> = (0..1000u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+    assert_eq!(codec.pack(header, &long, &variable, 120 * 160)?.1, depth);
+    Ok(())
+  }
+
+  #[test]
+  fn test_max_memory() -> Result<()> {
+    let mut codec = Codec::load(Path::new("pretrained"), &Device::Cpu)?;
+    let cover = RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, (x ^ y) as u8]));
+    let full = codec.encode(&cover, b"tiles", None)?;
+    let unsplit = codec.decode(&full)?;
+    assert_eq!(unsplit.message, "tiles");
+    let per_pixel = (codec.config.activations_per_pixel() * 4) as u64;
+
+    // Room for a 40x40 pass, which leaves tiles of 32x32 next to their margins
+    codec.set_max_memory(Some(codec.weight_bytes + 40 * 40 * per_pixel));
+    assert_eq!(codec.plan((48, 64))?.tile, Some(40 - 2 * codec.config.tile_margin().0));
+    // The margins cover the receptive field of the dense model, so the tiles add up to a pass over the whole image
+    let tiled = codec.encode(&cover, b"tiles", None)?;
+    assert_eq!(tiled, full);
+    let payloads = codec.decode_images(&[full.clone(), tiled], &DecodeOptions::default())?;
+    for payload in payloads {
+      assert_eq!(payload?, unsplit);
+    }
+
+    codec.set_max_memory(Some(codec.weight_bytes + 64 * 48 * 3 / 2 * per_pixel));
+    assert_eq!(codec.plan((48, 64))?.batch, 1);
+    codec.set_max_memory(Some(codec.weight_bytes));
+    assert!(codec.encode(&cover, b"tiles", None).is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn test_resync_crop() -> Result<()> {
+    let codec = Codec::load(Path::new("pretrained"), &Device::Cpu)?;
+    let mut rng = rng::from_seed(Some(7));
+    let cover = RgbImage::from_fn(320, 320, |x, y| {
+      image::Rgb([(x * 3 + y) as u8, (y * 2) as u8, rng.gen_range(100..160)])
+    });
+    let options = EncodeOptions {
+      spread: payload::Spread::Tiles,
+      ..Default::default()
+    };
+    let stego = codec.encode_with(&cover, b"cropped", &options)?;
+    let png = image::load_from_memory(&image_io::encode_image(&stego, ImageFormat::Png)?)?.to_rgb8();
+    // A crop that starts inside a tile and keeps two by two tiles of pixels
+    let cropped = imageops::crop_imm(&png, 37, 61, 256, 256).to_image();
+    let resync = DecodeOptions {
+      resync: Some(sync::Tiling::Plain),
+      ..Default::default()
+    };
+    assert_eq!(codec.decode_with(&cropped, &resync)?.message, "cropped");
+    Ok(())
+  }
+
+  #[test]
+  fn test_odd_size_spreads() -> Result<()> {
+    let codec = Codec::load(Path::new("pretrained"), &Device::Cpu)?;
+    // Odd sides, which the logits keep, and room for two by two tiles
+    let cover = RgbImage::from_fn(289, 263, |x, y| {
+      image::Rgb([(x * 255 / 289) as u8, (y * 255 / 263) as u8, (x ^ y) as u8])
+    });
+    let options = EncodeOptions {
+      spread: payload::Spread::Fountain,
+      ..Default::default()
+    };
+    let stego = codec.encode_with(&cover, b"fountain", &options)?;
+    assert_eq!(codec.decode(&stego)?.message, "fountain");
+
+    let options = EncodeOptions {
+      spread: payload::Spread::Tiles,
+      ..Default::default()
+    };
+    let stego = codec.encode_with(&cover, b"tiles", &options)?;
+    let resync = DecodeOptions {
+      resync: Some(sync::Tiling::Plain),
+      ..Default::default()
+    };
+    assert_eq!(codec.decode_with(&stego, &resync)?.message, "tiles");
+    Ok(())
+  }
+
+  #[test]
+  fn test_clean_cover() -> Result<()> {
+    let codec = Codec::load(Path::new("pretrained"), &Device::Cpu)?;
+    let mut rng = rng::from_seed(Some(3));
+    let covers = [
+      RgbImage::from_fn(320, 320, |x, y| image::Rgb([(x * 3 + y) as u8, (y * 2) as u8, 128])),
+      RgbImage::from_fn(101, 77, |x, y| {
+        image::Rgb([(x * 255 / 101) as u8, (y * 255 / 77) as u8, (x ^ y) as u8])
+      }),
+      RgbImage::from_fn(200, 150, |_, _| image::Rgb(rng.gen())),
+    ];
+    // Nothing was hidden, so nothing reads out of the noise of the decoder
+    for cover in covers {
+      let err = codec.decode(&cover).unwrap_err();
+      assert!(matches!(err.downcast_ref(), Some(SteganoError::DecodeFailed)), "{err}");
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_fallback() -> Result<()> {
+    let codec = Codec::load(Path::new("pretrained"), &Device::Cpu)?;
+    let cover = RgbImage::from_fn(32, 24, |x, y| image::Rgb([(x * 8) as u8, (y * 10) as u8, 60]));
+    let image = image_io::to_tensor(&cover, &Device::Cpu)?;
+    // The CPU copies give the same logits as the networks on the device
+    let logits = codec.decode_pass(&image)?;
+    let fallback = codec.fallback()?.1.forward(&image)?;
+    assert_eq!((logits - fallback)?.abs()?.max_all()?.to_scalar::<f32>()?, 0.);
+
+    codec.warn("out of memory".to_string());
+    assert_eq!(codec.take_warnings(), ["out of memory"]);
+    assert!(codec.take_warnings().is_empty());
+    Ok(())
+  }
+}
@@ -0,0 +1,78 @@
+use anyhow::Result;
+use candle_core::Tensor;
+
+// Chroma is capped so that a residual with almost no chroma to start with is not blown up.
+const MAX_CHROMA_GAIN: f32 = 4.;
+
+// BT.601 full range, the transform of JPEG.
+const RGB_TO_YCBCR: [[f32; 3]; 3] = [
+  [0.299, 0.587, 0.114],
+  [-0.168736, -0.331264, 0.5],
+  [0.5, -0.418688, -0.081312],
+];
+const YCBCR_TO_RGB: [[f32; 3]; 3] = [[1., 0., 1.402], [1., -0.344136, -0.714136], [1., 1.772, 0.]];
+
+// Multiplies the channels of every pixel of a (n, 3, h, w) tensor by `matrix`.
+fn transform(x: &Tensor, matrix: &[[f32; 3]; 3]) -> Result<Tensor> {
+  let (n, c, h, w) = x.dims4()?;
+  let matrix = Tensor::new(matrix, x.device())?.to_dtype(x.dtype())?;
+  let pixels = x.reshape((n, c, h * w))?;
+  Ok(
+    matrix
+      .broadcast_left(n)?
+      .contiguous()?
+      .matmul(&pixels)?
+      .reshape((n, c, h, w))?,
+  )
+}
+
+pub fn rgb_to_ycbcr(x: &Tensor) -> Result<Tensor> {
+  transform(x, &RGB_TO_YCBCR)
+}
+
+pub fn ycbcr_to_rgb(x: &Tensor) -> Result<Tensor> {
+  transform(x, &YCBCR_TO_RGB)
+}
+
+// Moves an encoder residual from luma into chroma, where the eye is much less sensitive: luma is scaled by
+// `luma_weight` and both chroma channels are boosted to keep the total energy, at most by `MAX_CHROMA_GAIN`.
+pub fn shift_to_chroma(residual: &Tensor, luma_weight: f32) -> Result<Tensor> {
+  let ycbcr = rgb_to_ycbcr(residual)?;
+  let luma = ycbcr.narrow(1, 0, 1)?;
+  let chroma = ycbcr.narrow(1, 1, 2)?;
+  let energy = |x: &Tensor| -> Result<f32> { Ok(x.sqr()?.sum_all()?.to_scalar::<f32>()?) };
+  let (luma_energy, chroma_energy) = (energy(&luma)?, energy(&chroma)?);
+  let gain = match chroma_energy > 0. {
+    true => ((luma_energy * (1. - luma_weight.powi(2)) + chroma_energy) / chroma_energy)
+      .sqrt()
+      .min(MAX_CHROMA_GAIN),
+    false => 1.,
+  };
+  let ycbcr = Tensor::cat(&[(luma * luma_weight as f64)?, (chroma * gain as f64)?], 1)?;
+  ycbcr_to_rgb(&ycbcr)
+}
+
+#[cfg(test)]
+mod tests {
+  use candle_core::Device;
+
+  use super::*;
+
+  #[test]
+  fn test_shift_to_chroma() -> Result<()> {
+    let values: Vec<f32> = (0..48).map(|i| ((i * 37) % 11) as f32 / 10. - 0.5).collect();
+    let residual = Tensor::from_vec(values, (1, 3, 4, 4), &Device::Cpu)?;
+    let roundtrip = ycbcr_to_rgb(&rgb_to_ycbcr(&residual)?)?;
+    assert!((roundtrip - &residual)?.abs()?.max_all()?.to_scalar::<f32>()? < 1e-4);
+
+    let energy = |x: &Tensor| -> Result<f32> { Ok(rgb_to_ycbcr(x)?.sqr()?.sum_all()?.to_scalar::<f32>()?) };
+    let shifted = shift_to_chroma(&residual, 0.)?;
+    let luma = rgb_to_ycbcr(&shifted)?.narrow(1, 0, 1)?;
+    assert!(luma.abs()?.max_all()?.to_scalar::<f32>()? < 1e-4);
+    assert!((energy(&shifted)? - energy(&residual)?).abs() < 1e-3 * energy(&residual)?);
+
+    let unchanged = shift_to_chroma(&residual, 1.)?;
+    assert!((unchanged - &residual)?.abs()?.max_all()?.to_scalar::<f32>()? < 1e-4);
+    Ok(())
+  }
+}
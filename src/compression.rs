@@ -0,0 +1,105 @@
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+// Algorithm the packed payload is compressed with before error correction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+  None,
+  #[default]
+  Deflate,
+  Zstd,
+  Brotli,
+}
+
+impl Compression {
+  pub(crate) fn id(self) -> u8 {
+    self as u8
+  }
+
+  pub(crate) fn from_id(id: u8) -> Option<Self> {
+    [Self::None, Self::Deflate, Self::Zstd, Self::Brotli]
+      .into_iter()
+      .find(|compression| compression.id() == id)
+  }
+
+  // Levels accepted by the algorithm and the one used if none is given.
+  fn levels(self) -> (u32, u32, u32) {
+    match self {
+      Self::None => (0, 0, 0),
+      Self::Deflate => (0, 10, 6),
+      Self::Zstd => (1, 22, 3),
+      Self::Brotli => (0, 11, 11),
+    }
+  }
+
+  // Compresses at `level`, clamped to the range of the algorithm.
+  pub fn compress(self, data: &[u8], level: Option<u32>) -> std::io::Result<Vec<u8>> {
+    let (min, max, default) = self.levels();
+    let level = level.unwrap_or(default).clamp(min, max);
+    Ok(match self {
+      Self::None => data.to_vec(),
+      Self::Deflate => miniz_oxide::deflate::compress_to_vec(data, level as u8),
+      Self::Zstd => zstd::bulk::compress(data, level as i32)?,
+      Self::Brotli => {
+        let mut compressed = Vec::new();
+        let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, level, 22);
+        writer.write_all(data)?;
+        drop(writer);
+        compressed
+      }
+    })
+  }
+
+  // Decompresses to at most `limit` bytes, `None` for data that would go beyond, so that a few bytes read from an image
+  // cannot expand into gigabytes, or that is not a whole stream: noise often inflates to a few bytes before deflate
+  // fails on it.
+  pub fn decompress(self, data: &[u8], limit: usize) -> Option<Vec<u8>> {
+    match self {
+      Self::None => (data.len() <= limit).then(|| data.to_vec()),
+      Self::Deflate => miniz_oxide::inflate::decompress_to_vec_with_limit(data, limit).ok(),
+      Self::Zstd => zstd::bulk::decompress(data, limit).ok(),
+      Self::Brotli => {
+        let mut decompressed = Vec::new();
+        brotli::Decompressor::new(data, 4096)
+          .take(limit as u64 + 1)
+          .read_to_end(&mut decompressed)
+          .ok()?;
+        (decompressed.len() <= limit).then_some(decompressed)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_roundtrip() {
+    let data = "a fairly long secret message ".repeat(8);
+    for compression in [
+      Compression::None,
+      Compression::Deflate,
+      Compression::Zstd,
+      Compression::Brotli,
+    ] {
+      for level in [None, Some(0), Some(100)] {
+        let compressed = compression.compress(data.as_bytes(), level).unwrap();
+        assert_eq!(
+          compression.decompress(&compressed, data.len()).unwrap(),
+          data.as_bytes()
+        );
+        // One byte short of the data
+        assert!(compression.decompress(&compressed, data.len() - 1).is_none());
+      }
+      assert_eq!(Compression::from_id(compression.id()), Some(compression));
+    }
+    assert!(Compression::Zstd.decompress(b"garbage", 100).is_none());
+    let compressed = Compression::Deflate.compress(data.as_bytes(), None).unwrap();
+    assert!(Compression::Deflate
+      .decompress(&compressed[..compressed.len() / 2], data.len())
+      .is_none());
+  }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use clap::Command;
+use toml::{Table, Value};
+
+// Defaults for command line flags from `steganogan.toml`. Top-level keys apply to every subcommand with a flag of that
+// name, tables to one subcommand and its own subcommands:
+//
+// model = "models/finetuned"
+// device = "cpu"
+//
+// [encode]
+// compress = "zstd"
+// max-delta = 4
+//
+// Flags given on the command line still take precedence.
+#[derive(Debug, Default)]
+pub struct Config(Table);
+
+impl Config {
+  // Reads `--config`, or `steganogan-rs/steganogan.toml` in the user config directory if it exists.
+  pub fn load(args: &[OsString]) -> Result<Self> {
+    let path = match explicit_path(args) {
+      Some(path) => path,
+      None => match dirs::config_dir().map(|dir| dir.join("steganogan-rs").join("steganogan.toml")) {
+        Some(path) if path.exists() => path,
+        _ => return Ok(Self::default()),
+      },
+    };
+    let text = std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let table = text
+      .parse::<Table>()
+      .with_context(|| format!("Invalid config {}", path.display()))?;
+    Ok(Self(table))
+  }
+
+  // Sets the configured values as the defaults of the flags, so that clap validates them like any other value.
+  pub fn apply(&self, command: Command) -> Result<Command> {
+    let mut known = HashSet::new();
+    collect_ids(&command, &mut known);
+    check_keys(&self.0, &command, &known)?;
+    Ok(apply(command, &Table::new(), &self.0))
+  }
+}
+
+// `--config` has to be known before the arguments are parsed.
+fn explicit_path(args: &[OsString]) -> Option<PathBuf> {
+  let mut args = args.iter().map(|arg| arg.to_string_lossy());
+  while let Some(arg) = args.next() {
+    if arg == "--config" {
+      return args.next().map(|path| PathBuf::from(path.as_ref()));
+    }
+    if let Some(path) = arg.strip_prefix("--config=") {
+      return Some(PathBuf::from(path));
+    }
+  }
+  None
+}
+
+fn id(key: &str) -> String {
+  key.replace('-', "_")
+}
+
+fn collect_ids(command: &Command, ids: &mut HashSet<String>) {
+  ids.extend(command.get_arguments().map(|arg| arg.get_id().to_string()));
+  for subcommand in command.get_subcommands() {
+    collect_ids(subcommand, ids);
+  }
+}
+
+// Rejects misspelled keys, which would otherwise be silently ignored.
+fn check_keys(table: &Table, command: &Command, known: &HashSet<String>) -> Result<()> {
+  for (key, value) in table {
+    match value {
+      Value::Table(table) => match command.find_subcommand(key) {
+        Some(subcommand) => check_keys(table, subcommand, known)?,
+        None => bail!("Unknown subcommand [{key}] in the config"),
+      },
+      _ if !known.contains(&id(key)) => bail!("Unknown flag {key} in the config"),
+      _ => {}
+    }
+  }
+  Ok(())
+}
+
+fn apply(mut command: Command, inherited: &Table, table: &Table) -> Command {
+  let mut values = inherited.clone();
+  values.extend(
+    table
+      .iter()
+      .filter(|(_, value)| !value.is_table())
+      .map(|(k, v)| (k.clone(), v.clone())),
+  );
+  for (key, value) in &values {
+    let id = id(key);
+    if command.get_arguments().any(|arg| arg.get_id() == id.as_str()) {
+      let value = match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+      };
+      command = command.mut_arg(id, |arg| arg.default_value(value));
+    }
+  }
+  let names: Vec<String> = command.get_subcommands().map(|c| c.get_name().to_string()).collect();
+  for name in names {
+    let table = table.get(&name).and_then(Value::as_table).cloned().unwrap_or_default();
+    command = command.mut_subcommand(name, |subcommand| apply(subcommand, &values, &table));
+  }
+  command
+}
+
+#[cfg(test)]
+mod tests {
+  use clap::Arg;
+
+  use super::*;
+
+  #[test]
+  fn test_apply() -> Result<()> {
+    let command = || {
+      Command::new("test")
+        .arg(Arg::new("device").long("device").default_value("auto"))
+        .subcommand(Command::new("encode").arg(Arg::new("model").short('m').default_value("pretrained")))
+        .subcommand(Command::new("decode").arg(Arg::new("model").short('m').default_value("pretrained")))
+    };
+    let config = Config("device = 'cpu'\nmodel = 'a'\n[decode]\nmodel = 'b'\n".parse()?);
+    let matches = config.apply(command())?.get_matches_from(["test", "encode"]);
+    assert_eq!(matches.get_one::<String>("device").unwrap(), "cpu");
+    let model = |args: &[&str]| -> Result<String> {
+      let matches = config.apply(command())?.get_matches_from(args);
+      let (_, matches) = matches.subcommand().unwrap();
+      Ok(matches.get_one::<String>("model").unwrap().clone())
+    };
+    assert_eq!(model(&["test", "encode"])?, "a");
+    assert_eq!(model(&["test", "decode"])?, "b");
+    assert_eq!(model(&["test", "decode", "-m", "c"])?, "c");
+
+    assert!(Config("max-delta = 3".parse()?).apply(command()).is_err());
+    assert!(Config("[embed]\nmodel = 'a'".parse()?).apply(command()).is_err());
+    assert_eq!(
+      explicit_path(&["x".into(), "--config=a.toml".into()]),
+      Some("a.toml".into())
+    );
+    Ok(())
+  }
+}
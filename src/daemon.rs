@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use candle_core::Device;
+use serde::{Deserialize, Serialize};
+use steganogan_rs::codec::Codec;
+use steganogan_rs::zoo;
+
+use crate::{remote, DecodeArgs, EncodeArgs};
+
+const SOCKET_ENV: &str = "STEGANOGAN_SOCKET";
+// Requests are handled one at a time, so a client that connects and sends nothing holds up the others this long.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Socket of the `serve` daemon, `$STEGANOGAN_SOCKET` or `steganogan-rs.sock` in the runtime directory, which only the
+// user can enter. Without one it goes into a directory of the user's own under the temporary directory, see
+// `private_dir`.
+pub fn socket_path() -> PathBuf {
+  match std::env::var_os(SOCKET_ENV) {
+    Some(path) => PathBuf::from(path),
+    None => dirs::runtime_dir()
+      .unwrap_or_else(private_dir)
+      .join("steganogan-rs.sock"),
+  }
+}
+
+#[cfg(unix)]
+fn uid() -> u32 {
+  // SAFETY: getuid has no preconditions and cannot fail
+  unsafe { libc::getuid() }
+}
+
+// `steganogan-rs-<uid>` in the temporary directory, which `serve` creates with mode 0700.
+#[cfg(unix)]
+fn private_dir() -> PathBuf {
+  std::env::temp_dir().join(format!("steganogan-rs-{}", uid()))
+}
+
+#[cfg(not(unix))]
+fn private_dir() -> PathBuf {
+  std::env::temp_dir()
+}
+
+// Text a command prints, returned instead of printed so that the daemon can send it back to the client.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Output {
+  pub stdout: String,
+  pub stderr: String,
+  /// URL printed after stdout, as a hyperlink if stdout is a terminal
+  pub link: Option<String>,
+  /// Text the client copies to the clipboard
+  pub clipboard: Option<String>,
+}
+
+impl Output {
+  pub fn print(&self) {
+    print!("{}", self.stdout);
+    if let Some(url) = &self.link {
+      match std::io::stdout().is_terminal() {
+        true => println!("\x1b]8;;{url}\x1b\\{url}\x1b]8;;\x1b\\"),
+        false => println!("{url}"),
+      }
+    }
+    eprint!("{}", self.stderr);
+  }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Request {
+  Encode(EncodeArgs),
+  Decode(DecodeArgs),
+}
+
+impl Request {
+  pub fn run(self, models: &mut Models) -> Result<Output> {
+    let mut output = match self {
+      Request::Encode(args) => crate::encode(args, models),
+      Request::Decode(args) => crate::decode(args, models),
+    }?;
+    for warning in models.codecs.values().flat_map(Codec::take_warnings) {
+      output.stderr += &format!("warning: {warning}\n");
+    }
+    Ok(output)
+  }
+
+  // The daemon has its own working directory, so relative paths are resolved by the client.
+  fn absolute(&self) -> Result<Self> {
+    let cwd = std::env::current_dir()?;
+    let model = |model: &String| {
+      if Path::new(model).exists() {
+        cwd.join(model).display().to_string()
+      } else {
+        model.clone()
+      }
+    };
+    let path = |path: &Option<PathBuf>| {
+      path.as_ref().map(|path| {
+        if remote::is_url(path) {
+          path.clone()
+        } else {
+          cwd.join(path)
+        }
+      })
+    };
+    Ok(match self {
+      Request::Encode(args) => Request::Encode(EncodeArgs {
+        input: path(&args.input),
+        output: path(&args.output),
+        input_dir: path(&args.input_dir),
+        output_dir: path(&args.output_dir),
+        data_file: path(&args.data_file),
+        model: model(&args.model),
+        sign_key: path(&args.sign_key),
+        mask: path(&args.mask),
+        manifest: path(&args.manifest),
+        registry: path(&args.registry),
+        ..args.clone()
+      }),
+      Request::Decode(args) => Request::Decode(DecodeArgs {
+        input: path(&args.input),
+        input_dir: path(&args.input_dir),
+        output: path(&args.output),
+        model: model(&args.model),
+        verify_key: path(&args.verify_key),
+        mask: path(&args.mask),
+        save_dir: cwd.join(&args.save_dir),
+        manifest: path(&args.manifest),
+        registry: path(&args.registry),
+        ..args.clone()
+      }),
+    })
+  }
+}
+
+// Loaded models by path, kept for the lifetime of the process.
+pub struct Models {
+  device: Device,
+  /// Device memory limit of every codec, see `Codec::set_max_memory`
+  max_memory: Option<u64>,
+  codecs: HashMap<PathBuf, Codec>,
+}
+
+impl Models {
+  pub fn new(device: &Device, max_memory: Option<u64>) -> Self {
+    Self {
+      device: device.clone(),
+      max_memory,
+      codecs: HashMap::new(),
+    }
+  }
+
+  pub fn get(&mut self, model: &str) -> Result<&Codec> {
+    let key = match zoo::is_embedded(model) {
+      true => PathBuf::from(model),
+      false => zoo::resolve(model)?,
+    };
+    if !self.codecs.contains_key(&key) {
+      let mut codec = Codec::open(model, &self.device)?;
+      codec.set_max_memory(self.max_memory);
+      self.codecs.insert(key.clone(), codec);
+    }
+    Ok(&self.codecs[&key])
+  }
+}
+
+type Response = std::result::Result<Output, String>;
+
+// Requests and responses are single JSON lines, one request per connection.
+fn handle<S: std::io::Read + Write>(stream: S, models: &mut Models) -> Result<()> {
+  let mut reader = BufReader::new(stream);
+  let mut line = String::new();
+  reader.read_line(&mut line)?;
+  let request: Request = serde_json::from_str(&line)?;
+  let response: Response = request.run(models).map_err(|err| format!("{err:#}"));
+  let mut stream = reader.into_inner();
+  writeln!(stream, "{}", serde_json::to_string(&response)?)?;
+  Ok(())
+}
+
+// Listens on `socket` with mode 0600, creating the private directory of `socket_path` if that is where it goes. Other
+// users can neither connect nor put a socket of their own in its place.
+#[cfg(unix)]
+fn bind(socket: &Path) -> Result<std::os::unix::net::UnixListener> {
+  use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+  use std::os::unix::net::{UnixListener, UnixStream};
+
+  let private = private_dir();
+  if socket.parent() == Some(private.as_path()) {
+    std::fs::DirBuilder::new()
+      .mode(0o700)
+      .recursive(true)
+      .create(&private)?;
+    let metadata = std::fs::metadata(&private)?;
+    if metadata.uid() != uid() || metadata.mode() & 0o077 != 0 {
+      return Err(anyhow!(
+        "{} must belong to this user only, with mode 0700",
+        private.display()
+      ));
+    }
+  }
+  if socket.exists() {
+    if UnixStream::connect(socket).is_ok() {
+      return Err(anyhow!("A daemon is already listening on {}", socket.display()));
+    }
+    std::fs::remove_file(socket)?;
+  }
+  let listener = UnixListener::bind(socket)?;
+  std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+  Ok(listener)
+}
+
+#[cfg(unix)]
+pub fn serve(socket: &Path, models: &mut Models) -> Result<()> {
+  let listener = bind(socket)?;
+  eprintln!("listening on {}", socket.display());
+  for stream in listener.incoming() {
+    if let Err(err) = stream.map_err(anyhow::Error::from).and_then(|stream| {
+      stream.set_read_timeout(Some(READ_TIMEOUT))?;
+      handle(stream, models)
+    }) {
+      eprintln!("request failed: {err:#}");
+    }
+  }
+  Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn serve(_socket: &Path, _models: &mut Models) -> Result<()> {
+  Err(anyhow!("The daemon needs Unix domain sockets"))
+}
+
+// Runs the request on the daemon, or returns None if no daemon is listening. Fails for a socket of another user, who
+// would get to read the request and answer it.
+#[cfg(unix)]
+pub fn delegate(socket: &Path, request: &Request) -> Result<Option<Output>> {
+  use std::os::unix::fs::MetadataExt;
+
+  let Ok(mut stream) = std::os::unix::net::UnixStream::connect(socket) else {
+    return Ok(None);
+  };
+  if std::fs::metadata(socket)?.uid() != uid() {
+    return Err(anyhow!(
+      "{} belongs to another user, stop that daemon or pass --no-daemon",
+      socket.display()
+    ));
+  }
+  writeln!(stream, "{}", serde_json::to_string(&request.absolute()?)?)?;
+  let mut line = String::new();
+  BufReader::new(stream).read_line(&mut line)?;
+  let response: Response = serde_json::from_str(&line)?;
+  response.map(Some).map_err(|err| anyhow!(err))
+}
+
+#[cfg(not(unix))]
+pub fn delegate(_socket: &Path, _request: &Request) -> Result<Option<Output>> {
+  Ok(None)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use std::os::unix::fs::PermissionsExt;
+
+  use super::*;
+
+  #[test]
+  fn test_delegate() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let socket = dir.join("test.sock");
+    let _ = std::fs::remove_file(&socket);
+    let listener = bind(&socket)?;
+    assert_eq!(std::fs::metadata(&socket)?.permissions().mode() & 0o777, 0o600);
+    let server = std::thread::spawn(move || -> Result<()> {
+      let mut models = Models::new(&Device::Cpu, None);
+      handle(listener.accept()?.0, &mut models)
+    });
+
+    let image = dir.join("blank.png");
+    image::RgbImage::new(32, 32).save(&image)?;
+    let request = Request::Decode(DecodeArgs {
+      input: Some(image),
+      input_dir: None,
+      watch: None,
+      output: None,
+      jobs: None,
+      batch_size: 1,
+      fail_fast: false,
+      manifest: None,
+      model: "pretrained".to_string(),
+      verify_key: None,
+      key: None,
+      save_dir: dir.to_path_buf(),
+      all_candidates: false,
+      to_clipboard: false,
+      mask: None,
+      resync: false,
+      profile: None,
+      channels: None,
+      search_transforms: false,
+      registry: None,
+      error_rate: false,
+    });
+    let output = delegate(&socket, &request)?.unwrap();
+    server.join().unwrap()?;
+    assert_eq!(output.stdout, "No data found\n");
+    Ok(())
+  }
+}
@@ -0,0 +1,192 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use anyhow::{bail, Result};
+use candle_core::{Device, Tensor};
+use image::imageops::{self, FilterType};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+#[derive(Debug, Clone)]
+pub struct DataOptions {
+  pub image_size: u32,
+  pub batch_size: usize,
+  pub augment: bool,
+  pub prefetch: usize,
+}
+
+pub struct Dataset {
+  images: Vec<PathBuf>,
+  options: DataOptions,
+}
+
+impl Dataset {
+  pub fn open(dir: &Path, options: DataOptions) -> Result<Self> {
+    let images = list_images(dir)?;
+    Ok(Self { images, options })
+  }
+
+  // Holds out a fixed random subset of images for validation, without augmentation.
+  pub fn split(self, val_fraction: f32) -> Result<(Self, Self)> {
+    let mut images = self.images;
+    let n_val = (images.len() as f32 * val_fraction).ceil() as usize;
+    if n_val == 0 || n_val >= images.len() {
+      bail!("Cannot hold out {n_val} of {} images for validation", images.len());
+    }
+    images.shuffle(&mut StdRng::seed_from_u64(0));
+    let val_images = images.split_off(images.len() - n_val);
+    let val_options = DataOptions {
+      augment: false,
+      ..self.options.clone()
+    };
+    Ok((
+      Self {
+        images,
+        options: self.options,
+      },
+      Self {
+        images: val_images,
+        options: val_options,
+      },
+    ))
+  }
+
+  pub fn len(&self) -> usize {
+    self.images.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.images.is_empty()
+  }
+
+  pub fn batches(&self, seed: u64, device: &Device) -> Batches {
+    let (sender, receiver) = mpsc::sync_channel(self.options.prefetch);
+    let mut images = self.images.clone();
+    let options = self.options.clone();
+    thread::spawn(move || {
+      let mut rng = StdRng::seed_from_u64(seed);
+      if options.augment {
+        images.shuffle(&mut rng);
+      }
+      for chunk in images.chunks(options.batch_size) {
+        let batch = chunk
+          .iter()
+          .map(|path| load_sample(path, &options, &mut rng))
+          .collect::<Result<Vec<_>>>()
+          .map(|samples| (samples.concat(), samples.len()));
+        if sender.send(batch).is_err() {
+          break;
+        }
+      }
+    });
+    Batches {
+      receiver,
+      image_size: self.options.image_size as usize,
+      device: device.clone(),
+    }
+  }
+}
+
+pub fn list_images(dir: &Path) -> Result<Vec<PathBuf>> {
+  let mut images: Vec<PathBuf> = std::fs::read_dir(dir)?
+    .filter_map(|entry| Some(entry.ok()?.path()))
+    .filter(|path| image::ImageFormat::from_path(path).is_ok())
+    .collect();
+  images.sort();
+  if images.is_empty() {
+    bail!("No images found in {}", dir.display());
+  }
+  Ok(images)
+}
+
+pub struct Batches {
+  receiver: mpsc::Receiver<Result<(Vec<f32>, usize)>>,
+  image_size: usize,
+  device: Device,
+}
+
+impl Iterator for Batches {
+  type Item = Result<Tensor>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let batch = self.receiver.recv().ok()?;
+    Some(batch.and_then(|(data, n)| {
+      Ok(Tensor::from_vec(
+        data,
+        (n, 3, self.image_size, self.image_size),
+        &self.device,
+      )?)
+    }))
+  }
+}
+
+fn load_sample(path: &Path, options: &DataOptions, rng: &mut StdRng) -> Result<Vec<f32>> {
+  let size = options.image_size;
+  let mut img = image::open(path)?;
+  if !options.augment || img.width() < size || img.height() < size {
+    img = img.resize_to_fill(size, size, FilterType::Triangle);
+  }
+  let (x, y) = (
+    rng.gen_range(0..=img.width() - size),
+    rng.gen_range(0..=img.height() - size),
+  );
+  let mut img = imageops::crop_imm(&img.to_rgb8(), x, y, size, size).to_image();
+
+  let (mut gain, mut bias) = (1f32, 0f32);
+  if options.augment {
+    if rng.gen_bool(0.5) {
+      imageops::flip_horizontal_in_place(&mut img);
+    }
+    if rng.gen_bool(0.5) {
+      imageops::flip_vertical_in_place(&mut img);
+    }
+    gain = rng.gen_range(0.9..1.1);
+    bias = rng.gen_range(-0.05..0.05);
+  }
+
+  let n = (size * size) as usize;
+  let mut sample = vec![0f32; 3 * n];
+  for (i, pixel) in img.pixels().enumerate() {
+    for c in 0..3 {
+      sample[c * n + i] = ((pixel[c] as f32 / 127.5 - 1.) * gain + bias).clamp(-1., 1.);
+    }
+  }
+  Ok(sample)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_batches() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    for i in 0..3 {
+      image::RgbImage::from_pixel(40 + i * 8, 24, image::Rgb([i as u8 * 100, 0, 255]))
+        .save(dir.join(format!("{i}.png")))?;
+    }
+    let options = DataOptions {
+      image_size: 16,
+      batch_size: 2,
+      augment: true,
+      prefetch: 1,
+    };
+    let dataset = Dataset::open(dir, options)?;
+    assert_eq!(dataset.len(), 3);
+    let batches = dataset.batches(0, &Device::Cpu).collect::<Result<Vec<_>>>()?;
+    assert_eq!(batches.len(), 2);
+    assert_eq!(batches[0].dims(), [2, 3, 16, 16]);
+    assert_eq!(batches[1].dims(), [1, 3, 16, 16]);
+    let max = batches[0].abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()?;
+    assert!(max <= 1.);
+
+    let (train, val) = dataset.split(0.2)?;
+    assert_eq!((train.len(), val.len()), (2, 1));
+    assert!(!val.options.augment);
+    assert!(train.split(0.).is_err());
+    Ok(())
+  }
+}
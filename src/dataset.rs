@@ -0,0 +1,256 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use image::{ImageFormat, RgbImage};
+use rand::rngs::StdRng;
+use rand::Rng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use steganogan_rs::codec::{Codec, EncodeOptions};
+use steganogan_rs::compression::Compression;
+use steganogan_rs::payload::{self, Header, NullPayload, PayloadType};
+use steganogan_rs::utils::to_hex;
+use steganogan_rs::{data, image_io, rng};
+
+use crate::{pipeline, GenDatasetArgs};
+
+// One image of a generated dataset.
+#[derive(Debug, Serialize)]
+struct Sample {
+  /// Path relative to the dataset directory
+  image: String,
+  /// 0 for a cover, 1 for a stego image
+  label: u8,
+  /// Cover of the pair, the image itself for a cover
+  cover: String,
+  /// Fraction of the cover's message capacity the message fills
+  rate: f32,
+  /// Message length, 0 for covers and the zero payload
+  bytes: usize,
+  /// Message bits per pixel of the cover
+  bpp: f64,
+  /// Path of the message relative to the dataset directory
+  #[serde(skip_serializing_if = "Option::is_none")]
+  payload: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  payload_sha256: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+  model: &'a str,
+  rates: &'a [f32],
+  seed: Option<u64>,
+  samples: Vec<Sample>,
+}
+
+// A cover and its stego images with their messages, one per rate.
+type Pair = (PathBuf, RgbImage, Vec<(Vec<u8>, RgbImage)>);
+
+// Random messages that fill `rates` of the message capacity of an image of `size`, where a rate of 1 is as long a
+// message as fits once. Rate 0 gets none, for the zero payload.
+fn messages(codec: &Codec, size: (u32, u32), rates: &[f32], rng: &mut StdRng) -> Vec<Vec<u8>> {
+  let header = Header {
+    size,
+    compression: Compression::None,
+    payload_type: PayloadType::Binary,
+    ..Default::default()
+  };
+  let capacity = codec.capacity(size);
+  let longest = payload::fit(&header, &vec![0; capacity / 8], capacity, None);
+  rates
+    .iter()
+    .map(|&rate| {
+      let len = (longest as f64 * rate as f64).round() as usize;
+      (0..len).map(|_| rng.gen()).collect()
+    })
+    .collect()
+}
+
+// Writes every cover of `-i` as PNG to `cover/` and a stego image of it per rate to `stego/RATE/`, with the hidden
+// messages in `payloads/RATE/`. `labels.csv` lists every image with label 0 for covers and 1 for stego images, and
+// `manifest.json` adds the pairs, rates and messages.
+pub fn run(args: &GenDatasetArgs, codec: &Codec) -> Result<()> {
+  let output = &args.output;
+  for rate in &args.rates {
+    std::fs::create_dir_all(output.join("stego").join(rate.to_string()))?;
+    if *rate > 0. {
+      std::fs::create_dir_all(output.join("payloads").join(rate.to_string()))?;
+    }
+  }
+  std::fs::create_dir_all(output.join("cover"))?;
+
+  let samples = Mutex::new(Vec::new());
+  pipeline::run(
+    data::list_images(&args.input)?.into_iter().enumerate().collect(),
+    pipeline::workers(args.jobs),
+    args.batch_size,
+    |(index, path)| Ok((index, image::open(&path).map(|image| image.to_rgb8()), path)),
+    |batch| {
+      let mut covers = Vec::new();
+      for (index, image, path) in batch {
+        match image {
+          Ok(image) => {
+            // Seeded per cover, as reads finish in any order
+            let mut rng = rng::from_seed(args.seed.map(|seed| seed.wrapping_add(index as u64)));
+            let messages = messages(codec, image.dimensions(), &args.rates, &mut rng);
+            covers.push((path, image, messages));
+          }
+          // Unreadable covers are skipped
+          Err(err) => eprintln!("warning: skipped {}: {err}", path.display()),
+        }
+      }
+      let items: Vec<_> = covers
+        .iter()
+        .flat_map(|(_, image, messages)| {
+          messages.iter().zip(&args.rates).map(move |(message, &rate)| {
+            let options = EncodeOptions {
+              compression: Compression::None,
+              payload_type: PayloadType::Binary,
+              null_payload: (rate == 0.).then_some(NullPayload::Zeros),
+              ..Default::default()
+            };
+            (image, message.as_slice(), options)
+          })
+        })
+        .collect();
+      let mut stego = codec.encode_images(&items)?.into_iter();
+      Ok(
+        covers
+          .into_iter()
+          .map(|(path, image, messages)| {
+            let stego = messages.into_iter().zip(stego.by_ref()).collect();
+            (path, image, stego)
+          })
+          .collect(),
+      )
+    },
+    |(path, cover, stego): Pair| {
+      let name = Path::new(path.file_stem().context("Invalid image name")?).with_extension("png");
+      let pixels = (cover.width() * cover.height()) as f64;
+      let cover_path = Path::new("cover").join(&name);
+      write_png(&cover, &output.join(&cover_path))?;
+      let mut written = vec![Sample {
+        image: cover_path.display().to_string(),
+        label: 0,
+        cover: cover_path.display().to_string(),
+        rate: 0.,
+        bytes: 0,
+        bpp: 0.,
+        payload: None,
+        payload_sha256: None,
+      }];
+      for ((message, stego), rate) in stego.into_iter().zip(&args.rates) {
+        let image = Path::new("stego").join(rate.to_string()).join(&name);
+        write_png(&stego, &output.join(&image))?;
+        let payload = (*rate > 0.)
+          .then(|| -> Result<_> {
+            let payload = Path::new("payloads")
+              .join(rate.to_string())
+              .join(&name)
+              .with_extension("bin");
+            std::fs::write(output.join(&payload), &message)?;
+            Ok(payload.display().to_string())
+          })
+          .transpose()?;
+        written.push(Sample {
+          image: image.display().to_string(),
+          label: 1,
+          cover: cover_path.display().to_string(),
+          rate: *rate,
+          bytes: message.len(),
+          bpp: message.len() as f64 * 8. / pixels,
+          payload_sha256: payload.as_ref().map(|_| to_hex(&Sha256::digest(&message))),
+          payload,
+        });
+      }
+      samples.lock().unwrap().extend(written);
+      Ok(())
+    },
+  )?;
+
+  let mut samples = samples.into_inner().unwrap();
+  samples.sort_by(|a, b| a.image.cmp(&b.image));
+  let mut labels = String::from("image,label\n");
+  for sample in &samples {
+    labels += &format!("{},{}\n", sample.image, sample.label);
+  }
+  std::fs::write(output.join("labels.csv"), labels)?;
+  let stego = samples.iter().filter(|sample| sample.label == 1).count();
+  println!(
+    "{} covers and {stego} stego images written to {}",
+    samples.len() - stego,
+    output.display()
+  );
+  let manifest = Manifest {
+    model: &args.model,
+    rates: &args.rates,
+    seed: args.seed,
+    samples,
+  };
+  std::fs::write(output.join("manifest.json"), serde_json::to_string_pretty(&manifest)?)?;
+  Ok(())
+}
+
+fn write_png(image: &RgbImage, path: &Path) -> Result<()> {
+  std::fs::write(path, image_io::encode_image(image, ImageFormat::Png)?)
+    .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+  use candle_core::Device;
+
+  use super::*;
+
+  #[test]
+  fn test_gen_dataset() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path().join("dataset");
+    let covers = dir.join("covers");
+    std::fs::create_dir_all(&covers)?;
+    for (name, width) in [("a.png", 32), ("b.png", 40)] {
+      let cover = RgbImage::from_fn(width, 24, |x, y| image::Rgb([(x * 6) as u8, (y * 10) as u8, 90]));
+      write_png(&cover, &covers.join(name))?;
+    }
+    let args = GenDatasetArgs {
+      input: covers,
+      output: dir.join("dataset"),
+      model: "pretrained".to_string(),
+      rates: vec![0., 0.5, 1.],
+      seed: Some(1),
+      jobs: Some(2),
+      batch_size: 2,
+    };
+    let codec = Codec::load(Path::new("pretrained"), &Device::Cpu)?;
+    run(&args, &codec)?;
+
+    let labels = std::fs::read_to_string(args.output.join("labels.csv"))?;
+    assert_eq!(labels.lines().count(), 1 + 2 * 4);
+    assert!(labels.contains("cover/a.png,0\n") && labels.contains("stego/0.5/b.png,1\n"));
+    let manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(args.output.join("manifest.json"))?)?;
+    let sample = |image: &str| {
+      manifest["samples"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|s| s["image"] == image)
+        .cloned()
+    };
+    let (half, full) = (sample("stego/0.5/a.png").unwrap(), sample("stego/1/a.png").unwrap());
+    assert!(half["bytes"].as_u64() > Some(0) && full["bytes"].as_u64() > half["bytes"].as_u64());
+    assert_eq!(sample("stego/0/a.png").unwrap()["bytes"], 0);
+    let payload = std::fs::read(args.output.join(full["payload"].as_str().unwrap()))?;
+    assert_eq!(payload.len() as u64, full["bytes"].as_u64().unwrap());
+    // The longest message still fits into the cover
+    let header = Header {
+      size: (32, 24),
+      compression: Compression::None,
+      payload_type: PayloadType::Binary,
+      ..Default::default()
+    };
+    assert!(payload::encoded_len(&payload::pack(&header, &payload)) <= codec.capacity((32, 24)));
+    Ok(())
+  }
+}
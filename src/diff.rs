@@ -0,0 +1,107 @@
+use anyhow::{ensure, Result};
+use image::{Rgb, RgbImage};
+use serde::Serialize;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct ChannelStats {
+  /// Largest absolute difference of the channel over all pixels
+  pub max: u8,
+  /// Mean absolute difference of the channel
+  pub mean: f32,
+}
+
+#[derive(Debug)]
+pub struct Residual {
+  /// Statistics of the R, G and B channels
+  pub channels: [ChannelStats; 3],
+  width: u32,
+  height: u32,
+  // Largest absolute channel difference of every pixel
+  magnitudes: Vec<u8>,
+}
+
+pub fn residual(cover: &RgbImage, stego: &RgbImage) -> Result<Residual> {
+  ensure!(
+    cover.dimensions() == stego.dimensions(),
+    "The images have different sizes ({}x{} and {}x{})",
+    cover.width(),
+    cover.height(),
+    stego.width(),
+    stego.height()
+  );
+  let mut sums = [0u64; 3];
+  let mut channels = [ChannelStats::default(); 3];
+  let magnitudes = cover
+    .pixels()
+    .zip(stego.pixels())
+    .map(|(a, b)| {
+      let mut magnitude = 0;
+      for c in 0..3 {
+        let diff = a[c].abs_diff(b[c]);
+        sums[c] += diff as u64;
+        channels[c].max = channels[c].max.max(diff);
+        magnitude = magnitude.max(diff);
+      }
+      magnitude
+    })
+    .collect();
+  let pixels = (cover.width() as u64 * cover.height() as u64).max(1);
+  for (stats, sum) in channels.iter_mut().zip(sums) {
+    stats.mean = sum as f32 / pixels as f32;
+  }
+  Ok(Residual {
+    channels,
+    width: cover.width(),
+    height: cover.height(),
+    magnitudes,
+  })
+}
+
+impl Residual {
+  pub fn max(&self) -> u8 {
+    self.channels.iter().map(|stats| stats.max).max().unwrap_or(0)
+  }
+
+  // Heatmap of the residual multiplied by `amplify`, by default stretched so that the largest difference is white.
+  // Colors go from black over red and yellow to white, like the "hot" colormap.
+  pub fn heatmap(&self, amplify: Option<f32>) -> RgbImage {
+    let amplify = amplify.unwrap_or(255. / self.max().max(1) as f32);
+    let pixels = self
+      .magnitudes
+      .iter()
+      .flat_map(|&magnitude| hot(magnitude as f32 * amplify).0)
+      .collect();
+    RgbImage::from_raw(self.width, self.height, pixels).unwrap()
+  }
+}
+
+// Maps 0..=255 to the colormap, each channel ramps up over a third of the range.
+fn hot(value: f32) -> Rgb<u8> {
+  let ramp = |start: f32| (value * 3. - start).clamp(0., 255.) as u8;
+  Rgb([ramp(0.), ramp(255.), ramp(510.)])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_residual() -> Result<()> {
+    let cover = RgbImage::from_pixel(4, 2, Rgb([100, 100, 100]));
+    let mut stego = cover.clone();
+    stego.put_pixel(1, 0, Rgb([104, 98, 100]));
+    stego.put_pixel(3, 1, Rgb([100, 101, 100]));
+    let residual = residual(&cover, &stego)?;
+    assert_eq!(residual.channels[0], ChannelStats { max: 4, mean: 0.5 });
+    assert_eq!(residual.channels[1], ChannelStats { max: 2, mean: 0.375 });
+    assert_eq!(residual.channels[2], ChannelStats { max: 0, mean: 0. });
+
+    let heatmap = residual.heatmap(None);
+    assert_eq!(heatmap.get_pixel(1, 0), &Rgb([255, 255, 255]));
+    assert_eq!(heatmap.get_pixel(3, 1), &Rgb([191, 0, 0]));
+    assert_eq!(heatmap.get_pixel(0, 0), &Rgb([0, 0, 0]));
+    assert_eq!(residual.heatmap(Some(1.)).get_pixel(1, 0), &Rgb([12, 0, 0]));
+    assert!(super::residual(&cover, &RgbImage::new(2, 2)).is_err());
+    Ok(())
+  }
+}
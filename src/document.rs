@@ -0,0 +1,281 @@
+use std::collections::HashSet;
+use std::io::{Cursor, Read, Write};
+
+use anyhow::{bail, Result};
+use image::{ImageFormat, RgbImage};
+use lopdf::{Document, Object, Stream};
+use zip::write::FileOptions;
+use zip::ZipArchive;
+
+// Documents whose raster images can be watermarked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+  Pdf,
+  Epub,
+}
+
+impl Kind {
+  pub fn detect(bytes: &[u8]) -> Option<Self> {
+    if bytes.starts_with(b"%PDF-") {
+      return Some(Self::Pdf);
+    }
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).ok()?;
+    let mut mimetype = String::new();
+    archive.by_name("mimetype").ok()?.read_to_string(&mut mimetype).ok()?;
+    (mimetype.trim() == "application/epub+zip").then_some(Self::Epub)
+  }
+}
+
+// A raster image of a document, named by its PDF object or its path in the EPUB.
+pub struct Image {
+  pub name: String,
+  pub image: RgbImage,
+}
+
+// The raster images of a document whose sides are both at least `min_size` pixels, in PDF object or EPUB entry order.
+// Images in formats that cannot be rewritten losslessly, like indexed or grayscale PDF images, are left out.
+pub fn images(bytes: &[u8], min_size: u32) -> Result<Vec<Image>> {
+  let mut images = Vec::new();
+  rewrite(bytes, min_size, |image| {
+    images.push(image);
+    Ok(None)
+  })?;
+  Ok(images)
+}
+
+// Replaces every image of `images` with the result of `f` and returns the new document with the number of images
+// replaced. Images are written losslessly, as Flate streams in PDF and as PNG files in EPUB.
+pub fn map_images(
+  bytes: &[u8],
+  min_size: u32,
+  mut f: impl FnMut(&Image) -> Result<RgbImage>,
+) -> Result<(Vec<u8>, usize)> {
+  let mut count = 0;
+  let output = rewrite(bytes, min_size, |image| {
+    count += 1;
+    f(&image).map(Some)
+  })?;
+  Ok((output, count))
+}
+
+fn rewrite(bytes: &[u8], min_size: u32, f: impl FnMut(Image) -> Result<Option<RgbImage>>) -> Result<Vec<u8>> {
+  match Kind::detect(bytes) {
+    Some(Kind::Pdf) => rewrite_pdf(bytes, min_size, f),
+    Some(Kind::Epub) => rewrite_epub(bytes, min_size, f),
+    None => bail!("Not a PDF or EPUB document"),
+  }
+}
+
+fn rewrite_pdf(bytes: &[u8], min_size: u32, mut f: impl FnMut(Image) -> Result<Option<RgbImage>>) -> Result<Vec<u8>> {
+  let mut doc = Document::load_mem(bytes)?;
+  for (id, object) in doc.objects.iter_mut() {
+    let Object::Stream(stream) = object else {
+      continue;
+    };
+    let Some(image) = pdf_image(stream).filter(|image| image.width().min(image.height()) >= min_size) else {
+      continue;
+    };
+    let name = format!("object {} {}", id.0, id.1);
+    if let Some(image) = f(Image { name, image })? {
+      stream.dict.remove(b"Decode");
+      stream.dict.set("ColorSpace", Object::Name(b"DeviceRGB".to_vec()));
+      stream.dict.set("BitsPerComponent", 8);
+      stream.set_plain_content(image.into_raw());
+      stream.compress()?;
+    }
+  }
+  let mut output = Vec::new();
+  doc.save_to(&mut output)?;
+  Ok(output)
+}
+
+// JPEG images, and 8-bit RGB images that are uncompressed or Flate compressed without a predictor.
+fn pdf_image(stream: &Stream) -> Option<RgbImage> {
+  let dict = &stream.dict;
+  if dict.get(b"Subtype").and_then(Object::as_name).ok() != Some(b"Image") {
+    return None;
+  }
+  let width = dict.get(b"Width").and_then(Object::as_i64).ok()? as u32;
+  let height = dict.get(b"Height").and_then(Object::as_i64).ok()? as u32;
+  let filters = stream.filters().unwrap_or_default();
+  if let [filter] = filters.as_slice() {
+    if filter == "DCTDecode" {
+      let image = image::load_from_memory_with_format(&stream.content, ImageFormat::Jpeg).ok()?;
+      return Some(image.to_rgb8());
+    }
+  }
+  let rgb = match dict.get(b"ColorSpace").ok()? {
+    Object::Name(name) => name == b"DeviceRGB",
+    // The number of components is checked against the data length below
+    Object::Array(array) => array.first().and_then(|name| name.as_name().ok()) == Some(b"ICCBased"),
+    _ => false,
+  };
+  let bits = dict.get(b"BitsPerComponent").and_then(Object::as_i64).ok();
+  if !rgb || bits != Some(8) || dict.has(b"DecodeParms") {
+    return None;
+  }
+  let mut raw = match filters.as_slice() {
+    [] => stream.content.clone(),
+    [filter] if filter == "FlateDecode" => miniz_oxide::inflate::decompress_to_vec_zlib(&stream.content).ok()?,
+    _ => return None,
+  };
+  raw.truncate((width * height * 3) as usize);
+  RgbImage::from_raw(width, height, raw)
+}
+
+// Other images than PNG are renamed to .png, and their references in the text files of the book are updated.
+fn rewrite_epub(bytes: &[u8], min_size: u32, mut f: impl FnMut(Image) -> Result<Option<RgbImage>>) -> Result<Vec<u8>> {
+  let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+  let mut entries = Vec::new();
+  for i in 0..archive.len() {
+    let mut file = archive.by_index(i)?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    entries.push((file.name().to_string(), file.compression(), contents));
+  }
+  let names: Vec<_> = entries.iter().map(|(name, ..)| file_name(name).to_string()).collect();
+  let mut renamed = Vec::new();
+  for (name, _, contents) in entries.iter_mut() {
+    let Ok(format) = ImageFormat::from_path(&*name) else {
+      continue;
+    };
+    let new_name = match format {
+      ImageFormat::Png => name.clone(),
+      _ => format!("{}.png", name.rsplit_once('.').map_or(&name[..], |(stem, _)| stem)),
+    };
+    // References are rewritten by file name, which must not be ambiguous
+    let (old, new) = (file_name(name), file_name(&new_name));
+    if old != new && (names.iter().filter(|n| *n == old).count() > 1 || names.iter().any(|n| n == new)) {
+      continue;
+    }
+    let Ok(image) = image::load_from_memory_with_format(contents, format) else {
+      continue;
+    };
+    let image = image.to_rgb8();
+    if image.width().min(image.height()) < min_size {
+      continue;
+    }
+    if let Some(image) = f(Image {
+      name: name.clone(),
+      image,
+    })? {
+      *contents = crate::image_io::encode_image(&image, ImageFormat::Png)?;
+      if old != new {
+        renamed.push((old.to_string(), new.to_string()));
+        *name = new_name;
+      }
+    }
+  }
+
+  let renamed_names: HashSet<_> = renamed.iter().map(|(_, new)| new.as_str()).collect();
+  let mut output = zip::ZipWriter::new(Cursor::new(Vec::new()));
+  for (name, compression, contents) in entries {
+    output.start_file(name.as_str(), FileOptions::default().compression_method(compression))?;
+    let is_text = [".opf", ".xhtml", ".html", ".htm", ".ncx", ".css", ".svg", ".xml"]
+      .iter()
+      .any(|ext| name.to_ascii_lowercase().ends_with(ext));
+    match (is_text, String::from_utf8(contents)) {
+      (true, Ok(text)) if !renamed.is_empty() => {
+        output.write_all(update_references(&text, &renamed, &renamed_names).as_bytes())?
+      }
+      (_, Ok(text)) => output.write_all(text.as_bytes())?,
+      (_, Err(err)) => output.write_all(err.as_bytes())?,
+    }
+  }
+  Ok(output.finish()?.into_inner())
+}
+
+fn file_name(path: &str) -> &str {
+  path.rsplit('/').next().unwrap_or(path)
+}
+
+// Points references to renamed images at the new names, and fixes their media types in the package manifest.
+fn update_references(text: &str, renamed: &[(String, String)], renamed_names: &HashSet<&str>) -> String {
+  let mut text = text.to_string();
+  for (old, new) in renamed {
+    for prefix in ["/", "\"", "'", "("] {
+      text = text.replace(&format!("{prefix}{old}"), &format!("{prefix}{new}"));
+    }
+  }
+  text
+    .split_inclusive('>')
+    .map(
+      |tag| match tag.contains("<item ") && renamed_names.iter().any(|name| tag.contains(name)) {
+        true => ["image/jpeg", "image/gif", "image/webp", "image/bmp"]
+          .iter()
+          .fold(tag.to_string(), |tag, media_type| tag.replace(media_type, "image/png")),
+        false => tag.to_string(),
+      },
+    )
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use image::Rgb;
+  use lopdf::dictionary;
+
+  use super::*;
+
+  fn invert(image: &Image) -> Result<RgbImage> {
+    let mut image = image.image.clone();
+    image::imageops::invert(&mut image);
+    Ok(image)
+  }
+
+  #[test]
+  fn test_pdf() -> Result<()> {
+    let mut doc = Document::with_version("1.5");
+    let image = |size: u32| {
+      let dict = dictionary! {
+        "Type" => "XObject",
+        "Subtype" => "Image",
+        "Width" => size as i64,
+        "Height" => size as i64,
+        "ColorSpace" => "DeviceRGB",
+        "BitsPerComponent" => 8,
+      };
+      Stream::new(dict, vec![10; (size * size * 3) as usize])
+    };
+    doc.add_object(image(16));
+    doc.add_object(image(4));
+    let mut pdf = Vec::new();
+    doc.save_to(&mut pdf)?;
+
+    assert_eq!(Kind::detect(&pdf), Some(Kind::Pdf));
+    assert_eq!(images(&pdf, 1)?.len(), 2);
+    let (pdf, count) = map_images(&pdf, 8, invert)?;
+    assert_eq!(count, 1);
+    let images = images(&pdf, 8)?;
+    assert_eq!(images[0].image, RgbImage::from_pixel(16, 16, Rgb([245; 3])));
+    Ok(())
+  }
+
+  #[test]
+  fn test_epub() -> Result<()> {
+    let mut epub = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let stored = FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    epub.start_file("mimetype", stored)?;
+    epub.write_all(b"application/epub+zip")?;
+    epub.start_file("OEBPS/content.opf", FileOptions::default())?;
+    epub.write_all(br#"<item id="c" href="images/cover.jpg" media-type="image/jpeg"/>"#)?;
+    epub.start_file("OEBPS/images/cover.jpg", FileOptions::default())?;
+    epub.write_all(&crate::image_io::encode_image(
+      &RgbImage::from_pixel(16, 16, Rgb([0; 3])),
+      ImageFormat::Jpeg,
+    )?)?;
+    let epub = epub.finish()?.into_inner();
+
+    assert_eq!(Kind::detect(&epub), Some(Kind::Epub));
+    let (epub, count) = map_images(&epub, 8, invert)?;
+    assert_eq!(count, 1);
+    let mut archive = ZipArchive::new(Cursor::new(&epub))?;
+    let mut opf = String::new();
+    archive.by_name("OEBPS/content.opf")?.read_to_string(&mut opf)?;
+    assert_eq!(opf, r#"<item id="c" href="images/cover.png" media-type="image/png"/>"#);
+    let images = images(&epub, 8)?;
+    assert_eq!(images[0].name, "OEBPS/images/cover.png");
+    assert_eq!(images[0].image, RgbImage::from_pixel(16, 16, Rgb([255; 3])));
+    Ok(())
+  }
+}
@@ -0,0 +1,304 @@
+use lazy_static::lazy_static;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{CHUNK_SIZE, ENCODED_SIZE};
+
+/// Backend of the code payloads are written with: GF(256) SIMD kernels with the `simd` feature, the parity table
+/// otherwise. Both write the same parity.
+#[cfg(feature = "simd")]
+pub type StandardScheme = SimdParity;
+#[cfg(not(feature = "simd"))]
+pub type StandardScheme = ParityTable;
+
+lazy_static! {
+  /// The code payloads are written with
+  pub static ref STANDARD: StandardScheme = StandardScheme::new(CHUNK_SIZE, ENCODED_SIZE - CHUNK_SIZE);
+  // Blocks of 236 bytes, two of which and a delimiter fit the stream of a robust tile. Short payloads take a block or
+  // two, so the polynomial division is cheap enough without a table.
+  static ref STRONG: ReedSolomon = ReedSolomon::new(40, 196);
+}
+
+// Error correction of the coded payload, recorded in its frame. Decoding tries each.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ecc {
+  /// Blocks of 5 data and 25 parity bytes that correct 12 byte errors
+  #[default]
+  Standard,
+  /// Blocks of 40 data and 196 parity bytes that correct 98 byte errors. Errors average out over longer blocks, so
+  /// far fewer of them fail at the same error rate, at a lower rate for payloads shorter than a block
+  Strong,
+}
+
+impl Ecc {
+  pub const ALL: [Ecc; 2] = [Ecc::Standard, Ecc::Strong];
+
+  pub fn scheme(self) -> &'static dyn EccScheme {
+    match self {
+      Ecc::Standard => &*STANDARD,
+      Ecc::Strong => &*STRONG,
+    }
+  }
+
+  /// The code with these data and parity bytes per block
+  pub fn from_block(block: (usize, usize)) -> Option<Self> {
+    Self::ALL.into_iter().find(|ecc| ecc.scheme().block() == block)
+  }
+}
+
+/// A systematic block code: data is coded in blocks of `block().0` bytes followed by `block().1` parity bytes. The last
+/// block may be shorter and is coded as if padded with leading zeros, so its data ends where the parity begins.
+pub trait EccScheme: Send + Sync {
+  /// Data and parity bytes of a full block
+  fn block(&self) -> (usize, usize);
+
+  /// Writes the parity of at most `block().0` data bytes
+  fn parity(&self, data: &[u8], parity: &mut [u8]);
+
+  /// Codes `data` into `encoded`, which holds every block followed by its parity, one block after the other in
+  /// parallel unless the backend codes many blocks at once
+  fn encode_into(&self, data: &[u8], encoded: &mut [u8]) {
+    let (size, parity) = self.block();
+    encoded
+      .par_chunks_mut(size + parity)
+      .zip(data.par_chunks(size))
+      .for_each(|(block, chunk)| {
+        let (head, parity) = block.split_at_mut(chunk.len());
+        head.copy_from_slice(chunk);
+        self.parity(chunk, parity);
+      });
+  }
+
+  /// Corrected data of a received block and the bytes fixed, `None` when it has too many errors
+  fn correct(&self, block: &[u8]) -> Option<(Vec<u8>, usize)>;
+
+  /// Byte errors corrected in a block
+  fn max_errors(&self) -> usize {
+    self.block().1 / 2
+  }
+}
+
+/// Reed-Solomon over GF(256) with a polynomial division per block.
+pub struct ReedSolomon {
+  block: (usize, usize),
+  encoder: reed_solomon::Encoder,
+  decoder: reed_solomon::Decoder,
+}
+
+impl ReedSolomon {
+  pub fn new(data: usize, parity: usize) -> Self {
+    Self {
+      block: (data, parity),
+      encoder: reed_solomon::Encoder::new(parity),
+      decoder: reed_solomon::Decoder::new(parity),
+    }
+  }
+}
+
+impl EccScheme for ReedSolomon {
+  fn block(&self) -> (usize, usize) {
+    self.block
+  }
+
+  fn parity(&self, data: &[u8], parity: &mut [u8]) {
+    parity.copy_from_slice(self.encoder.encode(data).ecc());
+  }
+
+  fn correct(&self, block: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let (decoded, errors) = self.decoder.correct_err_count(block, None).ok()?;
+    Some((decoded.data().to_vec(), errors))
+  }
+}
+
+/// Reed-Solomon with the parity of every byte value at every position of a block, counted from its end, precomputed.
+/// The parity is linear, so the parity of a block is the XOR of the rows of its bytes: a few table lookups and wide
+/// XORs the compiler vectorizes instead of a polynomial division per block. Corrections go through `ReedSolomon`.
+pub struct ParityTable {
+  code: ReedSolomon,
+  rows: Vec<u8>,
+}
+
+impl ParityTable {
+  pub fn new(data: usize, parity: usize) -> Self {
+    let code = ReedSolomon::new(data, parity);
+    let mut rows = vec![0; data * 256 * parity];
+    for (i, row) in rows.chunks_mut(parity).enumerate() {
+      let (position, byte) = (i / 256, i % 256);
+      let mut block = vec![0; position + 1];
+      block[0] = byte as u8;
+      code.parity(&block, row);
+    }
+    Self { code, rows }
+  }
+}
+
+impl EccScheme for ParityTable {
+  fn block(&self) -> (usize, usize) {
+    self.code.block
+  }
+
+  fn parity(&self, data: &[u8], parity: &mut [u8]) {
+    let len = parity.len();
+    parity.fill(0);
+    for (position, byte) in data.iter().rev().enumerate() {
+      let row = &self.rows[(position * 256 + *byte as usize) * len..][..len];
+      for (p, r) in parity.iter_mut().zip(row) {
+        *p ^= r;
+      }
+    }
+  }
+
+  fn correct(&self, block: &[u8]) -> Option<(Vec<u8>, usize)> {
+    self.code.correct(block)
+  }
+}
+
+/// Reed-Solomon that codes all full blocks at once, a column at a time: parity byte `i` of every block is the sum of
+/// data byte `j` of every block times a constant of the code, which the SIMD GF(256) kernels of `reed-solomon-erasure`
+/// multiply over whole columns. Those crates only recover erasures of their own codes, so corrections and the last,
+/// shorter block still go through `ParityTable`.
+#[cfg(feature = "simd")]
+pub struct SimdParity {
+  table: ParityTable,
+  // Parity byte `i` of a full block with only data byte `j` set to 1, at `j * parity + i`
+  generator: Vec<u8>,
+}
+
+#[cfg(feature = "simd")]
+impl SimdParity {
+  pub fn new(data: usize, parity: usize) -> Self {
+    let table = ParityTable::new(data, parity);
+    let mut generator = vec![0; data * parity];
+    for (j, row) in generator.chunks_mut(parity).enumerate() {
+      let mut block = vec![0; data];
+      block[j] = 1;
+      table.parity(&block, row);
+    }
+    Self { table, generator }
+  }
+}
+
+#[cfg(feature = "simd")]
+impl EccScheme for SimdParity {
+  fn block(&self) -> (usize, usize) {
+    self.table.block()
+  }
+
+  fn parity(&self, data: &[u8], parity: &mut [u8]) {
+    self.table.parity(data, parity);
+  }
+
+  fn encode_into(&self, data: &[u8], encoded: &mut [u8]) {
+    use reed_solomon_erasure::galois_8;
+
+    let (size, parity) = self.block();
+    let blocks = data.len() / size;
+    let (full, rest) = encoded.split_at_mut(blocks * (size + parity));
+    self.table.encode_into(&data[blocks * size..], rest);
+    if blocks == 0 {
+      return;
+    }
+    let columns: Vec<Vec<u8>> = (0..size)
+      .into_par_iter()
+      .map(|j| data[j..].iter().step_by(size).take(blocks).copied().collect())
+      .collect();
+    let parities: Vec<Vec<u8>> = (0..parity)
+      .into_par_iter()
+      .map(|i| {
+        let mut sum = vec![0; blocks];
+        for (j, column) in columns.iter().enumerate() {
+          galois_8::mul_slice_xor(self.generator[j * parity + i], column, &mut sum);
+        }
+        sum
+      })
+      .collect();
+    full
+      .par_chunks_mut(size + parity)
+      .zip(data.par_chunks(size))
+      .enumerate()
+      .for_each(|(index, (block, chunk))| {
+        block[..size].copy_from_slice(chunk);
+        for (byte, column) in block[size..].iter_mut().zip(&parities) {
+          *byte = column[index];
+        }
+      });
+  }
+
+  fn correct(&self, block: &[u8]) -> Option<(Vec<u8>, usize)> {
+    self.table.correct(block)
+  }
+}
+
+// Bytes the decoder fixed, and blocks with too many errors, which are passed on as received.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Correction {
+  pub corrected: usize,
+  pub failed_blocks: usize,
+  pub blocks: usize,
+  /// Most bytes fixed in a single block
+  pub worst_block: usize,
+}
+
+/// Codes the data with `scheme`, compression is up to the caller (see `payload::pack`).
+pub fn encode(scheme: &dyn EccScheme, data: &[u8]) -> Vec<u8> {
+  let (size, parity) = scheme.block();
+  let mut encoded = vec![0; data.len() + data.len().div_ceil(size) * parity];
+  scheme.encode_into(data, &mut encoded);
+  encoded
+}
+
+pub fn correct(scheme: &dyn EccScheme, bytes: &[u8]) -> (Vec<u8>, Correction) {
+  let (size, parity) = scheme.block();
+  let blocks: Vec<(Vec<u8>, Option<usize>)> = bytes
+    .par_chunks(size + parity)
+    .map(|block| match scheme.correct(block) {
+      Some((data, errors)) => (data, Some(errors)),
+      None => (block[..block.len().saturating_sub(parity)].to_vec(), None),
+    })
+    .collect();
+  let mut correction = Correction {
+    blocks: blocks.len(),
+    ..Default::default()
+  };
+  let mut data = Vec::with_capacity(blocks.len() * size);
+  for (block, errors) in blocks {
+    match errors {
+      Some(errors) => {
+        correction.corrected += errors;
+        correction.worst_block = correction.worst_block.max(errors);
+      }
+      None => correction.failed_blocks += 1,
+    }
+    data.extend(block);
+  }
+  (data, correction)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_backends() {
+    let data: Vec<u8> = (0..23u32).map(|i| (i * 151 + 7) as u8).collect();
+    let reference = ReedSolomon::new(CHUNK_SIZE, ENCODED_SIZE - CHUNK_SIZE);
+    let mut encoded = encode(&reference, &data);
+    assert_eq!(encode(&*STANDARD, &data), encoded);
+    assert_eq!(
+      encode(&ParityTable::new(CHUNK_SIZE, ENCODED_SIZE - CHUNK_SIZE), &data),
+      encoded
+    );
+    // A payload of full blocks only, and one shorter than a block
+    assert_eq!(encode(&*STANDARD, &data[..20]), encode(&reference, &data[..20]));
+    assert_eq!(encode(&*STANDARD, &data[..3]), encode(&reference, &data[..3]));
+
+    for i in (0..encoded.len()).step_by(3) {
+      encoded[i] ^= 0x5a;
+    }
+    let (corrected, correction) = correct(&*STANDARD, &encoded);
+    assert_eq!(corrected, data);
+    assert_eq!(correction.failed_blocks, 0);
+    assert_eq!(correction.worst_block, 10);
+  }
+}
@@ -0,0 +1,291 @@
+use std::io::Write;
+
+use anyhow::{bail, Result};
+use candle_core::{DType, Device, Tensor};
+use image::{DynamicImage, ImageFormat, RgbImage};
+
+use crate::codec::{Codec, DecodeOptions, EncodeOptions};
+use crate::compression::Compression;
+use crate::image_io;
+use crate::model::Arch;
+use crate::payload::{Payload, Spread};
+use crate::weights;
+use crate::zoo;
+
+// Where the weights of a `SteganoGan` come from.
+#[derive(Debug, Clone)]
+pub enum Weights {
+  /// Model directory, weights file or name of a downloaded model, like `Codec::open`
+  Model(String),
+  /// Encoder and decoder safetensors in memory, like `Codec::from_buffers`
+  Buffers { encoder: Vec<u8>, decoder: Vec<u8> },
+}
+
+impl Default for Weights {
+  fn default() -> Self {
+    Weights::Model("pretrained".to_string())
+  }
+}
+
+// Typed configuration of a `SteganoGan`, for crates that use the library rather than the command line.
+#[derive(Debug, Clone)]
+pub struct SteganoGanBuilder {
+  device: Device,
+  dtype: DType,
+  arch: Option<Arch>,
+  spread: Spread,
+  compression: Compression,
+  max_pixels: Option<u64>,
+  max_memory: Option<u64>,
+  weights: Weights,
+}
+
+impl Default for SteganoGanBuilder {
+  fn default() -> Self {
+    Self {
+      device: Device::Cpu,
+      dtype: DType::F32,
+      arch: None,
+      spread: Spread::default(),
+      compression: Compression::default(),
+      max_pixels: None,
+      max_memory: None,
+      weights: Weights::default(),
+    }
+  }
+}
+
+impl SteganoGanBuilder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn device(mut self, device: Device) -> Self {
+    self.device = device;
+    self
+  }
+
+  // Precision the networks run in, f16 or bf16 for speed on GPUs. Weights are stored as f32 and cast on load.
+  pub fn dtype(mut self, dtype: DType) -> Self {
+    self.dtype = dtype;
+    self
+  }
+
+  // Decoder architecture for weights that do not record their config, like PyTorch checkpoints.
+  pub fn arch(mut self, arch: Arch) -> Self {
+    self.arch = Some(arch);
+    self
+  }
+
+  // Redundancy on top of the Reed-Solomon code of every block: how the payload fills the image, see `Spread`.
+  pub fn ecc(mut self, spread: Spread) -> Self {
+    self.spread = spread;
+    self
+  }
+
+  pub fn compression(mut self, compression: Compression) -> Self {
+    self.compression = compression;
+    self
+  }
+
+  // Largest image to accept, see `Codec::set_max_pixels`.
+  pub fn max_pixels(mut self, max_pixels: u64) -> Self {
+    self.max_pixels = Some(max_pixels);
+    self
+  }
+
+  // Device memory to stay under by batching fewer images and tiling large ones, see `Codec::set_max_memory`.
+  pub fn max_memory(mut self, max_memory: u64) -> Self {
+    self.max_memory = Some(max_memory);
+    self
+  }
+
+  pub fn weights(mut self, weights: Weights) -> Self {
+    self.weights = weights;
+    self
+  }
+
+  pub fn build(self) -> Result<SteganoGan> {
+    let mut codec = match &self.weights {
+      Weights::Model(model) => {
+        let model = zoo::resolve(model)?;
+        let mut config = weights::model_config(&model, "encoder")?;
+        config.arch = self.arch.unwrap_or(config.arch);
+        Codec::build(config, &self.device, self.dtype, |varmap, component| {
+          Ok(weights::load(varmap, &model, component)?)
+        })?
+      }
+      Weights::Buffers { encoder, decoder } => {
+        let mut config = weights::buffer_config(encoder)?.unwrap_or_default();
+        config.arch = self.arch.unwrap_or(config.arch);
+        Codec::build(config, &self.device, self.dtype, |varmap, component| {
+          let buffer = if component == "encoder" { encoder } else { decoder };
+          weights::load_buffer(varmap, buffer, component)
+        })?
+      }
+    };
+    codec.set_max_pixels(self.max_pixels);
+    codec.set_max_memory(self.max_memory);
+    Ok(SteganoGan {
+      codec,
+      spread: self.spread,
+      compression: self.compression,
+    })
+  }
+}
+
+// A loaded model with the behavior its `SteganoGanBuilder` configured, which no call can change.
+pub struct SteganoGan {
+  codec: Codec,
+  spread: Spread,
+  compression: Compression,
+}
+
+impl SteganoGan {
+  pub fn builder() -> SteganoGanBuilder {
+    SteganoGanBuilder::new()
+  }
+
+  pub fn codec(&self) -> &Codec {
+    &self.codec
+  }
+
+  pub fn encode_image(&self, cover: &RgbImage, message: &[u8]) -> Result<RgbImage> {
+    let options = EncodeOptions {
+      compression: self.compression,
+      spread: self.spread,
+      ..Default::default()
+    };
+    self.codec.encode_with(cover, message, &options)
+  }
+
+  // Fails with `SteganoError::DecodeFailed` if the image holds no readable payload.
+  pub fn decode_image(&self, img: &RgbImage) -> Result<Payload> {
+    let options = DecodeOptions {
+      resync: self.spread.tiling(),
+      ..Default::default()
+    };
+    self.codec.decode_with(img, &options)
+  }
+
+  // Any color type and bit depth is converted to 8-bit RGB first, alpha is dropped.
+  pub fn encode_dynamic(&self, cover: &DynamicImage, message: &[u8]) -> Result<RgbImage> {
+    self.encode_image(&cover.to_rgb8(), message)
+  }
+
+  pub fn decode_dynamic(&self, img: &DynamicImage) -> Result<Payload> {
+    self.decode_image(&img.to_rgb8())
+  }
+
+  // Hides the message in packed 8-bit RGB pixels, row by row, and returns the stego pixels in the same layout.
+  pub fn encode_rgb(&self, pixels: &[u8], width: u32, height: u32, message: &[u8]) -> Result<Vec<u8>> {
+    Ok(
+      self
+        .encode_image(&rgb_image(pixels, width, height)?, message)?
+        .into_raw(),
+    )
+  }
+
+  pub fn decode_rgb(&self, pixels: &[u8], width: u32, height: u32) -> Result<Payload> {
+    self.decode_image(&rgb_image(pixels, width, height)?)
+  }
+
+  // Decodes an image given as the bytes of any supported format, like an upload.
+  pub fn decode_bytes(&self, img: &[u8]) -> Result<Payload> {
+    self.decode_dynamic(&image::load_from_memory(img)?)
+  }
+
+  // Hides the message in a (3, h, w) or (1, 3, h, w) tensor with values in [0, 1], like the output of a generative
+  // model, see `Codec::encode_tensor`.
+  pub fn encode_tensor(&self, cover: &Tensor, message: &[u8]) -> Result<Tensor> {
+    let options = EncodeOptions {
+      compression: self.compression,
+      spread: self.spread,
+      ..Default::default()
+    };
+    self.codec.encode_tensor(cover, message, &options)
+  }
+
+  pub fn decode_tensor(&self, img: &Tensor) -> Result<Payload> {
+    let options = DecodeOptions {
+      resync: self.spread.tiling(),
+      ..Default::default()
+    };
+    self.codec.decode_tensor(img, &options)
+  }
+
+  // Hides one message in every image of an (n, 3, h, w) batch in a single forward pass, see `Codec::encode_batch`.
+  pub fn encode_batch(&self, covers: &Tensor, messages: &[&[u8]]) -> Result<Tensor> {
+    let options = EncodeOptions {
+      compression: self.compression,
+      spread: self.spread,
+      ..Default::default()
+    };
+    self.codec.encode_batch(covers, messages, &options)
+  }
+
+  pub fn decode_batch(&self, imgs: &Tensor) -> Result<Vec<Result<Payload>>> {
+    let options = DecodeOptions {
+      resync: self.spread.tiling(),
+      ..Default::default()
+    };
+    self.codec.decode_batch(imgs, &options)
+  }
+
+  // Hides the message in a cover given as the bytes of any supported image format and writes the stego image to
+  // `output` as PNG, which keeps the payload intact.
+  pub fn encode_bytes_into(&self, cover: &[u8], message: &[u8], output: &mut impl Write) -> Result<()> {
+    let cover = image::load_from_memory(cover)?.to_rgb8();
+    let stego = self.encode_image(&cover, message)?;
+    output.write_all(&image_io::encode_image(&stego, ImageFormat::Png)?)?;
+    Ok(())
+  }
+}
+
+fn rgb_image(pixels: &[u8], width: u32, height: u32) -> Result<RgbImage> {
+  let expected = width as usize * height as usize * 3;
+  if pixels.len() != expected {
+    bail!(
+      "Expected {expected} bytes of RGB pixels for {width}x{height}, got {}",
+      pixels.len()
+    );
+  }
+  Ok(RgbImage::from_raw(width, height, pixels.to_vec()).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_builder() -> Result<()> {
+    let cover = RgbImage::from_fn(96, 72, |x, y| {
+      image::Rgb([(x * 255 / 96) as u8, (y * 255 / 72) as u8, (x ^ y) as u8])
+    });
+    for dtype in [DType::F32, DType::F16] {
+      let engine = SteganoGan::builder()
+        .dtype(dtype)
+        .compression(Compression::None)
+        .build()?;
+      let stego = engine.encode_image(&cover, b"builder")?;
+      assert_eq!(stego.dimensions(), cover.dimensions());
+      assert_eq!(engine.decode_image(&stego)?.message, "builder");
+    }
+
+    let engine = SteganoGan::builder().max_pixels(96 * 72).build()?;
+    let mut png = Vec::new();
+    engine.encode_bytes_into(&image_io::encode_image(&cover, ImageFormat::Png)?, b"bytes", &mut png)?;
+    assert_eq!(
+      image::load_from_memory(&png)?.to_rgb8().dimensions(),
+      cover.dimensions()
+    );
+    let stego = engine.encode_rgb(cover.as_raw(), 96, 72, b"raw")?;
+    assert_eq!(stego.len(), cover.as_raw().len());
+    assert_eq!(engine.decode_rgb(&stego, 96, 72)?.message, "raw");
+    assert_eq!(engine.decode_bytes(&png)?.message, "bytes");
+    assert!(engine.encode_rgb(&stego[1..], 96, 72, b"raw").is_err());
+    let large = RgbImage::new(97, 72);
+    assert!(engine.encode_image(&large, b"too large").is_err());
+    Ok(())
+  }
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+use std::path::PathBuf;
+
+pub type Result<T> = std::result::Result<T, SteganoError>;
+
+#[derive(Debug)]
+pub enum SteganoError {
+  /// Weights could not be read or do not match the model
+  WeightLoad {
+    path: PathBuf,
+    reason: String,
+  },
+  /// A tensor or weight has an unexpected shape
+  ShapeMismatch(String),
+  /// The payload does not fit into the image even once
+  CapacityExceeded {
+    needed: usize,
+    available: usize,
+  },
+  /// No valid copy of the payload could be recovered
+  DecodeFailed,
+  /// The payload was written in a format version this build can not read
+  NewerVersion {
+    version: u8,
+    supported: u8,
+  },
+  /// The image format can not be written without corrupting the payload
+  UnsupportedFormat(String),
+  /// The image has more pixels than the codec is allowed to process
+  ImageTooLarge {
+    pixels: u64,
+    max: u64,
+  },
+  Candle(candle_core::Error),
+}
+
+impl fmt::Display for SteganoError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      SteganoError::WeightLoad { path, reason } => {
+        write!(f, "Failed to load weights from {}: {reason}", path.display())
+      }
+      SteganoError::ShapeMismatch(msg) => write!(f, "Shape mismatch: {msg}"),
+      SteganoError::CapacityExceeded { needed, available } => write!(
+        f,
+        "Payload needs {needed} bits, but the image only holds {available}, use a larger image or a shorter message"
+      ),
+      SteganoError::DecodeFailed => write!(f, "No data found"),
+      SteganoError::NewerVersion { version, supported } => write!(
+        f,
+        "Payload was created with a newer version of steganogan-rs (format {version}, this build reads up to \
+         {supported}), please upgrade"
+      ),
+      SteganoError::UnsupportedFormat(msg) => write!(f, "{msg}"),
+      SteganoError::ImageTooLarge { pixels, max } => {
+        write!(f, "Image has {pixels} pixels, but at most {max} are allowed")
+      }
+      SteganoError::Candle(err) => err.fmt(f),
+    }
+  }
+}
+
+impl std::error::Error for SteganoError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      SteganoError::Candle(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<candle_core::Error> for SteganoError {
+  fn from(err: candle_core::Error) -> Self {
+    match err {
+      candle_core::Error::WithBacktrace { inner, .. } => (*inner).into(),
+      candle_core::Error::UnexpectedShape { msg, expected, got } => {
+        SteganoError::ShapeMismatch(format!("{msg}, expected {expected:?}, got {got:?}"))
+      }
+      err => SteganoError::Candle(err),
+    }
+  }
+}
+
+// Whether an error is a device running out of memory, which a pass over less data or on the CPU can avoid.
+pub fn is_out_of_memory(err: &anyhow::Error) -> bool {
+  err.chain().any(|err| {
+    let message = err.to_string().to_ascii_lowercase();
+    ["out of memory", "out_of_memory", "alloc_failed"]
+      .iter()
+      .any(|pattern| message.contains(pattern))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use candle_core::{DType, Device, Tensor};
+  use candle_nn::VarBuilder;
+
+  use super::*;
+  use crate::model::critic::Critic;
+
+  #[test]
+  fn test_shape_mismatch() -> Result<()> {
+    let device = &Device::Cpu;
+    let tensors = HashMap::from([(
+      "layers.0.weight".to_string(),
+      Tensor::zeros((8, 3, 3, 3), DType::F32, device)?,
+    )]);
+    let err = Critic::new(32, VarBuilder::from_tensors(tensors, DType::F32, device))
+      .err()
+      .unwrap();
+    assert!(matches!(err, SteganoError::ShapeMismatch(_)), "{err}");
+    Ok(())
+  }
+
+  #[test]
+  fn test_out_of_memory() {
+    let err = anyhow::Error::from(candle_core::Error::Msg(
+      "DriverError(CUDA_ERROR_OUT_OF_MEMORY, \"out of memory\")".into(),
+    ));
+    assert!(is_out_of_memory(&err.context("decoding")));
+    assert!(is_out_of_memory(&anyhow::anyhow!("CUDNN_STATUS_ALLOC_FAILED")));
+    assert!(!is_out_of_memory(&anyhow::Error::from(SteganoError::DecodeFailed)));
+  }
+}
@@ -0,0 +1,298 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use image::RgbImage;
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::Rng;
+use serde::Serialize;
+
+use crate::compression::Compression;
+use crate::ecc::Ecc;
+use crate::model::critic::Critic;
+use crate::model::decoder::Decoder;
+use crate::model::encoder::Encoder;
+use crate::weights::{self, ModelConfig};
+use crate::{image_io, payload};
+
+#[derive(Debug, Serialize)]
+pub struct ImageResult {
+  pub image: String,
+  pub bit_accuracy: f32,
+  pub recovered: bool,
+  pub psnr: f32,
+  pub ssim: f32,
+  pub rs_bpp: f32,
+  pub cover_score: f32,
+  pub stego_score: f32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Distribution {
+  pub mean: f32,
+  pub std: f32,
+  pub min: f32,
+  pub median: f32,
+  pub max: f32,
+}
+
+impl Distribution {
+  fn new(values: &[f32]) -> Self {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f32::total_cmp);
+    let n = sorted.len() as f32;
+    let mean = sorted.iter().sum::<f32>() / n;
+    let var = sorted.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / n;
+    Self {
+      mean,
+      std: var.sqrt(),
+      min: sorted[0],
+      median: sorted[sorted.len() / 2],
+      max: sorted[sorted.len() - 1],
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Summary {
+  pub images: usize,
+  pub bit_accuracy: f32,
+  pub recovery_rate: f32,
+  pub psnr: f32,
+  pub ssim: f32,
+  pub rs_bpp: f32,
+  pub cover_score: Distribution,
+  pub stego_score: Distribution,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+  pub model: String,
+  pub data_depth: usize,
+  pub message_size: usize,
+  pub summary: Summary,
+  pub images: Vec<ImageResult>,
+}
+
+impl Report {
+  pub fn write(&self, path: &Path) -> Result<()> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+      Some("json") => std::fs::write(path, serde_json::to_string_pretty(self)?)?,
+      Some("csv") => {
+        let mut csv = String::from("image,bit_accuracy,recovered,psnr,ssim,rs_bpp,cover_score,stego_score\n");
+        for r in self.images.iter() {
+          csv += &format!(
+            "{},{},{},{},{},{},{},{}\n",
+            r.image, r.bit_accuracy, r.recovered, r.psnr, r.ssim, r.rs_bpp, r.cover_score, r.stego_score
+          );
+        }
+        std::fs::write(path, csv)?;
+      }
+      _ => bail!("Unsupported report format {}, use .json or .csv", path.display()),
+    }
+    Ok(())
+  }
+}
+
+pub struct Evaluator {
+  config: ModelConfig,
+  device: Device,
+  encoder: Encoder,
+  decoder: Decoder,
+  critic: Critic,
+}
+
+impl Evaluator {
+  pub fn new(model: &Path, device: &Device) -> Result<Self> {
+    let config = weights::model_config(model, "encoder")?;
+    let mut vars = [VarMap::new(), VarMap::new(), VarMap::new()];
+    let vb = |i: usize| VarBuilder::from_varmap(&vars[i], DType::F32, device);
+    let encoder = Encoder::from_config(&config, vb(0))?;
+    let decoder = Decoder::from_config(&config, vb(1))?;
+    let critic = Critic::new(config.hidden_size, vb(2))?;
+    for (varmap, component) in vars.iter_mut().zip(["encoder", "decoder", "critic"]) {
+      weights::load(varmap, model, component)?;
+    }
+    Ok(Self {
+      config,
+      device: device.clone(),
+      encoder,
+      decoder,
+      critic,
+    })
+  }
+
+  // Hides a random message in the image exactly like `encode` does and reads it back from the quantized stego image.
+  pub fn evaluate_image(&self, path: &Path, message_size: usize, rng: &mut StdRng) -> Result<ImageResult> {
+    let cover = image::open(path)?.to_rgb8();
+    let message: String = (0..message_size)
+      .map(|_| char::from(rng.sample(Alphanumeric)))
+      .collect();
+    let header = payload::Header {
+      size: cover.dimensions(),
+      source_size: None,
+      chunk: None,
+      frame: None,
+      channels: None,
+      compression: Compression::default(),
+      payload_type: payload::PayloadType::Text,
+      ecc: Ecc::Standard,
+      signature: None,
+      mac: None,
+    };
+    let (stego, bits) = self.embed(&cover, &payload::pack(&header, message.as_bytes()))?;
+    let cover_tensor = ((image_io::to_tensor(&cover, &self.device)? / 127.5)? - 1.)?;
+    let stego_tensor = image_io::to_tensor(&stego, &self.device)?;
+    let decoded = self.decode_bits(&stego)?;
+
+    let bit_accuracy = decoded.iter().zip(bits.iter()).filter(|(a, b)| a == b).count() as f32 / bits.len() as f32;
+    let score = |x: &Tensor| -> Result<f32> { Ok(self.critic.forward(x)?.mean_all()?.to_scalar()?) };
+    Ok(ImageResult {
+      image: path.display().to_string(),
+      bit_accuracy,
+      recovered: payload::extract(&decoded).is_ok_and(|payload| payload.message == message),
+      psnr: psnr(&cover, &stego),
+      ssim: ssim(&cover, &stego),
+      rs_bpp: self.config.data_depth as f32 * (2. * bit_accuracy - 1.),
+      cover_score: score(&cover_tensor)?,
+      stego_score: score(&((stego_tensor / 127.5)? - 1.)?)?,
+    })
+  }
+
+  // Tiles the packed payload over the cover and returns the quantized stego image with the embedded bits.
+  pub fn embed(&self, cover: &RgbImage, packed: &[u8]) -> Result<(RgbImage, Vec<u8>)> {
+    let (h, w) = (cover.height() as usize, cover.width() as usize);
+    let bits = payload::tile(packed, self.config.data_depth, h, w)?;
+    let data = Tensor::from_vec(bits.clone(), (1, self.config.data_depth, h, w), &self.device)?.to_dtype(DType::F32)?;
+    let cover_tensor = self
+      .config
+      .preprocess
+      .encoder_input(&image_io::to_tensor(cover, &self.device)?)?;
+    let stego = image_io::from_tensor(&self.encoder.forward(&cover_tensor, &data)?)?;
+    Ok((stego, bits))
+  }
+
+  fn decode_logits(&self, img: &RgbImage) -> Result<Tensor> {
+    let pixels = image_io::to_tensor(img, &self.device)?;
+    Ok(self.decoder.forward(&self.config.preprocess.decoder_input(&pixels)?)?)
+  }
+
+  pub fn decode_bits(&self, img: &RgbImage) -> Result<Vec<u8>> {
+    let mut logits = self.decode_logits(img)?.flatten_all()?.to_vec1::<f32>()?;
+    self.config.center_logits(&mut logits);
+    Ok(logits.iter().map(|&logit| (logit > 0.) as u8).collect())
+  }
+
+  // Decision thresholds of the decoder channels, calibrated on random messages hidden in the images.
+  pub fn calibrate(&self, images: &[PathBuf], message_size: usize, rng: &mut StdRng) -> Result<Vec<f32>> {
+    let mut calibration = weights::Calibration::new(self.config.data_depth);
+    for path in images {
+      let cover = image::open(path)?.to_rgb8();
+      let message: Vec<u8> = (0..message_size).map(|_| rng.gen()).collect();
+      let (stego, bits) = self.embed(&cover, &message)?;
+      let logits = self.decode_logits(&stego)?;
+      calibration.add(&logits, &Tensor::from_vec(bits, logits.shape(), &self.device)?)?;
+    }
+    Ok(calibration.thresholds())
+  }
+
+  // Decodes with `thresholds` from now on, as if the model had been saved with them.
+  pub fn set_thresholds(&mut self, thresholds: Vec<f32>) {
+    self.config.thresholds = thresholds;
+  }
+
+  pub fn evaluate(&self, images: &[PathBuf], message_size: usize, model: &str, rng: &mut StdRng) -> Result<Report> {
+    if images.is_empty() {
+      bail!("No images to evaluate");
+    }
+    let results = images
+      .iter()
+      .map(|path| self.evaluate_image(path, message_size, rng))
+      .collect::<Result<Vec<_>>>()?;
+    let mean = |f: fn(&ImageResult) -> f32| results.iter().map(f).sum::<f32>() / results.len() as f32;
+    let summary = Summary {
+      images: results.len(),
+      bit_accuracy: mean(|r| r.bit_accuracy),
+      recovery_rate: mean(|r| r.recovered as u8 as f32),
+      psnr: mean(|r| r.psnr),
+      ssim: mean(|r| r.ssim),
+      rs_bpp: mean(|r| r.rs_bpp),
+      cover_score: Distribution::new(&results.iter().map(|r| r.cover_score).collect::<Vec<_>>()),
+      stego_score: Distribution::new(&results.iter().map(|r| r.stego_score).collect::<Vec<_>>()),
+    };
+    Ok(Report {
+      model: model.to_string(),
+      data_depth: self.config.data_depth,
+      message_size,
+      summary,
+      images: results,
+    })
+  }
+}
+
+pub fn psnr(a: &RgbImage, b: &RgbImage) -> f32 {
+  let mse = a
+    .as_raw()
+    .iter()
+    .zip(b.as_raw().iter())
+    .map(|(x, y)| (*x as f32 - *y as f32).powi(2))
+    .sum::<f32>()
+    / a.as_raw().len() as f32;
+  10. * (255f32.powi(2) / mse).log10()
+}
+
+const SSIM_WINDOW: u32 = 8;
+const SSIM_STRIDE: u32 = 4;
+
+// Mean SSIM over the luma channel, computed on 8x8 windows with a stride of 4.
+pub fn ssim(a: &RgbImage, b: &RgbImage) -> f32 {
+  let luma = |img: &RgbImage| image::imageops::grayscale(img);
+  let (a, b) = (luma(a), luma(b));
+  let (c1, c2) = ((0.01f32 * 255.).powi(2), (0.03f32 * 255.).powi(2));
+  let window = SSIM_WINDOW.min(a.width()).min(a.height());
+  let n = (window * window) as f32;
+  let mut total = 0.;
+  let mut count = 0;
+  for y in (0..=a.height() - window).step_by(SSIM_STRIDE as usize) {
+    for x in (0..=a.width() - window).step_by(SSIM_STRIDE as usize) {
+      let pixels = || (y..y + window).flat_map(move |py| (x..x + window).map(move |px| (px, py)));
+      let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0f32, 0f32, 0f32, 0f32, 0f32);
+      for (px, py) in pixels() {
+        let (va, vb) = (a.get_pixel(px, py)[0] as f32, b.get_pixel(px, py)[0] as f32);
+        sa += va;
+        sb += vb;
+        saa += va * va;
+        sbb += vb * vb;
+        sab += va * vb;
+      }
+      let (ma, mb) = (sa / n, sb / n);
+      let (va, vb, cov) = (saa / n - ma * ma, sbb / n - mb * mb, sab / n - ma * mb);
+      total += ((2. * ma * mb + c1) * (2. * cov + c2)) / ((ma * ma + mb * mb + c1) * (va + vb + c2));
+      count += 1;
+    }
+  }
+  total / count as f32
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_image_metrics() {
+    let a = RgbImage::from_fn(32, 24, |x, y| image::Rgb([(x * 8) as u8, (y * 10) as u8, 128]));
+    let mut b = a.clone();
+    b.pixels_mut().step_by(3).for_each(|p| p[0] = p[0].saturating_add(20));
+    assert!((ssim(&a, &a) - 1.).abs() < 1e-6);
+    assert!(ssim(&a, &b) < 1.);
+    assert!(psnr(&a, &b) > 20. && psnr(&a, &b) < 40.);
+  }
+
+  #[test]
+  fn test_distribution() {
+    let d = Distribution::new(&[3., 1., 2.]);
+    assert_eq!((d.min, d.median, d.max, d.mean), (1., 2., 3., 2.));
+  }
+}
@@ -0,0 +1,242 @@
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+use candle_core::Device;
+use image::ImageFormat;
+
+use crate::codec::Codec;
+use crate::{image_io, SteganoError};
+
+/// Opaque handle to a loaded model.
+pub struct SteganoCodec(Codec);
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SteganoStatus {
+  Ok = 0,
+  InvalidArgument,
+  WeightLoad,
+  ShapeMismatch,
+  CapacityExceeded,
+  DecodeFailed,
+  UnsupportedFormat,
+  Other,
+}
+
+thread_local! {
+  static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(err: &anyhow::Error) -> SteganoStatus {
+  let status = match err.downcast_ref::<SteganoError>() {
+    Some(SteganoError::WeightLoad { .. }) => SteganoStatus::WeightLoad,
+    Some(SteganoError::ShapeMismatch(_)) => SteganoStatus::ShapeMismatch,
+    Some(SteganoError::CapacityExceeded { .. }) => SteganoStatus::CapacityExceeded,
+    Some(SteganoError::DecodeFailed | SteganoError::NewerVersion { .. }) => SteganoStatus::DecodeFailed,
+    Some(SteganoError::UnsupportedFormat(_)) => SteganoStatus::UnsupportedFormat,
+    Some(SteganoError::ImageTooLarge { .. }) => SteganoStatus::InvalidArgument,
+    _ if err.is::<image::ImageError>() => SteganoStatus::InvalidArgument,
+    _ => SteganoStatus::Other,
+  };
+  let msg = CString::new(format!("{err:#}").replace('\0', "")).unwrap_or_default();
+  LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+  status
+}
+
+// Runs `f`, storing its error or panic message for `steganogan_last_error`.
+fn guard(f: impl FnOnce() -> anyhow::Result<()>) -> SteganoStatus {
+  match catch_unwind(AssertUnwindSafe(f)) {
+    Ok(Ok(())) => SteganoStatus::Ok,
+    Ok(Err(err)) => set_error(&err),
+    Err(_) => set_error(&anyhow::anyhow!("panic in steganogan")),
+  }
+}
+
+unsafe fn slice<'a>(data: *const u8, len: usize) -> anyhow::Result<&'a [u8]> {
+  if data.is_null() {
+    anyhow::bail!("null buffer");
+  }
+  Ok(std::slice::from_raw_parts(data, len))
+}
+
+unsafe fn write_buffer(buffer: Vec<u8>, out: *mut *mut u8, out_len: *mut usize) {
+  let buffer = buffer.into_boxed_slice();
+  *out_len = buffer.len();
+  *out = Box::into_raw(buffer) as *mut u8;
+}
+
+/// Loads a model directory, PyTorch checkpoint or downloaded model by name. Returns NULL on failure.
+///
+/// # Safety
+/// `model` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn steganogan_load(model: *const c_char) -> *mut SteganoCodec {
+  let mut codec = ptr::null_mut();
+  guard(|| {
+    if model.is_null() {
+      anyhow::bail!("null model");
+    }
+    let model = CStr::from_ptr(model).to_str()?;
+    let device = Device::cuda_if_available(0)?;
+    codec = Box::into_raw(Box::new(SteganoCodec(Codec::open(model, &device)?)));
+    Ok(())
+  });
+  codec
+}
+
+/// Loads a model from the bytes of its encoder and decoder safetensors, e.g. bundled with an app. Returns NULL on
+/// failure.
+///
+/// # Safety
+/// The buffers must be valid for their lengths.
+#[no_mangle]
+pub unsafe extern "C" fn steganogan_load_buffers(
+  encoder: *const u8,
+  encoder_len: usize,
+  decoder: *const u8,
+  decoder_len: usize,
+) -> *mut SteganoCodec {
+  let mut codec = ptr::null_mut();
+  guard(|| {
+    let loaded = Codec::from_buffers(slice(encoder, encoder_len)?, slice(decoder, decoder_len)?, &Device::Cpu)?;
+    codec = Box::into_raw(Box::new(SteganoCodec(loaded)));
+    Ok(())
+  });
+  codec
+}
+
+/// Rejects images with more than `max_pixels` pixels in encode and decode, 0 removes the limit.
+///
+/// # Safety
+/// `codec` must come from `steganogan_load` or `steganogan_load_buffers`.
+#[no_mangle]
+pub unsafe extern "C" fn steganogan_set_max_pixels(codec: *mut SteganoCodec, max_pixels: u64) {
+  if !codec.is_null() {
+    (*codec).0.set_max_pixels((max_pixels > 0).then_some(max_pixels));
+  }
+}
+
+/// # Safety
+/// `codec` must come from `steganogan_load` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn steganogan_free(codec: *mut SteganoCodec) {
+  if !codec.is_null() {
+    drop(Box::from_raw(codec));
+  }
+}
+
+/// Hides `data` in the image (any format the crate reads) and writes the stego PNG to `out`, to be released with
+/// `steganogan_free_buffer`.
+///
+/// # Safety
+/// `codec` must come from `steganogan_load`, the input buffers must be valid for their lengths and the out pointers
+/// must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn steganogan_encode(
+  codec: *const SteganoCodec,
+  image: *const u8,
+  image_len: usize,
+  data: *const u8,
+  data_len: usize,
+  out: *mut *mut u8,
+  out_len: *mut usize,
+) -> SteganoStatus {
+  if codec.is_null() || out.is_null() || out_len.is_null() {
+    return SteganoStatus::InvalidArgument;
+  }
+  guard(|| {
+    let cover = image::load_from_memory(slice(image, image_len)?)?.to_rgb8();
+    let stego = (*codec).0.encode(&cover, slice(data, data_len)?, None)?;
+    write_buffer(image_io::encode_image(&stego, ImageFormat::Png)?, out, out_len);
+    Ok(())
+  })
+}
+
+/// Recovers the message from a stego image and writes it (not NUL-terminated) to `out`, to be released with
+/// `steganogan_free_buffer`.
+///
+/// # Safety
+/// Same as for `steganogan_encode`.
+#[no_mangle]
+pub unsafe extern "C" fn steganogan_decode(
+  codec: *const SteganoCodec,
+  image: *const u8,
+  image_len: usize,
+  out: *mut *mut u8,
+  out_len: *mut usize,
+) -> SteganoStatus {
+  if codec.is_null() || out.is_null() || out_len.is_null() {
+    return SteganoStatus::InvalidArgument;
+  }
+  guard(|| {
+    let img = image::load_from_memory(slice(image, image_len)?)?.to_rgb8();
+    let payload = (*codec).0.decode(&img)?;
+    write_buffer(payload.message.into_bytes(), out, out_len);
+    Ok(())
+  })
+}
+
+/// # Safety
+/// `buffer` and `len` must come from `steganogan_encode` or `steganogan_decode`.
+#[no_mangle]
+pub unsafe extern "C" fn steganogan_free_buffer(buffer: *mut u8, len: usize) {
+  if !buffer.is_null() {
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buffer, len)));
+  }
+}
+
+/// Message of the last error on this thread, NULL if there was none. Valid until the next call on the same thread.
+#[no_mangle]
+pub extern "C" fn steganogan_last_error() -> *const c_char {
+  LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |msg| msg.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use super::*;
+
+  #[test]
+  fn test_encode_decode() {
+    unsafe {
+      let model = CString::new("pretrained").unwrap();
+      let codec = steganogan_load(model.as_ptr());
+      assert!(!codec.is_null());
+
+      let mut cover = Vec::new();
+      image::RgbImage::new(64, 48)
+        .write_to(&mut Cursor::new(&mut cover), ImageFormat::Png)
+        .unwrap();
+      let (mut out, mut out_len) = (ptr::null_mut(), 0);
+      let data = b"hello";
+      let status = steganogan_encode(
+        codec,
+        cover.as_ptr(),
+        cover.len(),
+        data.as_ptr(),
+        5,
+        &mut out,
+        &mut out_len,
+      );
+      assert_eq!(status, SteganoStatus::Ok);
+      let stego = image::load_from_memory(std::slice::from_raw_parts(out, out_len)).unwrap();
+      assert_eq!((stego.width(), stego.height()), (64, 48));
+      steganogan_free_buffer(out, out_len);
+
+      let status = steganogan_decode(codec, data.as_ptr(), 5, &mut out, &mut out_len);
+      assert_eq!(status, SteganoStatus::InvalidArgument);
+      assert!(!steganogan_last_error().is_null());
+      steganogan_free(codec);
+    }
+  }
+
+  #[test]
+  fn test_header() {
+    // Regenerate with `cbindgen --output include/steganogan.h` when the API changes
+    let generated = include_str!(concat!(env!("OUT_DIR"), "/steganogan.h"));
+    assert_eq!(include_str!("../include/steganogan.h"), generated);
+  }
+}
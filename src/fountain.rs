@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use rayon::prelude::*;
+
+use crate::error::{Result, SteganoError};
+use crate::utils::{self, Bits};
+
+// LT fountain code for `Spread::Fountain`. The packed payload is cut into symbols and every packet carries the XOR of
+// a pseudo-random set of them, so that any set of intact packets that spans all symbols decodes. A packet is a magic
+// byte, the number of symbols and the seed of its set, followed by the XOR, Reed-Solomon coded on its own. Every packet
+// fills a block of pixels with all their payload channels, so that the blocks left whole in a cropped image still
+// decode. The distinct packets repeat over the image with a period of several blocks in both directions, and the logits
+// of the blocks of a packet are summed before it is read: a single block has too many bit errors for its code.
+const MAGIC: u8 = 0xf5;
+const HEADER_LEN: usize = 5;
+const SYMBOL_SIZE: usize = 15;
+const PACKET_LEN: usize = HEADER_LEN + SYMBOL_SIZE;
+pub const PACKET_BITS: usize = PACKET_LEN / utils::CHUNK_SIZE * utils::ENCODED_SIZE * 8;
+// Decoding eliminates over dense rows of this many bits, which limits the payload to about 60 KB.
+pub const MAX_SYMBOLS: usize = 4096;
+// Blocks sampled per offset when looking for the block grid of a cropped image.
+const SAMPLE_BLOCKS: usize = 16;
+// Robust soliton parameters, see `degrees`.
+const SOLITON_C: f64 = 0.1;
+const SOLITON_DELTA: f64 = 0.5;
+
+// Symbols a payload of `len` bytes is cut into.
+pub fn symbols(len: usize) -> usize {
+  len.div_ceil(SYMBOL_SIZE).max(1)
+}
+
+// Blocks of the period of the distinct packets of a payload of `k` symbols, rows and columns: about square, with room
+// for the first seeds whose sets span every symbol and a tenth more of them in case some packets do not decode. Block
+// `(row, col)` of the image carries seed `(row % rows) * cols + col % cols`.
+pub fn period(k: usize) -> (usize, usize) {
+  let degrees = degrees(k);
+  let mut solver = Solver::new(k);
+  let mut seeds = 0;
+  while !solver.complete() {
+    solver.add(Row::new(seeds, &degrees, [0; SYMBOL_SIZE]));
+    seeds += 1;
+  }
+  let packets = seeds as usize + seeds as usize / 10 + 1;
+  let cols = (packets as f64).sqrt().ceil() as usize;
+  (packets.div_ceil(cols), cols)
+}
+
+// Cumulative robust soliton distribution over the degrees 1 to `k`: mostly small sets, which are cheap to combine,
+// with enough single symbols to start elimination and a spike at `k / R` so that every symbol is covered.
+fn degrees(k: usize) -> Vec<f64> {
+  let r = SOLITON_C * (k as f64 / SOLITON_DELTA).ln() * (k as f64).sqrt();
+  let spike = ((k as f64 / r).round() as usize).clamp(1, k);
+  let weights: Vec<f64> = (1..=k)
+    .map(|d| {
+      let ideal = if d == 1 {
+        1. / k as f64
+      } else {
+        1. / (d * (d - 1)) as f64
+      };
+      let robust = match d.cmp(&spike) {
+        std::cmp::Ordering::Less => r / (d * k) as f64,
+        std::cmp::Ordering::Equal => r * (r / SOLITON_DELTA).ln().max(0.) / k as f64,
+        std::cmp::Ordering::Greater => 0.,
+      };
+      ideal + robust
+    })
+    .collect();
+  let total: f64 = weights.iter().sum();
+  weights
+    .iter()
+    .scan(0., |sum, weight| {
+      *sum += weight / total;
+      Some(*sum)
+    })
+    .collect()
+}
+
+// Symbols packet `seed` combines. ChaCha20 rather than `StdRng` to keep the sets stable between rand versions.
+fn neighbours(seed: u16, degrees: &[f64]) -> Vec<usize> {
+  let k = degrees.len();
+  let mut rng = ChaCha20Rng::seed_from_u64(seed as u64);
+  let sample: f64 = rng.gen();
+  let degree = degrees.partition_point(|&p| p < sample).min(k - 1) + 1;
+  let mut set = Vec::with_capacity(degree);
+  while set.len() < degree {
+    let symbol = rng.gen_range(0..k);
+    if !set.contains(&symbol) {
+      set.push(symbol);
+    }
+  }
+  set
+}
+
+// Rows and columns of the block a packet takes at `depth` payload bits per pixel, about square unless the payload
+// bits are fewer rows than that.
+fn block(depth: usize, height: usize) -> (usize, usize) {
+  let rows = ((PACKET_BITS as f64 / depth as f64).sqrt().ceil() as usize).clamp(1, height.max(1));
+  (rows, PACKET_BITS.div_ceil(depth * rows))
+}
+
+// Index into the `(depth, height, width)` payload bits of bit `bit` of the packet in block `(row, col)` of the grid
+// that starts at `origin`. The bits go through the block row by row, one channel after the other.
+fn position(bit: usize, (row, col): (usize, usize), origin: (usize, usize), shape: (usize, usize, usize)) -> usize {
+  let (_, height, width) = shape;
+  let (rows, cols) = block(shape.0, height);
+  let (channel, rest) = (bit / (rows * cols), bit % (rows * cols));
+  let y = origin.0 + row * rows + rest / cols;
+  let x = origin.1 + col * cols + rest % cols;
+  (channel * height + y) * width + x
+}
+
+// Whole blocks that fit from `origin` to the end of the payload bits.
+fn grid(origin: (usize, usize), (depth, height, width): (usize, usize, usize)) -> (usize, usize) {
+  let (rows, cols) = block(depth, height);
+  ((height - origin.0) / rows, (width - origin.1) / cols)
+}
+
+// Payload bits of `shape`, one coded packet per block and zeros where no whole block fits.
+pub fn encode(packed: &[u8], shape: (usize, usize, usize)) -> Result<Vec<u8>> {
+  let (depth, height, width) = shape;
+  let k = symbols(packed.len());
+  let (rows, cols) = block(depth, height);
+  let (grid_rows, grid_cols) = grid((0, 0), shape);
+  let (period_rows, period_cols) = period(k.min(MAX_SYMBOLS));
+  if k > MAX_SYMBOLS || grid_rows < period_rows || grid_cols < period_cols {
+    return Err(SteganoError::CapacityExceeded {
+      needed: period_rows * rows * period_cols * cols * depth,
+      available: depth * height * width,
+    });
+  }
+  let mut source = packed.to_vec();
+  source.resize(k * SYMBOL_SIZE, 0);
+  let degrees = degrees(k);
+  let packets: Vec<Bits> = (0..period_rows * period_cols)
+    .into_par_iter()
+    .map(|index| {
+      let seed = index as u16;
+      let mut packet = vec![MAGIC];
+      packet.extend((k as u16).to_le_bytes());
+      packet.extend(seed.to_le_bytes());
+      let mut symbol = [0; SYMBOL_SIZE];
+      for i in neighbours(seed, &degrees) {
+        symbol
+          .iter_mut()
+          .zip(&source[i * SYMBOL_SIZE..(i + 1) * SYMBOL_SIZE])
+          .for_each(|(x, s)| *x ^= s);
+      }
+      packet.extend(symbol);
+      utils::bytes_to_encoded_bits(&packet)
+    })
+    .collect();
+  let mut bits = vec![0; depth * height * width];
+  for block in (0..grid_rows).flat_map(|row| (0..grid_cols).map(move |col| (row, col))) {
+    let packet = &packets[(block.0 % period_rows) * period_cols + block.1 % period_cols];
+    for (bit, value) in packet.iter().enumerate() {
+      bits[position(bit, block, (0, 0), shape)] = *value as u8;
+    }
+  }
+  Ok(bits)
+}
+
+// Thresholded bits of the packet in `block` of the grid at `origin`, the first `len` of them.
+fn read(
+  logits: &[f32],
+  block: (usize, usize),
+  origin: (usize, usize),
+  shape: (usize, usize, usize),
+  len: usize,
+) -> Vec<u8> {
+  (0..len)
+    .map(|bit| (logits[position(bit, block, origin, shape)] > 0.) as u8)
+    .collect()
+}
+
+// Number of symbols and seed in the header of the packet in `block`, if its first Reed-Solomon block decodes on its
+// own. Damaged blocks often fail, but enough of a grid succeed to find it and the number of symbols.
+fn header(
+  logits: &[f32],
+  block: (usize, usize),
+  origin: (usize, usize),
+  shape: (usize, usize, usize),
+) -> Option<(usize, u16)> {
+  let bits = read(logits, block, origin, shape, utils::ENCODED_SIZE * 8);
+  let (header, correction) = utils::correct(&utils::bits_to_bytes(&bits));
+  (correction.failed_blocks == 0 && header.len() == HEADER_LEN && header[0] == MAGIC).then(|| {
+    (
+      u16::from_le_bytes([header[1], header[2]]) as usize,
+      u16::from_le_bytes([header[3], header[4]]),
+    )
+  })
+}
+
+// Where the block grid starts: the offset at which most of a sample of blocks begin with a readable packet header.
+// That is the top left corner unless the image was cropped.
+fn find_origin(logits: &[f32], shape: (usize, usize, usize)) -> Option<(usize, usize)> {
+  let (depth, height, width) = shape;
+  let (rows, cols) = block(depth, height);
+  let offsets: Vec<(usize, usize)> = (0..rows.min(height))
+    .flat_map(|y| (0..cols.min(width)).map(move |x| (y, x)))
+    .collect();
+  let (score, origin) = offsets
+    .into_par_iter()
+    .map(|origin| {
+      let (grid_rows, grid_cols) = grid(origin, shape);
+      let blocks = grid_rows * grid_cols;
+      let score = (0..blocks)
+        .step_by(blocks.div_ceil(SAMPLE_BLOCKS).max(1))
+        .filter(|&index| header(logits, (index / grid_cols, index % grid_cols), origin, shape).is_some())
+        .count();
+      (score, std::cmp::Reverse(origin))
+    })
+    .max()?;
+  (score > 0).then_some(origin.0)
+}
+
+// Row of the system being solved: the symbols a packet combines as a bit set, and their XOR.
+#[derive(Clone)]
+struct Row {
+  set: Vec<u64>,
+  symbol: [u8; SYMBOL_SIZE],
+}
+
+impl Row {
+  // Row of the packet with `seed` out of `degrees.len()` symbols.
+  fn new(seed: u16, degrees: &[f64], symbol: [u8; SYMBOL_SIZE]) -> Self {
+    let mut row = Self {
+      set: vec![0; degrees.len().div_ceil(64)],
+      symbol,
+    };
+    for i in neighbours(seed, degrees) {
+      row.set[i / 64] ^= 1 << (i % 64);
+    }
+    row
+  }
+
+  fn lowest(&self) -> Option<usize> {
+    let word = self.set.iter().position(|&word| word != 0)?;
+    Some(word * 64 + self.set[word].trailing_zeros() as usize)
+  }
+
+  fn has(&self, i: usize) -> bool {
+    self.set[i / 64] >> (i % 64) & 1 == 1
+  }
+
+  fn xor(&mut self, other: &Row) {
+    self.set.iter_mut().zip(&other.set).for_each(|(a, b)| *a ^= b);
+    self.symbol.iter_mut().zip(&other.symbol).for_each(|(a, b)| *a ^= b);
+  }
+}
+
+// Gaussian elimination over GF(2), keeping the row with each lowest symbol as the pivot of that symbol.
+struct Solver {
+  pivots: Vec<Option<Row>>,
+  solved: usize,
+}
+
+impl Solver {
+  fn new(k: usize) -> Self {
+    Self {
+      pivots: vec![None; k],
+      solved: 0,
+    }
+  }
+
+  fn complete(&self) -> bool {
+    self.solved == self.pivots.len()
+  }
+
+  fn add(&mut self, mut row: Row) {
+    while let Some(lowest) = row.lowest() {
+      match &self.pivots[lowest] {
+        Some(pivot) => row.xor(pivot),
+        None => {
+          self.pivots[lowest] = Some(row);
+          self.solved += 1;
+          return;
+        }
+      }
+    }
+  }
+
+  // The symbols in order, `None` unless every one has a pivot.
+  fn solve(mut self) -> Option<Vec<u8>> {
+    if !self.complete() {
+      return None;
+    }
+    // Back substitution from the last symbol, whose pivot row holds only that symbol
+    for i in (0..self.pivots.len()).rev() {
+      let (head, solved) = self.pivots.split_at_mut(i + 1);
+      let row = head[i].as_mut()?;
+      for (j, pivot) in solved.iter().enumerate() {
+        if row.has(i + 1 + j) {
+          row.xor(pivot.as_ref()?);
+        }
+      }
+    }
+    Some(
+      self
+        .pivots
+        .into_iter()
+        .flat_map(|row| row.map(|row| row.symbol))
+        .flatten()
+        .collect(),
+    )
+  }
+}
+
+// Recovers the packed payload, zero padded to whole symbols, from the logits of `(depth, height, width)` payload bits,
+// along with the number of blocks summed into packets that decoded. `None` if those do not span every symbol.
+pub fn decode(logits: &[f32], shape: (usize, usize, usize)) -> Option<(Vec<u8>, usize)> {
+  let origin = find_origin(logits, shape)?;
+  let (grid_rows, grid_cols) = grid(origin, shape);
+  let blocks: Vec<(usize, usize)> = (0..grid_rows)
+    .flat_map(|row| (0..grid_cols).map(move |col| (row, col)))
+    .collect();
+  let mut counts = HashMap::new();
+  for (k, _) in blocks
+    .par_iter()
+    .filter_map(|&block| header(logits, block, origin, shape))
+    .collect::<Vec<_>>()
+  {
+    *counts.entry(k).or_insert(0) += 1;
+  }
+  let (k, _) = counts.into_iter().max_by_key(|&(k, count)| (count, k))?;
+  if k == 0 || k > MAX_SYMBOLS {
+    return None;
+  }
+
+  // A crop moves the grid by whole blocks, which keeps the blocks of a packet a period apart
+  let (period_rows, period_cols) = period(k);
+  let packets: Vec<(usize, u16, [u8; SYMBOL_SIZE])> = (0..period_rows * period_cols)
+    .into_par_iter()
+    .filter_map(|index| {
+      let copies: Vec<&(usize, usize)> = blocks
+        .iter()
+        .filter(|(row, col)| row % period_rows == index / period_cols && col % period_cols == index % period_cols)
+        .collect();
+      let mut sums = vec![0.; PACKET_BITS];
+      for &&block in &copies {
+        for (bit, sum) in sums.iter_mut().enumerate() {
+          *sum += logits[position(bit, block, origin, shape)];
+        }
+      }
+      let bits: Vec<u8> = sums.iter().map(|&sum| (sum > 0.) as u8).collect();
+      let (packet, correction) = utils::correct(&utils::bits_to_bytes(&bits));
+      if copies.is_empty() || correction.failed_blocks > 0 || packet.len() != PACKET_LEN || packet[0] != MAGIC {
+        return None;
+      }
+      let packet_k = u16::from_le_bytes([packet[1], packet[2]]) as usize;
+      let seed = u16::from_le_bytes([packet[3], packet[4]]);
+      (packet_k == k).then_some((copies.len(), seed, packet[HEADER_LEN..].try_into().ok()?))
+    })
+    .collect();
+
+  let degrees = degrees(k);
+  let mut solver = Solver::new(k);
+  for (_, seed, symbol) in &packets {
+    solver.add(Row::new(*seed, &degrees, *symbol));
+    if solver.complete() {
+      break;
+    }
+  }
+  let count = packets.iter().map(|(copies, _, _)| copies).sum();
+  Some((solver.solve()?, count))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_fountain() -> Result<()> {
+    let packed: Vec<u8> = (0..200u32).map(|i| (i * 37 % 251) as u8).collect();
+    let shape = (2, 200, 180);
+    let bits = encode(&packed, shape)?;
+    let logits: Vec<f32> = bits.iter().map(|&bit| bit as f32 * 2. - 1.).collect();
+    let (data, count) = decode(&logits, shape).unwrap();
+    assert_eq!(data[..packed.len()], packed);
+
+    // A crop that is not aligned with the blocks keeps enough of them whole
+    let (top, left, height, width) = (13, 7, 160, 150);
+    let cropped: Vec<f32> = (0..shape.0)
+      .flat_map(|c| (top..top + height).flat_map(move |y| (left..left + width).map(move |x| (c, y, x))))
+      .map(|(c, y, x)| logits[(c * shape.1 + y) * shape.2 + x])
+      .collect();
+    let (data, cropped_count) = decode(&cropped, (shape.0, height, width)).unwrap();
+    assert_eq!(data[..packed.len()], packed);
+    assert!(cropped_count < count);
+
+    let (crumb_height, crumb_width) = (60, 60);
+    let crumb: Vec<f32> = (0..shape.0)
+      .flat_map(|c| (0..crumb_height).flat_map(move |y| (0..crumb_width).map(move |x| (c, y, x))))
+      .map(|(c, y, x)| logits[(c * shape.1 + y) * shape.2 + x])
+      .collect();
+    assert!(decode(&crumb, (shape.0, crumb_height, crumb_width)).is_none());
+    assert!(matches!(
+      encode(&packed, (2, 60, 60)),
+      Err(SteganoError::CapacityExceeded { .. })
+    ));
+    Ok(())
+  }
+
+  #[test]
+  fn test_pixel_blocks() -> anyhow::Result<()> {
+    // The blocks are rectangles of pixels: bits written into the color channels of an image survive a crop of the
+    // image itself, read back through the tensor layout the networks see
+    let packed: Vec<u8> = (0..120u32).map(|i| (i * 13 % 253) as u8).collect();
+    let (height, width) = (180, 200);
+    let bits = encode(&packed, (3, height, width))?;
+    let img = image::RgbImage::from_fn(width as u32, height as u32, |x, y| {
+      image::Rgb(std::array::from_fn(|c| {
+        bits[(c * height + y as usize) * width + x as usize] * 255
+      }))
+    });
+    let cropped = image::imageops::crop_imm(&img, 11, 5, 150, 140).to_image();
+    let tensor = crate::image_io::to_tensor(&cropped, &candle_core::Device::Cpu)?;
+    let logits: Vec<f32> = ((tensor / 127.5)? - 1.)?.flatten_all()?.to_vec1()?;
+    let (data, _) = decode(&logits, (3, 140, 150)).unwrap();
+    assert_eq!(data[..packed.len()], packed);
+    Ok(())
+  }
+}
@@ -0,0 +1,181 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::Result;
+use image::ImageFormat;
+use steganogan_rs::codec::{Codec, EncodeOptions};
+use steganogan_rs::{image_io, SteganoError};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status, Streaming};
+
+use proto::steganogan_server::{Steganogan, SteganoganServer};
+use proto::{CapacityRequest, CapacityResponse, DecodeResponse, EncodeRequest, ImageChunk};
+
+mod proto {
+  tonic::include_proto!("steganogan.v1");
+}
+
+const CHUNK_SIZE: usize = 64 << 10;
+// Largest image a request may stream, the body limit of the HTTP API.
+const BODY_LIMIT: usize = 64 << 20;
+
+fn status(err: anyhow::Error) -> Status {
+  let msg = format!("{err:#}");
+  match err.downcast_ref::<SteganoError>() {
+    Some(SteganoError::DecodeFailed) => Status::not_found(msg),
+    Some(SteganoError::NewerVersion { .. }) => Status::failed_precondition(msg),
+    Some(SteganoError::CapacityExceeded { .. } | SteganoError::UnsupportedFormat(_)) => Status::invalid_argument(msg),
+    Some(SteganoError::ImageTooLarge { .. }) => Status::resource_exhausted(msg),
+    _ if err.is::<image::ImageError>() => Status::invalid_argument(msg),
+    _ => Status::internal(msg),
+  }
+}
+
+// Appends a streamed chunk to the image, `false` without appending if that takes it past `BODY_LIMIT`.
+fn append(image: &mut Vec<u8>, chunk: Vec<u8>) -> bool {
+  let fits = image.len().saturating_add(chunk.len()) <= BODY_LIMIT;
+  if fits {
+    image.extend(chunk);
+  }
+  fits
+}
+
+fn too_large() -> Status {
+  Status::resource_exhausted(format!("image exceeds the limit of {} MB", BODY_LIMIT >> 20))
+}
+
+async fn run_blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T, Status> {
+  tokio::task::spawn_blocking(f)
+    .await
+    .map_err(|err| Status::internal(err.to_string()))?
+    .map_err(status)
+}
+
+pub struct Service {
+  codec: Arc<Codec>,
+}
+
+type ImageStream = Pin<Box<dyn Stream<Item = Result<ImageChunk, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Steganogan for Service {
+  type EncodeStream = ImageStream;
+
+  async fn encode(&self, request: Request<Streaming<EncodeRequest>>) -> Result<Response<Self::EncodeStream>, Status> {
+    let mut stream = request.into_inner();
+    let first = stream
+      .message()
+      .await?
+      .ok_or_else(|| Status::invalid_argument("empty request"))?;
+    let mut image = Vec::new();
+    if !append(&mut image, first.image) {
+      return Err(too_large());
+    }
+    while let Some(chunk) = stream.message().await? {
+      if !append(&mut image, chunk.image) {
+        return Err(too_large());
+      }
+    }
+    let format = match first.format.as_str() {
+      "" | "png" => ImageFormat::Png,
+      "webp" => ImageFormat::WebP,
+      format => {
+        return Err(Status::invalid_argument(format!(
+          "unsupported format '{format}', use png or webp"
+        )))
+      }
+    };
+    let codec = self.codec.clone();
+    let output = run_blocking(move || {
+      let cover = image::load_from_memory(&image)?.to_rgb8();
+      let size = (first.max_dim > 0)
+        .then(|| image_io::fit_within(cover.dimensions(), first.max_dim))
+        .flatten();
+      let stego = codec.encode(&cover, first.data.as_bytes(), size)?;
+      image_io::encode_image(&stego, format)
+    })
+    .await?;
+    let chunks: Vec<_> = output
+      .chunks(CHUNK_SIZE)
+      .map(|chunk| ImageChunk { image: chunk.to_vec() })
+      .collect();
+    Ok(Response::new(Box::pin(tokio_stream::iter(chunks).map(Ok))))
+  }
+
+  async fn decode(&self, request: Request<Streaming<ImageChunk>>) -> Result<Response<DecodeResponse>, Status> {
+    let mut stream = request.into_inner();
+    let mut image = Vec::new();
+    while let Some(chunk) = stream.next().await {
+      if !append(&mut image, chunk?.image) {
+        return Err(too_large());
+      }
+    }
+    let codec = self.codec.clone();
+    let payload = run_blocking(move || codec.decode(&image::load_from_memory(&image)?.to_rgb8())).await?;
+    Ok(Response::new(DecodeResponse {
+      message: payload.message,
+    }))
+  }
+
+  async fn capacity(&self, request: Request<CapacityRequest>) -> Result<Response<CapacityResponse>, Status> {
+    let request = request.into_inner();
+    let size = (request.width, request.height);
+    // The cover is rescaled like on encode
+    let options = EncodeOptions {
+      size: (request.max_dim > 0)
+        .then(|| image_io::fit_within(size, request.max_dim))
+        .flatten(),
+      ..Default::default()
+    };
+    let (needed_bits, available_bits) = self
+      .codec
+      .capacity_for(size, request.data.as_bytes(), &options)
+      .map_err(status)?;
+    Ok(Response::new(CapacityResponse {
+      available_bits: available_bits as u64,
+      needed_bits: needed_bits as u64,
+      fits: needed_bits <= available_bits,
+    }))
+  }
+}
+
+pub fn serve(addr: SocketAddr, codec: Codec) -> Result<()> {
+  let runtime = tokio::runtime::Runtime::new()?;
+  runtime.block_on(async {
+    let (mut health, health_service) = tonic_health::server::health_reporter();
+    health.set_serving::<SteganoganServer<Service>>().await;
+    let service = Service { codec: Arc::new(codec) };
+    eprintln!("listening on {addr}");
+    tonic::transport::Server::builder()
+      .add_service(health_service)
+      .add_service(SteganoganServer::new(service))
+      .serve(addr)
+      .await?;
+    Ok(())
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_status() {
+    assert_eq!(status(SteganoError::DecodeFailed.into()).code(), tonic::Code::NotFound);
+    assert_eq!(
+      status(SteganoError::UnsupportedFormat("avif".to_string()).into()).code(),
+      tonic::Code::InvalidArgument
+    );
+    assert_eq!(status(anyhow::anyhow!("boom")).code(), tonic::Code::Internal);
+  }
+
+  #[test]
+  fn test_append() {
+    let mut image = vec![0; BODY_LIMIT - 2];
+    assert!(append(&mut image, vec![1, 2]));
+    assert!(!append(&mut image, vec![3]));
+    assert_eq!(image.len(), BODY_LIMIT);
+    assert_eq!(too_large().code(), tonic::Code::ResourceExhausted);
+  }
+}
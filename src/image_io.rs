@@ -0,0 +1,150 @@
+use std::io::Cursor;
+
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{ColorType, ImageFormat, RgbImage};
+
+use crate::error::SteganoError;
+
+pub fn is_lossy(format: ImageFormat) -> bool {
+  matches!(format, ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::Avif)
+}
+
+pub fn encode_image(img: &RgbImage, format: ImageFormat) -> Result<Vec<u8>> {
+  let mut out = Cursor::new(Vec::new());
+  match format {
+    ImageFormat::WebP => {
+      WebPEncoder::new_lossless(&mut out).encode(img.as_raw(), img.width(), img.height(), ColorType::Rgb8)?
+    }
+    ImageFormat::Jpeg => JpegEncoder::new_with_quality(&mut out, 100).encode_image(img)?,
+    ImageFormat::Avif => Err(SteganoError::UnsupportedFormat(
+      "AVIF output is not supported: no lossless AVIF encoder is available, use PNG or WebP".to_string(),
+    ))?,
+    format => img.write_to(&mut out, format)?,
+  }
+  Ok(out.into_inner())
+}
+
+// Size that fits within `max_dim` keeping the aspect ratio, or None if the image is small enough already.
+pub fn fit_within((w, h): (u32, u32), max_dim: u32) -> Option<(u32, u32)> {
+  if w.max(h) <= max_dim {
+    return None;
+  }
+  let scale = max_dim as f64 / w.max(h) as f64;
+  Some((
+    ((w as f64 * scale).round() as u32).max(1),
+    ((h as f64 * scale).round() as u32).max(1),
+  ))
+}
+
+// Image as a (1, 3, h, w) f32 tensor with raw 0..255 values, pixel (x, y) at `[0, c, y, x]` like the training batches
+// of `data::Batches` and the PyTorch models the pretrained weights come from.
+pub fn to_tensor(img: &RgbImage, device: &Device) -> Result<Tensor> {
+  let (w, h) = (img.width() as usize, img.height() as usize);
+  let x = Tensor::from_vec(img.as_raw().clone(), (h, w, 3), device)?
+    .permute((2, 0, 1))?
+    .unsqueeze(0)?;
+  Ok(x.to_dtype(DType::F32)?)
+}
+
+// Inverse of `to_tensor` for a [-1, 1] normalized encoder output.
+pub fn from_tensor(x: &Tensor) -> Result<RgbImage> {
+  let (_, _, h, w) = x.dims4()?;
+  let x = ((x.get(0)?.permute((1, 2, 0))? + 1.)? * 127.5)?;
+  Ok(RgbImage::from_raw(w as u32, h as u32, quantize(&x)?).unwrap())
+}
+
+// An (n, 3, h, w) batch of image tensors with values in [0, 1] as the 0..255 values `to_tensor` gives the models.
+pub fn from_chw(x: &Tensor) -> Result<Tensor> {
+  Ok((x * 255.)?)
+}
+
+// Inverse of `from_chw` for a [-1, 1] normalized encoder output, like `from_tensor` without rounding.
+pub fn to_chw(x: &Tensor) -> Result<Tensor> {
+  Ok(((x + 1.)? / 2.)?)
+}
+
+// 8-bit levels of a tensor of 0-255 values, rounded to the nearest level with halves away from zero, then clamped
+// (NaN becomes 0). Casting the tensor would truncate, moving every pixel down by half a level on average, and how it
+// handles values out of range depends on the device.
+pub fn quantize(x: &Tensor) -> Result<Vec<u8>> {
+  let values = x.flatten_all()?.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+  Ok(values.into_iter().map(|v| v.round().clamp(0., 255.) as u8).collect())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sample() -> RgbImage {
+    RgbImage::from_fn(16, 9, |x, y| {
+      image::Rgb([(x * 16) as u8, (y * 28) as u8, (x * y) as u8])
+    })
+  }
+
+  #[test]
+  fn test_webp_lossless() -> Result<()> {
+    let img = sample();
+    let webp = encode_image(&img, ImageFormat::WebP)?;
+    assert_eq!(image::load_from_memory(&webp)?.to_rgb8(), img);
+    Ok(())
+  }
+
+  #[test]
+  fn test_is_lossy() {
+    assert!(is_lossy(ImageFormat::Jpeg));
+    assert!(!is_lossy(ImageFormat::Png));
+    assert!(!is_lossy(ImageFormat::WebP));
+  }
+
+  #[test]
+  fn test_tensor_round_trip() -> Result<()> {
+    let img = sample();
+    let x = ((to_tensor(&img, &Device::Cpu)? / 127.5)? - 1.)?;
+    assert_eq!(x.dims(), [1, 3, 9, 16]);
+    assert_eq!(from_tensor(&x)?, img);
+    // Pixel (x, y) is at [c, y, x]
+    let pixel = img.get_pixel(11, 4);
+    let x = to_tensor(&img, &Device::Cpu)?.get(0)?;
+    for c in 0..3 {
+      assert_eq!(x.get(c)?.get(4)?.get(11)?.to_scalar::<f32>()?, pixel[c] as f32);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_chw() -> Result<()> {
+    let img = sample();
+    let chw = (Tensor::from_vec(img.as_raw().clone(), (9, 16, 3), &Device::Cpu)?
+      .permute((2, 0, 1))?
+      .unsqueeze(0)?
+      .to_dtype(DType::F32)?
+      / 255.)?;
+    let pixels = to_tensor(&img, &Device::Cpu)?;
+    let diff = |a: &Tensor, b: &Tensor| -> Result<f32> { Ok((a - b)?.abs()?.max_all()?.to_scalar::<f32>()?) };
+    assert!(diff(&from_chw(&chw)?, &pixels)? < 1e-4);
+    assert!(diff(&to_chw(&((pixels / 127.5)? - 1.)?)?, &chw)? < 1e-4);
+    Ok(())
+  }
+
+  #[test]
+  fn test_quantize() -> Result<()> {
+    let x = Tensor::new(&[-3f32, 0.49, 0.5, 1.5, 127.49, 254.6, 300., f32::NAN], &Device::Cpu)?;
+    assert_eq!(quantize(&x)?, [0, 0, 1, 2, 127, 255, 255, 0]);
+    Ok(())
+  }
+
+  #[test]
+  fn test_fit_within() {
+    assert_eq!(fit_within((4000, 3000), 1000), Some((1000, 750)));
+    assert_eq!(fit_within((300, 1200), 600), Some((150, 600)));
+    assert_eq!(fit_within((640, 480), 1000), None);
+  }
+
+  #[test]
+  fn test_avif_rejected() {
+    assert!(encode_image(&sample(), ImageFormat::Avif).is_err());
+  }
+}
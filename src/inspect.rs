@@ -0,0 +1,177 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use candle_core::quantized::gguf_file;
+
+use crate::utils;
+use crate::weights;
+
+pub struct TensorInfo {
+  pub name: String,
+  pub shape: Vec<usize>,
+  pub dtype: String,
+}
+
+// What a weights file holds, for `inspect`.
+pub struct Summary {
+  pub path: PathBuf,
+  /// Size of the file in bytes
+  pub size: u64,
+  pub tensors: Vec<TensorInfo>,
+  pub metadata: BTreeMap<String, String>,
+}
+
+impl Summary {
+  pub fn parameters(&self) -> usize {
+    self
+      .tensors
+      .iter()
+      .map(|tensor| tensor.shape.iter().product::<usize>())
+      .sum()
+  }
+
+  // Parameters under every first name part, like the `layers` or `conv1` of a network.
+  pub fn layer_parameters(&self) -> BTreeMap<&str, usize> {
+    let mut layers = BTreeMap::new();
+    for tensor in &self.tensors {
+      let layer = tensor.name.split('.').next().unwrap_or_default();
+      *layers.entry(layer).or_default() += tensor.shape.iter().product::<usize>();
+    }
+    layers
+  }
+}
+
+impl fmt::Display for Summary {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let mut dtypes: Vec<&str> = self.tensors.iter().map(|tensor| tensor.dtype.as_str()).collect();
+    dtypes.sort();
+    dtypes.dedup();
+    writeln!(
+      f,
+      "{}: {} tensors, {} parameters, {:.1} KB, {}",
+      self.path.display(),
+      self.tensors.len(),
+      self.parameters(),
+      self.size as f64 / 1024.,
+      dtypes.join("/")
+    )?;
+    let tree = utils::tensor_tree(
+      self
+        .tensors
+        .iter()
+        .map(|tensor| (tensor.name.as_str(), format!("{:?} {}", tensor.shape, tensor.dtype))),
+    );
+    writeln!(f, "{tree}")?;
+    writeln!(f, "parameters by layer:")?;
+    for (layer, parameters) in self.layer_parameters() {
+      writeln!(f, "  {layer}: {parameters}")?;
+    }
+    if !self.metadata.is_empty() {
+      writeln!(f, "metadata:")?;
+      for (key, value) in &self.metadata {
+        writeln!(f, "  {key} = {value}")?;
+      }
+    }
+    Ok(())
+  }
+}
+
+// Summaries of a weights file, or of every component of a model directory.
+pub fn inspect(model: &Path) -> Result<Vec<Summary>> {
+  if !model.is_dir() {
+    return Ok(vec![read(model)?]);
+  }
+  let mut files: Vec<PathBuf> = std::fs::read_dir(model)?
+    .map(|entry| Ok(entry?.path()))
+    .collect::<Result<Vec<_>>>()?
+    .into_iter()
+    .filter(|path| {
+      let extension = path.extension().and_then(|ext| ext.to_str());
+      matches!(extension, Some("safetensors" | "gguf" | "onnx")) && !path.ends_with("optimizer.safetensors")
+    })
+    .collect();
+  files.sort();
+  if files.is_empty() {
+    bail!("No weights files in {}", model.display());
+  }
+  files.iter().map(|path| read(path)).collect()
+}
+
+fn read(path: &Path) -> Result<Summary> {
+  let (mut tensors, metadata): (Vec<TensorInfo>, HashMap<String, String>) =
+    match path.extension().and_then(|ext| ext.to_str()) {
+      Some("safetensors") => {
+        let buffer = std::fs::read(path)?;
+        let tensors = safetensors::SafeTensors::deserialize(&buffer)?
+          .tensors()
+          .into_iter()
+          .map(|(name, view)| TensorInfo {
+            name,
+            shape: view.shape().to_vec(),
+            dtype: format!("{:?}", view.dtype()),
+          })
+          .collect();
+        (tensors, weights::read_metadata(path)?.unwrap_or_default())
+      }
+      Some("gguf") => {
+        let content = gguf_file::Content::read(&mut std::fs::File::open(path)?)?;
+        let tensors = content
+          .tensor_infos
+          .iter()
+          .map(|(name, info)| TensorInfo {
+            name: name.clone(),
+            shape: info.shape.dims().to_vec(),
+            dtype: format!("{:?}", info.ggml_dtype),
+          })
+          .collect();
+        (tensors, weights::gguf_metadata(path)?)
+      }
+      #[cfg(feature = "onnx")]
+      Some("onnx") => {
+        let weights = crate::onnx::read(path)?;
+        (tensor_infos(weights.tensors), weights.metadata)
+      }
+      #[cfg(not(feature = "onnx"))]
+      Some("onnx") => bail!("Inspecting ONNX weights requires the `onnx` feature"),
+      _ => (tensor_infos(weights::read_pytorch(path)?), Default::default()),
+    };
+  tensors.sort_by(|a, b| a.name.cmp(&b.name));
+  Ok(Summary {
+    path: path.to_path_buf(),
+    size: std::fs::metadata(path)?.len(),
+    tensors,
+    metadata: metadata.into_iter().collect(),
+  })
+}
+
+fn tensor_infos(tensors: Vec<(String, candle_core::Tensor)>) -> Vec<TensorInfo> {
+  tensors
+    .into_iter()
+    .map(|(name, tensor)| TensorInfo {
+      name,
+      shape: tensor.dims().to_vec(),
+      dtype: format!("{:?}", tensor.dtype()),
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_inspect() -> Result<()> {
+    let summaries = inspect(Path::new("pretrained"))?;
+    let decoder = summaries
+      .iter()
+      .find(|summary| summary.path.ends_with("decoder.safetensors"))
+      .unwrap();
+    assert!(decoder.parameters() > 0);
+    assert_eq!(decoder.layer_parameters().values().sum::<usize>(), decoder.parameters());
+    let text = decoder.to_string();
+    assert!(text.contains("parameters by layer:") && text.contains("weight: ["));
+    Ok(())
+  }
+}
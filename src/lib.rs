@@ -0,0 +1,47 @@
+pub mod analyze;
+pub mod animation;
+pub mod attack;
+pub mod codec;
+pub mod color;
+pub mod compression;
+pub mod data;
+pub mod diff;
+#[cfg(feature = "documents")]
+pub mod document;
+pub mod ecc;
+pub mod engine;
+pub mod error;
+pub mod eval;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fountain;
+pub mod image_io;
+pub mod inspect;
+pub mod mask;
+pub mod metadata;
+#[cfg(feature = "mobile")]
+pub mod mobile;
+pub mod model;
+#[cfg(feature = "node")]
+pub mod node;
+#[cfg(feature = "onnx")]
+pub mod onnx;
+pub mod payload;
+pub mod pool;
+pub mod preprocess;
+pub mod rng;
+pub mod signing;
+pub mod stego_key;
+pub mod sync;
+pub mod texture;
+pub mod train;
+pub mod transform;
+pub mod utils;
+pub mod watermark;
+pub mod weights;
+#[cfg(feature = "video")]
+pub mod y4m;
+pub mod zoo;
+
+pub use engine::{SteganoGan, SteganoGanBuilder};
+pub use error::{Result, SteganoError};
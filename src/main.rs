@@ -1,140 +1,1925 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
-use anyhow::Result;
-use candle_core::Device;
+use anyhow::{anyhow, bail, Context, Result};
+use candle_core::{DType, Device};
 use candle_nn::{VarBuilder, VarMap};
-use clap::{Args, Parser, Subcommand};
-use model::decoder::Decoder;
-use model::encoder::Encoder;
+use clap::builder::RangedU64ValueParser;
+use clap::{ArgGroup, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use ed25519_dalek::VerifyingKey;
+use image::{ImageFormat, RgbImage};
+use serde::{Deserialize, Serialize};
+use steganogan_rs::animation::Animation;
+use steganogan_rs::codec::{Codec, DecodeOptions, EncodeOptions};
+use steganogan_rs::compression::Compression;
+use steganogan_rs::ecc::Ecc;
+use steganogan_rs::mask::Mask;
+use steganogan_rs::metadata::Metadata;
+use steganogan_rs::model::attention::Attention;
+use steganogan_rs::model::critic::Critic;
+use steganogan_rs::model::decoder::Decoder;
+use steganogan_rs::model::detector::Detector;
+use steganogan_rs::model::encoder::Encoder;
+use steganogan_rs::model::Arch;
+use steganogan_rs::payload::{NullPayload, PayloadType, Spread};
+use steganogan_rs::preprocess::Profile;
+use steganogan_rs::stego_key::StegoKey;
+use steganogan_rs::transform::Transform;
+use steganogan_rs::watermark::Watermark;
+use steganogan_rs::weights::ModelConfig;
+use steganogan_rs::{
+  analyze, attack, data, diff, eval, image_io, inspect, payload, rng, signing, sync, train, weights, zoo, SteganoError,
+};
 
-mod model;
-#[allow(dead_code)]
-mod utils;
+mod benchmark;
+mod clipboard;
+mod config;
+mod daemon;
+mod dataset;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod message;
+mod pipeline;
+mod registry;
+mod remote;
+#[cfg(feature = "http")]
+mod server;
+mod split;
+mod template;
+#[cfg(feature = "video")]
+mod video;
+mod watch;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
   #[command(subcommand)]
   command: Command,
+  /// Run encode/decode in this process even if a daemon is running
+  #[arg(long, global = true)]
+  no_daemon: bool,
+  /// Flag defaults, by default steganogan-rs/steganogan.toml in the user config directory
+  #[arg(long, global = true, value_name = "PATH")]
+  config: Option<PathBuf>,
+  /// Device to run the models on, a GPU if available by default
+  #[arg(long, global = true, value_enum, default_value_t = DeviceKind::Auto)]
+  device: DeviceKind,
+  /// Keep encode and decode under this much device memory, like 4G or 512M, by batching fewer images and tiling large
+  /// ones
+  #[arg(long, global = true, value_name = "SIZE", value_parser = parse_memory)]
+  max_memory: Option<u64>,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum DeviceKind {
+  Auto,
+  Cpu,
+  Cuda,
+}
+
+// Set once from `--device` before any command runs.
+static DEVICE: OnceLock<DeviceKind> = OnceLock::new();
+
+// Set once from `--max-memory` before any command runs.
+static MAX_MEMORY: OnceLock<Option<u64>> = OnceLock::new();
+
+fn max_memory() -> Option<u64> {
+  MAX_MEMORY.get().copied().flatten()
+}
+
+// A number of bytes with an optional binary K, M, G or T suffix.
+fn parse_memory(s: &str) -> Result<u64, String> {
+  let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+  let shift = match s[digits.len()..]
+    .to_ascii_uppercase()
+    .trim_end_matches("IB")
+    .trim_end_matches('B')
+  {
+    "" => 0,
+    "K" => 10,
+    "M" => 20,
+    "G" => 30,
+    "T" => 40,
+    _ => {
+      return Err(format!(
+        "invalid memory size '{s}', expected a number of bytes like 512M or 4G"
+      ))
+    }
+  };
+  let value: f64 = digits
+    .parse()
+    .map_err(|_| format!("invalid memory size '{s}', expected a number of bytes like 512M or 4G"))?;
+  Ok((value * (1u64 << shift) as f64) as u64)
+}
+
+fn device() -> Result<Device> {
+  Ok(match DEVICE.get().copied().unwrap_or(DeviceKind::Auto) {
+    DeviceKind::Auto => Device::cuda_if_available(0)?,
+    DeviceKind::Cpu => Device::Cpu,
+    DeviceKind::Cuda => Device::new_cuda(0)?,
+  })
 }
 
 #[derive(Subcommand)]
 enum Command {
   Encode(EncodeArgs),
   Decode(DecodeArgs),
+  #[command(subcommand)]
+  Models(ModelsCommand),
+  /// Convert a checkpoint into safetensors with embedded model metadata
+  Convert(ConvertArgs),
+  /// Export the encoder and decoder of a model to ONNX
+  #[cfg(feature = "onnx")]
+  ExportOnnx(ExportOnnxArgs),
+  /// Continue training pretrained weights on a directory of images
+  Finetune(FinetuneArgs),
+  /// Benchmark a model on a directory of images with random messages
+  Evaluate(EvaluateArgs),
+  /// Train a steganalysis detector on covers and stego images of a model
+  TrainDetector(TrainDetectorArgs),
+  /// Write a labeled dataset of covers and their stego images at several payload rates, for training detectors
+  GenDataset(GenDatasetArgs),
+  /// Classify images as clean or stego with a trained detector
+  Detect(DetectArgs),
+  /// Check which common image manipulations a stego image survives
+  Attack(AttackArgs),
+  /// Estimate how likely a message in an image survives common manipulations, before embedding it
+  Analyze(AnalyzeArgs),
+  /// Time encode and decode at common resolutions on this machine
+  Bench(BenchArgs),
+  /// Write a heatmap of where a stego image differs from its cover
+  Diff(DiffArgs),
+  /// Print the layer tree, parameter counts, size, dtypes and metadata of a model's weights
+  Inspect(InspectArgs),
+  /// Sign images with a creator ID and verify their provenance
+  #[command(subcommand)]
+  Watermark(WatermarkCommand),
+  /// Watermark the raster images of PDF and EPUB documents
+  #[cfg(feature = "documents")]
+  #[command(subcommand)]
+  Document(DocumentCommand),
+  /// Keep models loaded and serve encode/decode requests from other invocations over a Unix socket
+  Serve(ServeArgs),
+  /// Print a shell completion script or the man page
+  Completions(CompletionsArgs),
+  /// Serve encode/decode as an HTTP API
+  #[cfg(feature = "http")]
+  Server(ServerArgs),
+  /// Serve encode/decode as a gRPC service
+  #[cfg(feature = "grpc")]
+  Grpc(GrpcArgs),
+}
+
+#[derive(Subcommand)]
+enum ModelsCommand {
+  /// List published pretrained models
+  List,
+  /// Download a pretrained model into the cache directory
+  Pull { name: String },
 }
 
+#[derive(Subcommand)]
+enum WatermarkCommand {
+  /// Generate a signing key, the public key is written to <OUTPUT>.pub
+  Keygen {
+    #[arg(short)]
+    output: PathBuf,
+  },
+  /// Embed the creator ID and the current time, signed with the key
+  Apply(WatermarkApplyArgs),
+  /// Decode the watermark and check its signature against the public key
+  Verify(WatermarkVerifyArgs),
+}
+
+#[cfg(feature = "documents")]
+#[derive(Subcommand)]
+enum DocumentCommand {
+  /// Hide the data in every large raster image and write the rewritten document
+  Embed(DocumentEmbedArgs),
+  /// Decode the data of every large raster image
+  Decode(DocumentDecodeArgs),
+}
+
+#[cfg(feature = "documents")]
 #[derive(Args)]
-struct EncodeArgs {
+struct DocumentEmbedArgs {
+  /// PDF or EPUB document
   #[arg(short)]
   input: PathBuf,
   #[arg(short)]
   output: PathBuf,
+  /// Data to hide, like an ID of the recipient of this copy
   #[arg(short)]
   data: String,
+  /// Only images whose sides are both at least this many pixels
+  #[arg(long, default_value_t = 256)]
+  min_size: u32,
+  /// Model directory, PyTorch checkpoint or name of a downloaded model
+  #[arg(short, long, default_value = "pretrained")]
+  model: String,
+}
+
+#[cfg(feature = "documents")]
+#[derive(Args)]
+struct DocumentDecodeArgs {
+  #[arg(short)]
+  input: PathBuf,
+  /// Only images whose sides are both at least this many pixels
+  #[arg(long, default_value_t = 256)]
+  min_size: u32,
+  /// Model directory, PyTorch checkpoint or name of a downloaded model
+  #[arg(short, long, default_value = "pretrained")]
+  model: String,
+}
+
+#[derive(Args)]
+struct WatermarkApplyArgs {
+  #[arg(short)]
+  input: PathBuf,
+  #[arg(short)]
+  output: PathBuf,
+  #[arg(long)]
+  creator: String,
+  /// Signing key from `watermark keygen`
+  #[arg(long)]
+  key: PathBuf,
+  /// Model directory, PyTorch checkpoint or name of a downloaded model
+  #[arg(short, long, default_value = "pretrained")]
+  model: String,
 }
 
 #[derive(Args)]
+struct WatermarkVerifyArgs {
+  #[arg(short)]
+  input: PathBuf,
+  /// Public key of the creator
+  #[arg(long)]
+  public_key: PathBuf,
+  /// Model directory, PyTorch checkpoint or name of a downloaded model
+  #[arg(short, long, default_value = "pretrained")]
+  model: String,
+}
+
+#[derive(Args, Clone, Serialize, Deserialize)]
+#[command(group(ArgGroup::new("directory").args(["input_dir", "watch"])))]
+struct EncodeArgs {
+  #[arg(short, required_unless_present_any = ["input_dir", "watch"])]
+  input: Option<PathBuf>,
+  #[arg(short, required_unless_present = "output_dir")]
+  output: Option<PathBuf>,
+  /// Text to hide, with {filename}, {index} and {timestamp} expanded for every image of --per-image or --watch
+  #[arg(short, required_unless_present_any = ["data_file", "data_clipboard", "null_payload"])]
+  data: Option<String>,
+  /// Split the payload across the images in this directory in file name order, or hide all of it in each with
+  /// --per-image
+  #[arg(long, conflicts_with = "input", requires = "output_dir")]
+  input_dir: Option<PathBuf>,
+  /// Directory for the PNG stego images of --input-dir or --watch
+  #[arg(long, conflicts_with = "output", requires = "directory")]
+  output_dir: Option<PathBuf>,
+  /// Hide the payload in every image that appears in this directory, until interrupted
+  #[arg(long, value_name = "DIR", conflicts_with_all = ["input", "verify"], requires = "output_dir")]
+  watch: Option<PathBuf>,
+  /// Hide the whole payload in every image of --input-dir instead of splitting it across them
+  #[arg(long, requires = "input_dir")]
+  per_image: bool,
+  /// Append a record of every stego image to this JSON Lines file, for `decode --registry` to trace payloads back
+  #[arg(long, value_name = "FILE")]
+  registry: Option<PathBuf>,
+  /// Name the stego images of --input-dir or --watch after the BLAKE3 hash of their bytes
+  #[arg(long, requires = "directory", conflicts_with = "keep_format")]
+  name_by_hash: bool,
+  /// Threads reading and writing the images of --input-dir while the model runs, the number of CPUs by default
+  #[arg(long, requires = "input_dir")]
+  jobs: Option<usize>,
+  /// Images of --input-dir the model encodes in one forward pass, those of similar sizes are padded to the same size
+  #[arg(long, default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+  batch_size: usize,
+  /// Stop at the first image of --input-dir that can not be read, instead of skipping it
+  #[arg(long, requires = "input_dir")]
+  fail_fast: bool,
+  /// Write what happened to every image of --input-dir to this JSON file
+  #[arg(long, requires = "input_dir")]
+  manifest: Option<PathBuf>,
+  /// Hide the contents of this file, which may be binary
+  #[arg(long, conflicts_with = "data")]
+  data_file: Option<PathBuf>,
+  /// Hide the text on the clipboard, needs the clipboard feature
+  #[arg(long, conflicts_with_all = ["data", "data_file"])]
+  data_clipboard: bool,
+  /// Encode all-zero or random payload bits instead of any data, for cover/stego pairs that show the encoder's own
+  /// footprint to steganalysis. Nothing can be decoded from them
+  #[arg(
+    long,
+    value_enum,
+    value_name = "BITS",
+    num_args = 0..=1,
+    default_missing_value = "zeros",
+    conflicts_with_all = ["data", "data_file", "data_clipboard", "sign_key", "key", "verify", "registry"]
+  )]
+  null_payload: Option<NullPayload>,
+  /// Seed for the bits of `--null-payload random`, random by default; the rest of encoding is deterministic
+  #[arg(long, requires = "null_payload")]
+  seed: Option<u64>,
+  /// Type of the payload, inferred from -d or --data-file if not set
+  #[arg(long = "type", value_enum, conflicts_with = "input_dir")]
+  payload_type: Option<PayloadType>,
+  /// Model directory, PyTorch checkpoint or name of a downloaded model
+  #[arg(short, long, default_value = "pretrained")]
+  model: String,
+  /// Do not copy EXIF/ICC/text metadata from the input image
+  #[arg(long)]
+  strip_metadata: bool,
+  /// Allow writing to a lossy format (JPEG, GIF), which will likely corrupt the payload
+  #[arg(long)]
+  allow_lossy: bool,
+  /// Write the output in the format of the input, replacing the extension of -o. Lossy inputs get lossless WebP (PNG
+  /// for GIF) unless --allow-lossy is given
+  #[arg(long, conflicts_with = "input_dir")]
+  keep_format: bool,
+  /// Resize the cover to WxH before encoding
+  #[arg(long, value_parser = parse_size, conflicts_with = "max_dim")]
+  resize: Option<(u32, u32)>,
+  /// Downscale the cover so that neither side exceeds N pixels
+  #[arg(long, value_name = "N")]
+  max_dim: Option<u32>,
+  /// Sign the payload with this key from `watermark keygen`
+  #[arg(long)]
+  sign_key: Option<PathBuf>,
+  /// Passphrase that scrambles the payload and adds a MAC of it, decoding needs the same one
+  #[arg(long)]
+  key: Option<String>,
+  /// Payload compression, `none` is used instead if it would not make the payload smaller
+  #[arg(long, value_enum, default_value_t = Compression::Deflate)]
+  compress: Compression,
+  /// Compression level, clamped to the range of the algorithm
+  #[arg(long, value_name = "N")]
+  compress_level: Option<u32>,
+  /// Change no pixel channel by more than N of 255 levels, decoding gets less robust the lower it is
+  #[arg(long, value_name = "N")]
+  max_delta: Option<u8>,
+  /// Only embed where this image is light, decoding needs the same --mask
+  #[arg(long, conflicts_with = "input_dir")]
+  mask: Option<PathBuf>,
+  /// Put more of the change into textured regions and less into smooth ones, higher exponents shift more
+  #[arg(long, value_name = "EXPONENT", num_args = 0..=1, default_missing_value = "1")]
+  adaptive_strength: Option<f32>,
+  /// Hide the payload mostly in color (chroma) rather than brightness, keeping only WEIGHT (0-1) of the luma change
+  #[arg(long, value_name = "WEIGHT", value_parser = parse_weight, num_args = 0..=1, default_missing_value = "0.25")]
+  luma_weight: Option<f32>,
+  /// Decode the written image again and fail if it does not hold the payload
+  #[arg(long, conflicts_with = "input_dir")]
+  verify: bool,
+  /// How the payload fills the image: repeated copies, fountain code packets of which any large enough set decodes, or
+  /// tiles with pilot bits for `decode --resync`, robust tiles also spread every bit over all channels
+  #[arg(long, value_enum, default_value_t = Spread::Repeat, conflicts_with = "input_dir")]
+  spread: Spread,
+  /// Error correction: the standard short blocks, or long blocks that fail far less often at high error rates
+  #[arg(long, value_enum, default_value_t = Ecc::Standard)]
+  ecc: Ecc,
+  /// Preset for images that will go through a known kind of damage, in place of --spread and --ecc
+  #[arg(long, value_enum, conflicts_with_all = ["spread", "ecc", "input_dir"])]
+  profile: Option<RobustnessProfile>,
+  /// Lay the payload out in only the first N data channels of the model and leave the others 0, recorded in the
+  /// payload. Fewer channels hold fewer bits, but a model with fewer data channels can still decode them
+  #[arg(long, value_name = "N", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+  channels: Option<usize>,
+  /// Pick as few leading data channels as still hold several copies of a short payload, in place of --channels
+  #[arg(long, conflicts_with = "channels")]
+  variable_rate: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RobustnessProfile {
+  /// Survives screenshots and re-rasterization: robust tiles with one bit per 2x2 pixels and pilot markers, and the
+  /// strong error correction
+  Screenshot,
+}
+
+impl RobustnessProfile {
+  fn spread(self) -> Spread {
+    match self {
+      RobustnessProfile::Screenshot => Spread::RobustTiles,
+    }
+  }
+
+  fn ecc(self) -> Ecc {
+    match self {
+      RobustnessProfile::Screenshot => Ecc::Strong,
+    }
+  }
+
+  fn tiling(self) -> sync::Tiling {
+    match self {
+      RobustnessProfile::Screenshot => sync::Tiling::Robust,
+    }
+  }
+}
+
+fn parse_rate(s: &str) -> Result<f32, String> {
+  match s.parse::<f32>() {
+    Ok(rate) if (0. ..=1.).contains(&rate) => Ok(rate),
+    _ => Err(format!("{s} is not a payload rate between 0 and 1")),
+  }
+}
+
+fn parse_weight(s: &str) -> Result<f32, String> {
+  match s.parse::<f32>() {
+    Ok(weight) if (0. ..=1.).contains(&weight) => Ok(weight),
+    _ => Err(format!("{s} is not a weight between 0 and 1")),
+  }
+}
+
+fn parse_size(s: &str) -> Result<(u32, u32), String> {
+  let (w, h) = s.split_once('x').ok_or("expected WxH")?;
+  let parse = |v: &str| {
+    v.parse::<u32>()
+      .ok()
+      .filter(|v| *v > 0)
+      .ok_or(format!("invalid size '{s}'"))
+  };
+  Ok((parse(w)?, parse(h)?))
+}
+
+#[derive(Args, Clone, Serialize, Deserialize)]
 struct DecodeArgs {
+  #[arg(short, required_unless_present_any = ["input_dir", "watch"])]
+  input: Option<PathBuf>,
+  /// Reassemble a payload split across the images in this directory
+  #[arg(long, conflicts_with = "input", requires = "output")]
+  input_dir: Option<PathBuf>,
+  /// Decode every image that appears in this directory, until interrupted
+  #[arg(long, value_name = "DIR", conflicts_with_all = ["input", "input_dir", "to_clipboard"])]
+  watch: Option<PathBuf>,
+  /// File to write the payload reassembled from --input-dir to
+  #[arg(short, requires = "input_dir")]
+  output: Option<PathBuf>,
+  /// Threads reading the images of --input-dir while the model runs, the number of CPUs by default
+  #[arg(long, requires = "input_dir")]
+  jobs: Option<usize>,
+  /// Images of --input-dir the model decodes in one forward pass, those of similar sizes are padded to the same size
+  #[arg(long, default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+  batch_size: usize,
+  /// Stop at the first image of --input-dir that can not be read, instead of skipping it
+  #[arg(long, requires = "input_dir")]
+  fail_fast: bool,
+  /// Write what happened to every image of --input-dir to this JSON file
+  #[arg(long, requires = "input_dir")]
+  manifest: Option<PathBuf>,
+  /// Model directory, PyTorch checkpoint or name of a downloaded model
+  #[arg(short, long, default_value = "pretrained")]
+  model: String,
+  /// Fail unless the payload is signed by the owner of this public key
+  #[arg(long)]
+  verify_key: Option<PathBuf>,
+  /// Passphrase the payload was scrambled with, which also checks its MAC
+  #[arg(long)]
+  key: Option<String>,
+  /// Directory to save file payloads to, under their original name
+  #[arg(long, default_value = ".")]
+  save_dir: PathBuf,
+  /// Print every decoded candidate with its votes, error correction and CRC status
+  #[arg(long, conflicts_with = "input_dir")]
+  all_candidates: bool,
+  /// Copy the decoded text to the clipboard, needs the clipboard feature
+  #[arg(long, conflicts_with_all = ["input_dir", "all_candidates"])]
+  to_clipboard: bool,
+  /// Mask the payload was embedded with
+  #[arg(long)]
+  mask: Option<PathBuf>,
+  /// Realign an image encoded with --spread tiles that may have been cropped or shifted
+  #[arg(long)]
+  resync: bool,
+  /// Decode an image encoded with this --profile, which also realigns it
+  #[arg(long, value_enum, conflicts_with = "resync")]
+  profile: Option<RobustnessProfile>,
+  /// Read only the first N data channels, those an image encoded with `--channels N` uses, which is otherwise detected
+  #[arg(long, value_name = "N", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+  channels: Option<usize>,
+  /// Also try undoing small rotations, flips and rescaling, for images edited before they were shared
+  #[arg(long, conflicts_with_all = ["input_dir", "all_candidates"])]
+  search_transforms: bool,
+  /// Look the decoded payload up in this file of `encode --registry` and print where it was embedded
+  #[arg(long, value_name = "FILE")]
+  registry: Option<PathBuf>,
+  /// Estimate the bit error rate of a successful decode from the error correction, to see how close it was to failing
+  #[arg(long, conflicts_with_all = ["input_dir", "all_candidates", "search_transforms"])]
+  error_rate: bool,
+}
+
+#[cfg(feature = "onnx")]
+#[derive(Args)]
+struct ExportOnnxArgs {
+  /// Model directory, PyTorch checkpoint or name of a downloaded model
+  #[arg(short, long, default_value = "pretrained")]
+  model: String,
+  /// Output directory for encoder.onnx and decoder.onnx
+  #[arg(short)]
+  output: PathBuf,
+}
+
+#[derive(Args)]
+struct ConvertArgs {
+  /// Model directory, PyTorch checkpoint, or GGUF or ONNX file
+  #[arg(short)]
+  input: PathBuf,
+  /// Output directory
+  #[arg(short)]
+  output: PathBuf,
+  #[arg(long, value_enum, default_value_t = Arch::Dense)]
+  arch: Arch,
+  /// Bits per pixel, detected from the weight shapes by default
+  #[arg(long)]
+  data_depth: Option<usize>,
+  /// Channels of the conv blocks, detected from the weight shapes by default
+  #[arg(long)]
+  hidden_size: Option<usize>,
+  /// Input normalization the checkpoint was trained with
+  #[arg(long, value_enum, default_value_t = Profile::Steganogan)]
+  preprocess: Profile,
+  /// Attention blocks the checkpoint has after every conv block
+  #[arg(long, value_enum, default_value_t = Attention::None)]
+  attention: Attention,
+  /// Conv blocks between the first one and the output (U-Net levels for --arch unet)
+  #[arg(long, default_value_t = 2, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+  num_blocks: usize,
+  /// The conv blocks use depthwise-separable convolutions
+  #[arg(long)]
+  separable: bool,
+}
+
+#[derive(Args)]
+struct FinetuneArgs {
+  /// Directory with training images
+  #[arg(short)]
+  input: PathBuf,
+  /// Output model directory
+  #[arg(short)]
+  output: PathBuf,
+  /// Model directory, PyTorch checkpoint or name of a downloaded model
+  #[arg(short, long, default_value = "pretrained")]
+  model: String,
+  /// Resume training from a checkpoint directory (e.g. <output>/last)
+  #[arg(long)]
+  resume: Option<PathBuf>,
+  /// Training images are cropped to this size
+  #[arg(long, default_value_t = 128)]
+  image_size: u32,
+  #[arg(long, default_value_t = 4)]
+  batch_size: usize,
+  /// Disable random crops, flips and color jitter
+  #[arg(long)]
+  no_augment: bool,
+  /// Fraction of images held out for validation
+  #[arg(long, default_value_t = 0.1)]
+  val_split: f32,
+  /// Stop after this many epochs without improvement of validation accuracy
+  #[arg(long)]
+  early_stop_patience: Option<usize>,
+  /// Also write TensorBoard event files next to metrics.csv
+  #[arg(long)]
+  tensorboard: bool,
+  /// Do not save sample cover/stego images after each epoch
+  #[arg(long)]
+  no_samples: bool,
+  /// Train the decoder for this input normalization instead of the one of the model
+  #[arg(long, value_enum)]
+  preprocess: Option<Profile>,
+  /// Train a new decoder of this architecture, starting from the encoder and critic of the model
+  #[arg(long, value_enum, conflicts_with = "resume")]
+  arch: Option<Arch>,
+  /// Add attention blocks to the conv blocks of the encoder and decoder, trained from scratch
+  #[arg(long, value_enum, conflicts_with = "resume")]
+  attention: Option<Attention>,
+  /// Use this many conv blocks instead of those of the model, blocks it does not have are trained from scratch
+  #[arg(long, conflicts_with = "resume", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+  num_blocks: Option<usize>,
+  /// Switch the conv blocks to depthwise-separable convolutions for fast CPU inference, trained from scratch
+  #[arg(long, conflicts_with = "resume")]
+  separable: bool,
+  #[command(flatten)]
+  train: train::TrainOptions,
+}
+
+#[derive(Args)]
+struct TrainDetectorArgs {
+  /// Directory with cover images
   #[arg(short)]
   input: PathBuf,
+  /// Output directory for detector.safetensors
+  #[arg(short)]
+  output: PathBuf,
+  /// Model whose stego images the detector learns to recognize
+  #[arg(short, long, default_value = "pretrained")]
+  model: String,
+  /// Width of the first detector layers
+  #[arg(long, default_value_t = 16)]
+  hidden_size: usize,
+  /// Training images are cropped to this size
+  #[arg(long, default_value_t = 128)]
+  image_size: u32,
+  #[arg(long, default_value_t = 8)]
+  batch_size: usize,
+  #[arg(long, default_value_t = 10)]
+  epochs: usize,
+  #[arg(long, default_value_t = 1e-3)]
+  lr: f64,
+  /// Fraction of images held out for validation
+  #[arg(long, default_value_t = 0.1)]
+  val_split: f32,
+  /// Seed for training payloads and data order, random by default
+  #[arg(long)]
+  seed: Option<u64>,
+}
+
+#[derive(Args)]
+struct DetectArgs {
+  /// Images or directories of images
+  #[arg(required = true)]
+  input: Vec<PathBuf>,
+  /// Directory with detector.safetensors from `train-detector`
+  #[arg(short, long)]
+  detector: PathBuf,
+}
+
+#[derive(Args)]
+struct GenDatasetArgs {
+  /// Directory with cover images
+  #[arg(short)]
+  input: PathBuf,
+  /// Dataset directory, for cover/, stego/RATE/, payloads/RATE/, labels.csv and manifest.json
+  #[arg(short)]
+  output: PathBuf,
+  /// Model directory, PyTorch checkpoint or name of a downloaded model
+  #[arg(short, long, default_value = "pretrained")]
+  model: String,
+  /// Payload rates as fractions of the message capacity of every cover, 0 for the zero payload of `--null-payload`
+  #[arg(long, value_parser = parse_rate, value_delimiter = ',', default_values_t = [0.1, 0.4, 1.])]
+  rates: Vec<f32>,
+  /// Seed for the random messages, random by default
+  #[arg(long)]
+  seed: Option<u64>,
+  /// Threads reading and writing the images while the model runs, the number of CPUs by default
+  #[arg(long)]
+  jobs: Option<usize>,
+  /// Covers the model encodes in one forward pass, each with a stego image per rate
+  #[arg(long, default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+  batch_size: usize,
+}
+
+#[derive(Args)]
+struct EvaluateArgs {
+  /// Directory with cover images
+  #[arg(short)]
+  input: PathBuf,
+  /// Report file, .json or .csv
+  #[arg(short)]
+  output: PathBuf,
+  /// Model directory, PyTorch checkpoint or name of a downloaded model
+  #[arg(short, long, default_value = "pretrained")]
+  model: String,
+  /// Length of the random message hidden in every image
+  #[arg(long, default_value_t = 32)]
+  message_size: usize,
+  /// Seed for the random messages, random by default
+  #[arg(long)]
+  seed: Option<u64>,
+  /// Calibrate the decision threshold of every decoder channel on the images first and store it in the model
+  #[arg(long)]
+  calibrate: bool,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+  /// Model directory, PyTorch checkpoint or name of a downloaded model
+  #[arg(short, long, default_value = "pretrained")]
+  model: String,
+  /// Timed runs per resolution, after one warm-up run
+  #[arg(short = 'n', long, default_value_t = 3)]
+  iterations: usize,
+  /// Resolutions to time, 512x512, 1920x1080 and 3840x2160 by default
+  #[arg(long, value_parser = parse_size, value_delimiter = ',')]
+  sizes: Vec<(u32, u32)>,
+  /// Images encoded and decoded in one forward pass, the times stay per image
+  #[arg(long, default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+  batch_size: usize,
+}
+
+#[derive(Args)]
+struct AttackArgs {
+  /// Stego image
+  #[arg(short)]
+  input: PathBuf,
+  /// Model directory, PyTorch checkpoint or name of a downloaded model
+  #[arg(short, long, default_value = "pretrained")]
+  model: String,
+  /// Expected message, by default the one decoded from the unmodified image
+  #[arg(long)]
+  message: Option<String>,
+  /// Also write the results to a JSON file
+  #[arg(short)]
+  output: Option<PathBuf>,
+  /// Seed for randomized attacks, random by default
+  #[arg(long)]
+  seed: Option<u64>,
+}
+
+#[derive(Args)]
+struct AnalyzeArgs {
+  /// Cover image
+  #[arg(short)]
+  input: PathBuf,
+  /// Message to plan for
+  #[arg(short)]
+  data: String,
+  /// Model directory, PyTorch checkpoint or name of a downloaded model
+  #[arg(short, long, default_value = "pretrained")]
+  model: String,
+  /// Payload compression, as for encode
+  #[arg(long, value_enum, default_value_t = Compression::Deflate)]
+  compress: Compression,
+  /// Compression level, clamped to the range of the algorithm
+  #[arg(long, value_name = "N")]
+  compress_level: Option<u32>,
+  /// Also write the results to a JSON file
+  #[arg(short)]
+  output: Option<PathBuf>,
+  /// Seed for randomized attacks, random by default
+  #[arg(long)]
+  seed: Option<u64>,
+}
+
+#[derive(Args)]
+struct InspectArgs {
+  /// Weights file, model directory or name of a downloaded model
+  #[arg(default_value = "pretrained")]
+  model: String,
 }
 
-fn encode(args: EncodeArgs) -> Result<()> {
-  let device = &Device::cuda_if_available(0)?;
+#[derive(Args)]
+struct DiffArgs {
+  /// Cover image
+  cover: PathBuf,
+  /// Stego image
+  stego: PathBuf,
+  /// Heatmap image
+  #[arg(short)]
+  output: PathBuf,
+  /// Multiply the differences by this factor, by default the largest one is scaled to white
+  #[arg(long)]
+  amplify: Option<f32>,
+}
 
-  let mut enc_varmap = VarMap::new();
-  let vb = VarBuilder::from_varmap(&enc_varmap, candle_core::DType::F32, device);
-  let encoder = Encoder::new(8, 32, vb.clone())?;
-  enc_varmap.load("pretrained/encoder.safetensors")?;
+#[derive(Args)]
+struct CompletionsArgs {
+  #[arg(value_enum, required_unless_present = "man")]
+  shell: Option<clap_complete::Shell>,
+  /// Print the man page in roff instead
+  #[arg(long, conflicts_with = "shell")]
+  man: bool,
+}
 
-  let img = image::open(args.input)?;
-  let img_bytes = img.to_rgb8().into_raw();
-  let img_tensor = candle_core::Tensor::from_vec(img_bytes, (img.width() as usize, img.height() as usize, 3), device)?
-    .permute((2, 1, 0))?
-    .unsqueeze(0)?;
-  let img_tensor = ((img_tensor.to_dtype(candle_core::DType::F32)? / 127.5)? - 1.)?;
+#[derive(Args)]
+struct ServeArgs {
+  /// Socket path, by default $STEGANOGAN_SOCKET or steganogan-rs.sock in the runtime directory
+  #[arg(long)]
+  socket: Option<PathBuf>,
+  /// Models to load on startup, others are loaded on first use
+  #[arg(short, long, default_value = "pretrained")]
+  model: Vec<String>,
+}
 
-  let data_size = (img.height() * img.width() * 8) as usize;
-  let mut message = utils::bytes_to_encoded_bits(args.data.as_bytes());
-  message.extend([0; 32]);
-  let mut data = message.clone();
-  while data.len() < data_size {
-    data.extend(message.clone());
+#[cfg(feature = "http")]
+#[derive(Args)]
+struct ServerArgs {
+  #[arg(long, default_value = "127.0.0.1:8080")]
+  listen: std::net::SocketAddr,
+  #[command(flatten)]
+  service: ServiceArgs,
+}
+
+#[cfg(feature = "grpc")]
+#[derive(Args)]
+struct GrpcArgs {
+  #[arg(long, default_value = "127.0.0.1:50051")]
+  listen: std::net::SocketAddr,
+  #[command(flatten)]
+  service: ServiceArgs,
+}
+
+// Device memory the HTTP and gRPC servers keep their forward passes under without `--max-memory`.
+#[cfg(any(feature = "http", feature = "grpc"))]
+const SERVICE_MAX_MEMORY: u64 = 4 << 30;
+
+#[cfg(any(feature = "http", feature = "grpc"))]
+#[derive(Args)]
+struct ServiceArgs {
+  /// Model directory, PyTorch checkpoint or name of a downloaded model
+  #[arg(short, long, default_value = "pretrained")]
+  model: String,
+  /// Largest image a request may send, in pixels
+  #[arg(long, default_value_t = 50_000_000)]
+  max_pixels: u64,
+}
+
+#[cfg(any(feature = "http", feature = "grpc"))]
+impl ServiceArgs {
+  // The codec requests share: no upload can take more than the limits of the server, whatever its size.
+  fn open(&self) -> Result<steganogan_rs::codec::Codec> {
+    let mut codec = steganogan_rs::codec::Codec::open(&self.model, &device()?)?;
+    codec.set_max_pixels(Some(self.max_pixels));
+    codec.set_max_memory(Some(max_memory().unwrap_or(SERVICE_MAX_MEMORY)));
+    Ok(codec)
   }
-  data.truncate(data_size);
-  let data = candle_core::Tensor::from_vec(data, (1, 8, img.height() as usize, img.width() as usize), device)?;
-  let data = data.to_dtype(candle_core::DType::F32)?;
+}
 
-  let x = encoder.forward(&img_tensor, &data)?;
+// Cover image with its metadata and the size it is resized to before encoding.
+struct Cover {
+  image: RgbImage,
+  metadata: Metadata,
+  size: Option<(u32, u32)>,
+}
 
-  let x = ((x.get(0)?.clamp(-1., 1.)?.permute((2, 1, 0))? + 1.)? * 127.5)?;
-  let img = image::RgbImage::from_raw(
-    img.width(),
-    img.height(),
-    x.flatten_all()?.to_dtype(candle_core::DType::U8)?.to_vec1::<u8>()?,
-  )
-  .unwrap();
+impl Cover {
+  fn read(path: &Path, args: &EncodeArgs) -> Result<Self> {
+    Self::decode(&std::fs::read(path)?, args)
+  }
+
+  fn decode(input: &[u8], args: &EncodeArgs) -> Result<Self> {
+    let metadata = if args.strip_metadata {
+      Metadata::default()
+    } else {
+      Metadata::read(input)
+    };
+    let image = image::load_from_memory(input)?.to_rgb8();
+    let size = match (args.resize, args.max_dim) {
+      (Some(size), _) => Some(size),
+      (None, Some(max_dim)) => image_io::fit_within(image.dimensions(), max_dim),
+      (None, None) => None,
+    };
+    Ok(Self { image, metadata, size })
+  }
+
+  fn encode(&self, codec: &Codec, data: &[u8], options: EncodeOptions, output: &Path) -> Result<()> {
+    self.write(&self.stego(codec, data, options)?, output)
+  }
+
+  fn stego(&self, codec: &Codec, data: &[u8], options: EncodeOptions) -> Result<RgbImage> {
+    let options = EncodeOptions {
+      size: self.size,
+      ..options
+    };
+    codec.encode_with(&self.image, data, &options)
+  }
+
+  // Writes the stego image in the format of the extension of `output`, with the metadata of the cover.
+  fn write(&self, stego: &RgbImage, output: &Path) -> Result<()> {
+    let format = ImageFormat::from_path(output)?;
+    let encoded = image_io::encode_image(stego, format)?;
+    remote::write(output, &self.metadata.embed(encoded, format)?)?;
+    Ok(())
+  }
+}
 
-  img.save(args.output)?;
+// `args` with the variables of a `-d` template expanded for the `index`th image of --per-image or --watch.
+fn expand_data(args: &EncodeArgs, input: &Path, index: usize) -> Result<EncodeArgs> {
+  let vars = template::Vars { input, index };
+  Ok(EncodeArgs {
+    data: args
+      .data
+      .as_deref()
+      .map(|data| template::expand(data, &vars))
+      .transpose()?,
+    ..args.clone()
+  })
+}
+
+// Path and BLAKE3 hash of a written stego image, renamed after the hash for --name-by-hash, which keeps the extension.
+fn hash_output(path: &Path, rename: bool) -> Result<(PathBuf, String)> {
+  let hash = blake3::hash(&std::fs::read(path)?).to_hex().to_string();
+  if !rename {
+    return Ok((path.to_path_buf(), hash));
+  }
+  let named = path
+    .with_file_name(&hash)
+    .with_extension(path.extension().unwrap_or_default());
+  std::fs::rename(path, &named)?;
+  Ok((named, hash))
+}
+
+fn read_mask(path: &Path) -> Result<Mask> {
+  let img = image::open(path).with_context(|| format!("Failed to read mask {}", path.display()))?;
+  Ok(Mask::new(&img))
+}
+
+// Format of an image file from its contents rather than its extension.
+fn input_format(path: &Path) -> Result<ImageFormat> {
+  image::io::Reader::open(path)?
+    .with_guessed_format()?
+    .format()
+    .with_context(|| format!("Unknown image format of {}", path.display()))
+}
+
+// `output` with the extension of the format of `input` for --keep-format. JPEG stays JPEG only with --allow-lossy, GIF
+// becomes PNG as animations are written as APNG, and AVIF has no lossless encoder.
+fn keep_format(input: &Path, output: &Path, allow_lossy: bool) -> Result<PathBuf> {
+  let format = match input_format(input)? {
+    ImageFormat::Jpeg if !allow_lossy => ImageFormat::WebP,
+    ImageFormat::Avif => ImageFormat::WebP,
+    ImageFormat::Gif => ImageFormat::Png,
+    format => format,
+  };
+  Ok(output.with_extension(format.extensions_str()[0]))
+}
 
+// Notes the size change and what it means for the payload when the output is in another format than the cover.
+fn conversion_report(cover: &[u8], output: &Path) -> Result<String> {
+  let (from, to) = (image::guess_format(cover)?, ImageFormat::from_path(output)?);
+  if from == to {
+    return Ok(String::new());
+  }
+  // Uploads are not read back for their size
+  let mut report = match remote::is_url(output) {
+    true => format!("converted {from:?} to {to:?}\n"),
+    false => {
+      let (before, after) = (cover.len() as u64, std::fs::metadata(output)?.len());
+      let change = (after as f64 / before.max(1) as f64 - 1.) * 100.;
+      format!("converted {from:?} to {to:?}: {before} -> {after} bytes ({change:+.0}%)\n")
+    }
+  };
+  if image_io::is_lossy(to) {
+    report += &format!("warning: {to:?} compression will likely corrupt the payload\n");
+  } else if image_io::is_lossy(from) {
+    report += &format!(
+      "note: the output is lossless and usually larger than the {from:?} cover, saving it as {from:?} again would \
+       destroy the payload\n"
+    );
+  }
+  Ok(report)
+}
+
+fn encode(args: EncodeArgs, models: &mut daemon::Models) -> Result<daemon::Output> {
+  let output = match (&args.input, &args.output) {
+    (Some(input), _) if args.keep_format && remote::is_url(input) => bail!("--keep-format needs a local -i"),
+    (Some(input), Some(output)) if args.keep_format => Some(keep_format(input, output, args.allow_lossy)?),
+    _ => args.output.clone(),
+  };
+  // Unknown extensions, like those of videos, are reported when writing
+  if let Some(format) = output.as_deref().and_then(|output| ImageFormat::from_path(output).ok()) {
+    if image_io::is_lossy(format) && !args.allow_lossy {
+      bail!("{format:?} is a lossy format and will corrupt the payload, use PNG or WebP (or pass --allow-lossy)");
+    }
+  }
+
+  let key = args.sign_key.as_deref().map(signing::read_signing_key).transpose()?;
+  let stego_key = args.key.as_deref().map(StegoKey::new);
+  let mask = args.mask.as_deref().map(read_mask).transpose()?;
+  let options = EncodeOptions {
+    sign_key: key.as_ref(),
+    stego_key: stego_key.as_ref(),
+    compression: args.compress,
+    compression_level: args.compress_level,
+    max_delta: args.max_delta,
+    mask: mask.as_ref(),
+    adaptive_strength: args.adaptive_strength,
+    luma_weight: args.luma_weight,
+    spread: args.profile.map_or(args.spread, RobustnessProfile::spread),
+    ecc: args.profile.map_or(args.ecc, RobustnessProfile::ecc),
+    null_payload: args.null_payload,
+    channels: args.channels,
+    variable_rate: args.variable_rate,
+    seed: args.seed,
+    ..Default::default()
+  };
+  let codec = models.get(&args.model)?;
+  if args.input_dir.is_some() {
+    return match args.per_image {
+      true => split::encode_each(&args, codec, options),
+      false => split::encode(&args, codec, options),
+    };
+  }
+
+  let (Some(input), Some(output)) = (&args.input, &output) else {
+    bail!("-i and -o are required without --input-dir");
+  };
+  let (payload_type, message) = message::read(&args)?;
+  let options = EncodeOptions {
+    payload_type,
+    ..options
+  };
+  #[cfg(feature = "video")]
+  if video::is_video(input) {
+    if remote::is_url(input) || remote::is_url(output) {
+      bail!("Videos are read and written as local files only");
+    }
+    if args.verify {
+      bail!("--verify is not supported for videos");
+    }
+    return video::encode(&args, codec, &message, options, input, output);
+  }
+  let cover = remote::read(input)?;
+  let mut result = match Animation::read(&cover)? {
+    Some(animation) => encode_animation(&args, codec, &animation, &message, options, output)?,
+    None => {
+      Cover::decode(&cover, &args)?.encode(codec, &message, options, output)?;
+      daemon::Output {
+        stdout: "done\n".to_string(),
+        ..Default::default()
+      }
+    }
+  };
+  if args.output.as_ref() != Some(output) {
+    result.stdout += &format!("written to {}\n", output.display());
+  }
+  result.stderr += &conversion_report(&cover, output)?;
+  if args.verify {
+    verify(codec, output, &message, &options)?;
+    result.stdout += "verified\n";
+  }
+  register(&args, &message, input, output)?;
+  Ok(result)
+}
+
+// Appends a record of the written stego image to --registry, if given.
+fn register(args: &EncodeArgs, message: &[u8], input: &Path, output: &Path) -> Result<()> {
+  let Some(path) = &args.registry else {
+    return Ok(());
+  };
+  let hash = match remote::is_url(output) {
+    true => None,
+    false => Some(hash_output(output, false)?.1),
+  };
+  registry::append(path, &[registry::Record::new(message, input, output, hash)])
+}
+
+// Decodes the written stego image or animation and checks that it holds `message`, which fails if the output format
+// or a color conversion on the way broke the payload.
+fn verify(codec: &Codec, output: &Path, message: &[u8], options: &EncodeOptions) -> Result<()> {
+  let options = DecodeOptions {
+    stego_key: options.stego_key,
+    mask: options.mask,
+    resync: options.spread.tiling(),
+    channels: options.channels,
+  };
+  match codec.decode_frames(&read_frames(output)?, &options) {
+    Ok(payload) if payload.data == message => Ok(()),
+    Ok(_) => bail!("Verification failed: {} holds a different payload", output.display()),
+    Err(err) if matches!(err.downcast_ref(), Some(SteganoError::DecodeFailed)) => {
+      bail!("Verification failed: no payload found in {}", output.display())
+    }
+    Err(err) => Err(err.context(format!("Verification of {} failed", output.display()))),
+  }
+}
+
+// Hides the payload in every frame, so that decoding can combine them, and writes the animation as APNG.
+fn encode_animation(
+  args: &EncodeArgs,
+  codec: &Codec,
+  animation: &Animation,
+  message: &[u8],
+  options: EncodeOptions,
+  output: &Path,
+) -> Result<daemon::Output> {
+  if args.resize.is_some() || args.max_dim.is_some() {
+    bail!("--resize and --max-dim are not supported for animated covers");
+  }
+  if ImageFormat::from_path(output)? != ImageFormat::Png {
+    bail!("Animated covers are written as APNG, use a .png output");
+  }
+  let frames = animation
+    .rgb_frames()
+    .iter()
+    .map(|frame| codec.encode_with(frame, message, &options))
+    .collect::<Result<Vec<_>>>()?;
+  remote::write(output, &animation.with_rgb_frames(&frames).to_apng()?)?;
+  Ok(daemon::Output {
+    stdout: format!("done, {} frames\n", frames.len()),
+    ..Default::default()
+  })
+}
+
+// The frames of an animated image, or the image itself, from a file or URL.
+fn read_frames(path: &Path) -> Result<Vec<RgbImage>> {
+  let bytes = remote::read(path)?;
+  Ok(match Animation::read(&bytes)? {
+    Some(animation) => animation.rgb_frames(),
+    None => vec![image::load_from_memory(&bytes)?.to_rgb8()],
+  })
+}
+
+fn decode(args: DecodeArgs, models: &mut daemon::Models) -> Result<daemon::Output> {
+  let key = args
+    .verify_key
+    .as_deref()
+    .map(signing::read_verifying_key)
+    .transpose()?;
+  let stego_key = args.key.as_deref().map(StegoKey::new);
+  let mask = args.mask.as_deref().map(read_mask).transpose()?;
+  let options = DecodeOptions {
+    stego_key: stego_key.as_ref(),
+    mask: mask.as_ref(),
+    resync: match args.profile {
+      Some(profile) => Some(profile.tiling()),
+      None => args.resync.then_some(sync::Tiling::Plain),
+    },
+    channels: args.channels,
+  };
+  let codec = models.get(&args.model)?;
+  if args.input_dir.is_some() {
+    return split::decode(&args, codec, &options, key.as_ref());
+  }
+  let input = args.input.as_ref().context("-i is required without --input-dir")?;
+  #[cfg(feature = "video")]
+  if video::is_video(input) {
+    if remote::is_url(input) {
+      bail!("Videos are read as local files only");
+    }
+    let mut output = daemon::Output::default();
+    let (decoded, size) = video::decode(&args, codec, input, &options, &mut output)?;
+    return report(decoded, size, key.as_ref(), &args, output);
+  }
+  let frames = read_frames(input)?;
+  if args.all_candidates {
+    return Ok(daemon::Output {
+      stdout: format_candidates(&codec.candidates(&frames, &options)?),
+      ..Default::default()
+    });
+  }
+  let mut output = daemon::Output::default();
+  let decoded = match args.search_transforms {
+    true => codec.decode_search(&frames, &options).map(|(transform, payload)| {
+      if transform != Transform::Identity {
+        output.stderr += &format!("decoded after undoing a {transform}\n");
+      }
+      payload
+    }),
+    false => codec.decode_frames(&frames, &options),
+  };
+  if args.error_rate && decoded.is_ok() {
+    output.stderr += &match codec.estimate_errors(&frames, &options)? {
+      Some(estimate) => format_error_rate(&estimate),
+      None => "error rate: unknown, the payload is not repeated in copies\n".to_string(),
+    };
+  }
+  report(decoded, frames[0].dimensions(), key.as_ref(), &args, output)
+}
+
+// Renders a decoded payload with warnings about its signature, integrity and header, or "No data found".
+fn report(
+  decoded: Result<payload::Payload>,
+  (width, height): (u32, u32),
+  key: Option<&VerifyingKey>,
+  args: &DecodeArgs,
+  mut output: daemon::Output,
+) -> Result<daemon::Output> {
+  match decoded {
+    Ok(payload) => {
+      let signed = payload.header.as_ref().is_some_and(|header| header.signature.is_some());
+      match key.map(|key| payload.verify(key)) {
+        Some(Some(true)) => output.stderr += "signature: valid\n",
+        Some(Some(false)) => bail!("The payload signature does not match --verify-key"),
+        Some(None) => bail!("The payload is not signed"),
+        None if signed => output.stderr += "signature: present, pass --verify-key to check it\n",
+        None => {}
+      }
+      let authenticated = payload.header.as_ref().is_some_and(|header| header.mac.is_some());
+      match args.key.as_deref().map(|key| payload.authenticate(&StegoKey::new(key))) {
+        Some(Some(true)) => output.stderr += "integrity: verified\n",
+        Some(Some(false)) => output.stderr += "integrity: tampered, the MAC does not match --key\n",
+        Some(None) => output.stderr += "integrity: unverified, the payload has no MAC\n",
+        None if authenticated => output.stderr += "integrity: unverified, pass --key to check the MAC\n",
+        None => {}
+      }
+      if let Some(header) = payload.header.as_ref().filter(|header| header.size != (width, height)) {
+        let (w, h) = header.size;
+        output.stderr +=
+          &format!("warning: the payload was embedded into a {w}x{h} image, but this one is {width}x{height}\n");
+      }
+      if let Some(chunk) = payload.header.as_ref().and_then(|header| header.chunk) {
+        output.stderr += &format!(
+          "warning: this is part {} of {} of a split payload, decode all parts with --input-dir\n",
+          chunk.index + 1,
+          chunk.count
+        );
+      }
+      message::render(&payload, args, &mut output)?;
+      if let Some(path) = &args.registry {
+        output.stderr += &registry::report(&registry::lookup(path, &payload.data)?);
+      }
+    }
+    Err(err) if matches!(err.downcast_ref(), Some(SteganoError::DecodeFailed)) => output.stdout = format!("{err}\n"),
+    Err(err) => return Err(err),
+  }
+
+  Ok(output)
+}
+
+fn format_error_rate(estimate: &payload::ErrorEstimate) -> String {
+  let mut out = format!(
+    "error rate: {}{:.3}% of bits, worst block {}/{} correctable bytes, {} of {} blocks failed\n",
+    if estimate.failed_blocks > 0 { ">=" } else { "" },
+    estimate.bit_error_rate * 100.,
+    estimate.worst_block,
+    estimate.correctable,
+    estimate.failed_blocks,
+    estimate.blocks
+  );
+  if estimate.marginal() {
+    out += "warning: the decode was marginal, re-encode with a larger image, a shorter message or a robust --spread\n";
+  }
+  out
+}
+
+fn format_candidates(candidates: &[payload::Candidate]) -> String {
+  let mut out = String::new();
+  for (i, candidate) in candidates.iter().enumerate() {
+    let source = match candidate.aggregated {
+      true => format!("sum of {} copies", candidate.votes),
+      false => format!("{} vote(s)", candidate.votes),
+    };
+    let crc = match candidate.crc {
+      Some(true) => "ok",
+      Some(false) => "mismatch",
+      None => "none",
+    };
+    let status = if candidate.valid { "valid" } else { "damaged" };
+    out += &format!(
+      "#{} {source}, {} byte(s) corrected, {} uncorrectable block(s), CRC {crc}, {status}\n  {:?}\n",
+      i + 1,
+      candidate.correction.corrected,
+      candidate.correction.failed_blocks,
+      candidate.message
+    );
+  }
+  if candidates.is_empty() {
+    out += "No candidates found\n";
+  }
+  out
+}
+
+fn models(command: ModelsCommand) -> Result<()> {
+  match command {
+    ModelsCommand::List => {
+      for model in zoo::MODELS.iter() {
+        let status = if zoo::is_cached(model)? {
+          "downloaded"
+        } else {
+          "available"
+        };
+        println!(
+          "{}\t{status}\tdata_depth={} hidden_size={}\t{}",
+          model.name, model.data_depth, model.hidden_size, model.description
+        );
+      }
+    }
+    ModelsCommand::Pull { name } => {
+      let model = zoo::find(&name).ok_or_else(|| anyhow!("Unknown model '{name}'"))?;
+      let dir = zoo::pull(model)?;
+      println!("{name} saved to {}", dir.display());
+    }
+  }
+  Ok(())
+}
+
+fn convert(args: ConvertArgs) -> Result<()> {
+  let device = &Device::Cpu;
+  let detected = weights::model_config(&args.input, "encoder")?;
+  let config = ModelConfig {
+    arch: args.arch,
+    data_depth: args.data_depth.unwrap_or(detected.data_depth),
+    hidden_size: args.hidden_size.unwrap_or(detected.hidden_size),
+    preprocess: args.preprocess.preprocess(),
+    attention: args.attention,
+    num_blocks: args.num_blocks,
+    separable: args.separable,
+    thresholds: Vec::new(),
+  };
+  if args.data_depth.is_none() || args.hidden_size.is_none() {
+    println!("data_depth={} hidden_size={}", config.data_depth, config.hidden_size);
+  }
+  std::fs::create_dir_all(&args.output)?;
+  for component in ["encoder", "decoder", "critic"] {
+    let mut varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, candle_core::DType::F32, device);
+    match component {
+      "encoder" => drop(Encoder::from_config(&config, vb)?),
+      "decoder" => drop(Decoder::from_config(&config, vb)?),
+      _ => drop(Critic::new(config.hidden_size, vb)?),
+    }
+    weights::load(&mut varmap, &args.input, component)
+      .with_context(|| format!("Cannot load {component} weights, check --data-depth and --hidden-size"))?;
+    weights::save(
+      &varmap,
+      &args.output.join(format!("{component}.safetensors")),
+      &config,
+      &HashMap::new(),
+    )?;
+  }
   println!("done");
   Ok(())
 }
 
-fn map_inc(map: &mut HashMap<String, usize>, k: String) {
-  *map.entry(k).or_default() += 1;
-}
-
-fn decode(args: DecodeArgs) -> Result<()> {
-  let device = &Device::cuda_if_available(0)?;
-
-  let mut dec_varmap = VarMap::new();
-  let vb = VarBuilder::from_varmap(&dec_varmap, candle_core::DType::F32, device);
-  let decoder = Decoder::new(8, 32, vb.clone())?;
-  dec_varmap.load("pretrained/decoder.safetensors")?;
-
-  let img = image::open(args.input)?;
-  let img_bytes = img.to_rgb8().into_raw();
-  let img_tensor = candle_core::Tensor::from_vec(img_bytes, (img.width() as usize, img.height() as usize, 3), device)?
-    .permute((2, 1, 0))?
-    .unsqueeze(0)?;
-  let img_tensor = (img_tensor.to_dtype(candle_core::DType::F32)? / 255.)?;
-
-  let data = decoder
-    .forward(&img_tensor)?
-    .flatten_all()?
-    .gt(0.)?
-    .to_dtype(candle_core::DType::U8)?
-    .to_vec1::<u8>()?;
-
-  let data = utils::bits_to_bytes(&data);
-  let parts = utils::split_bytes(data.as_slice(), &[0; 4]);
-  let mut results: HashMap<String, usize> = HashMap::new();
-  for part in parts.iter() {
-    match utils::encoded_bytes_to_data(part).and_then(|part| Ok(String::from_utf8(part)?)) {
-      Ok(result) => {
-        let result = result.replace('\0', "");
-        if !result.is_empty() {
-          map_inc(&mut results, result)
+#[cfg(feature = "onnx")]
+fn export_onnx(args: ExportOnnxArgs) -> Result<()> {
+  let model = zoo::resolve(&args.model)?;
+  let config = weights::model_config(&model, "encoder")?;
+  std::fs::create_dir_all(&args.output)?;
+  for component in ["encoder", "decoder"] {
+    let mut varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    match component {
+      "encoder" => drop(Encoder::from_config(&config, vb)?),
+      _ => drop(Decoder::from_config(&config, vb)?),
+    }
+    weights::load(&mut varmap, &model, component)?;
+    let path = args.output.join(format!("{component}.onnx"));
+    std::fs::write(&path, steganogan_rs::onnx::export(&varmap, &config, component)?)?;
+    println!("{}", path.display());
+  }
+  Ok(())
+}
+
+fn finetune(args: FinetuneArgs) -> Result<()> {
+  let device = &device()?;
+  let model = match &args.resume {
+    Some(dir) => dir.clone(),
+    None => zoo::resolve(&args.model)?,
+  };
+  let options = args.train;
+  let data_options = data::DataOptions {
+    image_size: args.image_size,
+    batch_size: args.batch_size,
+    augment: !args.no_augment,
+    prefetch: 2,
+  };
+  let mut config = weights::model_config(&model, "encoder")?;
+  if let Some(profile) = args.preprocess {
+    config.preprocess = profile.preprocess();
+  }
+  let model_config = config.clone();
+  config.arch = args.arch.unwrap_or(config.arch);
+  config.attention = args.attention.unwrap_or(config.attention);
+  config.num_blocks = args.num_blocks.unwrap_or(config.num_blocks);
+  config.separable |= args.separable;
+  let same_layout = ModelConfig {
+    preprocess: config.preprocess,
+    ..model_config
+  } == config;
+  let mut trainer = train::Trainer::new(config, &options, device)?;
+  match &args.resume {
+    Some(dir) => {
+      trainer.resume(dir)?;
+      println!("resumed from {} at epoch {}", dir.display(), trainer.epoch());
+    }
+    None if !same_layout => {
+      let fresh = trainer.load_matching(&model)?;
+      println!(
+        "{fresh} tensors do not match {} and start from scratch",
+        model.display()
+      );
+    }
+    None => trainer.load(&model)?,
+  }
+
+  let (dataset, validation) = data::Dataset::open(&args.input, data_options)?.split(args.val_split)?;
+  let mut logger =
+    train::log::MetricsLogger::new(&args.output, args.resume.is_some(), args.tensorboard, !args.no_samples)?;
+  // A fresh run into an existing output directory saves its own best checkpoint over that of the old run
+  let mut best_acc = match args.resume {
+    Some(_) => weights::read_metadata(&args.output.join("best").join("encoder.safetensors"))
+      .ok()
+      .flatten()
+      .and_then(|metadata| metadata.get("val.decoder_acc")?.parse::<f32>().ok())
+      .unwrap_or(0.),
+    None => 0.,
+  };
+  println!(
+    "training on {} images, validating on {}",
+    dataset.len(),
+    validation.len()
+  );
+  let mut stale_epochs = 0;
+  for epoch in trainer.epoch() + 1..=options.epochs {
+    let metrics = trainer.train_epoch(&dataset, |step, metrics| logger.log(step, epoch, metrics))?;
+    if logger.wants_samples() {
+      if let Some(cover) = dataset.batches(0, device).next() {
+        let cover = cover?;
+        logger.save_samples(epoch, &cover, &trainer.generate(&cover)?)?;
+      }
+    }
+    logger.flush()?;
+    let val = trainer.validate(&validation)?;
+    println!(
+      "epoch {epoch}: encoder_mse={:.5} decoder_bce={:.5} decoder_acc={:.4} psnr={:.2} cover_score={:.4} generated_score={:.4} val_acc={:.4} val_rs_bpp={:.3} val_psnr={:.2}",
+      metrics.encoder_mse,
+      metrics.decoder_bce,
+      metrics.decoder_acc,
+      metrics.psnr,
+      metrics.cover_score,
+      metrics.generated_score,
+      val.decoder_acc,
+      val.rs_bpp,
+      val.psnr
+    );
+
+    let extra = HashMap::from([
+      ("train.epoch".to_string(), epoch.to_string()),
+      ("val.decoder_acc".to_string(), val.decoder_acc.to_string()),
+      ("val.rs_bpp".to_string(), val.rs_bpp.to_string()),
+      ("val.psnr".to_string(), val.psnr.to_string()),
+    ]);
+    trainer.save(&args.output.join("last"), &extra)?;
+    if val.decoder_acc > best_acc {
+      best_acc = val.decoder_acc;
+      trainer.save(&args.output.join("best"), &extra)?;
+      stale_epochs = 0;
+      println!("new best checkpoint (val_acc={best_acc:.4})");
+    } else {
+      stale_epochs += 1;
+      if args
+        .early_stop_patience
+        .is_some_and(|patience| stale_epochs >= patience)
+      {
+        println!("no improvement for {stale_epochs} epochs, stopping early");
+        break;
+      }
+    }
+  }
+
+  println!("done");
+  Ok(())
+}
+
+fn train_detector(args: TrainDetectorArgs) -> Result<()> {
+  let device = &device()?;
+  let model = zoo::resolve(&args.model)?;
+  let mut trainer = train::detector::DetectorTrainer::new(&model, args.hidden_size, args.lr, args.seed, device)?;
+  let data_options = data::DataOptions {
+    image_size: args.image_size,
+    batch_size: args.batch_size,
+    augment: true,
+    prefetch: 2,
+  };
+  let (dataset, validation) = data::Dataset::open(&args.input, data_options)?.split(args.val_split)?;
+  println!(
+    "training on {} images, validating on {}",
+    dataset.len(),
+    validation.len()
+  );
+  let mut best_acc = 0.;
+  for epoch in 1..=args.epochs {
+    let metrics = trainer.train_epoch(&dataset)?;
+    let val = trainer.validate(&validation)?;
+    println!(
+      "epoch {epoch}: bce={:.5} acc={:.4} val_bce={:.5} val_acc={:.4}",
+      metrics.bce, metrics.accuracy, val.bce, val.accuracy
+    );
+    if val.accuracy > best_acc {
+      best_acc = val.accuracy;
+      let extra = HashMap::from([
+        ("detector.model".to_string(), args.model.clone()),
+        ("train.epoch".to_string(), epoch.to_string()),
+        ("val.accuracy".to_string(), val.accuracy.to_string()),
+      ]);
+      trainer.save(&args.output, &extra)?;
+    }
+  }
+  // 0.5 is chance, the closer the detector gets to it the less detectable the model is
+  println!("best val_acc={best_acc:.4}");
+  Ok(())
+}
+
+fn detect(args: DetectArgs) -> Result<()> {
+  let device = &device()?;
+  let config = weights::model_config(&args.detector, "detector")?;
+  let mut varmap = VarMap::new();
+  let detector = Detector::new(config.hidden_size, VarBuilder::from_varmap(&varmap, DType::F32, device))?;
+  weights::load(&mut varmap, &args.detector, "detector")?;
+
+  let mut images = Vec::new();
+  for path in args.input {
+    match path.is_dir() {
+      true => images.extend(data::list_images(&path)?),
+      false => images.push(path),
+    }
+  }
+  let mut stego = 0;
+  for path in images.iter() {
+    let img = image::open(path)?.to_rgb8();
+    let x = ((image_io::to_tensor(&img, device)? / 127.5)? - 1.)?;
+    let logit = detector.forward_t(&x, false)?.to_vec1::<f32>()?[0];
+    let probability = 1. / (1. + (-logit).exp());
+    let label = if probability >= 0.5 { "stego" } else { "clean" };
+    stego += (probability >= 0.5) as usize;
+    println!("{label:<7}{probability:.3}  {}", path.display());
+  }
+  println!("{stego}/{} images classified as stego", images.len());
+  Ok(())
+}
+
+fn evaluate(args: EvaluateArgs) -> Result<()> {
+  let device = &device()?;
+  let model = zoo::resolve(&args.model)?;
+  let mut evaluator = eval::Evaluator::new(&model, device)?;
+  let images = data::list_images(&args.input)?;
+  let mut rng = rng::from_seed(args.seed);
+  if args.calibrate {
+    let thresholds = evaluator.calibrate(&images, args.message_size, &mut rng)?;
+    weights::store_thresholds(&model, &thresholds)?;
+    println!("thresholds: {thresholds:?}");
+    evaluator.set_thresholds(thresholds);
+  }
+  let report = evaluator.evaluate(&images, args.message_size, &args.model, &mut rng)?;
+  report.write(&args.output)?;
+
+  let summary = &report.summary;
+  println!(
+    "{} images: bit_accuracy={:.4} recovery_rate={:.3} psnr={:.2} ssim={:.4} rs_bpp={:.3} stego_score={:.4}±{:.4}",
+    summary.images,
+    summary.bit_accuracy,
+    summary.recovery_rate,
+    summary.psnr,
+    summary.ssim,
+    summary.rs_bpp,
+    summary.stego_score.mean,
+    summary.stego_score.std
+  );
+  Ok(())
+}
+
+fn attack(args: AttackArgs) -> Result<()> {
+  let device = &device()?;
+  let model = zoo::resolve(&args.model)?;
+  let evaluator = eval::Evaluator::new(&model, device)?;
+  let img = image::open(&args.input)?.to_rgb8();
+  let reference = evaluator.decode_bits(&img)?;
+  let message = match args.message {
+    Some(message) => message,
+    None => {
+      let payload =
+        payload::extract(&reference).with_context(|| format!("Failed to decode {}", args.input.display()))?;
+      payload.message
+    }
+  };
+
+  let mut rng = rng::from_seed(args.seed);
+  let mut results = Vec::new();
+  for attack in attack::suite() {
+    let bits = evaluator.decode_bits(&attack.apply(&img, &mut rng)?)?;
+    let bit_agreement = (bits.len() == reference.len())
+      .then(|| bits.iter().zip(reference.iter()).filter(|(a, b)| a == b).count() as f32 / bits.len() as f32);
+    let result = attack::AttackResult {
+      attack: attack.name(),
+      recovered: payload::extract(&bits).is_ok_and(|payload| payload.message == message),
+      bit_agreement,
+    };
+    let agreement = result.bit_agreement.map_or("-".to_string(), |a| format!("{a:.4}"));
+    let status = if result.recovered { "survived" } else { "failed" };
+    println!("{:<24}{status:<10}{agreement}", result.attack);
+    results.push(result);
+  }
+  let survived = results.iter().filter(|r| r.recovered).count();
+  println!("survived {survived}/{} attacks", results.len());
+
+  if let Some(output) = args.output {
+    std::fs::write(output, serde_json::to_string_pretty(&results)?)?;
+  }
+  Ok(())
+}
+
+fn analyze(args: AnalyzeArgs) -> Result<()> {
+  let device = &device()?;
+  let evaluator = eval::Evaluator::new(&zoo::resolve(&args.model)?, device)?;
+  let cover = image::open(&args.input)?.to_rgb8();
+  let analysis = analyze::analyze(
+    &evaluator,
+    &cover,
+    args.data.as_bytes(),
+    args.compress,
+    args.compress_level,
+    &mut rng::from_seed(args.seed),
+  )?;
+  println!(
+    "{} bytes packed in {} Reed-Solomon blocks, {} copies",
+    analysis.packed_len, analysis.blocks, analysis.copies
+  );
+  println!("{:<24}{:<12}{:<13}recovered", "attack", "bit errors", "probability");
+  for estimate in &analysis.estimates {
+    let ber = estimate
+      .bit_error_rate
+      .map_or("-".to_string(), |ber| format!("{ber:.4}"));
+    let probability = estimate.probability.map_or("-".to_string(), |p| format!("{p:.4}"));
+    let recovered = if estimate.recovered { "yes" } else { "no" };
+    println!("{:<24}{ber:<12}{probability:<13}{recovered}", estimate.attack);
+  }
+
+  if let Some(output) = args.output {
+    std::fs::write(output, serde_json::to_string_pretty(&analysis)?)?;
+  }
+  Ok(())
+}
+
+fn inspect(args: InspectArgs) -> Result<()> {
+  for summary in inspect::inspect(&zoo::resolve(&args.model)?)? {
+    println!("{summary}");
+  }
+  Ok(())
+}
+
+fn diff(args: DiffArgs) -> Result<()> {
+  let cover = image::open(&args.cover)?.to_rgb8();
+  let stego = image::open(&args.stego)?.to_rgb8();
+  let residual = diff::residual(&cover, &stego)?;
+  for (name, stats) in ["R", "G", "B"].iter().zip(residual.channels) {
+    println!("{name}: max={} mean={:.3}", stats.max, stats.mean);
+  }
+  residual.heatmap(args.amplify).save(&args.output)?;
+  Ok(())
+}
+
+fn watermark(command: WatermarkCommand, no_daemon: bool) -> Result<()> {
+  match command {
+    WatermarkCommand::Keygen { output } => {
+      let public = PathBuf::from(format!("{}.pub", output.display()));
+      signing::write_keys(&signing::generate_key(&mut rng::from_seed(None)), &output, &public)?;
+      println!("public key saved to {}", public.display());
+      Ok(())
+    }
+    WatermarkCommand::Apply(args) => {
+      let key = signing::read_signing_key(&args.key)?;
+      let args = EncodeArgs {
+        input: Some(args.input),
+        output: Some(args.output),
+        data: Some(Watermark::now(&args.creator).sign(&key)),
+        input_dir: None,
+        output_dir: None,
+        watch: None,
+        per_image: false,
+        registry: None,
+        name_by_hash: false,
+        jobs: None,
+        batch_size: 1,
+        fail_fast: false,
+        manifest: None,
+        data_file: None,
+        data_clipboard: false,
+        null_payload: None,
+        seed: None,
+        payload_type: Some(PayloadType::Text),
+        model: args.model,
+        strip_metadata: false,
+        allow_lossy: false,
+        keep_format: false,
+        resize: None,
+        max_dim: None,
+        sign_key: None,
+        key: None,
+        compress: Compression::Deflate,
+        compress_level: None,
+        max_delta: None,
+        mask: None,
+        adaptive_strength: None,
+        luma_weight: None,
+        spread: Spread::Repeat,
+        ecc: Ecc::Standard,
+        profile: None,
+        channels: None,
+        variable_rate: false,
+        verify: false,
+      };
+      run(daemon::Request::Encode(args), no_daemon)
+    }
+    WatermarkCommand::Verify(args) => {
+      let key = signing::read_verifying_key(&args.public_key)?;
+      let codec = steganogan_rs::codec::Codec::open(&args.model, &device()?)?;
+      let payload = codec.decode(&image::open(&args.input)?.to_rgb8())?;
+      let watermark = Watermark::verify(&payload.message, &key)?;
+      println!(
+        "valid watermark of '{}' applied at {} (Unix time)",
+        watermark.creator, watermark.timestamp
+      );
+      Ok(())
+    }
+  }
+}
+
+#[cfg(feature = "documents")]
+fn document(command: DocumentCommand) -> Result<()> {
+  use steganogan_rs::document;
+
+  match command {
+    DocumentCommand::Embed(args) => {
+      let codec = steganogan_rs::codec::Codec::open(&args.model, &device()?)?;
+      let input = std::fs::read(&args.input)?;
+      let (output, count) = document::map_images(&input, args.min_size, |image| {
+        codec
+          .encode(&image.image, args.data.as_bytes(), None)
+          .with_context(|| format!("Failed to embed into {}", image.name))
+      })?;
+      if count == 0 {
+        bail!("No images of at least {0}x{0} pixels found", args.min_size);
+      }
+      std::fs::write(&args.output, output)?;
+      println!("done, {count} images");
+    }
+    DocumentCommand::Decode(args) => {
+      let codec = steganogan_rs::codec::Codec::open(&args.model, &device()?)?;
+      for image in document::images(&std::fs::read(&args.input)?, args.min_size)? {
+        match codec.decode(&image.image) {
+          Ok(payload) => println!("{}: {}", image.name, payload.message),
+          Err(err) if matches!(err.downcast_ref(), Some(SteganoError::DecodeFailed)) => {
+            println!("{}: {err}", image.name)
+          }
+          Err(err) => return Err(err),
         }
       }
-      Err(_) => continue,
     }
   }
-  match results.iter().max_by_key(|(_, v)| *v).map(|(k, _)| k) {
-    Some(result) => println!("{result}"),
-    None => println!("No data found"),
+  Ok(())
+}
+
+fn completions(args: CompletionsArgs) -> Result<()> {
+  let mut command = Cli::command();
+  match args.shell {
+    Some(shell) => clap_complete::generate(shell, &mut command, "steganogan-rs", &mut std::io::stdout()),
+    None => clap_mangen::Man::new(command).render(&mut std::io::stdout())?,
+  }
+  Ok(())
+}
+
+fn serve(args: ServeArgs) -> Result<()> {
+  let mut models = daemon::Models::new(&device()?, max_memory());
+  for model in args.model.iter() {
+    models.get(model)?;
   }
+  daemon::serve(&args.socket.unwrap_or_else(daemon::socket_path), &mut models)
+}
 
+// Hands the request to a running daemon if there is one, otherwise loads the models and runs it here.
+fn run(request: daemon::Request, no_daemon: bool) -> Result<()> {
+  // The daemon keeps the device and memory limit it was started with, so requests that set their own run here
+  let own_device = !matches!(DEVICE.get(), None | Some(DeviceKind::Auto)) || max_memory().is_some();
+  let output = if no_daemon || own_device {
+    None
+  } else {
+    daemon::delegate(&daemon::socket_path(), &request)?
+  };
+  let output = match output {
+    Some(output) => output,
+    None => request.run(&mut daemon::Models::new(&device()?, max_memory()))?,
+  };
+  output.print();
+  if let Some(text) = &output.clipboard {
+    eprintln!("copying to the clipboard");
+    clipboard::write(text)?;
+  }
   Ok(())
 }
 
+// Encodes every image that appears in --watch into --output-dir, with the models loaded once. The watch runs here
+// rather than in a daemon, which would serve nothing else until it is interrupted.
+fn watch_encode(args: EncodeArgs) -> Result<()> {
+  let (Some(dir), Some(output_dir)) = (&args.watch, &args.output_dir) else {
+    bail!("--watch requires --output-dir");
+  };
+  std::fs::create_dir_all(output_dir)?;
+  if dir.canonicalize()? == output_dir.canonicalize()? {
+    bail!("--output-dir must not be the watched directory, the stego images would be encoded again");
+  }
+  expand_data(&args, dir, 0)?;
+  let mut models = daemon::Models::new(&device()?, max_memory());
+  let mut index = 0;
+  watch::run(dir, |input| {
+    let name = input.file_stem().context("Invalid image name")?;
+    let mut output = output_dir.join(name).with_extension("png");
+    if args.keep_format {
+      output = keep_format(input, &output, args.allow_lossy)?;
+    }
+    index += 1;
+    let args = EncodeArgs {
+      input: Some(input.to_path_buf()),
+      output: Some(output.clone()),
+      output_dir: None,
+      watch: None,
+      ..expand_data(&args, input, index)?
+    };
+    // Registered here, under the final name
+    let mut result = daemon::Request::Encode(EncodeArgs {
+      registry: None,
+      ..args.clone()
+    })
+    .run(&mut models)?;
+    if args.name_by_hash {
+      output = hash_output(&output, true)?.0;
+      result.stdout += &format!("written to {}\n", output.display());
+    }
+    register(&args, &message::read(&args)?.1, input, &output)?;
+    Ok(result)
+  })
+}
+
+fn watch_decode(args: DecodeArgs) -> Result<()> {
+  let dir = args.watch.as_ref().context("--watch is required")?;
+  let mut models = daemon::Models::new(&device()?, max_memory());
+  watch::run(dir, |input| {
+    let args = DecodeArgs {
+      input: Some(input.to_path_buf()),
+      watch: None,
+      ..args.clone()
+    };
+    daemon::Request::Decode(args).run(&mut models)
+  })
+}
+
 fn main() -> Result<()> {
-  let args = Cli::parse();
+  let args: Vec<_> = std::env::args_os().collect();
+  let command = config::Config::load(&args)?.apply(Cli::command())?;
+  let args = Cli::from_arg_matches(&command.get_matches_from(args))?;
+  let _ = DEVICE.set(args.device);
+  let _ = MAX_MEMORY.set(args.max_memory);
+  let no_daemon = args.no_daemon;
   match args.command {
-    Command::Encode(args) => encode(args),
-    Command::Decode(args) => decode(args),
+    Command::Encode(mut args) => {
+      if args.data_clipboard {
+        args.data = Some(clipboard::read()?);
+      }
+      match args.watch.is_some() {
+        true => watch_encode(args),
+        false => run(daemon::Request::Encode(args), no_daemon),
+      }
+    }
+    Command::Decode(args) if args.watch.is_some() => watch_decode(args),
+    Command::Decode(args) => run(daemon::Request::Decode(args), no_daemon),
+    Command::Models(command) => models(command),
+    Command::Convert(args) => convert(args),
+    #[cfg(feature = "onnx")]
+    Command::ExportOnnx(args) => export_onnx(args),
+    Command::Finetune(args) => finetune(args),
+    Command::TrainDetector(args) => train_detector(args),
+    Command::GenDataset(args) => {
+      let mut models = daemon::Models::new(&device()?, max_memory());
+      dataset::run(&args, models.get(&args.model)?)
+    }
+    Command::Detect(args) => detect(args),
+    Command::Evaluate(args) => evaluate(args),
+    Command::Attack(args) => attack(args),
+    Command::Analyze(args) => analyze(args),
+    Command::Bench(args) => benchmark::run(args),
+    Command::Diff(args) => diff(args),
+    Command::Inspect(args) => inspect(args),
+    Command::Watermark(command) => watermark(command, no_daemon),
+    #[cfg(feature = "documents")]
+    Command::Document(command) => document(command),
+    Command::Serve(args) => serve(args),
+    Command::Completions(args) => completions(args),
+    #[cfg(feature = "http")]
+    Command::Server(args) => server::serve(args.listen, args.service.open()?),
+    #[cfg(feature = "grpc")]
+    Command::Grpc(args) => grpc::serve(args.listen, args.service.open()?),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_cli() {
+    Cli::command().debug_assert();
+  }
+
+  #[test]
+  fn test_parse_memory() {
+    assert_eq!(parse_memory("4G"), Ok(4 << 30));
+    assert_eq!(parse_memory("512MiB"), Ok(512 << 20));
+    assert_eq!(parse_memory("1.5k"), Ok(1536));
+    assert_eq!(parse_memory("1000"), Ok(1000));
+    assert!(parse_memory("4X").is_err() && parse_memory("G").is_err());
+  }
+
+  #[test]
+  fn test_report_integrity() -> Result<()> {
+    let Command::Decode(args) = Cli::try_parse_from(["steganogan-rs", "decode", "-i", "stego.png"])?.command else {
+      unreachable!()
+    };
+    let decoded = |mac| payload::Payload {
+      header: Some(payload::Header {
+        size: (64, 48),
+        mac,
+        ..Default::default()
+      }),
+      message: "hello".to_string(),
+      data: b"hello".to_vec(),
+    };
+    let output = report(Ok(decoded(None)), (64, 48), None, &args, Default::default())?;
+    assert!(!output.stderr.contains("integrity"));
+    let mac = Some([0; payload::MAC_LEN]);
+    let output = report(Ok(decoded(mac)), (64, 48), None, &args, Default::default())?;
+    assert!(output
+      .stderr
+      .contains("integrity: unverified, pass --key to check the MAC"));
+    Ok(())
   }
 }
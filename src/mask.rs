@@ -0,0 +1,97 @@
+use anyhow::Result;
+use candle_core::{Device, Tensor};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GrayImage, RgbImage};
+
+use crate::image_io;
+
+// Region of interest for embedding: payload bits are only placed where the mask is light, and the image is left
+// untouched elsewhere. The mask is not stored in the image, decoding needs the same one.
+#[derive(Debug, Clone)]
+pub struct Mask(GrayImage);
+
+impl Mask {
+  pub fn new(img: &DynamicImage) -> Self {
+    Self(img.to_luma8())
+  }
+
+  // The mask stretched over an image of `size`, as a (1, 1, h, w) tensor of 0 and 1 in the layout of
+  // `image_io::to_tensor`.
+  pub fn tensor(&self, (width, height): (u32, u32), device: &Device) -> Result<Tensor> {
+    let resized = imageops::resize(&self.0, width, height, FilterType::Nearest);
+    let rgb = DynamicImage::ImageLuma8(resized).to_rgb8();
+    let x = image_io::to_tensor(&rgb, device)?;
+    Ok(x.narrow(1, 0, 1)?.ge(128f32)?.to_dtype(candle_core::DType::F32)?)
+  }
+
+  // Puts the cover pixels back outside the mask, where rounding the encoder output may have changed them.
+  pub fn restore(&self, stego: &mut RgbImage, cover: &RgbImage) {
+    let mask = imageops::resize(&self.0, cover.width(), cover.height(), FilterType::Nearest);
+    for ((stego, cover), mask) in stego.pixels_mut().zip(cover.pixels()).zip(mask.pixels()) {
+      if mask[0] < 128 {
+        *stego = *cover;
+      }
+    }
+  }
+
+  // Which positions of one payload plane (h * w values, in tensor order) carry bits.
+  pub fn positions(&self, size: (u32, u32)) -> Result<Vec<bool>> {
+    let x = self.tensor(size, &Device::Cpu)?;
+    Ok(x.flatten_all()?.to_vec1::<f32>()?.into_iter().map(|v| v > 0.).collect())
+  }
+}
+
+// Spreads `bits` over the masked positions of every payload plane, in order. Unmasked positions are 0.
+pub fn scatter(bits: &[u8], positions: &[bool], data_depth: usize) -> Vec<u8> {
+  let mut bits = bits.iter();
+  (0..data_depth)
+    .flat_map(|_| positions.iter())
+    .map(|&masked| match masked {
+      true => *bits.next().unwrap_or(&0),
+      false => 0,
+    })
+    .collect()
+}
+
+// Inverse of `scatter`, the values of the masked positions of every plane.
+pub fn gather<T: Copy>(values: &[T], positions: &[bool]) -> Vec<T> {
+  values
+    .chunks(positions.len().max(1))
+    .flat_map(|plane| {
+      plane
+        .iter()
+        .zip(positions)
+        .filter(|(_, &masked)| masked)
+        .map(|(v, _)| *v)
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use image::Luma;
+
+  use super::*;
+
+  #[test]
+  fn test_positions() -> Result<()> {
+    // Left half of a 4x2 image
+    let mask = Mask::new(&DynamicImage::ImageLuma8(GrayImage::from_fn(2, 1, |x, _| {
+      Luma([(x == 0) as u8 * 255])
+    })));
+    let positions = mask.positions((4, 2))?;
+    assert_eq!(positions.iter().filter(|&&p| p).count(), 4);
+    let x = image_io::to_tensor(
+      &image::RgbImage::from_fn(4, 2, |x, _| image::Rgb([x as u8; 3])),
+      &Device::Cpu,
+    )?;
+    let columns = x.narrow(1, 0, 1)?.flatten_all()?.to_vec1::<f32>()?;
+    assert!(positions.iter().zip(columns).all(|(&p, column)| p == (column < 2.)));
+
+    let bits = [1, 0, 1, 1, 0, 1, 1, 1];
+    let scattered = scatter(&bits, &positions, 2);
+    assert_eq!(scattered.len(), 16);
+    assert_eq!(gather(&scattered, &positions), bits);
+    Ok(())
+  }
+}
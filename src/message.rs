@@ -0,0 +1,121 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use steganogan_rs::payload::{self, Payload, PayloadType};
+
+use crate::{daemon, DecodeArgs, EncodeArgs};
+
+// Message to hide and its type, either `--type` or inferred from the source: a `--data-file` is sent as a file, `-d`
+// as a URL or JSON if it parses as one, and as text otherwise.
+pub fn read(args: &EncodeArgs) -> Result<(PayloadType, Vec<u8>)> {
+  // The encoder ignores the message
+  if args.null_payload.is_some() {
+    return Ok((PayloadType::Binary, Vec::new()));
+  }
+  let (payload_type, message) = match (&args.data_file, &args.data) {
+    (Some(path), _) => {
+      let contents = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+      match args.payload_type.unwrap_or(PayloadType::File) {
+        PayloadType::File => {
+          let name = path.file_name().context("Invalid file name")?.to_string_lossy();
+          (PayloadType::File, payload::file_message(&name, &contents))
+        }
+        payload_type => (payload_type, contents),
+      }
+    }
+    (None, Some(data)) => match args.payload_type {
+      Some(PayloadType::File) => bail!("--type file needs --data-file"),
+      Some(payload_type) => (payload_type, data.clone().into_bytes()),
+      None => (infer(data), data.clone().into_bytes()),
+    },
+    (None, None) => bail!("-d or --data-file is required"),
+  };
+
+  if matches!(payload_type, PayloadType::Text | PayloadType::Json | PayloadType::Url) {
+    let text = std::str::from_utf8(&message).context("The data is not UTF-8 text, send it with --type file")?;
+    match payload_type {
+      PayloadType::Json => {
+        serde_json::from_str::<Value>(text).context("The data is not valid JSON")?;
+      }
+      PayloadType::Url if !is_url(text) => bail!("The data is not a URL"),
+      _ => {}
+    }
+  }
+  Ok((payload_type, message))
+}
+
+fn infer(data: &str) -> PayloadType {
+  if is_url(data) {
+    PayloadType::Url
+  } else if serde_json::from_str::<Value>(data).is_ok_and(|value| value.is_object() || value.is_array()) {
+    PayloadType::Json
+  } else {
+    PayloadType::Text
+  }
+}
+
+fn is_url(data: &str) -> bool {
+  let valid_scheme = |scheme: &str| {
+    scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+      && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+  };
+  !data.contains(char::is_whitespace)
+    && data
+      .split_once("://")
+      .is_some_and(|(scheme, rest)| valid_scheme(scheme) && !rest.is_empty())
+}
+
+// Prints text, pretty-prints JSON, links URLs and saves files to `--save-dir` under their original name.
+pub fn render(payload: &Payload, args: &DecodeArgs, output: &mut daemon::Output) -> Result<()> {
+  let payload_type = payload
+    .header
+    .as_ref()
+    .map_or(PayloadType::Text, |header| header.payload_type);
+  if args.to_clipboard {
+    match payload_type {
+      PayloadType::File | PayloadType::Binary => bail!("--to-clipboard needs a text payload"),
+      _ => output.clipboard = Some(payload.message.clone()),
+    }
+  }
+  match payload_type {
+    PayloadType::File => {
+      let (name, contents) = payload.file().context("Malformed file payload")?;
+      // Only the name, the payload must not pick the directory
+      let name = Path::new(name)
+        .file_name()
+        .context("The file payload has no valid name")?;
+      let path = args.save_dir.join(name);
+      if path.exists() {
+        bail!("{} already exists, pass another --save-dir", path.display());
+      }
+      std::fs::write(&path, contents).with_context(|| format!("Failed to write {}", path.display()))?;
+      output.stdout = format!("saved {} ({} bytes)\n", path.display(), contents.len());
+    }
+    PayloadType::Json => {
+      output.stdout = match serde_json::from_str::<Value>(&payload.message) {
+        Ok(value) => format!("{value:#}\n"),
+        Err(_) => format!("{}\n", payload.message),
+      }
+    }
+    PayloadType::Url => output.link = Some(payload.message.clone()),
+    PayloadType::Text | PayloadType::Binary => output.stdout = format!("{}\n", payload.message),
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_infer() {
+    assert_eq!(infer("hello"), PayloadType::Text);
+    assert_eq!(infer("42"), PayloadType::Text);
+    assert_eq!(infer(r#"{"a": [1, 2]}"#), PayloadType::Json);
+    assert_eq!(infer("https://example.com/a?b=c"), PayloadType::Url);
+    assert_eq!(infer("see https://example.com"), PayloadType::Text);
+    assert!(!is_url("://example.com"));
+    assert!(!is_url("https://"));
+  }
+}
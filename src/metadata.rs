@@ -0,0 +1,223 @@
+use anyhow::{bail, Result};
+use image::ImageFormat;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+const PNG_TEXT_CHUNKS: [&[u8; 4]; 3] = [b"tEXt", b"zTXt", b"iTXt"];
+const JPEG_EXIF_HEADER: &[u8] = b"Exif\0\0";
+const JPEG_ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+const JPEG_MAX_SEGMENT: usize = 65533;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Metadata {
+  pub exif: Option<Vec<u8>>,
+  pub icc: Option<Vec<u8>>,
+  pub png_text: Vec<([u8; 4], Vec<u8>)>,
+}
+
+impl Metadata {
+  pub fn read(bytes: &[u8]) -> Self {
+    if bytes.starts_with(&PNG_SIGNATURE) {
+      Self::read_png(bytes)
+    } else if bytes.starts_with(&[0xff, 0xd8]) {
+      Self::read_jpeg(bytes)
+    } else {
+      Self::default()
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.exif.is_none() && self.icc.is_none() && self.png_text.is_empty()
+  }
+
+  pub fn embed(&self, image: Vec<u8>, format: ImageFormat) -> Result<Vec<u8>> {
+    if self.is_empty() {
+      return Ok(image);
+    }
+    match format {
+      ImageFormat::Png => self.embed_png(image),
+      ImageFormat::Jpeg => self.embed_jpeg(image),
+      _ => Ok(image),
+    }
+  }
+
+  fn read_png(bytes: &[u8]) -> Self {
+    let mut metadata = Self::default();
+    for (kind, data) in png_chunks(bytes) {
+      match kind {
+        b"iCCP" => {
+          let Some(name_end) = data.iter().position(|b| *b == 0) else {
+            continue;
+          };
+          if let Some(compressed) = data.get(name_end + 2..) {
+            metadata.icc = miniz_oxide::inflate::decompress_to_vec_zlib(compressed).ok();
+          }
+        }
+        b"eXIf" => metadata.exif = Some(data.to_vec()),
+        kind if PNG_TEXT_CHUNKS.contains(&kind) => metadata.png_text.push((*kind, data.to_vec())),
+        _ => {}
+      }
+    }
+    metadata
+  }
+
+  fn read_jpeg(bytes: &[u8]) -> Self {
+    let mut metadata = Self::default();
+    let mut icc_chunks = Vec::new();
+    for (marker, data) in jpeg_segments(bytes) {
+      match marker {
+        0xe1 if data.starts_with(JPEG_EXIF_HEADER) => metadata.exif = Some(data[JPEG_EXIF_HEADER.len()..].to_vec()),
+        0xe2 if data.starts_with(JPEG_ICC_HEADER) && data.len() > JPEG_ICC_HEADER.len() + 2 => {
+          icc_chunks.push((data[JPEG_ICC_HEADER.len()], &data[JPEG_ICC_HEADER.len() + 2..]));
+        }
+        _ => {}
+      }
+    }
+    if !icc_chunks.is_empty() {
+      icc_chunks.sort_by_key(|(seq, _)| *seq);
+      metadata.icc = Some(icc_chunks.into_iter().flat_map(|(_, chunk)| chunk.to_vec()).collect());
+    }
+    metadata
+  }
+
+  fn embed_png(&self, image: Vec<u8>) -> Result<Vec<u8>> {
+    let Some((b"IHDR", ihdr)) = png_chunks(&image).next() else {
+      bail!("Invalid PNG: missing IHDR chunk");
+    };
+    let insert_at = PNG_SIGNATURE.len() + 12 + ihdr.len();
+
+    let mut chunks = Vec::new();
+    if let Some(icc) = &self.icc {
+      let mut data = b"ICC Profile\0\0".to_vec();
+      data.extend(miniz_oxide::deflate::compress_to_vec_zlib(
+        icc,
+        miniz_oxide::deflate::CompressionLevel::DefaultLevel as u8,
+      ));
+      write_png_chunk(&mut chunks, b"iCCP", &data);
+    }
+    if let Some(exif) = &self.exif {
+      write_png_chunk(&mut chunks, b"eXIf", exif);
+    }
+    for (kind, data) in self.png_text.iter() {
+      write_png_chunk(&mut chunks, kind, data);
+    }
+
+    let mut out = image;
+    out.splice(insert_at..insert_at, chunks);
+    Ok(out)
+  }
+
+  fn embed_jpeg(&self, image: Vec<u8>) -> Result<Vec<u8>> {
+    if !image.starts_with(&[0xff, 0xd8]) {
+      bail!("Invalid JPEG: missing SOI marker");
+    }
+    let insert_at = match jpeg_segments(&image).next() {
+      Some((0xe0, app0)) => 2 + 4 + app0.len(),
+      _ => 2,
+    };
+
+    let mut segments = Vec::new();
+    if let Some(exif) = &self.exif {
+      let data = [JPEG_EXIF_HEADER, exif].concat();
+      if data.len() <= JPEG_MAX_SEGMENT {
+        write_jpeg_segment(&mut segments, 0xe1, &data);
+      }
+    }
+    if let Some(icc) = &self.icc {
+      let chunk_size = JPEG_MAX_SEGMENT - JPEG_ICC_HEADER.len() - 2;
+      let count = icc.len().div_ceil(chunk_size);
+      if count <= u8::MAX as usize {
+        for (seq, chunk) in icc.chunks(chunk_size).enumerate() {
+          let data = [JPEG_ICC_HEADER, &[seq as u8 + 1, count as u8], chunk].concat();
+          write_jpeg_segment(&mut segments, 0xe2, &data);
+        }
+      }
+    }
+
+    let mut out = image;
+    out.splice(insert_at..insert_at, segments);
+    Ok(out)
+  }
+}
+
+fn png_chunks(bytes: &[u8]) -> impl Iterator<Item = (&[u8; 4], &[u8])> {
+  let mut rest = bytes.get(PNG_SIGNATURE.len()..).unwrap_or_default();
+  std::iter::from_fn(move || {
+    let len = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+    let kind: &[u8; 4] = rest.get(4..8)?.try_into().ok()?;
+    let data = rest.get(8..8 + len)?;
+    rest = rest.get(12 + len..)?;
+    Some((kind, data))
+  })
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+  let mut hasher = crc32fast::Hasher::new();
+  hasher.update(kind);
+  hasher.update(data);
+  out.extend((data.len() as u32).to_be_bytes());
+  out.extend(kind);
+  out.extend(data);
+  out.extend(hasher.finalize().to_be_bytes());
+}
+
+fn jpeg_segments(bytes: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+  let mut rest = bytes.get(2..).unwrap_or_default();
+  std::iter::from_fn(move || {
+    let (0xff, marker) = (*rest.first()?, *rest.get(1)?) else {
+      return None;
+    };
+    if marker == 0xda || marker == 0xd9 {
+      return None;
+    }
+    let len = u16::from_be_bytes(rest.get(2..4)?.try_into().ok()?) as usize;
+    let data = rest.get(4..2 + len)?;
+    rest = rest.get(2 + len..)?;
+    Some((marker, data))
+  })
+}
+
+fn write_jpeg_segment(out: &mut Vec<u8>, marker: u8, data: &[u8]) {
+  out.extend([0xff, marker]);
+  out.extend((data.len() as u16 + 2).to_be_bytes());
+  out.extend(data);
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use super::*;
+
+  fn encode(format: ImageFormat) -> Result<Vec<u8>> {
+    let mut buf = Cursor::new(Vec::new());
+    image::RgbImage::new(4, 4).write_to(&mut buf, format)?;
+    Ok(buf.into_inner())
+  }
+
+  fn sample() -> Metadata {
+    Metadata {
+      exif: Some(b"MM\0*\0\0\0\x08\0\0".to_vec()),
+      icc: Some((0..200).collect()),
+      png_text: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn test_png_roundtrip() -> Result<()> {
+    let mut metadata = sample();
+    metadata.png_text.push((*b"tEXt", b"Comment\0hello".to_vec()));
+    let png = metadata.embed(encode(ImageFormat::Png)?, ImageFormat::Png)?;
+    assert_eq!(Metadata::read(&png), metadata);
+    image::load_from_memory(&png)?;
+    Ok(())
+  }
+
+  #[test]
+  fn test_jpeg_roundtrip() -> Result<()> {
+    let metadata = sample();
+    let jpeg = metadata.embed(encode(ImageFormat::Jpeg)?, ImageFormat::Jpeg)?;
+    assert_eq!(Metadata::read(&jpeg), metadata);
+    image::load_from_memory(&jpeg)?;
+    Ok(())
+  }
+}
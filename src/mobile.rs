@@ -0,0 +1,139 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+use anyhow::Result;
+use candle_core::Device;
+use image::ImageFormat;
+use jni::objects::{JByteArray, JClass, JString};
+use jni::sys::{jbyteArray, jlong, jstring};
+use jni::JNIEnv;
+
+use crate::codec::Codec;
+use crate::image_io;
+
+// Largest image processed on a phone, the networks need a few hundred MB of activations at this size.
+pub const MAX_PIXELS: u64 = 4_000_000;
+
+// Loads a CPU codec from bundled weights with the phone memory limit.
+pub fn load(encoder: &[u8], decoder: &[u8]) -> Result<Codec> {
+  let mut codec = Codec::from_buffers(encoder, decoder, &Device::Cpu)?;
+  codec.set_max_pixels(Some(MAX_PIXELS));
+  Ok(codec)
+}
+
+pub fn encode(codec: &Codec, image: &[u8], data: &str) -> Result<Vec<u8>> {
+  let cover = image::load_from_memory(image)?.to_rgb8();
+  image_io::encode_image(&codec.encode(&cover, data.as_bytes(), None)?, ImageFormat::Png)
+}
+
+pub fn decode(codec: &Codec, image: &[u8]) -> Result<String> {
+  Ok(codec.decode(&image::load_from_memory(image)?.to_rgb8())?.message)
+}
+
+// Turns an error into a pending `RuntimeException`, returning `fallback` to the JVM.
+fn or_throw<T>(env: &mut JNIEnv, result: Result<T>, fallback: T) -> T {
+  result.unwrap_or_else(|err| {
+    let _ = env.throw_new("java/lang/RuntimeException", format!("{err:#}"));
+    fallback
+  })
+}
+
+// Runs `f`, turning a panic into an error: unwinding into the JVM would abort it.
+fn guard<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+  catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| Err(anyhow::anyhow!("panic in steganogan")))
+}
+
+// The codec behind a handle. Java callers only pass handles `load` returned, or 0, and pass each to `free` once after
+// their last call with it (see README), so a nonzero handle points to a live `Codec`.
+unsafe fn codec<'a>(handle: jlong) -> Result<&'a Codec> {
+  (handle as *const Codec)
+    .as_ref()
+    .ok_or_else(|| anyhow::anyhow!("codec is closed"))
+}
+
+#[no_mangle]
+pub extern "system" fn Java_org_steganogan_Steganogan_load(
+  mut env: JNIEnv,
+  _class: JClass,
+  encoder: JByteArray,
+  decoder: JByteArray,
+) -> jlong {
+  let result = guard(|| {
+    let codec = load(&env.convert_byte_array(&encoder)?, &env.convert_byte_array(&decoder)?)?;
+    Ok(Box::into_raw(Box::new(codec)) as jlong)
+  });
+  or_throw(&mut env, result, 0)
+}
+
+#[no_mangle]
+pub extern "system" fn Java_org_steganogan_Steganogan_encode(
+  mut env: JNIEnv,
+  _class: JClass,
+  handle: jlong,
+  image: JByteArray,
+  data: JString,
+) -> jbyteArray {
+  let result = guard(|| {
+    let data: String = env.get_string(&data)?.into();
+    // SAFETY: `handle` comes from `load` and is not freed yet, see `codec`
+    let output = encode(unsafe { codec(handle)? }, &env.convert_byte_array(&image)?, &data)?;
+    Ok(env.byte_array_from_slice(&output)?.into_raw())
+  });
+  or_throw(&mut env, result, ptr::null_mut())
+}
+
+#[no_mangle]
+pub extern "system" fn Java_org_steganogan_Steganogan_decode(
+  mut env: JNIEnv,
+  _class: JClass,
+  handle: jlong,
+  image: JByteArray,
+) -> jstring {
+  let result = guard(|| {
+    // SAFETY: `handle` comes from `load` and is not freed yet, see `codec`
+    let message = decode(unsafe { codec(handle)? }, &env.convert_byte_array(&image)?)?;
+    Ok(env.new_string(message)?.into_raw())
+  });
+  or_throw(&mut env, result, ptr::null_mut())
+}
+
+#[no_mangle]
+pub extern "system" fn Java_org_steganogan_Steganogan_free(mut env: JNIEnv, _class: JClass, handle: jlong) {
+  let result = guard(|| {
+    if handle != 0 {
+      // SAFETY: `handle` comes from `load`, and Java callers free it once and do not use it afterwards
+      drop(unsafe { Box::from_raw(handle as *mut Codec) });
+    }
+    Ok(())
+  });
+  or_throw(&mut env, result, ())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use super::*;
+  use crate::SteganoError;
+
+  #[test]
+  fn test_load_buffers() -> Result<()> {
+    let codec = load(
+      &std::fs::read("pretrained/encoder.safetensors")?,
+      &std::fs::read("pretrained/decoder.safetensors")?,
+    )?;
+    let mut cover = Vec::new();
+    image::RgbImage::new(64, 48).write_to(&mut Cursor::new(&mut cover), ImageFormat::Png)?;
+    let stego = image::load_from_memory(&encode(&codec, &cover, "hello")?)?;
+    assert_eq!((stego.width(), stego.height()), (64, 48));
+
+    let mut large = Vec::new();
+    image::RgbImage::new(2001, 2000).write_to(&mut Cursor::new(&mut large), ImageFormat::Png)?;
+    let err = decode(&codec, &large).unwrap_err();
+    assert!(
+      matches!(err.downcast_ref(), Some(SteganoError::ImageTooLarge { .. })),
+      "{err}"
+    );
+    Ok(())
+  }
+}
@@ -0,0 +1,98 @@
+use candle_core::{Module, Tensor};
+use candle_nn::ops::sigmoid;
+use candle_nn::{conv2d, Conv2d, Conv2dConfig, VarBuilder};
+
+use crate::error::Result;
+
+// Width of the channel attention bottleneck relative to the number of channels.
+const REDUCTION: usize = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Attention {
+  #[default]
+  None,
+  /// Squeeze-and-excitation: channels are reweighted from their average over the image
+  Se,
+  /// CBAM: channels are reweighted from their average and maximum, then pixels from the mean and maximum over channels
+  Cbam,
+}
+
+// Attention applied to the output of a `ConvBlock`.
+#[derive(Debug)]
+pub struct AttentionBlock {
+  fc1: Conv2d,
+  fc2: Conv2d,
+  spatial: Option<Conv2d>,
+}
+
+impl AttentionBlock {
+  pub fn new(attention: Attention, channels: usize, vb: VarBuilder) -> Result<Option<Self>> {
+    if attention == Attention::None {
+      return Ok(None);
+    }
+    let hidden = (channels / REDUCTION).max(1);
+    let spatial = match attention {
+      Attention::Cbam => {
+        let config = Conv2dConfig {
+          padding: 3,
+          ..Default::default()
+        };
+        Some(conv2d(2, 1, 7, config, vb.pp("spatial"))?)
+      }
+      _ => None,
+    };
+    Ok(Some(Self {
+      fc1: conv2d(channels, hidden, 1, Default::default(), vb.pp("fc1"))?,
+      fc2: conv2d(hidden, channels, 1, Default::default(), vb.pp("fc2"))?,
+      spatial,
+    }))
+  }
+
+  fn mlp(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+    self.fc2.forward(&self.fc1.forward(x)?.relu()?)
+  }
+}
+
+impl Module for AttentionBlock {
+  fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+    let channel = self.mlp(&x.mean_keepdim(3)?.mean_keepdim(2)?)?;
+    let channel = match self.spatial {
+      Some(_) => (channel + self.mlp(&x.max_keepdim(3)?.max_keepdim(2)?)?)?,
+      None => channel,
+    };
+    let x = x.broadcast_mul(&sigmoid(&channel)?)?;
+    match &self.spatial {
+      Some(conv) => {
+        let pooled = Tensor::cat(&[x.mean_keepdim(1)?, x.max_keepdim(1)?], 1)?;
+        x.broadcast_mul(&sigmoid(&conv.forward(&pooled)?)?)
+      }
+      None => Ok(x),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use candle_nn::VarMap;
+
+  use super::*;
+
+  #[test]
+  fn test_attention() -> Result<()> {
+    let device = &candle_core::Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, candle_core::DType::F32, device);
+    assert!(AttentionBlock::new(Attention::None, 16, vb.pp("none"))?.is_none());
+    let x = Tensor::randn(0f32, 1f32, (2, 16, 9, 7), device)?;
+    for attention in [Attention::Se, Attention::Cbam] {
+      let block = AttentionBlock::new(attention, 16, vb.pp(format!("{attention:?}")))?.unwrap();
+      let y = block.forward(&x)?;
+      assert_eq!(y.dims(), x.dims());
+      // Attention only scales the input down
+      let ratio = (y / &x)?.flatten_all()?.to_vec1::<f32>()?;
+      assert!(ratio.iter().all(|r| r.is_nan() || (0. ..=1.).contains(r)));
+    }
+    assert_eq!(varmap.all_vars().len(), 4 + 6);
+    Ok(())
+  }
+}
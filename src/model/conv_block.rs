@@ -1,32 +1,156 @@
-use anyhow::Result;
 use candle_core::{Module, Tensor};
 use candle_nn::ops::leaky_relu;
 use candle_nn::{batch_norm, conv2d, BatchNorm, BatchNormConfig, Conv2d, Conv2dConfig, VarBuilder};
 
+use super::attention::AttentionBlock;
+use crate::error::Result;
+use crate::weights::ModelConfig;
+
+#[derive(Debug)]
+enum Conv {
+  Full(Conv2d),
+  // A 3x3 convolution of every channel on its own followed by a 1x1 convolution mixing them, like MobileNet: about
+  // `in_channels` times cheaper than the full convolution
+  Separable { depthwise: Conv2d, pointwise: Conv2d },
+}
+
+impl Module for Conv {
+  fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+    match self {
+      Self::Full(conv) => conv.forward(x),
+      Self::Separable { depthwise, pointwise } => pointwise.forward(&depthwise.forward(x)?),
+    }
+  }
+}
+
 #[derive(Debug)]
 pub struct ConvBlock {
-  conv: Conv2d,
+  conv: Conv,
   bn: BatchNorm,
+  // Batch norm at inference as one scale and shift per channel, set by `fuse`
+  fused: Option<(Tensor, Tensor)>,
+  attention: Option<AttentionBlock>,
 }
 
 impl ConvBlock {
-  pub fn new(in_channels: usize, out_channels: usize, vb: VarBuilder) -> Result<Self> {
+  // Attention and separable convolutions as set in `config`.
+  pub fn new(in_channels: usize, out_channels: usize, config: &ModelConfig, vb: VarBuilder) -> Result<Self> {
     let conv_config = Conv2dConfig {
       padding: 1,
       ..Default::default()
     };
+    let conv = match config.separable {
+      true => Conv::Separable {
+        depthwise: conv2d(
+          in_channels,
+          in_channels,
+          3,
+          Conv2dConfig {
+            groups: in_channels,
+            ..conv_config
+          },
+          vb.pp("0.depthwise"),
+        )?,
+        pointwise: conv2d(in_channels, out_channels, 1, Default::default(), vb.pp("0.pointwise"))?,
+      },
+      false => Conv::Full(conv2d(in_channels, out_channels, 3, conv_config, vb.pp("0"))?),
+    };
     let bn_config = BatchNormConfig::default();
     Ok(Self {
-      conv: conv2d(in_channels, out_channels, 3, conv_config, vb.pp("0"))?,
+      conv,
       bn: batch_norm(out_channels, bn_config, vb.pp("2"))?,
+      fused: None,
+      // Named as the next module of the original `Sequential`
+      attention: AttentionBlock::new(config.attention, out_channels, vb.pp("3"))?,
     })
   }
 }
 
-impl Module for ConvBlock {
-  fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+impl ConvBlock {
+  // Folds the running statistics and affine parameters of batch norm into a scale and shift, so that inference runs
+  // two kernels for it instead of four. Has to be called again after the weights change.
+  pub fn fuse(&mut self) -> Result<()> {
+    let std = (self.bn.running_var() + self.bn.eps())?.sqrt()?;
+    let (scale, shift) = match self.bn.weight_and_bias() {
+      Some((weight, bias)) => {
+        let scale = (weight / &std)?;
+        (scale.clone(), (bias - (self.bn.running_mean() * scale)?)?)
+      }
+      None => {
+        let scale = std.recip()?;
+        (scale.clone(), (self.bn.running_mean() * scale)?.neg()?)
+      }
+    };
+    self.fused = Some((scale.reshape((1, (), 1, 1))?, shift.reshape((1, (), 1, 1))?));
+    Ok(())
+  }
+
+  // In training mode batch norm normalizes with the statistics of the batch and updates its running statistics,
+  // otherwise it uses the running statistics.
+  pub fn forward_t(&self, x: &Tensor, train: bool) -> candle_core::Result<Tensor> {
     let x = self.conv.forward(x)?;
     let x = leaky_relu(&x, 0.01)?;
-    self.bn.forward(&x)
+    let x = match (train, &self.fused) {
+      (true, _) => self.bn.forward_learning(&x)?,
+      (false, Some((scale, shift))) => x.broadcast_mul(scale)?.broadcast_add(shift)?,
+      (false, None) => self.bn.forward(&x)?,
+    };
+    match &self.attention {
+      Some(attention) => attention.forward(&x),
+      None => Ok(x),
+    }
+  }
+}
+
+impl Module for ConvBlock {
+  fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+    self.forward_t(x, false)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use candle_nn::VarMap;
+
+  use super::*;
+
+  #[test]
+  fn test_separable() -> Result<()> {
+    let device = &candle_core::Device::Cpu;
+    let x = Tensor::randn(0f32, 1f32, (1, 16, 8, 8), device)?;
+    let parameters = |separable: bool| -> Result<usize> {
+      let varmap = VarMap::new();
+      let vb = VarBuilder::from_varmap(&varmap, candle_core::DType::F32, device);
+      let config = ModelConfig {
+        separable,
+        ..Default::default()
+      };
+      let block = ConvBlock::new(16, 32, &config, vb)?;
+      assert_eq!(block.forward(&x)?.dims(), [1, 32, 8, 8]);
+      Ok(varmap.all_vars().iter().map(|var| var.elem_count()).sum())
+    };
+    // 16x32x3x3 weights against 16x3x3 + 16x32
+    assert_eq!(
+      parameters(false)? - parameters(true)?,
+      16 * 32 * 9 - 16 * 9 - 16 * 32 - 16
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn test_fuse() -> Result<()> {
+    let device = &candle_core::Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, candle_core::DType::F32, device);
+    let mut block = ConvBlock::new(8, 16, &ModelConfig::default(), vb)?;
+    for var in varmap.all_vars() {
+      var.set(&Tensor::rand(0.5f32, 1.5, var.dims(), device)?)?;
+    }
+    let x = Tensor::randn(0f32, 1f32, (2, 8, 6, 6), device)?;
+    let expected = block.forward(&x)?;
+    block.fuse()?;
+    let diff = (block.forward(&x)? - expected)?.abs()?.max_all()?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+    Ok(())
   }
 }
@@ -1,9 +1,13 @@
-use anyhow::Result;
 use candle_core::{Module, Tensor};
-use candle_nn::{batch_norm, conv2d, seq, Activation, BatchNormConfig, Conv2dConfig, Sequential, VarBuilder};
+use candle_nn::ops::leaky_relu;
+use candle_nn::{batch_norm, conv2d, BatchNorm, BatchNormConfig, Conv2d, Conv2dConfig, VarBuilder};
 
+use crate::error::Result;
+
+// Layers are named like the `Sequential` of the original model: conv at 0, 3 and 6, batch norm at 2, 5 and 8.
 pub struct Critic {
-  layers: Sequential,
+  blocks: Vec<(Conv2d, BatchNorm)>,
+  out: Conv2d,
 }
 
 impl Critic {
@@ -11,25 +15,38 @@ impl Critic {
     let conv_config = Conv2dConfig::default();
     let bn_config = BatchNormConfig::default();
     let vb = vb.pp("layers");
+    let blocks = (0..3)
+      .map(|i| {
+        let in_channels = if i == 0 { 3 } else { hidden_size };
+        Ok((
+          conv2d(in_channels, hidden_size, 3, conv_config, vb.pp(3 * i))?,
+          batch_norm(hidden_size, bn_config, vb.pp(3 * i + 2))?,
+        ))
+      })
+      .collect::<Result<_>>()?;
     Ok(Self {
-      layers: seq()
-        .add(conv2d(3, hidden_size, 3, conv_config, vb.pp("0"))?)
-        .add(Activation::LeakyRelu(0.01))
-        .add(batch_norm(hidden_size, bn_config, vb.pp("2"))?)
-        .add(conv2d(hidden_size, hidden_size, 3, conv_config, vb.pp("3"))?)
-        .add(Activation::LeakyRelu(0.01))
-        .add(batch_norm(hidden_size, bn_config, vb.pp("5"))?)
-        .add(conv2d(hidden_size, hidden_size, 3, conv_config, vb.pp("6"))?)
-        .add(Activation::LeakyRelu(0.01))
-        .add(batch_norm(hidden_size, bn_config, vb.pp("8"))?)
-        .add(conv2d(hidden_size, 1, 3, conv_config, vb.pp("9"))?),
+      blocks,
+      out: conv2d(hidden_size, 1, 3, conv_config, vb.pp("9"))?,
     })
   }
 }
 
 impl Critic {
-  fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
-    self.layers.forward(x)?.flatten_from(1)?.mean(1)
+  pub fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+    self.forward_t(x, false)
+  }
+
+  // Training uses batch statistics in batch norm, see `ConvBlock::forward_t`.
+  pub fn forward_t(&self, x: &Tensor, train: bool) -> candle_core::Result<Tensor> {
+    let mut x = x.clone();
+    for (conv, bn) in &self.blocks {
+      x = leaky_relu(&conv.forward(&x)?, 0.01)?;
+      x = match train {
+        true => bn.forward_learning(&x)?,
+        false => bn.forward(&x)?,
+      };
+    }
+    self.out.forward(&x)?.flatten_from(1)?.mean(1)
   }
 }
 
@@ -118,4 +135,19 @@ mod tests {
     assert_eq!(candle_core::test_utils::to_vec1_round(&out, 3)?, [1.261]);
     Ok(())
   }
+
+  #[test]
+  fn test_train_mode() -> Result<()> {
+    let device = &candle_core::Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, candle_core::DType::F32, device);
+    let critic = Critic::new(4, vb.clone())?;
+    let running_mean = || vb.get(4, "layers.2.running_mean")?.to_vec1::<f32>();
+    let x = (Tensor::randn(0f32, 1f32, (2, 3, 16, 16), device)? + 3.)?;
+    critic.forward(&x)?;
+    assert_eq!(running_mean()?, [0.; 4]);
+    critic.forward_t(&x, true)?;
+    assert_ne!(running_mean()?, [0.; 4]);
+    Ok(())
+  }
 }
@@ -1,39 +1,152 @@
-use anyhow::Result;
 use candle_core::{Module, Tensor};
 use candle_nn::{conv2d, Conv2d, Conv2dConfig, VarBuilder};
 
 use super::conv_block::ConvBlock;
+use super::Arch;
+use crate::error::Result;
+use crate::weights::ModelConfig;
 
 pub struct Decoder {
-  initial: ConvBlock,
-  convs: Vec<ConvBlock>,
+  layers: Layers,
   out: Conv2d,
 }
 
+enum Layers {
+  // Every block sees the outputs of all previous ones, the architecture of the pretrained weights.
+  Dense {
+    initial: ConvBlock,
+    convs: Vec<ConvBlock>,
+  },
+  // `num_blocks` levels of two blocks, halving the resolution and doubling the width on the way down, and one block
+  // per level on the way up that also gets the output of the same level on the way down.
+  Unet {
+    down: Vec<(ConvBlock, ConvBlock)>,
+    bottleneck: ConvBlock,
+    up: Vec<ConvBlock>,
+  },
+}
+
 impl Decoder {
   pub fn new(data_depth: usize, hidden_size: usize, vb: VarBuilder) -> Result<Self> {
+    let config = ModelConfig {
+      data_depth,
+      hidden_size,
+      ..Default::default()
+    };
+    Self::from_config(&config, vb)
+  }
+
+  pub fn from_config(config: &ModelConfig, vb: VarBuilder) -> Result<Self> {
+    let (data_depth, hidden_size) = (config.data_depth, config.hidden_size);
     let conv_config = Conv2dConfig {
       padding: 1,
       ..Default::default()
     };
-    Ok(Self {
-      initial: ConvBlock::new(3, hidden_size, vb.pp("conv1"))?,
-      convs: vec![
-        ConvBlock::new(hidden_size, hidden_size, vb.pp("conv2"))?,
-        ConvBlock::new(2 * hidden_size, hidden_size, vb.pp("conv3"))?,
-      ],
-      out: conv2d(3 * hidden_size, data_depth, 3, conv_config, vb.pp("conv4.0"))?,
-    })
+    let blocks = config.num_blocks;
+    let (layers, out_channels) = match config.arch {
+      Arch::Dense => (
+        Layers::Dense {
+          initial: ConvBlock::new(3, hidden_size, config, vb.pp("conv1"))?,
+          convs: (1..=blocks)
+            .map(|i| ConvBlock::new(i * hidden_size, hidden_size, config, vb.pp(format!("conv{}", i + 1))))
+            .collect::<Result<_>>()?,
+        },
+        (blocks + 1) * hidden_size,
+      ),
+      Arch::Unet => {
+        let width = |level: usize| hidden_size << level;
+        let down = (0..blocks)
+          .map(|level| {
+            let (in_channels, vb) = (
+              if level == 0 { 3 } else { width(level - 1) },
+              vb.pp(format!("down{level}")),
+            );
+            Ok((
+              ConvBlock::new(in_channels, width(level), config, vb.pp("0"))?,
+              ConvBlock::new(width(level), width(level), config, vb.pp("1"))?,
+            ))
+          })
+          .collect::<Result<_>>()?;
+        let up = (0..blocks)
+          .rev()
+          .map(|level| {
+            ConvBlock::new(
+              width(level + 1) + width(level),
+              width(level),
+              config,
+              vb.pp(format!("up{level}")),
+            )
+          })
+          .collect::<Result<_>>()?;
+        let bottleneck = ConvBlock::new(width(blocks - 1), width(blocks), config, vb.pp("bottleneck"))?;
+        (Layers::Unet { down, bottleneck, up }, hidden_size)
+      }
+    };
+    // Named after the blocks like in the pretrained weights, where it is `conv4.0`
+    let out = conv2d(
+      out_channels,
+      data_depth,
+      3,
+      conv_config,
+      vb.pp(format!("conv{}.0", blocks + 2)),
+    )?;
+    Ok(Self { layers, out })
   }
 
-  pub fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
-    let mut x = self.initial.forward(x)?;
-    let mut xc = x;
-    for layer in self.convs.iter() {
-      x = layer.forward(&xc)?;
-      xc = Tensor::cat(&[&xc, &x], 1)?;
+  // Fuses batch norm for inference, see `ConvBlock::fuse`.
+  pub fn fuse(&mut self) -> Result<()> {
+    match &mut self.layers {
+      Layers::Dense { initial, convs } => {
+        initial.fuse()?;
+        convs.iter_mut().try_for_each(ConvBlock::fuse)
+      }
+      Layers::Unet { down, bottleneck, up } => {
+        for (first, second) in down.iter_mut() {
+          first.fuse()?;
+          second.fuse()?;
+        }
+        bottleneck.fuse()?;
+        up.iter_mut().try_for_each(ConvBlock::fuse)
+      }
     }
-    self.out.forward(&xc)
+  }
+
+  pub fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+    self.forward_t(x, false)
+  }
+
+  // Training uses batch statistics in batch norm, see `ConvBlock::forward_t`.
+  pub fn forward_t(&self, x: &Tensor, train: bool) -> candle_core::Result<Tensor> {
+    let x = match &self.layers {
+      Layers::Dense { initial, convs } => {
+        let mut x = initial.forward_t(x, train)?;
+        let mut xc = x;
+        for layer in convs.iter() {
+          x = layer.forward_t(&xc, train)?;
+          xc = Tensor::cat(&[&xc, &x], 1)?;
+        }
+        xc
+      }
+      Layers::Unet { down, bottleneck, up } => {
+        let mut skips = Vec::with_capacity(down.len());
+        let mut x = x.clone();
+        for (level, (first, second)) in down.iter().enumerate() {
+          if level > 0 {
+            x = x.avg_pool2d(2)?;
+          }
+          x = second.forward_t(&first.forward_t(&x, train)?, train)?;
+          skips.push(x.clone());
+        }
+        x = bottleneck.forward_t(&x.avg_pool2d(2)?, train)?;
+        for (block, skip) in up.iter().zip(skips.iter().rev()) {
+          // Upsampling to the size of the skip undoes the rounding down of odd sizes when pooling
+          let (_, _, h, w) = skip.dims4()?;
+          x = block.forward_t(&Tensor::cat(&[&x.upsample_nearest2d(h, w)?, skip], 1)?, train)?;
+        }
+        x
+      }
+    };
+    self.out.forward(&x)
   }
 }
 
@@ -102,6 +215,28 @@ conv4
     Ok(())
   }
 
+  #[test]
+  fn test_unet() -> Result<()> {
+    let varmap = VarMap::new();
+    let device = &candle_core::Device::Cpu;
+    let vb = VarBuilder::from_varmap(&varmap, candle_core::DType::F32, device);
+    let config = ModelConfig {
+      arch: Arch::Unet,
+      data_depth: 4,
+      hidden_size: 8,
+      ..Default::default()
+    };
+    let decoder = Decoder::from_config(&config, vb)?;
+    let x = Tensor::randn(0f32, 1f32, (2, 3, 30, 22), device)?;
+    assert_eq!(decoder.forward_t(&x, true)?.shape().dims(), [2, 4, 30, 22]);
+    // Odd sides are rounded down by pooling and restored by upsampling to the skip connections
+    let x = Tensor::randn(0f32, 1f32, (1, 3, 29, 23), device)?;
+    assert_eq!(decoder.forward(&x)?.shape().dims(), [1, 4, 29, 23]);
+    let names = crate::utils::varmap_to_string(&varmap);
+    assert!(names.contains("down1") && names.contains("bottleneck") && names.contains("up0"));
+    Ok(())
+  }
+
   #[test]
   fn test_load() -> Result<()> {
     let device = &candle_core::Device::cuda_if_available(0)?;
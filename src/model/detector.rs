@@ -0,0 +1,121 @@
+use candle_core::{Module, Tensor};
+use candle_nn::{batch_norm, conv2d, linear, BatchNorm, BatchNormConfig, Conv2d, Conv2dConfig, Linear, VarBuilder};
+
+use crate::error::Result;
+
+// Convolution followed by batch normalization, the unit all SRNet layers are built from.
+struct ConvBn {
+  conv: Conv2d,
+  bn: BatchNorm,
+}
+
+impl ConvBn {
+  fn new(in_channels: usize, out_channels: usize, kernel_size: usize, vb: VarBuilder) -> Result<Self> {
+    let conv_config = Conv2dConfig {
+      padding: kernel_size / 2,
+      ..Default::default()
+    };
+    Ok(Self {
+      conv: conv2d(in_channels, out_channels, kernel_size, conv_config, vb.pp("conv"))?,
+      bn: batch_norm(out_channels, BatchNormConfig::default(), vb.pp("bn"))?,
+    })
+  }
+
+  // In training mode batch norm normalizes with the statistics of the batch and updates its running statistics,
+  // otherwise it uses the running statistics.
+  fn forward_t(&self, x: &Tensor, train: bool) -> candle_core::Result<Tensor> {
+    let x = self.conv.forward(x)?;
+    match train {
+      true => self.bn.forward_learning(&x),
+      false => self.bn.forward(&x),
+    }
+  }
+}
+
+// SRNet layer of type 2 (`shortcut: None`, keeps the size) or type 3 (halves the size with average pooling).
+struct ResidualBlock {
+  first: ConvBn,
+  second: ConvBn,
+  shortcut: Option<ConvBn>,
+}
+
+impl ResidualBlock {
+  fn new(in_channels: usize, out_channels: usize, downsample: bool, vb: VarBuilder) -> Result<Self> {
+    Ok(Self {
+      first: ConvBn::new(in_channels, out_channels, 3, vb.pp("first"))?,
+      second: ConvBn::new(out_channels, out_channels, 3, vb.pp("second"))?,
+      shortcut: downsample
+        .then(|| ConvBn::new(in_channels, out_channels, 1, vb.pp("shortcut")))
+        .transpose()?,
+    })
+  }
+
+  fn forward_t(&self, x: &Tensor, train: bool) -> candle_core::Result<Tensor> {
+    let y = self.second.forward_t(&self.first.forward_t(x, train)?.relu()?, train)?;
+    match &self.shortcut {
+      Some(shortcut) => y.avg_pool2d(2)? + shortcut.forward_t(&x.avg_pool2d(2)?, train)?,
+      None => y + x,
+    }
+  }
+}
+
+// Small SRNet-style steganalyzer: unpooled layers that keep the faint stego residual, downsampling residual blocks
+// and a linear layer over globally averaged features. Outputs one logit per image, positive for stego images.
+pub struct Detector {
+  stem: ConvBn,
+  blocks: Vec<ResidualBlock>,
+  fc: Linear,
+}
+
+impl Detector {
+  pub fn new(hidden_size: usize, vb: VarBuilder) -> Result<Self> {
+    let widths = [hidden_size, 2 * hidden_size, 4 * hidden_size];
+    let mut blocks = vec![ResidualBlock::new(hidden_size, hidden_size, false, vb.pp("blocks.0"))?];
+    for (i, pair) in widths.windows(2).enumerate() {
+      blocks.push(ResidualBlock::new(
+        pair[0],
+        pair[1],
+        true,
+        vb.pp(format!("blocks.{}", i + 1)),
+      )?);
+    }
+    Ok(Self {
+      stem: ConvBn::new(3, hidden_size, 3, vb.pp("stem"))?,
+      blocks,
+      fc: linear(widths[2], 1, vb.pp("fc"))?,
+    })
+  }
+
+  pub fn forward(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+    self.forward_t(x, false)
+  }
+
+  // Training uses batch statistics in batch norm, see `ConvBn::forward_t`.
+  pub fn forward_t(&self, x: &Tensor, train: bool) -> candle_core::Result<Tensor> {
+    let mut x = self.stem.forward_t(x, train)?.relu()?;
+    for block in self.blocks.iter() {
+      x = block.forward_t(&x, train)?.relu()?;
+    }
+    self.fc.forward(&x.mean((2, 3))?)?.flatten_all()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use candle_nn::VarMap;
+
+  use super::*;
+
+  #[test]
+  fn test_out_shape() -> Result<()> {
+    let varmap = VarMap::new();
+    let device = &candle_core::Device::cuda_if_available(0)?;
+    let vb = VarBuilder::from_varmap(&varmap, candle_core::DType::F32, device);
+    let detector = Detector::new(8, vb)?;
+    let x = Tensor::randn(0f32, 1f32, (4, 3, 33, 20), device)?;
+    assert_eq!(detector.forward(&x)?.shape().dims(), [4]);
+    // 9 convolutions with batch norms and the linear layer
+    assert_eq!(varmap.all_vars().len(), 9 * 6 + 2);
+    Ok(())
+  }
+}
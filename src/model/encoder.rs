@@ -1,8 +1,9 @@
-use anyhow::Result;
 use candle_core::{Module, Tensor};
 use candle_nn::{conv2d, Conv2d, Conv2dConfig, VarBuilder};
 
 use super::conv_block::ConvBlock;
+use crate::error::Result;
+use crate::weights::ModelConfig;
 
 pub struct Encoder {
   initial: ConvBlock,
@@ -13,33 +14,94 @@ pub struct Encoder {
 
 impl Encoder {
   pub fn new(data_depth: usize, hidden_size: usize, vb: VarBuilder) -> Result<Self> {
+    let config = ModelConfig {
+      data_depth,
+      hidden_size,
+      ..Default::default()
+    };
+    Self::from_config(&config, vb)
+  }
+
+  // The encoder is dense for every `Arch`.
+  pub fn from_config(config: &ModelConfig, vb: VarBuilder) -> Result<Self> {
+    let (data_depth, hidden_size) = (config.data_depth, config.hidden_size);
     let conv_config = Conv2dConfig {
       padding: 1,
       ..Default::default()
     };
+    // Block i gets the outputs of all blocks before it and the data, the pretrained weights have two such blocks
+    let blocks = config.num_blocks;
+    let convs = (1..=blocks)
+      .map(|i| {
+        ConvBlock::new(
+          i * hidden_size + data_depth,
+          hidden_size,
+          config,
+          vb.pp(format!("conv{}", i + 1)),
+        )
+      })
+      .collect::<Result<_>>()?;
     Ok(Self {
-      initial: ConvBlock::new(3, hidden_size, vb.pp("conv1"))?,
-      convs: vec![
-        ConvBlock::new(hidden_size + data_depth, hidden_size, vb.pp("conv2"))?,
-        ConvBlock::new(2 * hidden_size + data_depth, hidden_size, vb.pp("conv3"))?,
-      ],
-      out: conv2d(3 * hidden_size + data_depth, 3, 3, conv_config, vb.pp("conv4.0"))?,
+      initial: ConvBlock::new(3, hidden_size, config, vb.pp("conv1"))?,
+      convs,
+      out: conv2d(
+        (blocks + 1) * hidden_size + data_depth,
+        3,
+        3,
+        conv_config,
+        vb.pp(format!("conv{}.0", blocks + 2)),
+      )?,
       add_image: true,
     })
   }
 
+  // Fuses batch norm for inference, see `ConvBlock::fuse`.
+  pub fn fuse(&mut self) -> Result<()> {
+    self.initial.fuse()?;
+    self.convs.iter_mut().try_for_each(ConvBlock::fuse)
+  }
+
   pub fn forward(&self, image: &Tensor, data: &Tensor) -> candle_core::Result<Tensor> {
-    let mut x = self.initial.forward(image)?;
+    self.forward_t(image, data, false)
+  }
+
+  // Training uses batch statistics in batch norm, see `ConvBlock::forward_t`.
+  pub fn forward_t(&self, image: &Tensor, data: &Tensor, train: bool) -> candle_core::Result<Tensor> {
+    let x = self.network(image, data, train)?;
+    match self.add_image {
+      true => Self::compose(image, &x),
+      false => Ok(x),
+    }
+  }
+
+  // Stego image and its difference from the cover. Callers that post-process the residual (clamp, mask or scale it)
+  // use `residual` and `compose` instead.
+  pub fn forward_with_residual(&self, image: &Tensor, data: &Tensor) -> candle_core::Result<(Tensor, Tensor)> {
+    let residual = self.residual(image, data)?;
+    Ok((Self::compose(image, &residual)?, residual))
+  }
+
+  // What the encoder adds to the image.
+  pub fn residual(&self, image: &Tensor, data: &Tensor) -> candle_core::Result<Tensor> {
+    let x = self.network(image, data, false)?;
+    match self.add_image {
+      true => Ok(x),
+      false => x - image,
+    }
+  }
+
+  pub fn compose(image: &Tensor, residual: &Tensor) -> candle_core::Result<Tensor> {
+    image + residual
+  }
+
+  fn network(&self, image: &Tensor, data: &Tensor, train: bool) -> candle_core::Result<Tensor> {
+    let mut x = self.initial.forward_t(image, train)?;
     let mut xc = x;
     for layer in self.convs.iter() {
-      x = layer.forward(&Tensor::cat(&[&xc, data], 1)?)?;
+      x = layer.forward_t(&Tensor::cat(&[&xc, data], 1)?, train)?;
       xc = Tensor::cat(&[&xc, &x], 1)?;
     }
-    x = self.out.forward(&Tensor::cat(&[&xc, data], 1)?)?;
-    if self.add_image {
-      x = image.add(&x)?
-    }
-    Ok(x)
+    self.out.forward(&Tensor::cat(&[&xc, data], 1)?)
   }
 }
 
@@ -109,6 +171,25 @@ conv4
     Ok(())
   }
 
+  #[test]
+  fn test_num_blocks() -> Result<()> {
+    let varmap = VarMap::new();
+    let device = &candle_core::Device::Cpu;
+    let vb = VarBuilder::from_varmap(&varmap, candle_core::DType::F32, device);
+    let config = ModelConfig {
+      data_depth: 2,
+      hidden_size: 4,
+      num_blocks: 4,
+      ..Default::default()
+    };
+    let encoder = Encoder::from_config(&config, vb.clone())?;
+    assert_eq!(vb.get((3, 5 * 4 + 2, 3, 3), "conv6.0.weight")?.dims(), [3, 22, 3, 3]);
+    let image = Tensor::randn(0f32, 1f32, (1, 3, 8, 8), device)?;
+    let data = Tensor::randn(0f32, 1f32, (1, 2, 8, 8), device)?;
+    assert_eq!(encoder.forward(&image, &data)?.shape(), image.shape());
+    Ok(())
+  }
+
   #[test]
   fn test_load() -> Result<()> {
     let device = &candle_core::Device::cuda_if_available(0)?;
@@ -133,6 +214,9 @@ conv4
     let data = (Tensor::ones((1, 8, 127, 127), candle_core::DType::F32, device)? * 0.3)?;
     let out = encoder.forward(&image, &data)?.mean_all()?;
     assert_eq!(candle_core::test_utils::to_vec0_round(&out, 3)?, 0.201);
+    let (stego, residual) = encoder.forward_with_residual(&image, &data)?;
+    assert_eq!(candle_core::test_utils::to_vec0_round(&stego.mean_all()?, 3)?, 0.201);
+    assert_eq!(candle_core::test_utils::to_vec0_round(&residual.mean_all()?, 3)?, 0.001);
     Ok(())
   }
 }
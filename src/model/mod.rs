@@ -1,6 +1,16 @@
 #![allow(dead_code)]
 
+pub mod attention;
 mod conv_block;
 pub mod critic;
 pub mod decoder;
+pub mod detector;
 pub mod encoder;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Arch {
+  /// Densely connected blocks at full resolution, as in SteganoGAN
+  Dense,
+  /// U-Net decoder with skip connections across one downsampling level per `--num-blocks`, the encoder stays dense
+  Unet,
+}
@@ -0,0 +1,128 @@
+use std::sync::Arc;
+
+use candle_core::Device;
+use image::ImageFormat;
+use napi::bindgen_prelude::{AsyncTask, Buffer};
+use napi::{Env, Task};
+use napi_derive::napi;
+
+use crate::codec::Codec;
+use crate::image_io;
+
+fn to_napi(err: anyhow::Error) -> napi::Error {
+  napi::Error::from_reason(format!("{err:#}"))
+}
+
+/// A loaded model. Encoding and decoding run on the libuv thread pool, off the JS main thread.
+#[napi(js_name = "Steganogan")]
+pub struct JsCodec {
+  codec: Arc<Codec>,
+}
+
+#[napi]
+impl JsCodec {
+  /// Loads a model directory, PyTorch checkpoint or downloaded model by name, `pretrained` by default.
+  #[napi(constructor)]
+  pub fn new(model: Option<String>) -> napi::Result<Self> {
+    let load = || -> anyhow::Result<Codec> {
+      Codec::open(model.as_deref().unwrap_or("pretrained"), &Device::cuda_if_available(0)?)
+    };
+    Ok(Self {
+      codec: Arc::new(load().map_err(to_napi)?),
+    })
+  }
+
+  /// Hides `data` in the image and resolves to the stego image as PNG.
+  #[napi]
+  pub fn encode(&self, image: Buffer, data: String) -> AsyncTask<EncodeTask> {
+    AsyncTask::new(EncodeTask {
+      codec: self.codec.clone(),
+      image: image.to_vec(),
+      data,
+    })
+  }
+
+  /// Resolves to the message hidden in the image, rejects if there is none.
+  #[napi]
+  pub fn decode(&self, image: Buffer) -> AsyncTask<DecodeTask> {
+    AsyncTask::new(DecodeTask {
+      codec: self.codec.clone(),
+      image: image.to_vec(),
+    })
+  }
+}
+
+pub struct EncodeTask {
+  codec: Arc<Codec>,
+  image: Vec<u8>,
+  data: String,
+}
+
+impl Task for EncodeTask {
+  type Output = Vec<u8>;
+  type JsValue = Buffer;
+
+  fn compute(&mut self) -> napi::Result<Self::Output> {
+    let encode = || -> anyhow::Result<Vec<u8>> {
+      let cover = image::load_from_memory(&self.image)?.to_rgb8();
+      let stego = self.codec.encode(&cover, self.data.as_bytes(), None)?;
+      image_io::encode_image(&stego, ImageFormat::Png)
+    };
+    encode().map_err(to_napi)
+  }
+
+  fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+    Ok(output.into())
+  }
+}
+
+pub struct DecodeTask {
+  codec: Arc<Codec>,
+  image: Vec<u8>,
+}
+
+impl Task for DecodeTask {
+  type Output = String;
+  type JsValue = String;
+
+  fn compute(&mut self) -> napi::Result<Self::Output> {
+    let decode = || -> anyhow::Result<String> {
+      let img = image::load_from_memory(&self.image)?.to_rgb8();
+      Ok(self.codec.decode(&img)?.message)
+    };
+    decode().map_err(to_napi)
+  }
+
+  fn resolve(&mut self, _env: Env, output: Self::Output) -> napi::Result<Self::JsValue> {
+    Ok(output)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use super::*;
+
+  #[test]
+  fn test_tasks() {
+    let codec = JsCodec::new(None).unwrap().codec;
+    let mut cover = Vec::new();
+    image::RgbImage::new(64, 48)
+      .write_to(&mut Cursor::new(&mut cover), ImageFormat::Png)
+      .unwrap();
+    let mut encode = EncodeTask {
+      codec: codec.clone(),
+      image: cover,
+      data: "hello".to_string(),
+    };
+    let stego = image::load_from_memory(&encode.compute().unwrap()).unwrap();
+    assert_eq!((stego.width(), stego.height()), (64, 48));
+
+    let mut decode = DecodeTask {
+      codec,
+      image: b"hello".to_vec(),
+    };
+    assert!(decode.compute().is_err());
+  }
+}
@@ -0,0 +1,577 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+use candle_core::{DType, Device, Tensor, Var};
+use candle_nn::VarMap;
+use prost::Message;
+
+use crate::model::attention::Attention;
+use crate::model::Arch;
+use crate::weights::ModelConfig;
+
+const IR_VERSION: i64 = 7;
+// Opset 13 still takes the axes of reductions as attributes.
+const OPSET: i64 = 13;
+
+// Serializes the encoder or decoder in `varmap` as an ONNX model with dynamic batch, height and width axes. The
+// encoder takes `image` and `data` and outputs `stego`, the decoder takes `image` and outputs `logits`. Tensors keep
+// their names in the weights, and the model config is stored in the metadata.
+pub fn export(varmap: &VarMap, config: &ModelConfig, component: &str) -> Result<Vec<u8>> {
+  let vars = varmap.data().lock().unwrap();
+  let mut graph = Graph {
+    vars: &vars,
+    nodes: Vec::new(),
+    initializers: Vec::new(),
+  };
+  let image = value_info("image", 3);
+  let blocks = config.num_blocks;
+  let (inputs, output) = match component {
+    "encoder" => {
+      let data = value_info("data", config.data_depth);
+      let mut x = graph.conv_block("image", "conv1", config)?;
+      let mut xc = x.clone();
+      for i in 1..=blocks {
+        let input = graph.node("Concat", &[&xc, "data"], vec![int("axis", 1)]);
+        x = graph.conv_block(&input, &format!("conv{}", i + 1), config)?;
+        xc = graph.node("Concat", &[&xc, &x], vec![int("axis", 1)]);
+      }
+      let input = graph.node("Concat", &[&xc, "data"], vec![int("axis", 1)]);
+      let residual = graph.conv(&input, &format!("conv{}.0", blocks + 2), 1)?;
+      graph.output("Add", &["image", &residual], "stego");
+      (vec![image, data], value_info("stego", 3))
+    }
+    "decoder" => {
+      let x = match config.arch {
+        Arch::Dense => {
+          let mut x = graph.conv_block("image", "conv1", config)?;
+          let mut xc = x.clone();
+          for i in 1..=blocks {
+            x = graph.conv_block(&xc, &format!("conv{}", i + 1), config)?;
+            xc = graph.node("Concat", &[&xc, &x], vec![int("axis", 1)]);
+          }
+          xc
+        }
+        Arch::Unet => graph.unet("image", config)?,
+      };
+      let logits = graph.conv(&x, &format!("conv{}.0", blocks + 2), 1)?;
+      graph.output("Identity", &[&logits], "logits");
+      (vec![image], value_info("logits", config.data_depth))
+    }
+    _ => bail!("Unknown component '{component}'"),
+  };
+
+  let mut metadata: Vec<_> = config.to_metadata().into_iter().collect();
+  metadata.push(("component".to_string(), component.to_string()));
+  metadata.sort();
+  let model = ModelProto {
+    ir_version: IR_VERSION,
+    opset_import: vec![OperatorSetIdProto {
+      domain: String::new(),
+      version: OPSET,
+    }],
+    producer_name: env!("CARGO_PKG_NAME").to_string(),
+    producer_version: env!("CARGO_PKG_VERSION").to_string(),
+    graph: Some(GraphProto {
+      node: graph.nodes,
+      name: component.to_string(),
+      initializer: graph.initializers,
+      input: inputs,
+      output: vec![output],
+    }),
+    metadata_props: metadata
+      .into_iter()
+      .map(|(key, value)| StringStringEntryProto { key, value })
+      .collect(),
+  };
+  Ok(model.encode_to_vec())
+}
+
+// Float initializers of an ONNX model, converted to f32, and its metadata.
+pub struct Weights {
+  pub tensors: Vec<(String, Tensor)>,
+  pub metadata: HashMap<String, String>,
+}
+
+pub fn read(path: &Path) -> Result<Weights> {
+  let model = ModelProto::decode(std::fs::read(path)?.as_slice())?;
+  let mut tensors = Vec::new();
+  for tensor in model.graph.into_iter().flat_map(|graph| graph.initializer) {
+    let dtype = match tensor.data_type {
+      FLOAT => DType::F32,
+      FLOAT16 => DType::F16,
+      DOUBLE => DType::F64,
+      _ => continue,
+    };
+    if tensor.data_location == EXTERNAL {
+      bail!(
+        "Tensor '{}' is stored in an external file, which is not supported",
+        tensor.name
+      );
+    }
+    let dims: Vec<usize> = tensor.dims.iter().map(|&d| d as usize).collect();
+    let value = match (tensor.raw_data.is_empty(), dtype) {
+      (false, _) => Tensor::from_raw_buffer(&tensor.raw_data, dtype, &dims, &Device::Cpu)?,
+      (true, DType::F32) => Tensor::from_vec(tensor.float_data, dims, &Device::Cpu)?,
+      (true, _) => bail!("Tensor '{}' is not stored as raw data", tensor.name),
+    };
+    tensors.push((tensor.name, value.to_dtype(DType::F32)?));
+  }
+  let metadata = model
+    .metadata_props
+    .into_iter()
+    .map(|entry| (entry.key, entry.value))
+    .collect();
+  Ok(Weights { tensors, metadata })
+}
+
+struct Graph<'a> {
+  vars: &'a HashMap<String, Var>,
+  nodes: Vec<NodeProto>,
+  initializers: Vec<TensorProto>,
+}
+
+impl Graph<'_> {
+  // Adds a node with a single output, named after the operator and the node index.
+  fn node(&mut self, op_type: &str, inputs: &[&str], attribute: Vec<AttributeProto>) -> String {
+    let output = format!("{}_{}", op_type.to_lowercase(), self.nodes.len());
+    self.named_node(op_type, inputs, attribute, &output);
+    output
+  }
+
+  fn named_node(&mut self, op_type: &str, inputs: &[&str], attribute: Vec<AttributeProto>, output: &str) {
+    self.nodes.push(NodeProto {
+      input: inputs.iter().map(|input| input.to_string()).collect(),
+      output: vec![output.to_string()],
+      name: output.to_string(),
+      op_type: op_type.to_string(),
+      attribute,
+    });
+  }
+
+  fn output(&mut self, op_type: &str, inputs: &[&str], name: &str) {
+    self.named_node(op_type, inputs, Vec::new(), name);
+  }
+
+  // Adds a variable of the model as an initializer and returns its name and shape.
+  fn weight(&mut self, name: &str) -> Result<(String, Vec<usize>)> {
+    let var = self.vars.get(name).ok_or_else(|| anyhow!("Missing tensor '{name}'"))?;
+    if !self.initializers.iter().any(|tensor| tensor.name == name) {
+      let values = var.as_tensor().flatten_all()?.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+      self.initializers.push(TensorProto {
+        dims: var.dims().iter().map(|&d| d as i64).collect(),
+        data_type: FLOAT,
+        name: name.to_string(),
+        raw_data: values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        ..Default::default()
+      });
+    }
+    Ok((name.to_string(), var.dims().to_vec()))
+  }
+
+  fn constant(&mut self, name: &str, values: &[i64]) -> String {
+    if !self.initializers.iter().any(|tensor| tensor.name == name) {
+      self.initializers.push(TensorProto {
+        dims: vec![values.len() as i64],
+        data_type: INT64,
+        name: name.to_string(),
+        int64_data: values.to_vec(),
+        ..Default::default()
+      });
+    }
+    name.to_string()
+  }
+
+  // The convolutions of the model all keep the size of their input.
+  fn conv(&mut self, x: &str, prefix: &str, groups: usize) -> Result<String> {
+    let (weight, dims) = self.weight(&format!("{prefix}.weight"))?;
+    let (bias, _) = self.weight(&format!("{prefix}.bias"))?;
+    let kernel = dims[2] as i64;
+    let attributes = vec![
+      ints("kernel_shape", &[kernel, kernel]),
+      ints("pads", &[kernel / 2; 4]),
+      int("group", groups as i64),
+    ];
+    Ok(self.node("Conv", &[x, &weight, &bias], attributes))
+  }
+
+  // Same as `ConvBlock::forward` in inference mode.
+  fn conv_block(&mut self, x: &str, prefix: &str, config: &ModelConfig) -> Result<String> {
+    let x = match config.separable {
+      true => {
+        let depthwise = format!("{prefix}.0.depthwise");
+        let (_, dims) = self.weight(&format!("{depthwise}.weight"))?;
+        let x = self.conv(x, &depthwise, dims[0])?;
+        self.conv(&x, &format!("{prefix}.0.pointwise"), 1)?
+      }
+      false => self.conv(x, &format!("{prefix}.0"), 1)?,
+    };
+    let x = self.node("LeakyRelu", &[&x], vec![float("alpha", 0.01)]);
+    let mut inputs = vec![x];
+    for name in ["weight", "bias", "running_mean", "running_var"] {
+      inputs.push(self.weight(&format!("{prefix}.2.{name}"))?.0);
+    }
+    let inputs: Vec<&str> = inputs.iter().map(String::as_str).collect();
+    let x = self.node("BatchNormalization", &inputs, vec![float("epsilon", 1e-5)]);
+    self.attention(&x, &format!("{prefix}.3"), config.attention)
+  }
+
+  // Same as `AttentionBlock::forward`.
+  fn attention(&mut self, x: &str, prefix: &str, attention: Attention) -> Result<String> {
+    if attention == Attention::None {
+      return Ok(x.to_string());
+    }
+    let mlp = |graph: &mut Self, pooled: &str| -> Result<String> {
+      let hidden = graph.conv(pooled, &format!("{prefix}.fc1"), 1)?;
+      let hidden = graph.node("Relu", &[&hidden], Vec::new());
+      graph.conv(&hidden, &format!("{prefix}.fc2"), 1)
+    };
+    let average = self.node("GlobalAveragePool", &[x], Vec::new());
+    let mut channel = mlp(self, &average)?;
+    if attention == Attention::Cbam {
+      let max = self.node("GlobalMaxPool", &[x], Vec::new());
+      let max = mlp(self, &max)?;
+      channel = self.node("Add", &[&channel, &max], Vec::new());
+    }
+    let channel = self.node("Sigmoid", &[&channel], Vec::new());
+    let x = self.node("Mul", &[x, &channel], Vec::new());
+    if attention != Attention::Cbam {
+      return Ok(x);
+    }
+    let mean = self.node("ReduceMean", &[&x], vec![ints("axes", &[1]), int("keepdims", 1)]);
+    let max = self.node("ReduceMax", &[&x], vec![ints("axes", &[1]), int("keepdims", 1)]);
+    let pooled = self.node("Concat", &[&mean, &max], vec![int("axis", 1)]);
+    let spatial = self.conv(&pooled, &format!("{prefix}.spatial"), 1)?;
+    let spatial = self.node("Sigmoid", &[&spatial], Vec::new());
+    Ok(self.node("Mul", &[&x, &spatial], Vec::new()))
+  }
+
+  // Same as the U-Net layers of `Decoder::forward`.
+  fn unet(&mut self, x: &str, config: &ModelConfig) -> Result<String> {
+    let pool = |graph: &mut Self, x: &str| {
+      graph.node(
+        "AveragePool",
+        &[x],
+        vec![ints("kernel_shape", &[2, 2]), ints("strides", &[2, 2])],
+      )
+    };
+    let mut skips = Vec::new();
+    let mut x = x.to_string();
+    for level in 0..config.num_blocks {
+      if level > 0 {
+        x = pool(self, &x);
+      }
+      x = self.conv_block(&x, &format!("down{level}.0"), config)?;
+      x = self.conv_block(&x, &format!("down{level}.1"), config)?;
+      skips.push(x.clone());
+    }
+    let pooled = pool(self, &x);
+    x = self.conv_block(&pooled, "bottleneck", config)?;
+    let (zero, two, four) = (
+      self.constant("const_0", &[0]),
+      self.constant("const_2", &[2]),
+      self.constant("const_4", &[4]),
+    );
+    for (level, skip) in skips.iter().enumerate().rev() {
+      // Batch and channels of `x` with the height and width of the skip, as `upsample_nearest2d` rounds down
+      let shape = self.node("Shape", &[&x], Vec::new());
+      let batch_channels = self.node("Slice", &[&shape, &zero, &two], Vec::new());
+      let skip_shape = self.node("Shape", &[skip], Vec::new());
+      let size = self.node("Slice", &[&skip_shape, &two, &four], Vec::new());
+      let sizes = self.node("Concat", &[&batch_channels, &size], vec![int("axis", 0)]);
+      let attributes = vec![
+        string("mode", "nearest"),
+        string("coordinate_transformation_mode", "asymmetric"),
+        string("nearest_mode", "floor"),
+      ];
+      let upsampled = self.node("Resize", &[&x, "", "", &sizes], attributes);
+      let input = self.node("Concat", &[&upsampled, skip], vec![int("axis", 1)]);
+      x = self.conv_block(&input, &format!("up{level}"), config)?;
+    }
+    Ok(x)
+  }
+}
+
+// A float tensor of shape (batch, channels, height, width) with dynamic batch and spatial axes.
+fn value_info(name: &str, channels: usize) -> ValueInfoProto {
+  let param = |name: &str| Dimension {
+    dim_param: Some(name.to_string()),
+    ..Default::default()
+  };
+  let channels = Dimension {
+    dim_value: Some(channels as i64),
+    ..Default::default()
+  };
+  ValueInfoProto {
+    name: name.to_string(),
+    r#type: Some(TypeProto {
+      tensor_type: Some(TypeTensor {
+        elem_type: FLOAT,
+        shape: Some(TensorShapeProto {
+          dim: vec![param("batch"), channels, param("height"), param("width")],
+        }),
+      }),
+    }),
+  }
+}
+
+fn int(name: &str, i: i64) -> AttributeProto {
+  AttributeProto {
+    name: name.to_string(),
+    i,
+    r#type: ATTRIBUTE_INT,
+    ..Default::default()
+  }
+}
+
+fn ints(name: &str, ints: &[i64]) -> AttributeProto {
+  AttributeProto {
+    name: name.to_string(),
+    ints: ints.to_vec(),
+    r#type: ATTRIBUTE_INTS,
+    ..Default::default()
+  }
+}
+
+fn float(name: &str, f: f32) -> AttributeProto {
+  AttributeProto {
+    name: name.to_string(),
+    f,
+    r#type: ATTRIBUTE_FLOAT,
+    ..Default::default()
+  }
+}
+
+fn string(name: &str, s: &str) -> AttributeProto {
+  AttributeProto {
+    name: name.to_string(),
+    s: s.as_bytes().to_vec(),
+    r#type: ATTRIBUTE_STRING,
+    ..Default::default()
+  }
+}
+
+// The messages of onnx.proto the export writes and the import reads, with their field numbers there.
+const FLOAT: i32 = 1;
+const INT64: i32 = 7;
+const FLOAT16: i32 = 10;
+const DOUBLE: i32 = 11;
+const EXTERNAL: i32 = 1;
+const ATTRIBUTE_FLOAT: i32 = 1;
+const ATTRIBUTE_INT: i32 = 2;
+const ATTRIBUTE_STRING: i32 = 3;
+const ATTRIBUTE_INTS: i32 = 7;
+
+#[derive(Clone, PartialEq, Message)]
+struct ModelProto {
+  #[prost(int64, tag = "1")]
+  ir_version: i64,
+  #[prost(string, tag = "2")]
+  producer_name: String,
+  #[prost(string, tag = "3")]
+  producer_version: String,
+  #[prost(message, optional, tag = "7")]
+  graph: Option<GraphProto>,
+  #[prost(message, repeated, tag = "8")]
+  opset_import: Vec<OperatorSetIdProto>,
+  #[prost(message, repeated, tag = "14")]
+  metadata_props: Vec<StringStringEntryProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct OperatorSetIdProto {
+  #[prost(string, tag = "1")]
+  domain: String,
+  #[prost(int64, tag = "2")]
+  version: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct StringStringEntryProto {
+  #[prost(string, tag = "1")]
+  key: String,
+  #[prost(string, tag = "2")]
+  value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct GraphProto {
+  #[prost(message, repeated, tag = "1")]
+  node: Vec<NodeProto>,
+  #[prost(string, tag = "2")]
+  name: String,
+  #[prost(message, repeated, tag = "5")]
+  initializer: Vec<TensorProto>,
+  #[prost(message, repeated, tag = "11")]
+  input: Vec<ValueInfoProto>,
+  #[prost(message, repeated, tag = "12")]
+  output: Vec<ValueInfoProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct NodeProto {
+  #[prost(string, repeated, tag = "1")]
+  input: Vec<String>,
+  #[prost(string, repeated, tag = "2")]
+  output: Vec<String>,
+  #[prost(string, tag = "3")]
+  name: String,
+  #[prost(string, tag = "4")]
+  op_type: String,
+  #[prost(message, repeated, tag = "5")]
+  attribute: Vec<AttributeProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct AttributeProto {
+  #[prost(string, tag = "1")]
+  name: String,
+  #[prost(float, tag = "2")]
+  f: f32,
+  #[prost(int64, tag = "3")]
+  i: i64,
+  #[prost(bytes = "vec", tag = "4")]
+  s: Vec<u8>,
+  #[prost(int64, repeated, tag = "8")]
+  ints: Vec<i64>,
+  #[prost(int32, tag = "20")]
+  r#type: i32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TensorProto {
+  #[prost(int64, repeated, tag = "1")]
+  dims: Vec<i64>,
+  #[prost(int32, tag = "2")]
+  data_type: i32,
+  #[prost(float, repeated, tag = "4")]
+  float_data: Vec<f32>,
+  #[prost(int64, repeated, tag = "7")]
+  int64_data: Vec<i64>,
+  #[prost(string, tag = "8")]
+  name: String,
+  #[prost(bytes = "vec", tag = "9")]
+  raw_data: Vec<u8>,
+  #[prost(int32, tag = "14")]
+  data_location: i32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ValueInfoProto {
+  #[prost(string, tag = "1")]
+  name: String,
+  #[prost(message, optional, tag = "2")]
+  r#type: Option<TypeProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TypeProto {
+  #[prost(message, optional, tag = "1")]
+  tensor_type: Option<TypeTensor>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TypeTensor {
+  #[prost(int32, tag = "1")]
+  elem_type: i32,
+  #[prost(message, optional, tag = "2")]
+  shape: Option<TensorShapeProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TensorShapeProto {
+  #[prost(message, repeated, tag = "1")]
+  dim: Vec<Dimension>,
+}
+
+// `dim_value` and `dim_param` are a oneof in onnx.proto, which is the same on the wire as two optional fields.
+#[derive(Clone, PartialEq, Message)]
+struct Dimension {
+  #[prost(int64, optional, tag = "1")]
+  dim_value: Option<i64>,
+  #[prost(string, optional, tag = "2")]
+  dim_param: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+  use candle_nn::VarBuilder;
+
+  use super::*;
+  use crate::model::decoder::Decoder;
+  use crate::model::encoder::Encoder;
+
+  #[test]
+  fn test_export() -> Result<()> {
+    for config in [
+      ModelConfig::default(),
+      ModelConfig {
+        arch: Arch::Unet,
+        attention: Attention::Cbam,
+        separable: true,
+        ..Default::default()
+      },
+    ] {
+      for component in ["encoder", "decoder"] {
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        match component {
+          "encoder" => drop(Encoder::from_config(&config, vb)?),
+          _ => drop(Decoder::from_config(&config, vb)?),
+        }
+        let model = ModelProto::decode(export(&varmap, &config, component)?.as_slice())?;
+        let graph = model.graph.unwrap();
+        // Every variable is used, and every node input is a graph input, an initializer or an earlier output
+        assert_eq!(
+          graph.initializer.iter().filter(|t| t.data_type == FLOAT).count(),
+          varmap.all_vars().len()
+        );
+        let mut known: Vec<&str> = graph.input.iter().map(|v| v.name.as_str()).collect();
+        known.extend(graph.initializer.iter().map(|t| t.name.as_str()));
+        known.push("");
+        for node in &graph.node {
+          assert!(
+            node.input.iter().all(|input| known.contains(&input.as_str())),
+            "{}",
+            node.name
+          );
+          known.extend(node.output.iter().map(String::as_str));
+        }
+        assert!(known.contains(&graph.output[0].name.as_str()));
+        assert!(model
+          .metadata_props
+          .iter()
+          .any(|p| p.key == "component" && p.value == component));
+      }
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_import() -> Result<()> {
+    let config = ModelConfig {
+      preprocess: crate::preprocess::Profile::Symmetric.preprocess(),
+      ..Default::default()
+    };
+    let decoder = || -> Result<VarMap> {
+      let varmap = VarMap::new();
+      Decoder::from_config(&config, VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu))?;
+      Ok(varmap)
+    };
+    let mut varmap = decoder()?;
+    crate::weights::load(&mut varmap, Path::new("pretrained"), "decoder")?;
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    std::fs::write(dir.join("decoder.onnx"), export(&varmap, &config, "decoder")?)?;
+
+    assert_eq!(crate::weights::model_config(dir, "decoder")?, config);
+    let mut imported = decoder()?;
+    crate::weights::load(&mut imported, dir, "decoder")?;
+    let (vars, imported) = (varmap.data().lock().unwrap(), imported.data().lock().unwrap());
+    for (name, var) in vars.iter() {
+      let diff = (var.as_tensor() - imported[name].as_tensor())?.abs()?.max_all()?;
+      assert_eq!(diff.to_scalar::<f32>()?, 0.);
+    }
+    Ok(())
+  }
+}
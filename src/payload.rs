@@ -0,0 +1,1249 @@
+use std::collections::HashMap;
+
+use candle_core::{Device, Tensor};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::compression::Compression;
+use crate::ecc::{self, Ecc, EccScheme};
+use crate::error::{Result, SteganoError};
+use crate::fountain;
+use crate::stego_key::StegoKey;
+use crate::sync;
+use crate::utils::{self, Bits};
+
+// Header flags, one per optional field. Flags a version does not know fail the header, its layout changes with the
+// format version like that of the frame.
+const FLAG_RESIZED: u8 = 1;
+const FLAG_SIGNED: u8 = 2;
+const FLAG_CHUNKED: u8 = 4;
+const FLAG_FRAME: u8 = 8;
+const FLAG_MAC: u8 = 16;
+const FLAG_CHANNELS: u8 = 32;
+const KNOWN_FLAGS: u8 = FLAG_RESIZED | FLAG_SIGNED | FLAG_CHUNKED | FLAG_FRAME | FLAG_MAC | FLAG_CHANNELS;
+pub const SIGNATURE_LEN: usize = 64;
+// HMAC-SHA256 truncated to 128 bits, which keeps the header short and is still out of reach of a forger.
+pub const MAC_LEN: usize = 16;
+const DELIMITER_BITS: usize = 32;
+// Starts every packed payload: magic, format version, compression, Reed-Solomon data and parity bytes per block,
+// payload type, body length and a CRC32 of the frame and body. Only the magic and the version are guaranteed to stay
+// in place, so payloads of a newer format are recognized as such. 0xfe is a reserved deflate block type and never
+// starts the plain deflate streams written before the frame existed. The version covers the whole layout, frame and
+// header, and is bumped whenever either changes; version 1 headers repeated a magic and version of their own.
+const FRAME_MAGIC: [u8; 2] = [0xfe, b'S'];
+pub const FORMAT_VERSION: u8 = 2;
+const FRAME_LEN: usize = 15;
+// Copies that must decode to the same payload without a frame before it is taken for one written before frames
+// existed: any noise inflates to some text now and then, but hardly twice to the same.
+const LEGACY_VOTES: usize = 2;
+
+// What the message holds, so that decoders can render it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadType {
+  #[default]
+  Text,
+  /// Arbitrary bytes, like the chunks of a split payload
+  #[value(skip)]
+  Binary,
+  /// File name and contents, see `file_message`
+  File,
+  Json,
+  Url,
+}
+
+impl PayloadType {
+  fn id(self) -> u8 {
+    self as u8
+  }
+
+  fn from_id(id: u8) -> Option<Self> {
+    [Self::Text, Self::Binary, Self::File, Self::Json, Self::Url]
+      .into_iter()
+      .find(|kind| kind.id() == id)
+  }
+
+  fn is_text(self) -> bool {
+    !matches!(self, Self::Binary | Self::File)
+  }
+}
+
+// How the coded payload fills the payload bits, see `tile_with`. Decoding tells them apart on its own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Spread {
+  /// Copies of the payload one after another, whose logits decoding sums
+  #[default]
+  Repeat,
+  /// Fountain code packets that each mix a different set of pieces, any set of intact packets covering the payload
+  /// decodes
+  Fountain,
+  /// Copies within tiles of 128 pixels with pilot bits, which `decode --resync` realigns after a crop or shift
+  Tiles,
+  /// Tiles with one bit per pixel in all payload channels, interleaved over the tile
+  RobustTiles,
+}
+
+// Payload bits to encode in place of a message, for cover/stego pairs whose only difference is the encoder's own
+// footprint. Nothing decodes from them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NullPayload {
+  /// Every bit 0
+  Zeros,
+  /// Independent random bits, which look like a scrambled or compressed payload
+  Random,
+}
+
+impl Spread {
+  pub fn tiling(self) -> Option<sync::Tiling> {
+    match self {
+      Spread::Tiles => Some(sync::Tiling::Plain),
+      Spread::RobustTiles => Some(sync::Tiling::Robust),
+      Spread::Repeat | Spread::Fountain => None,
+    }
+  }
+}
+
+// Position of a payload split across several images, see `fit`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Chunk {
+  pub index: u16,
+  pub count: u16,
+  /// CRC32 of the whole reassembled data
+  pub checksum: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Header {
+  /// Size of the stego image the payload was embedded into
+  pub size: (u32, u32),
+  /// Size of the cover before it was rescaled on encode
+  pub source_size: Option<(u32, u32)>,
+  pub chunk: Option<Chunk>,
+  /// Index of the video frame the payload was embedded into
+  pub frame: Option<u32>,
+  /// Leading data channels the payload bits were laid out in, the rest left 0; all of the model's if not set
+  pub channels: Option<u8>,
+  /// Algorithm the header and message are compressed with, stored in the frame
+  pub compression: Compression,
+  /// Stored in the frame
+  pub payload_type: PayloadType,
+  /// Stored in the frame
+  pub ecc: Ecc,
+  /// Ed25519 signature over the rest of the header and the message
+  pub signature: Option<[u8; SIGNATURE_LEN]>,
+  /// HMAC over the rest of the header and the message keyed by the stego key, see `Payload::authenticate`
+  pub mac: Option<[u8; MAC_LEN]>,
+}
+
+impl Header {
+  fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = self.unsigned_bytes();
+    if let Some(signature) = &self.signature {
+      bytes.extend(signature);
+    }
+    if let Some(mac) = &self.mac {
+      bytes.extend(mac);
+    }
+    bytes
+  }
+
+  // Header without the signature and MAC themselves, which starts the signed data.
+  fn unsigned_bytes(&self) -> Vec<u8> {
+    let mut flags = 0;
+    if self.source_size.is_some() {
+      flags |= FLAG_RESIZED;
+    }
+    if self.signature.is_some() {
+      flags |= FLAG_SIGNED;
+    }
+    if self.chunk.is_some() {
+      flags |= FLAG_CHUNKED;
+    }
+    if self.frame.is_some() {
+      flags |= FLAG_FRAME;
+    }
+    if self.mac.is_some() {
+      flags |= FLAG_MAC;
+    }
+    if self.channels.is_some() {
+      flags |= FLAG_CHANNELS;
+    }
+    let mut bytes = vec![flags];
+    let sizes = [Some(self.size), self.source_size];
+    for (w, h) in sizes.into_iter().flatten() {
+      bytes.extend(w.to_le_bytes());
+      bytes.extend(h.to_le_bytes());
+    }
+    if let Some(chunk) = &self.chunk {
+      bytes.extend(chunk.index.to_le_bytes());
+      bytes.extend(chunk.count.to_le_bytes());
+      bytes.extend(chunk.checksum.to_le_bytes());
+    }
+    if let Some(frame) = self.frame {
+      bytes.extend(frame.to_le_bytes());
+    }
+    if let Some(channels) = self.channels {
+      bytes.push(channels);
+    }
+    bytes
+  }
+
+  fn signed_bytes(&self, message: &[u8]) -> Vec<u8> {
+    let mut bytes = self.unsigned_bytes();
+    bytes.extend(message);
+    bytes
+  }
+
+  fn from_bytes(bytes: &[u8]) -> Option<(Self, &[u8])> {
+    let (&flags, mut rest) = bytes.split_first()?;
+    if flags & !KNOWN_FLAGS != 0 {
+      return None;
+    }
+    let mut read_size = || {
+      if rest.len() < 8 {
+        return None;
+      }
+      let w = u32::from_le_bytes(rest[0..4].try_into().ok()?);
+      let h = u32::from_le_bytes(rest[4..8].try_into().ok()?);
+      rest = &rest[8..];
+      Some((w, h))
+    };
+    let size = read_size()?;
+    let source_size = if flags & FLAG_RESIZED != 0 {
+      Some(read_size()?)
+    } else {
+      None
+    };
+    let chunk = if flags & FLAG_CHUNKED != 0 {
+      let bytes = rest.get(..8)?;
+      rest = &rest[8..];
+      Some(Chunk {
+        index: u16::from_le_bytes(bytes[0..2].try_into().ok()?),
+        count: u16::from_le_bytes(bytes[2..4].try_into().ok()?),
+        checksum: u32::from_le_bytes(bytes[4..8].try_into().ok()?),
+      })
+    } else {
+      None
+    };
+    let frame = if flags & FLAG_FRAME != 0 {
+      let bytes = rest.get(..4)?;
+      rest = &rest[4..];
+      Some(u32::from_le_bytes(bytes.try_into().ok()?))
+    } else {
+      None
+    };
+    let channels = if flags & FLAG_CHANNELS != 0 {
+      let channels = *rest.first()?;
+      rest = &rest[1..];
+      Some(channels)
+    } else {
+      None
+    };
+    let signature = if flags & FLAG_SIGNED != 0 {
+      let signature = rest.get(..SIGNATURE_LEN)?.try_into().ok()?;
+      rest = &rest[SIGNATURE_LEN..];
+      Some(signature)
+    } else {
+      None
+    };
+    let mac = if flags & FLAG_MAC != 0 {
+      let mac = rest.get(..MAC_LEN)?.try_into().ok()?;
+      rest = &rest[MAC_LEN..];
+      Some(mac)
+    } else {
+      None
+    };
+    Some((
+      Self {
+        size,
+        source_size,
+        chunk,
+        frame,
+        channels,
+        signature,
+        mac,
+        ..Default::default()
+      },
+      rest,
+    ))
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Payload {
+  /// Missing for payloads written before headers were introduced
+  pub header: Option<Header>,
+  pub message: String,
+  /// Raw message bytes, the only meaningful form for the binary chunks of a split payload
+  pub data: Vec<u8>,
+}
+
+impl Payload {
+  // Name and contents of a `PayloadType::File` message.
+  pub fn file(&self) -> Option<(&str, &[u8])> {
+    if self.header.as_ref()?.payload_type != PayloadType::File {
+      return None;
+    }
+    let split = self.data.iter().position(|&byte| byte == 0)?;
+    let name = std::str::from_utf8(&self.data[..split]).ok()?;
+    Some((name, &self.data[split + 1..]))
+  }
+
+  // `None` for an unsigned payload, otherwise whether it was signed with the key's secret counterpart.
+  pub fn verify(&self, key: &VerifyingKey) -> Option<bool> {
+    let header = self.header.as_ref()?;
+    let signature = Signature::from_bytes(header.signature.as_ref()?);
+    Some(key.verify(&header.signed_bytes(&self.data), &signature).is_ok())
+  }
+
+  // `None` for a payload without a MAC, otherwise whether it was written with the stego key. Channel noise makes a
+  // payload fail to decode rather than change it, so a mismatch means it was replaced or edited.
+  pub fn authenticate(&self, key: &StegoKey) -> Option<bool> {
+    let header = self.header.as_ref()?;
+    Some(header.mac? == key.mac(&header.signed_bytes(&self.data)))
+  }
+}
+
+// Message of a `PayloadType::File`: the file name, a NUL byte and the contents.
+pub fn file_message(name: &str, contents: &[u8]) -> Vec<u8> {
+  let mut message = name.replace('\0', "").into_bytes();
+  message.push(0);
+  message.extend(contents);
+  message
+}
+
+pub fn pack(header: &Header, message: &[u8]) -> Vec<u8> {
+  pack_with(header, message, None, None, None)
+}
+
+pub fn pack_signed(header: &Header, message: &[u8], key: &SigningKey) -> Vec<u8> {
+  pack_with(header, message, None, Some(key), None)
+}
+
+// Compresses the header and message with `header.compression` at `level`, or stores them uncompressed if that does
+// not make them smaller, e.g. for data that is already compressed. Signs them with `key` and adds a MAC keyed by
+// `stego_key`, if given.
+pub fn pack_with(
+  header: &Header,
+  message: &[u8],
+  level: Option<u32>,
+  key: Option<&SigningKey>,
+  stego_key: Option<&StegoKey>,
+) -> Vec<u8> {
+  let mut header = header.clone();
+  // Both flags are part of the signed bytes, so they are set before either is computed
+  if key.is_some() {
+    header.signature = Some([0; SIGNATURE_LEN]);
+  }
+  if stego_key.is_some() {
+    header.mac = Some([0; MAC_LEN]);
+  }
+  let signed = header.signed_bytes(message);
+  if let Some(key) = key {
+    header.signature = Some(key.sign(&signed).to_bytes());
+  }
+  if let Some(stego_key) = stego_key {
+    header.mac = Some(stego_key.mac(&signed));
+  }
+  let mut data = header.to_bytes();
+  data.extend(message);
+  // Data the algorithm fails on or does not shrink is stored as it is
+  match header.compression.compress(&data, level) {
+    Ok(compressed) if compressed.len() < data.len() => {
+      frame(header.compression, header.payload_type, header.ecc, &compressed)
+    }
+    _ => frame(Compression::None, header.payload_type, header.ecc, &data),
+  }
+}
+
+// Most bytes a compressed body read from an image may expand to: 1032 times its length, the most deflate reaches, and
+// at least 1 MB. A bomb hidden in an image so expands to a thousand times what the image holds at most.
+fn decompress_limit(body: &[u8]) -> usize {
+  body.len().saturating_mul(1032).max(1 << 20)
+}
+
+fn frame(compression: Compression, payload_type: PayloadType, ecc: Ecc, body: &[u8]) -> Vec<u8> {
+  let (data, parity) = ecc.scheme().block();
+  let mut packed = FRAME_MAGIC.to_vec();
+  packed.extend([
+    FORMAT_VERSION,
+    compression.id(),
+    data as u8,
+    parity as u8,
+    payload_type.id(),
+  ]);
+  packed.extend((body.len() as u32).to_le_bytes());
+  let mut crc = crc32fast::Hasher::new();
+  crc.update(&packed);
+  crc.update(body);
+  packed.extend(crc.finalize().to_le_bytes());
+  packed.extend(body);
+  packed
+}
+
+// Frame as stored, only its length checked so that damaged payloads can still be inspected.
+struct Frame<'a> {
+  fields: &'a [u8],
+  /// Up to the stored length, shorter if the packed data is
+  body: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+  fn read(packed: &'a [u8]) -> Option<Self> {
+    let fields = packed
+      .get(..FRAME_LEN)
+      .filter(|fields| fields.starts_with(&FRAME_MAGIC))?;
+    let end = FRAME_LEN.saturating_add(length(fields)).min(packed.len());
+    Some(Self {
+      fields,
+      body: &packed[FRAME_LEN..end],
+    })
+  }
+
+  fn compression(&self) -> Option<Compression> {
+    Compression::from_id(self.fields[3])
+  }
+
+  fn payload_type(&self) -> Option<PayloadType> {
+    PayloadType::from_id(self.fields[6])
+  }
+
+  fn ecc(&self) -> Option<Ecc> {
+    Ecc::from_block((self.fields[4] as usize, self.fields[5] as usize))
+  }
+
+  // Whether the body is complete and matches the CRC.
+  fn crc_valid(&self) -> bool {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&self.fields[..11]);
+    crc.update(self.body);
+    self.body.len() == length(self.fields) && crc.finalize().to_le_bytes() == self.fields[11..15]
+  }
+
+  fn supported(&self) -> bool {
+    self.fields[2] == FORMAT_VERSION && self.ecc().is_some()
+  }
+}
+
+fn length(fields: &[u8]) -> usize {
+  u32::from_le_bytes([fields[7], fields[8], fields[9], fields[10]]) as usize
+}
+
+// What the frame records and the compressed body behind it.
+type Unframed<'a> = (Compression, PayloadType, Ecc, &'a [u8]);
+
+// Reads the frame in front of the compressed body, `None` for payloads written before it existed.
+fn unframe(packed: &[u8]) -> Result<Option<Unframed<'_>>> {
+  let Some(rest) = packed.strip_prefix(&FRAME_MAGIC[..]) else {
+    return Ok(None);
+  };
+  if let Some(&version) = rest.first().filter(|&&version| version > FORMAT_VERSION) {
+    return Err(SteganoError::NewerVersion {
+      version,
+      supported: FORMAT_VERSION,
+    });
+  }
+  let frame = Frame::read(packed)
+    .filter(|frame| frame.supported() && frame.crc_valid())
+    .ok_or(SteganoError::DecodeFailed)?;
+  match (frame.compression(), frame.payload_type(), frame.ecc()) {
+    (Some(compression), Some(payload_type), Some(ecc)) => Ok(Some((compression, payload_type, ecc, frame.body))),
+    _ => Err(SteganoError::DecodeFailed),
+  }
+}
+
+// Largest prefix of `data` that fits into `capacity` payload bits behind `header`, which must already have its
+// final size (chunk, signature and MAC present if they will be written), when packed at compression `level`.
+pub fn fit(header: &Header, data: &[u8], capacity: usize, level: Option<u32>) -> usize {
+  let fits = |len: usize| encoded_len(&pack_with(header, &data[..len], level, None, None)) <= capacity;
+  let (mut lo, mut hi) = (0, data.len());
+  while lo < hi {
+    let mid = (lo + hi).div_ceil(2);
+    if fits(mid) {
+      lo = mid;
+    } else {
+      hi = mid - 1;
+    }
+  }
+  lo
+}
+
+// Reads packed data, which needs a frame unless `legacy` also takes it for a payload written before frames existed.
+fn unpack_with(packed: &[u8], legacy: bool) -> Result<Payload> {
+  let ((compression, payload_type, ecc, compressed), framed) = match unframe(packed)? {
+    Some(unframed) => (unframed, true),
+    None if legacy => ((Compression::Deflate, PayloadType::Text, Ecc::Standard, packed), false),
+    None => return Err(SteganoError::DecodeFailed),
+  };
+  let data = compression
+    .decompress(compressed, decompress_limit(compressed))
+    .ok_or(SteganoError::DecodeFailed)?;
+  // A frame is always followed by a header, payloads written before it existed are the plain message
+  let (header, message) = match framed {
+    true => {
+      let (header, message) = Header::from_bytes(&data).ok_or(SteganoError::DecodeFailed)?;
+      let header = Header {
+        compression,
+        payload_type,
+        ecc,
+        ..header
+      };
+      (Some(header), message)
+    }
+    false => (None, &data[..]),
+  };
+  let text = match payload_type.is_text() {
+    true => String::from_utf8(message.to_vec())
+      .map_err(|_| SteganoError::DecodeFailed)?
+      .replace('\0', ""),
+    false => String::from_utf8_lossy(message).into_owned(),
+  };
+  if text.is_empty() {
+    return Err(SteganoError::DecodeFailed);
+  }
+  Ok(Payload {
+    header,
+    message: text,
+    data: message.to_vec(),
+  })
+}
+
+// Code of packed data, the one its frame records.
+fn ecc_of(packed: &[u8]) -> Ecc {
+  Frame::read(packed).and_then(|frame| frame.ecc()).unwrap_or_default()
+}
+
+// Error-corrected data followed by a 32 bit zero delimiter, one period of the tiled payload.
+fn encode(data: &[u8], data_size: usize) -> Result<Bits> {
+  let mut bits = Bits::from_vec(ecc::encode(ecc_of(data).scheme(), data));
+  bits.resize(bits.len() + DELIMITER_BITS, false);
+  if bits.len() > data_size {
+    return Err(SteganoError::CapacityExceeded {
+      needed: bits.len(),
+      available: data_size,
+    });
+  }
+  Ok(bits)
+}
+
+// Number of payload bits one copy of the packed data takes.
+pub fn encoded_len(data: &[u8]) -> usize {
+  let (size, parity) = ecc_of(data).scheme().block();
+  8 * (data.len() + data.len().div_ceil(size) * parity) + DELIMITER_BITS
+}
+
+// Repeats the encoded data over all `data_depth x height x width` payload bits.
+pub fn tile(data: &[u8], data_depth: usize, height: usize, width: usize) -> Result<Vec<u8>> {
+  tile_with(data, Spread::Repeat, data_depth, height, width)
+}
+
+pub fn tile_with(data: &[u8], spread: Spread, data_depth: usize, height: usize, width: usize) -> Result<Vec<u8>> {
+  let data_size = data_depth * height * width;
+  let bits = match spread {
+    Spread::Repeat => encode(data, data_size)?,
+    Spread::Fountain => return fountain::encode(data, (data_depth, height, width)),
+    Spread::Tiles | Spread::RobustTiles => {
+      let tiling = spread.tiling().unwrap_or(sync::Tiling::Plain);
+      let capacity = sync::capacity(data_depth, tiling);
+      let stream: Vec<u8> = utils::cycle_bits(&encode(data, capacity)?).take(capacity).collect();
+      return Ok(sync::layout(&stream, (data_depth, height, width), tiling));
+    }
+  };
+  Ok(utils::cycle_bits(&bits).take(data_size).collect())
+}
+
+// Same as `tile`, but uploads a single copy of the encoded data and repeats it on the device, returning the
+// `(1, data_depth, height, width)` payload tensor.
+pub fn tile_tensor(data: &[u8], data_depth: usize, height: usize, width: usize, device: &Device) -> Result<Tensor> {
+  let data_size = data_depth * height * width;
+  let bits = encode(data, data_size)?;
+  let period: Vec<f32> = bits.iter().map(|bit| *bit as u8 as f32).collect();
+  let copies = data_size.div_ceil(period.len());
+  let tiled = Tensor::from_vec(period, bits.len(), device)?
+    .repeat(copies)?
+    .narrow(0, 0, data_size)?
+    .reshape((1, data_depth, height, width))?;
+  Ok(tiled)
+}
+
+// Decodes hard 0/1 bits, see `extract_soft`.
+pub fn extract(bits: &[u8]) -> Result<Payload> {
+  let logits: Vec<f32> = bits.par_iter().map(|&bit| if bit == 1 { 1. } else { -1. }).collect();
+  extract_soft(&logits)
+}
+
+// Decodes the payload from the decoder's logits, positive for a 1 bit. The logits of all copies are summed per payload
+// position before thresholding, which recovers payloads where every single copy is too corrupted for the error
+// correction. Every code of `Ecc` is tried in turn, and payloads without a frame, which were all written with the
+// standard code, only after them: the deflate stream they fall back to reads text out of far too much noise to come
+// before a code that may still find a frame. For the same reason the sum only counts with a frame whose CRC matches.
+pub fn extract_soft(logits: &[f32]) -> Result<Payload> {
+  match extract_framed(logits) {
+    Err(SteganoError::DecodeFailed) => extract_with(logits, &*ecc::STANDARD, true),
+    result => result,
+  }
+}
+
+fn extract_framed(logits: &[f32]) -> Result<Payload> {
+  for ecc in Ecc::ALL {
+    match extract_with(logits, ecc.scheme(), false) {
+      Err(SteganoError::DecodeFailed) => {}
+      result => return result,
+    }
+  }
+  Err(SteganoError::DecodeFailed)
+}
+
+fn extract_with(logits: &[f32], scheme: &dyn EccScheme, legacy: bool) -> Result<Payload> {
+  let bits: Vec<u8> = logits.par_iter().map(|&logit| (logit > 0.) as u8).collect();
+  let data = utils::bits_to_bytes(&bits);
+  let delimiters = utils::find_delimiters(&data, &[0; 4]);
+  if let Some((aggregated, _)) = aggregate(logits, &delimiters) {
+    match unpack_with(&ecc::correct(scheme, &aggregated).0, false) {
+      Ok(payload) => return Ok(payload),
+      Err(err @ SteganoError::NewerVersion { .. }) => return Err(err),
+      Err(_) => {}
+    }
+  }
+
+  // Otherwise decode every copy on its own and take the most common result, e.g. when corrupted delimiters hide the
+  // period. Copies that are intact but of a newer format only matter if none can be read.
+  let mut parts = utils::split_bytes(data.as_slice(), &[0; 4]);
+  // What precedes the first delimiter is a whole copy when the copies start with the data, or all of them when no
+  // delimiter survived, as may happen to the two copies of a robust tile. Only a frame's CRC tells it from noise.
+  let leading = &data[..delimiters.first().map_or(data.len(), |&index| index)];
+  if !legacy && !leading.is_empty() {
+    parts.push(leading);
+  }
+  let (mut results, newer) = parts
+    .par_iter()
+    .map(|part| ecc::correct(scheme, part).0)
+    .fold(
+      || (HashMap::new(), None),
+      |(mut results, newer), result| match unpack_with(&result, legacy) {
+        Ok(_) => {
+          map_inc(&mut results, result);
+          (results, newer)
+        }
+        Err(SteganoError::NewerVersion { version, .. }) => (results, newer.max(Some(version))),
+        Err(_) => (results, newer),
+      },
+    )
+    .reduce(
+      || (HashMap::new(), None),
+      |(mut a, newer_a), (b, newer_b)| {
+        for (k, v) in b {
+          *a.entry(k).or_default() += v;
+        }
+        (a, newer_a.max(newer_b))
+      },
+    );
+  results.retain(|packed, votes| *votes >= LEGACY_VOTES || packed.starts_with(&FRAME_MAGIC));
+  match (most_common(results), newer) {
+    (Some(best), _) => unpack_with(&best, legacy),
+    (None, Some(version)) => Err(SteganoError::NewerVersion {
+      version,
+      supported: FORMAT_VERSION,
+    }),
+    (None, None) => Err(SteganoError::DecodeFailed),
+  }
+}
+
+// Decodes logits laid out as `shape`, `(data_depth, height, width)`, whatever the spread: repeated copies first, then
+// fountain code packets, which may also be read from a cropped image, then payloads written before frames existed.
+pub fn extract_spread(logits: &[f32], shape: (usize, usize, usize)) -> Result<Payload> {
+  match extract_framed(logits) {
+    Err(SteganoError::DecodeFailed) => {
+      match fountain::decode(logits, shape).map(|(packed, _)| unpack_with(&packed, false)) {
+        Some(Ok(payload)) => Ok(payload),
+        Some(Err(err @ SteganoError::NewerVersion { .. })) => Err(err),
+        _ => extract_with(logits, &*ecc::STANDARD, true),
+      }
+    }
+    result => result,
+  }
+}
+
+// Code of the payload in the logits: the first whose sum over all copies reads as an intact frame, the standard one
+// if none does.
+fn detect_ecc(logits: &[f32], data: &[u8]) -> Ecc {
+  let Some((aggregated, _)) = aggregate(logits, &utils::find_delimiters(data, &[0; 4])) else {
+    return Ecc::Standard;
+  };
+  Ecc::ALL
+    .into_iter()
+    .find(|ecc| Frame::read(&ecc::correct(ecc.scheme(), &aggregated).0).is_some_and(|frame| frame.crc_valid()))
+    .unwrap_or_default()
+}
+
+// Share of the blocks of the copies between delimiters coded with `ecc` that decode, how well a payload survived.
+pub fn block_success(logits: &[f32], ecc: Ecc) -> f32 {
+  let bits: Vec<u8> = logits.par_iter().map(|&logit| (logit > 0.) as u8).collect();
+  let data = utils::bits_to_bytes(&bits);
+  let (size, parity) = ecc.scheme().block();
+  let (decoded, blocks) = utils::split_bytes(&data, &[0; 4])
+    .par_iter()
+    .map(|part| {
+      let blocks = part.len().div_ceil(size + parity);
+      (blocks - ecc::correct(ecc.scheme(), part).1.failed_blocks, blocks)
+    })
+    .reduce(|| (0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+  decoded as f32 / blocks.max(1) as f32
+}
+
+// How damaged the payload bits were on their way through the image, from the Reed-Solomon corrections of the copies
+// between delimiters decoded on their own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorEstimate {
+  /// Share of bits read wrong, a lower bound when blocks failed
+  pub bit_error_rate: f32,
+  /// Most bytes corrected in one block
+  pub worst_block: usize,
+  /// Byte errors the code corrects in a block
+  pub correctable: usize,
+  /// Blocks beyond correction, including those of copies that fail the CRC after correction
+  pub failed_blocks: usize,
+  pub blocks: usize,
+}
+
+impl ErrorEstimate {
+  // Whether a little more damage would have lost copies: a block failed or needed more than 3/4 of the correction.
+  pub fn marginal(&self) -> bool {
+    self.failed_blocks > 0 || 4 * self.worst_block > 3 * self.correctable
+  }
+}
+
+// Estimates the channel bit error rate of repeated copies. Failed blocks count with one more byte error than the code
+// corrects, and byte errors are taken to come from independent bit errors. `None` without copies between delimiters,
+// as for fountain packets and tiles.
+pub fn estimate_errors(logits: &[f32]) -> Option<ErrorEstimate> {
+  let bits: Vec<u8> = logits.par_iter().map(|&logit| (logit > 0.) as u8).collect();
+  let data = utils::bits_to_bytes(&bits);
+  let scheme = detect_ecc(logits, &data).scheme();
+  // The first part is what follows the last delimiter, usually a copy cut off by the end of the image
+  let corrections: Vec<ecc::Correction> = utils::split_bytes(&data, &[0; 4])
+    .par_iter()
+    .skip(1)
+    .filter(|part| !part.is_empty())
+    .map(|part| {
+      let (packed, correction) = ecc::correct(scheme, part);
+      // A correction that lands on another codeword shows in the CRC
+      match Frame::read(&packed).is_some_and(|frame| !frame.crc_valid()) {
+        true => ecc::Correction {
+          corrected: 0,
+          failed_blocks: correction.blocks,
+          ..correction
+        },
+        false => correction,
+      }
+    })
+    .collect();
+  let blocks: usize = corrections.iter().map(|correction| correction.blocks).sum();
+  if blocks == 0 {
+    return None;
+  }
+  let failed_blocks = corrections.iter().map(|correction| correction.failed_blocks).sum();
+  let byte_errors = corrections
+    .iter()
+    .map(|correction| correction.corrected + correction.failed_blocks * (scheme.max_errors() + 1))
+    .sum::<usize>();
+  let (size, parity) = scheme.block();
+  let byte_error_rate = (byte_errors as f64 / (blocks * (size + parity)) as f64).min(1.);
+  Some(ErrorEstimate {
+    bit_error_rate: (1. - (1. - byte_error_rate).powf(1. / 8.)) as f32,
+    worst_block: corrections
+      .iter()
+      .map(|correction| correction.worst_block)
+      .max()
+      .unwrap_or(0),
+    correctable: scheme.max_errors(),
+    failed_blocks,
+    blocks,
+  })
+}
+
+// Sums the logits of all copies per payload position and returns the thresholded bytes of one copy without its
+// delimiter, along with the number of copies. The period is the most common distance between delimiters, and the
+// copies start where most of them end.
+fn aggregate(logits: &[f32], delimiters: &[usize]) -> Option<(Vec<u8>, usize)> {
+  let delimiter_len = DELIMITER_BITS / 8;
+  let mut periods = HashMap::new();
+  for pair in delimiters.windows(2) {
+    // Longer runs of zeros match several overlapping windows
+    if pair[1] - pair[0] > delimiter_len {
+      map_inc(&mut periods, pair[1] - pair[0]);
+    }
+  }
+  let (period, _) = periods.into_iter().max_by_key(|&(period, count)| (count, period))?;
+  let mut offsets = HashMap::new();
+  for index in delimiters {
+    map_inc(&mut offsets, (index + delimiter_len) % period);
+  }
+  let (offset, _) = offsets.into_iter().max_by_key(|&(offset, count)| (count, offset))?;
+
+  let (period, offset) = (period * 8, offset * 8);
+  let mut sums = vec![0.; period - DELIMITER_BITS];
+  for (i, logit) in logits.iter().enumerate() {
+    if let Some(sum) = sums.get_mut((i + period - offset) % period) {
+      *sum += logit;
+    }
+  }
+  let bits: Vec<u8> = sums.iter().map(|&sum| (sum > 0.) as u8).collect();
+  Some((utils::bits_to_bytes(&bits), logits.len().div_ceil(period)))
+}
+
+// One distinct decoding result with diagnostics, for salvaging damaged payloads by hand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+  /// Sum over all copies like in `extract_soft`, rather than copies decoded on their own
+  pub aggregated: bool,
+  /// Copies that decoded to this candidate
+  pub votes: usize,
+  /// Error correction of the least damaged of these copies
+  pub correction: ecc::Correction,
+  /// CRC check of the frame, `None` for payloads without one
+  pub crc: Option<bool>,
+  /// Whether the candidate decodes without errors, and has a frame or the votes a payload without one needs
+  pub valid: bool,
+  /// Message as far as it could be recovered, with invalid UTF-8 replaced
+  pub message: String,
+}
+
+// Every candidate `extract_spread` considers: the sum over all copies first, then the results of single copies by
+// votes, then the fountain code packets.
+pub fn candidates(logits: &[f32], shape: (usize, usize, usize)) -> Vec<Candidate> {
+  let bits: Vec<u8> = logits.par_iter().map(|&logit| (logit > 0.) as u8).collect();
+  let data = utils::bits_to_bytes(&bits);
+  let delimiters = utils::find_delimiters(&data, &[0; 4]);
+  let scheme = detect_ecc(logits, &data).scheme();
+  let mut candidates = Vec::new();
+  if let Some((aggregated, copies)) = aggregate(logits, &delimiters) {
+    let (packed, correction) = ecc::correct(scheme, &aggregated);
+    candidates.push(candidate(&packed, true, copies, correction));
+  }
+
+  let mut copies: HashMap<Vec<u8>, (usize, ecc::Correction)> = HashMap::new();
+  let parts = utils::split_bytes(data.as_slice(), &[0; 4]);
+  for (packed, correction) in parts
+    .par_iter()
+    .filter(|part| !part.is_empty())
+    .map(|part| ecc::correct(scheme, part))
+    .collect::<Vec<_>>()
+  {
+    let (votes, best) = copies.entry(packed).or_insert((0, correction));
+    *votes += 1;
+    if (correction.failed_blocks, correction.corrected) < (best.failed_blocks, best.corrected) {
+      *best = correction;
+    }
+  }
+  let mut copies: Vec<_> = copies.into_iter().collect();
+  copies.sort_by(|(a, (votes_a, _)), (b, (votes_b, _))| votes_b.cmp(votes_a).then_with(|| a.cmp(b)));
+  candidates.extend(
+    copies
+      .into_iter()
+      .map(|(packed, (votes, correction))| candidate(&packed, false, votes, correction)),
+  );
+  // Packets of the fountain code only decode together, they count as votes
+  if let Some((packed, packets)) = fountain::decode(logits, shape) {
+    candidates.push(candidate(&packed, true, packets, Default::default()));
+  }
+  candidates
+}
+
+fn candidate(packed: &[u8], aggregated: bool, votes: usize, correction: ecc::Correction) -> Candidate {
+  let frame = Frame::read(packed);
+  let data = match &frame {
+    Some(frame) => {
+      let compression = frame.compression().unwrap_or(Compression::None);
+      compression
+        .decompress(frame.body, decompress_limit(frame.body))
+        .unwrap_or_else(|| frame.body.to_vec())
+    }
+    None => Compression::Deflate
+      .decompress(packed, decompress_limit(packed))
+      .unwrap_or_default(),
+  };
+  let message = match frame.as_ref().and_then(|_| Header::from_bytes(&data)) {
+    Some((_, message)) => message,
+    None => &data[..],
+  };
+  Candidate {
+    aggregated,
+    votes,
+    correction,
+    crc: frame.map(|frame| frame.crc_valid()),
+    valid: unpack_with(packed, !aggregated && votes >= LEGACY_VOTES).is_ok(),
+    message: String::from_utf8_lossy(message).into_owned(),
+  }
+}
+
+fn map_inc<K: Eq + std::hash::Hash>(map: &mut HashMap<K, usize>, k: K) {
+  *map.entry(k).or_default() += 1;
+}
+
+// Key with the most votes, the lowest of those tied so that the result does not depend on the hash map's order.
+fn most_common<K: Ord>(votes: HashMap<K, usize>) -> Option<K> {
+  votes
+    .into_iter()
+    .max_by(|(a, votes_a), (b, votes_b)| votes_a.cmp(votes_b).then_with(|| b.cmp(a)))
+    .map(|(k, _)| k)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::Rng;
+
+  fn unpack(packed: &[u8]) -> Result<Payload> {
+    unpack_with(packed, true)
+  }
+
+  #[test]
+  fn test_pack_unpack() {
+    let header = Header {
+      size: (640, 480),
+      source_size: Some((1920, 1440)),
+      chunk: None,
+      frame: None,
+      channels: None,
+      compression: Compression::None,
+      payload_type: PayloadType::Text,
+      ecc: Ecc::Standard,
+      signature: None,
+      mac: None,
+    };
+    let payload = unpack(&pack(&header, b"hello")).unwrap();
+    assert_eq!(payload.header, Some(header));
+    assert_eq!(payload.message, "hello");
+
+    let header = Header {
+      size: (3, 2),
+      source_size: None,
+      chunk: Some(Chunk {
+        index: 1,
+        count: 3,
+        checksum: 0xdeadbeef,
+      }),
+      frame: Some(70000),
+      channels: Some(5),
+      compression: Compression::None,
+      payload_type: PayloadType::Binary,
+      ecc: Ecc::Standard,
+      signature: None,
+      mac: None,
+    };
+    let payload = unpack(&pack(&header, &[0, 0xff, 1])).unwrap();
+    assert_eq!(payload.header, Some(header));
+    assert_eq!(payload.data, [0, 0xff, 1]);
+
+    // A flag this version does not know changes the layout of the rest
+    let mut bytes = Header::default().to_bytes();
+    assert!(Header::from_bytes(&bytes).is_some());
+    bytes[0] |= 0x80;
+    assert!(Header::from_bytes(&bytes).is_none());
+  }
+
+  #[test]
+  fn test_signed_payload() {
+    let mut rng = crate::rng::from_seed(Some(0));
+    let key = crate::signing::generate_key(&mut rng);
+    let header = Header {
+      size: (640, 480),
+      ..Default::default()
+    };
+    let data = pack_signed(&header, b"hello", &key);
+    let payload = unpack(&data).unwrap();
+    assert_eq!(payload.message, "hello");
+    assert_eq!(payload.verify(&key.verifying_key()), Some(true));
+    assert_eq!(
+      payload.verify(&crate::signing::generate_key(&mut rng).verifying_key()),
+      Some(false)
+    );
+
+    // Repacked with the original signature, so only the signature check catches it
+    let forged = pack(payload.header.as_ref().unwrap(), b"hellO");
+    assert_eq!(unpack(&forged).unwrap().verify(&key.verifying_key()), Some(false));
+    assert_eq!(
+      unpack(&pack(&header, b"hello")).unwrap().verify(&key.verifying_key()),
+      None
+    );
+  }
+
+  #[test]
+  fn test_mac_payload() {
+    let mut rng = crate::rng::from_seed(Some(0));
+    let sign_key = crate::signing::generate_key(&mut rng);
+    let key = StegoKey::new("correct horse");
+    let header = Header {
+      size: (640, 480),
+      ..Default::default()
+    };
+    let payload = unpack(&pack_with(&header, b"hello", None, Some(&sign_key), Some(&key))).unwrap();
+    assert_eq!(payload.message, "hello");
+    assert_eq!(payload.authenticate(&key), Some(true));
+    assert_eq!(payload.verify(&sign_key.verifying_key()), Some(true));
+    assert_eq!(payload.authenticate(&StegoKey::new("battery staple")), Some(false));
+
+    // Repacked with the original MAC, or with a different size in the header
+    let forged = pack(payload.header.as_ref().unwrap(), b"hellO");
+    assert_eq!(unpack(&forged).unwrap().authenticate(&key), Some(false));
+    let resized = Header {
+      size: (320, 240),
+      ..payload.header.clone().unwrap()
+    };
+    assert_eq!(
+      unpack(&pack(&resized, b"hello")).unwrap().authenticate(&key),
+      Some(false)
+    );
+    assert_eq!(unpack(&pack(&header, b"hello")).unwrap().authenticate(&key), None);
+  }
+
+  #[test]
+  fn test_legacy_payload() {
+    let deflate = |data: &[u8]| miniz_oxide::deflate::compress_to_vec(data, 6);
+    let payload = unpack(&deflate(b"plain\0")).unwrap();
+    assert_eq!(payload.header, None);
+    assert_eq!(payload.message, "plain");
+    assert!(unpack(&deflate(b"\0\0")).is_err());
+  }
+
+  #[test]
+  fn test_file_payload() {
+    let header = Header {
+      payload_type: PayloadType::File,
+      ..Default::default()
+    };
+    let payload = unpack(&pack(&header, &file_message("notes.bin", &[0, 1, 2]))).unwrap();
+    assert_eq!(payload.file(), Some(("notes.bin", &[0, 1, 2][..])));
+    assert_eq!(unpack(&pack(&Header::default(), b"notes")).unwrap().file(), None);
+  }
+
+  #[test]
+  fn test_frame() {
+    let packed = pack(&Header::default(), b"hello");
+    assert_eq!(packed[..3], [0xfe, b'S', FORMAT_VERSION]);
+
+    let mut corrupted = packed.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    assert!(matches!(unpack(&corrupted), Err(SteganoError::DecodeFailed)));
+    assert!(matches!(
+      unpack(&packed[..packed.len() - 1]),
+      Err(SteganoError::DecodeFailed)
+    ));
+
+    let mut newer = packed.clone();
+    newer[2] = FORMAT_VERSION + 1;
+    assert!(matches!(
+      unpack(&newer),
+      Err(SteganoError::NewerVersion { version, .. }) if version == FORMAT_VERSION + 1
+    ));
+    let mut older = packed.clone();
+    older[2] = FORMAT_VERSION - 1;
+    assert!(matches!(unpack(&older), Err(SteganoError::DecodeFailed)));
+    let bits = tile(&newer, 1, 64, 64).unwrap();
+    let err = extract(&bits).unwrap_err();
+    assert!(err.to_string().contains("newer version"), "{err}");
+  }
+
+  #[test]
+  fn test_compression() {
+    let message = "a fairly long secret message ".repeat(8);
+    for compression in [Compression::Deflate, Compression::Zstd, Compression::Brotli] {
+      let header = Header {
+        compression,
+        ..Default::default()
+      };
+      let packed = pack(&header, message.as_bytes());
+      assert!(packed.len() < message.len());
+      let payload = unpack(&packed).unwrap();
+      assert_eq!(payload.header.unwrap().compression, compression);
+      assert_eq!(payload.message, message);
+    }
+
+    // Data that does not compress is stored as is
+    let mut rng = crate::rng::from_seed(Some(0));
+    let noise: Vec<u8> = (0..256).map(|_| rng.gen()).collect();
+    let header = Header {
+      payload_type: PayloadType::Binary,
+      ..Default::default()
+    };
+    let payload = unpack(&pack(&header, &noise)).unwrap();
+    assert_eq!(payload.header.unwrap().compression, Compression::None);
+    assert_eq!(payload.data, noise);
+  }
+
+  #[test]
+  fn test_tile_extract() {
+    let data = pack(&Header::default(), b"hello");
+    let bits = tile(&data, 1, 64, 64).unwrap();
+    assert_eq!(extract(&bits).unwrap().message, "hello");
+    assert!(matches!(extract(&[0; 4096]), Err(SteganoError::DecodeFailed)));
+    assert!(matches!(
+      tile(&data, 1, 8, 8),
+      Err(SteganoError::CapacityExceeded { available: 64, .. })
+    ));
+  }
+
+  #[test]
+  fn test_spread() {
+    let data = pack(&Header::default(), b"fountain");
+    let shape = (2, 80, 100);
+    let bits = tile_with(&data, Spread::Fountain, shape.0, shape.1, shape.2).unwrap();
+    let logits: Vec<f32> = bits.iter().map(|&bit| bit as f32 * 2. - 1.).collect();
+    assert!(extract(&bits).is_err());
+    assert_eq!(extract_spread(&logits, shape).unwrap().message, "fountain");
+    assert!(candidates(&logits, shape).iter().any(|candidate| candidate.valid));
+
+    let data = pack(&Header::default(), b"tiles");
+    let shape = (1, 150, 140);
+    let bits = tile_with(&data, Spread::Tiles, shape.0, shape.1, shape.2).unwrap();
+    let logits: Vec<f32> = bits.iter().map(|&bit| bit as f32 * 2. - 1.).collect();
+    let realigned = sync::realign(&logits, shape, sync::Tiling::Plain).unwrap();
+    assert_eq!(extract_soft(&realigned).unwrap().message, "tiles");
+  }
+
+  #[test]
+  fn test_estimate_errors() {
+    // Not a message whose compressed stream ends in a lone zero byte: that block's parity is all zeros and reads as
+    // delimiters
+    let data = pack(&Header::default(), b"error rate");
+    let period = encoded_len(&data);
+    let mut bits = tile(&data, 1, 128, 128).unwrap();
+    let intact = estimate_errors(&bits.iter().map(|&bit| bit as f32 * 2. - 1.).collect::<Vec<_>>()).unwrap();
+    assert_eq!(
+      (intact.bit_error_rate, intact.worst_block, intact.failed_blocks),
+      (0., 0, 0)
+    );
+    assert!(!intact.marginal());
+
+    // One byte more than the code corrects wrong in the first block of the second copy
+    bits[period..period + 8 * (ecc::STANDARD.max_errors() + 1)]
+      .iter_mut()
+      .for_each(|bit| *bit ^= 1);
+    let logits: Vec<f32> = bits.iter().map(|&bit| bit as f32 * 2. - 1.).collect();
+    let estimate = estimate_errors(&logits).unwrap();
+    assert_eq!((estimate.failed_blocks, estimate.blocks), (1, intact.blocks));
+    assert!(estimate.bit_error_rate > 0. && estimate.marginal());
+    assert_eq!(extract(&bits).unwrap().message, "error rate");
+  }
+
+  #[test]
+  fn test_aggregate() {
+    let data = pack(&Header::default(), b"hello");
+    let period = encoded_len(&data);
+    let mut bits = tile(&data, 1, 128, 128).unwrap();
+    // A fifth of the bits of every copy flipped, different ones in each copy, the delimiters left intact
+    for (copy, chunk) in bits.chunks_mut(period).enumerate() {
+      for (i, bit) in chunk.iter_mut().enumerate().take(period - DELIMITER_BITS) {
+        if (i + copy) % 5 == 0 {
+          *bit ^= 1;
+        }
+      }
+    }
+    assert_eq!(extract(&bits).unwrap().message, "hello");
+
+    // Two thirds of the copies are wrong at every position, but less confident than the right third
+    let data = pack(&Header::default(), b"soft");
+    let period = encoded_len(&data);
+    let bits = tile(&data, 1, 128, 128).unwrap();
+    let mut logits: Vec<f32> = bits.iter().map(|&bit| if bit == 1 { 1. } else { -1. }).collect();
+    for (copy, chunk) in logits.chunks_mut(period).enumerate() {
+      for (i, logit) in chunk.iter_mut().enumerate().take(period - DELIMITER_BITS) {
+        if (i + copy) % 3 != 0 {
+          *logit *= -0.1;
+        }
+      }
+    }
+    assert!(extract(&logits.iter().map(|&logit| (logit > 0.) as u8).collect::<Vec<_>>()).is_err());
+    assert_eq!(extract_soft(&logits).unwrap().message, "soft");
+  }
+
+  #[test]
+  fn test_most_common() {
+    let votes = HashMap::from([(b"b".to_vec(), 2), (b"a".to_vec(), 2), (b"c".to_vec(), 1)]);
+    assert_eq!(most_common(votes), Some(b"a".to_vec()));
+    assert_eq!(most_common(HashMap::<Vec<u8>, usize>::new()), None);
+  }
+
+  #[test]
+  fn test_noise() {
+    // The logits of a clean cover: no frame, and no two copies agree on a payload written before frames existed
+    let mut rng = crate::rng::from_seed(Some(0));
+    let shape = (1, 128, 128);
+    for _ in 0..5 {
+      let logits: Vec<f32> = (0..shape.1 * shape.2).map(|_| rng.gen_range(-1. ..1.)).collect();
+      assert!(matches!(
+        extract_spread(&logits, shape),
+        Err(SteganoError::DecodeFailed)
+      ));
+      assert!(candidates(&logits, shape).iter().all(|candidate| !candidate.valid));
+    }
+
+    // Such a payload reads from two copies, but not from one
+    let legacy = miniz_oxide::deflate::compress_to_vec(b"old", 6);
+    let bits = tile(&legacy, 1, 32, 32).unwrap();
+    assert_eq!(extract(&bits).unwrap().message, "old");
+    let copy = encoded_len(&legacy);
+    let mut single: Vec<u8> = (0..bits.len()).map(|_| rng.gen_range(0..2)).collect();
+    // One whole copy between delimiters, the rest noise
+    let copy = copy - DELIMITER_BITS..2 * copy;
+    single[copy.clone()].copy_from_slice(&bits[copy]);
+    assert!(extract(&single).is_err());
+    assert!(candidates(
+      &single.iter().map(|&bit| bit as f32 * 2. - 1.).collect::<Vec<_>>(),
+      (1, 32, 32)
+    )
+    .iter()
+    .any(|candidate| candidate.message == "old" && !candidate.valid));
+  }
+
+  #[test]
+  fn test_candidates() {
+    let data = pack(&Header::default(), b"hello");
+    let bits = tile(&data, 1, 128, 128).unwrap();
+    let logits: Vec<f32> = bits.iter().map(|&bit| if bit == 1 { 1. } else { -1. }).collect();
+    let candidates = candidates(&logits, (1, 128, 128));
+    assert!(candidates[0].aggregated && candidates[0].valid);
+    assert_eq!(candidates[0].votes, bits.len().div_ceil(encoded_len(&data)));
+    assert_eq!(candidates[1].crc, Some(true));
+    assert_eq!(candidates[1].message, "hello");
+
+    // A damaged copy still shows what is left of its message
+    let header = Header {
+      compression: Compression::None,
+      ..Default::default()
+    };
+    let mut damaged = pack(&header, b"hello");
+    *damaged.last_mut().unwrap() ^= 1;
+    let candidate = candidate(&damaged, false, 1, Default::default());
+    assert_eq!((candidate.crc, candidate.valid), (Some(false), false));
+    assert_eq!(candidate.message, "helln");
+  }
+
+  #[test]
+  fn test_fit() {
+    let data: Vec<u8> = (0..2000u32).map(|i| (i * 7919 % 251) as u8).collect();
+    let header = Header {
+      chunk: Some(Chunk::default()),
+      ..Default::default()
+    };
+    let len = fit(&header, &data, 4096, None);
+    assert!(len > 0 && len < data.len());
+    assert!(encoded_len(&pack(&header, &data[..len])) <= 4096);
+    assert!(encoded_len(&pack(&header, &data[..len + 1])) > 4096);
+    assert_eq!(fit(&header, &data, 1, None), 0);
+  }
+
+  #[test]
+  fn test_tile_tensor() -> Result<()> {
+    let data = pack(&Header::default(), b"hello");
+    let tiled = tile_tensor(&data, 3, 20, 30, &Device::Cpu)?;
+    assert_eq!(tiled.dims(), [1, 3, 20, 30]);
+    let bits = tiled.flatten_all()?.to_dtype(candle_core::DType::U8)?.to_vec1::<u8>()?;
+    assert_eq!(bits, tile(&data, 3, 20, 30)?);
+    Ok(())
+  }
+}
@@ -0,0 +1,158 @@
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+// Items waiting between two stages, per worker.
+const QUEUE: usize = 2;
+
+// Runs `read` on `workers` threads, `process` on the calling thread in the order the reads finish, and `write` on
+// `workers` threads again, so that reading and decoding files and encoding and writing images overlap with the model
+// instead of taking turns with it. `process` gets up to `batch_size` reads at once, for the model to run on all of
+// them in a single forward pass, and waits for a full batch unless the reads are done. Stops reading and processing at
+// the first error.
+pub fn run<I, R, P>(
+  items: Vec<I>,
+  workers: usize,
+  batch_size: usize,
+  read: impl Fn(I) -> Result<R> + Sync,
+  mut process: impl FnMut(Vec<R>) -> Result<Vec<P>>,
+  write: impl Fn(P) -> Result<()> + Sync,
+) -> Result<()>
+where
+  I: Send,
+  R: Send,
+  P: Send,
+{
+  let workers = workers.max(1);
+  let items = Mutex::new(items.into_iter());
+  let (read_tx, read_rx) = sync_channel(workers * QUEUE);
+  let (write_tx, write_rx) = sync_channel(workers * QUEUE);
+  // Owned by the writers only, so that the channel closes once they have all stopped
+  let write_rx = Arc::new(Mutex::new(write_rx));
+  std::thread::scope(|scope| {
+    for _ in 0..workers {
+      let (items, read, read_tx) = (&items, &read, read_tx.clone());
+      scope.spawn(move || {
+        // In a closure, so that the lock is released before reading
+        let next = || items.lock().unwrap().next();
+        while let Some(item) = next() {
+          let result = read(item);
+          let failed = result.is_err();
+          // Sending fails once processing has stopped
+          if read_tx.send(result).is_err() || failed {
+            break;
+          }
+        }
+      });
+    }
+    drop(read_tx);
+
+    let writers: Vec<_> = (0..workers)
+      .map(|_| {
+        let (write_rx, write) = (write_rx.clone(), &write);
+        scope.spawn(move || -> Result<()> {
+          let next = || write_rx.lock().unwrap().recv();
+          while let Ok(output) = next() {
+            write(output)?;
+          }
+          Ok(())
+        })
+      })
+      .collect();
+    drop(write_rx);
+
+    let processed = || -> Result<()> {
+      let mut reads = read_rx.into_iter();
+      loop {
+        let batch = reads.by_ref().take(batch_size.max(1)).collect::<Result<Vec<_>>>()?;
+        if batch.is_empty() {
+          return Ok(());
+        }
+        for output in process(batch)? {
+          // Sending fails once every writer has stopped on an error, which joining them reports
+          if write_tx.send(output).is_err() {
+            return Ok(());
+          }
+        }
+      }
+    };
+    let result = processed();
+    drop(write_tx);
+    for writer in writers {
+      writer.join().expect("writer thread panicked")?;
+    }
+    result
+  })
+}
+
+// Worker threads for `--jobs`, the number of CPUs by default.
+pub fn workers(jobs: Option<usize>) -> usize {
+  jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+}
+
+#[cfg(test)]
+mod tests {
+  use anyhow::bail;
+
+  use super::*;
+
+  #[test]
+  fn test_run() -> Result<()> {
+    let written = Mutex::new(Vec::new());
+    let mut order: Vec<u32> = Vec::new();
+    run(
+      (0..20).collect(),
+      3,
+      1,
+      |i: u32| Ok(i * 2),
+      |batch| {
+        order.extend(&batch);
+        Ok(batch.into_iter().map(|i| i + 1).collect())
+      },
+      |i| {
+        written.lock().unwrap().push(i);
+        Ok(())
+      },
+    )?;
+    let mut written = written.into_inner().unwrap();
+    written.sort();
+    assert_eq!(written, (0..20).map(|i| i * 2 + 1).collect::<Vec<_>>());
+    assert_eq!(order.len(), 20);
+
+    let failed = run(
+      (0..20).collect(),
+      3,
+      1,
+      |i: u32| if i == 5 { bail!("unreadable") } else { Ok(i) },
+      Ok,
+      |_| Ok(()),
+    );
+    assert_eq!(failed.unwrap_err().to_string(), "unreadable");
+    let failed = run((0..20).collect(), 3, 1, Ok, Ok, |i: u32| match i {
+      7 => bail!("disk full"),
+      _ => Ok(()),
+    });
+    assert_eq!(failed.unwrap_err().to_string(), "disk full");
+    // A single writer that fails on the first write, with more items left than the queue holds
+    let failed = run((0..20).collect(), 1, 1, Ok, Ok, |_: u32| -> Result<()> {
+      bail!("read-only")
+    });
+    assert_eq!(failed.unwrap_err().to_string(), "read-only");
+
+    let mut batches = Vec::new();
+    run(
+      (0..10).collect(),
+      2,
+      4,
+      |i: u32| Ok(i),
+      |batch| {
+        batches.push(batch.len());
+        Ok(batch)
+      },
+      |_| Ok(()),
+    )?;
+    assert_eq!(batches, [4, 4, 2]);
+    Ok(())
+  }
+}
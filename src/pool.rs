@@ -0,0 +1,96 @@
+use std::sync::Mutex;
+
+use anyhow::Result;
+use candle_core::Tensor;
+
+// Device tensors the codec builds for an image and can reuse for the next one of the same shape, most recently used
+// first. Batch jobs that embed one payload into many covers of the same size, like the frames of an animation or the
+// images of a document, tile and upload it once, and device memory stays at `capacity` tensors instead of churning
+// through an allocation per image.
+pub struct TensorPool {
+  capacity: usize,
+  entries: Mutex<Vec<(PoolKey, Tensor)>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolKey {
+  /// Height and width of the tensor
+  pub shape: (usize, usize),
+  /// What the tensor is built from, like the packed payload
+  pub content: Vec<u8>,
+}
+
+impl TensorPool {
+  // A pool of capacity 0 keeps nothing.
+  pub fn new(capacity: usize) -> Self {
+    Self {
+      capacity,
+      entries: Mutex::new(Vec::with_capacity(capacity)),
+    }
+  }
+
+  // The tensor stored under `key`, or the one `build` returns, which is stored in place of the least recently used.
+  pub fn get_or_insert_with(&self, key: PoolKey, build: impl FnOnce() -> Result<Tensor>) -> Result<Tensor> {
+    {
+      let mut entries = self.entries.lock().unwrap();
+      if let Some(index) = entries.iter().position(|(k, _)| *k == key) {
+        let entry = entries.remove(index);
+        let tensor = entry.1.clone();
+        entries.insert(0, entry);
+        return Ok(tensor);
+      }
+    }
+    // Other images go on while this one is built
+    let tensor = build()?;
+    if self.capacity > 0 {
+      let mut entries = self.entries.lock().unwrap();
+      entries.insert(0, (key, tensor.clone()));
+      entries.truncate(self.capacity);
+    }
+    Ok(tensor)
+  }
+
+  pub fn len(&self) -> usize {
+    self.entries.lock().unwrap().len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use candle_core::Device;
+
+  use super::*;
+
+  #[test]
+  fn test_pool() -> Result<()> {
+    let pool = TensorPool::new(2);
+    let key = |h: usize, content: u8| PoolKey {
+      shape: (h, 4),
+      content: vec![content],
+    };
+    let builds = std::cell::Cell::new(0);
+    let get = |key: PoolKey| {
+      pool.get_or_insert_with(key, || {
+        builds.set(builds.get() + 1);
+        Ok(Tensor::zeros(1, candle_core::DType::F32, &Device::Cpu)?)
+      })
+    };
+    get(key(4, 0))?;
+    get(key(4, 0))?;
+    get(key(4, 1))?;
+    get(key(8, 0))?;
+    assert_eq!(builds.get(), 3);
+    // (4, 0) was the least recently used and is built again, which evicts (4, 1)
+    get(key(4, 0))?;
+    get(key(8, 0))?;
+    assert_eq!(builds.get(), 4);
+    get(key(4, 1))?;
+    assert_eq!(builds.get(), 5);
+    assert_eq!(pool.len(), 2);
+    Ok(())
+  }
+}
@@ -0,0 +1,128 @@
+use anyhow::{anyhow, Result};
+use candle_core::Tensor;
+
+// Range 8-bit pixels are mapped to before they are fed to a network.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+  pub min: f32,
+  pub max: f32,
+}
+
+impl Range {
+  pub const SIGNED: Self = Self { min: -1., max: 1. };
+  pub const UNIT: Self = Self { min: 0., max: 1. };
+
+  // Maps a tensor of 0-255 pixel values to the range.
+  pub fn normalize(&self, pixels: &Tensor) -> Result<Tensor> {
+    Ok(pixels.affine(((self.max - self.min) / 255.) as f64, self.min as f64)?)
+  }
+
+  // Maps a tensor normalized to `from` to this range.
+  pub fn convert(&self, x: &Tensor, from: Range) -> Result<Tensor> {
+    if *self == from {
+      return Ok(x.clone());
+    }
+    let scale = (self.max - self.min) / (from.max - from.min);
+    Ok(x.affine(scale as f64, (self.min - from.min * scale) as f64)?)
+  }
+
+  fn parse(s: &str) -> Result<Self> {
+    let (min, max) = s.split_once(',').ok_or_else(|| anyhow!("Invalid range '{s}'"))?;
+    Ok(Self {
+      min: min.trim().parse()?,
+      max: max.trim().parse()?,
+    })
+  }
+}
+
+// Input normalization a checkpoint expects, stored in its metadata. The encoder always gets [-1, 1], which the training
+// data, noise layers and critic are built around; the decoder range depends on how the checkpoint was trained.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Preprocess {
+  pub decode: Range,
+}
+
+impl Default for Preprocess {
+  fn default() -> Self {
+    Profile::Steganogan.preprocess()
+  }
+}
+
+impl Preprocess {
+  pub const ENCODE: Range = Range::SIGNED;
+
+  pub fn encoder_input(&self, pixels: &Tensor) -> Result<Tensor> {
+    Self::ENCODE.normalize(pixels)
+  }
+
+  pub fn decoder_input(&self, pixels: &Tensor) -> Result<Tensor> {
+    self.decode.normalize(pixels)
+  }
+
+  // Maps an encoder output to the decoder range, so that training sees what decoding will.
+  pub fn encoded_to_decoder(&self, x: &Tensor) -> Result<Tensor> {
+    self.decode.convert(x, Self::ENCODE)
+  }
+
+  pub(crate) fn to_metadata(self) -> (String, String) {
+    let Range { min, max } = self.decode;
+    ("decode_range".to_string(), format!("{min},{max}"))
+  }
+
+  // Checkpoints saved before the range was recorded are assumed to follow the original library.
+  pub(crate) fn from_metadata(value: Option<&String>) -> Result<Self> {
+    match value {
+      Some(value) => Ok(Self {
+        decode: Range::parse(value)?,
+      }),
+      None => Ok(Self::default()),
+    }
+  }
+}
+
+// Named preprocessing for `convert` and `finetune`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Profile {
+  /// Decoder input in [0, 1], as the original SteganoGAN library decodes and the pretrained weights expect
+  Steganogan,
+  /// Decoder input in [-1, 1] like the encoder output it is trained on
+  Symmetric,
+}
+
+impl Profile {
+  pub fn preprocess(self) -> Preprocess {
+    match self {
+      Self::Steganogan => Preprocess { decode: Range::UNIT },
+      Self::Symmetric => Preprocess { decode: Range::SIGNED },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use candle_core::Device;
+
+  use super::*;
+
+  #[test]
+  fn test_ranges() -> Result<()> {
+    let pixels = Tensor::new(&[0f32, 127.5, 255.], &Device::Cpu)?;
+    let preprocess = Profile::Steganogan.preprocess();
+    let encoded = preprocess.encoder_input(&pixels)?;
+    assert_eq!(encoded.to_vec1::<f32>()?, [-1., 0., 1.]);
+    assert_eq!(preprocess.decoder_input(&pixels)?.to_vec1::<f32>()?, [0., 0.5, 1.]);
+    assert_eq!(
+      preprocess.encoded_to_decoder(&encoded)?.to_vec1::<f32>()?,
+      [0., 0.5, 1.]
+    );
+
+    let (key, value) = Profile::Symmetric.preprocess().to_metadata();
+    assert_eq!((key.as_str(), value.as_str()), ("decode_range", "-1,1"));
+    assert_eq!(
+      Preprocess::from_metadata(Some(&value))?,
+      Profile::Symmetric.preprocess()
+    );
+    assert_eq!(Preprocess::from_metadata(None)?, Preprocess::default());
+    Ok(())
+  }
+}
@@ -0,0 +1,114 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use steganogan_rs::utils::to_hex;
+
+// What `encode --registry` notes about a stego image, so that `decode --registry` can trace a recovered payload back to
+// where it went.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+  /// SHA-256 of the hidden data, which decoding looks records up by
+  pub payload_sha256: String,
+  /// The data if it is text
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub payload: Option<String>,
+  pub input: PathBuf,
+  pub output: PathBuf,
+  /// BLAKE3 hash of the stego image, unless it was uploaded
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub output_blake3: Option<String>,
+  /// Unix time of the encode
+  pub timestamp: u64,
+}
+
+impl Record {
+  pub fn new(data: &[u8], input: &Path, output: &Path, output_blake3: Option<String>) -> Self {
+    Self {
+      payload_sha256: to_hex(&Sha256::digest(data)),
+      payload: std::str::from_utf8(data).ok().map(str::to_string),
+      input: input.to_path_buf(),
+      output: output.to_path_buf(),
+      output_blake3,
+      timestamp: std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs(),
+    }
+  }
+}
+
+// Appends the records to a JSON Lines file, which several runs can share.
+pub fn append(path: &Path, records: &[Record]) -> Result<()> {
+  let mut lines = String::new();
+  for record in records {
+    lines += &(serde_json::to_string(record)? + "\n");
+  }
+  OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(path)
+    .and_then(|mut file| file.write_all(lines.as_bytes()))
+    .with_context(|| format!("Failed to write the registry {}", path.display()))
+}
+
+// Records of the images `data` was hidden in.
+pub fn lookup(path: &Path, data: &[u8]) -> Result<Vec<Record>> {
+  let registry = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+  let hash = to_hex(&Sha256::digest(data));
+  let mut records = Vec::new();
+  for (number, line) in registry.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+    let record: Record = serde_json::from_str(line)
+      .with_context(|| format!("Invalid record on line {} of {}", number + 1, path.display()))?;
+    if record.payload_sha256 == hash {
+      records.push(record);
+    }
+  }
+  Ok(records)
+}
+
+// Where the decoded data was registered, for stderr.
+pub fn report(records: &[Record]) -> String {
+  if records.is_empty() {
+    return "registry: the payload is not registered\n".to_string();
+  }
+  records
+    .iter()
+    .map(|record| {
+      format!(
+        "registry: {} from {} at {}\n",
+        record.output.display(),
+        record.input.display(),
+        record.timestamp
+      )
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_registry() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let path = tmp.path().join("registry.jsonl");
+    let first = Record::new(
+      b"order:1",
+      Path::new("a.png"),
+      Path::new("out/a.png"),
+      Some("ab".repeat(32)),
+    );
+    let second = Record::new(b"order:2", Path::new("b.png"), Path::new("out/b.png"), None);
+    append(&path, std::slice::from_ref(&first))?;
+    append(&path, &[second])?;
+    assert_eq!(lookup(&path, b"order:1")?, vec![first.clone()]);
+    assert!(lookup(&path, b"order:3")?.is_empty());
+    assert!(report(&[first]).starts_with("registry: out/a.png from a.png at "));
+    assert_eq!(report(&[]), "registry: the payload is not registered\n");
+    Ok(())
+  }
+}
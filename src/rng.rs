@@ -0,0 +1,54 @@
+use anyhow::Result;
+use candle_core::{Device, Shape, Tensor};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// All randomness goes through host-side `StdRng`s so that a `--seed` gives the same results on every device.
+pub fn from_seed(seed: Option<u64>) -> StdRng {
+  seeded(seed)
+}
+
+// Same for another generator, such as a ChaCha one whose position can be saved.
+pub fn seeded<R: SeedableRng>(seed: Option<u64>) -> R {
+  match seed {
+    Some(seed) => R::seed_from_u64(seed),
+    None => R::from_entropy(),
+  }
+}
+
+// Standard normal sample (Box-Muller transform).
+pub fn normal(rng: &mut impl Rng) -> f32 {
+  let (u1, u2) = (1. - rng.gen::<f32>(), rng.gen::<f32>());
+  (-2. * u1.ln()).sqrt() * (2. * std::f32::consts::PI * u2).cos()
+}
+
+pub fn uniform_tensor<S: Into<Shape>>(rng: &mut impl Rng, shape: S, device: &Device) -> Result<Tensor> {
+  let shape = shape.into();
+  let data: Vec<f32> = (0..shape.elem_count()).map(|_| rng.gen()).collect();
+  Ok(Tensor::from_vec(data, shape, device)?)
+}
+
+pub fn normal_tensor<S: Into<Shape>>(rng: &mut impl Rng, shape: S, device: &Device) -> Result<Tensor> {
+  let shape = shape.into();
+  let data: Vec<f32> = (0..shape.elem_count()).map(|_| normal(rng)).collect();
+  Ok(Tensor::from_vec(data, shape, device)?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_seeded() -> Result<()> {
+    let sample = |seed| -> Result<Vec<f32>> {
+      let mut rng = from_seed(Some(seed));
+      Ok(normal_tensor(&mut rng, 4, &Device::Cpu)?.to_vec1()?)
+    };
+    assert_eq!(sample(7)?, sample(7)?);
+    assert_ne!(sample(7)?, sample(8)?);
+    let mut rng = from_seed(Some(0));
+    let mean = (0..10000).map(|_| normal(&mut rng)).sum::<f32>() / 10000.;
+    assert!(mean.abs() < 0.05);
+    Ok(())
+  }
+}
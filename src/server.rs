@@ -0,0 +1,160 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Multipart, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use image::ImageFormat;
+use serde::Serialize;
+use steganogan_rs::codec::Codec;
+use steganogan_rs::{image_io, SteganoError};
+
+const BODY_LIMIT: usize = 64 << 20;
+
+struct ApiError(StatusCode, anyhow::Error);
+
+impl<E: Into<anyhow::Error>> From<E> for ApiError {
+  fn from(err: E) -> Self {
+    let err = err.into();
+    let status = match err.downcast_ref::<SteganoError>() {
+      Some(SteganoError::DecodeFailed | SteganoError::NewerVersion { .. }) => StatusCode::UNPROCESSABLE_ENTITY,
+      Some(SteganoError::CapacityExceeded { .. } | SteganoError::UnsupportedFormat(_)) => StatusCode::BAD_REQUEST,
+      Some(SteganoError::ImageTooLarge { .. }) => StatusCode::PAYLOAD_TOO_LARGE,
+      _ if err.is::<image::ImageError>() => StatusCode::BAD_REQUEST,
+      _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    Self(status, err)
+  }
+}
+
+impl IntoResponse for ApiError {
+  fn into_response(self) -> Response {
+    let error = format!("{:#}", self.1);
+    (self.0, Json(serde_json::json!({ "error": error }))).into_response()
+  }
+}
+
+fn bad_request(msg: &str) -> ApiError {
+  ApiError(StatusCode::BAD_REQUEST, anyhow!("{msg}"))
+}
+
+#[derive(Default)]
+struct Form {
+  image: Option<Bytes>,
+  data: Option<String>,
+  max_dim: Option<u32>,
+  format: Option<String>,
+}
+
+impl Form {
+  async fn read(mut multipart: Multipart) -> std::result::Result<Self, ApiError> {
+    let mut form = Form::default();
+    while let Some(field) = multipart
+      .next_field()
+      .await
+      .map_err(|err| bad_request(&err.to_string()))?
+    {
+      let name = field.name().unwrap_or_default().to_string();
+      let value = field.bytes().await.map_err(|err| bad_request(&err.to_string()))?;
+      let text = || String::from_utf8(value.to_vec()).map_err(|_| bad_request(&format!("'{name}' is not UTF-8")));
+      match name.as_str() {
+        "image" => form.image = Some(value.clone()),
+        "data" => form.data = Some(text()?),
+        "max_dim" => form.max_dim = Some(text()?.parse().map_err(|_| bad_request("invalid 'max_dim'"))?),
+        "format" => form.format = Some(text()?),
+        _ => return Err(bad_request(&format!("unexpected field '{name}'"))),
+      }
+    }
+    Ok(form)
+  }
+}
+
+// POST /encode with multipart fields `image`, `data` and optional `max_dim` and `format` (png or webp), returns the
+// stego image.
+async fn encode(State(codec): State<Arc<Codec>>, multipart: Multipart) -> std::result::Result<Response, ApiError> {
+  let form = Form::read(multipart).await?;
+  let image = form.image.ok_or_else(|| bad_request("missing 'image' field"))?;
+  let data = form.data.ok_or_else(|| bad_request("missing 'data' field"))?;
+  let format = match form.format.as_deref() {
+    None | Some("png") => ImageFormat::Png,
+    Some("webp") => ImageFormat::WebP,
+    Some(format) => return Err(bad_request(&format!("unsupported format '{format}', use png or webp"))),
+  };
+  let output = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+    let cover = image::load_from_memory(&image)?.to_rgb8();
+    let size = form
+      .max_dim
+      .and_then(|max_dim| image_io::fit_within(cover.dimensions(), max_dim));
+    let stego = codec.encode(&cover, data.as_bytes(), size)?;
+    image_io::encode_image(&stego, format)
+  })
+  .await??;
+  Ok(([(header::CONTENT_TYPE, format.to_mime_type())], output).into_response())
+}
+
+#[derive(Serialize)]
+struct Decoded {
+  message: String,
+}
+
+// POST /decode with a multipart `image` field, returns `{"message": ...}`.
+async fn decode(State(codec): State<Arc<Codec>>, multipart: Multipart) -> std::result::Result<Json<Decoded>, ApiError> {
+  let form = Form::read(multipart).await?;
+  let image = form.image.ok_or_else(|| bad_request("missing 'image' field"))?;
+  let payload =
+    tokio::task::spawn_blocking(move || codec.decode(&image::load_from_memory(&image)?.to_rgb8())).await??;
+  Ok(Json(Decoded {
+    message: payload.message,
+  }))
+}
+
+pub fn router(codec: Codec) -> Router {
+  Router::new()
+    .route("/encode", post(encode))
+    .route("/decode", post(decode))
+    .layer(DefaultBodyLimit::max(BODY_LIMIT))
+    .with_state(Arc::new(codec))
+}
+
+pub fn serve(addr: SocketAddr, codec: Codec) -> Result<()> {
+  let runtime = tokio::runtime::Runtime::new()?;
+  runtime.block_on(async {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    eprintln!("listening on http://{addr}");
+    axum::serve(listener, router(codec)).await?;
+    Ok(())
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_error_status() {
+    let status = |err: anyhow::Error| ApiError::from(err).0;
+    assert_eq!(
+      status(SteganoError::DecodeFailed.into()),
+      StatusCode::UNPROCESSABLE_ENTITY
+    );
+    assert_eq!(
+      status(
+        SteganoError::CapacityExceeded {
+          needed: 2,
+          available: 1
+        }
+        .into()
+      ),
+      StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+      status(SteganoError::ImageTooLarge { pixels: 2, max: 1 }.into()),
+      StatusCode::PAYLOAD_TOO_LARGE
+    );
+    assert_eq!(status(anyhow!("boom")), StatusCode::INTERNAL_SERVER_ERROR);
+  }
+}
@@ -0,0 +1,44 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use rand::Rng;
+
+use crate::utils::to_hex;
+
+pub fn generate_key(rng: &mut impl Rng) -> SigningKey {
+  SigningKey::from_bytes(&rng.gen())
+}
+
+// Keys are stored as hex, the signing key as its 32-byte secret.
+pub fn write_keys(key: &SigningKey, path: &Path, public_path: &Path) -> Result<()> {
+  std::fs::write(path, to_hex(&key.to_bytes()) + "\n")?;
+  std::fs::write(public_path, to_hex(key.verifying_key().as_bytes()) + "\n")?;
+  Ok(())
+}
+
+pub fn read_signing_key(path: &Path) -> Result<SigningKey> {
+  Ok(SigningKey::from_bytes(&read_key(path)?))
+}
+
+pub fn read_verifying_key(path: &Path) -> Result<VerifyingKey> {
+  VerifyingKey::from_bytes(&read_key(path)?).with_context(|| format!("Invalid public key in {}", path.display()))
+}
+
+fn read_key(path: &Path) -> Result<[u8; 32]> {
+  let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read key {}", path.display()))?;
+  match from_hex(text.trim()).map(<[u8; 32]>::try_from) {
+    Some(Ok(key)) => Ok(key),
+    _ => bail!("{} does not contain a 32-byte hex key", path.display()),
+  }
+}
+
+pub(crate) fn from_hex(s: &str) -> Option<Vec<u8>> {
+  s.as_bytes()
+    .chunks(2)
+    .map(|pair| match pair {
+      [hi, lo] => u8::from_str_radix(std::str::from_utf8(&[*hi, *lo]).ok()?, 16).ok(),
+      _ => None,
+    })
+    .collect()
+}
@@ -0,0 +1,567 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, bail, Context, Result};
+use ed25519_dalek::VerifyingKey;
+use serde::Serialize;
+use steganogan_rs::codec::{Codec, DecodeOptions, EncodeOptions};
+use steganogan_rs::payload::{self, Chunk, Header, PayloadType};
+use steganogan_rs::{data, SteganoError};
+
+use crate::{daemon, message, pipeline, registry, Cover, DecodeArgs, EncodeArgs};
+
+// What happened to an image of `--input-dir`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Status {
+  Encoded,
+  /// The data was already placed, or the cover is too small to hold any
+  Unused,
+  Decoded,
+  NoData,
+  /// The image holds a payload that is not part of a split one
+  NotSplit,
+  Failed,
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+  input: PathBuf,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  output: Option<PathBuf>,
+  status: Status,
+  /// 1-based part of the split payload the image holds
+  #[serde(skip_serializing_if = "Option::is_none")]
+  part: Option<u16>,
+  /// Bytes of the data in the image
+  #[serde(skip_serializing_if = "Option::is_none")]
+  bytes: Option<usize>,
+  /// BLAKE3 hash of the written stego image
+  #[serde(skip_serializing_if = "Option::is_none")]
+  blake3: Option<String>,
+  /// Text hidden in the image by `--per-image`, with the template variables expanded
+  #[serde(skip_serializing_if = "Option::is_none")]
+  payload: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error: Option<String>,
+}
+
+impl Entry {
+  fn new(input: &Path, status: Status) -> Self {
+    Self {
+      input: input.to_path_buf(),
+      output: None,
+      status,
+      part: None,
+      bytes: None,
+      blake3: None,
+      payload: None,
+      error: None,
+    }
+  }
+
+  fn failed(input: &Path, err: &anyhow::Error) -> Self {
+    Self {
+      error: Some(format!("{err:#}")),
+      ..Self::new(input, Status::Failed)
+    }
+  }
+}
+
+// Every image of `--input-dir` with what happened to it, printed as a summary and written as JSON by `--manifest`.
+#[derive(Debug, Default, Serialize)]
+struct Manifest {
+  /// CRC32 of the whole payload, which every part carries
+  #[serde(skip_serializing_if = "Option::is_none")]
+  payload_id: Option<String>,
+  succeeded: usize,
+  failed: usize,
+  images: Vec<Entry>,
+}
+
+impl Manifest {
+  fn push(&mut self, entry: Entry) {
+    match entry.status {
+      Status::Encoded | Status::Decoded => self.succeeded += 1,
+      Status::Failed => self.failed += 1,
+      _ => {}
+    }
+    self.images.push(entry);
+  }
+
+  // Failures with their reasons, empty if there were none.
+  fn summary(&self) -> String {
+    if self.failed == 0 {
+      return String::new();
+    }
+    let mut summary = format!("{} images succeeded, {} failed:\n", self.succeeded, self.failed);
+    for entry in self.images.iter().filter(|entry| entry.status == Status::Failed) {
+      summary += &format!(
+        "  {}: {}\n",
+        entry.input.display(),
+        entry.error.as_deref().unwrap_or_default()
+      );
+    }
+    summary
+  }
+
+  // Writes the manifest if asked to, also when the command fails.
+  fn write(&mut self, path: Option<&Path>) -> Result<()> {
+    self.images.sort_by(|a, b| a.input.cmp(&b.input));
+    if let Some(path) = path {
+      std::fs::write(path, serde_json::to_string_pretty(self)? + "\n")
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+  }
+}
+
+// Fills the covers of `--input-dir` in order, each with as much of the data as it holds, and writes the used ones
+// to `--output-dir` as PNG.
+pub fn encode(args: &EncodeArgs, codec: &Codec, options: EncodeOptions) -> Result<daemon::Output> {
+  let (Some(input_dir), Some(output_dir)) = (&args.input_dir, &args.output_dir) else {
+    bail!("--input-dir requires --output-dir");
+  };
+  if args.null_payload.is_some() {
+    bail!("--null-payload has no data to split, encode every image with --per-image");
+  }
+  let data = match (&args.data_file, &args.data) {
+    (Some(path), _) => std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?,
+    (None, Some(data)) => data.clone().into_bytes(),
+    (None, None) => bail!("-d or --data-file is required"),
+  };
+  let checksum = crc32fast::hash(&data);
+
+  // Chunk, channels, signature and MAC have a fixed size, so the placeholders give the final header length.
+  let header = Header {
+    chunk: Some(Chunk {
+      checksum,
+      ..Default::default()
+    }),
+    compression: options.compression,
+    payload_type: PayloadType::Binary,
+    ecc: options.ecc,
+    signature: options.sign_key.map(|_| [0; payload::SIGNATURE_LEN]),
+    mac: options.stego_key.map(|_| [0; payload::MAC_LEN]),
+    channels: (options.channels.is_some() || options.variable_rate).then_some(0),
+    ..Default::default()
+  };
+  let mut manifest = Manifest::default();
+  let mut plan: Vec<(_, Range<usize>)> = Vec::new();
+  let mut offset = 0;
+  for path in data::list_images(input_dir)? {
+    if offset == data.len() {
+      manifest.push(Entry::new(&path, Status::Unused));
+      continue;
+    }
+    // Unreadable covers are skipped, unless --fail-fast
+    let cover = match Cover::read(&path, args) {
+      Ok(cover) => cover,
+      Err(err) if args.fail_fast => return Err(err.context(format!("Failed to read {}", path.display()))),
+      Err(err) => {
+        manifest.push(Entry::failed(&path, &err));
+        continue;
+      }
+    };
+    let size = cover.size.unwrap_or(cover.image.dimensions());
+    let len = payload::fit(
+      &header,
+      &data[offset..],
+      codec.channel_capacity(size, options.channels.unwrap_or(codec.config().data_depth)),
+      options.compression_level,
+    );
+    if len > 0 {
+      plan.push((path, offset..offset + len));
+      offset += len;
+    } else {
+      manifest.push(Entry::new(&path, Status::Unused));
+    }
+  }
+  if offset < data.len() {
+    manifest.write(args.manifest.as_deref())?;
+    bail!(
+      "The images in {} hold only {offset} of {} bytes, add more or larger covers\n{}",
+      input_dir.display(),
+      data.len(),
+      manifest.summary().trim_end()
+    );
+  }
+  let count = u16::try_from(plan.len()).context("Too many chunks")?;
+
+  std::fs::create_dir_all(output_dir)?;
+  let outputs = plan
+    .iter()
+    .enumerate()
+    .map(|(index, (path, range))| {
+      let name = path.file_stem().context("Invalid image name")?;
+      Ok(Entry {
+        output: Some(output_dir.join(name).with_extension("png")),
+        part: Some(index as u16 + 1),
+        bytes: Some(range.len()),
+        ..Entry::new(path, Status::Encoded)
+      })
+    })
+    .collect::<Result<Vec<_>>>()?;
+  manifest.payload_id = Some(format!("{checksum:08x}"));
+  let written = Mutex::new(BTreeMap::new());
+  // The parts are numbered by now, so failing to write one fails the whole payload
+  pipeline::run(
+    plan.into_iter().enumerate().collect(),
+    pipeline::workers(args.jobs),
+    args.batch_size,
+    |(index, (path, range))| Ok((index, Cover::read(&path, args)?, range)),
+    |batch| {
+      let items: Vec<_> = batch
+        .iter()
+        .map(|(index, cover, range)| {
+          let chunk = Chunk {
+            index: *index as u16,
+            count,
+            checksum,
+          };
+          let options = EncodeOptions {
+            size: cover.size,
+            chunk: Some(chunk),
+            payload_type: PayloadType::Binary,
+            ..options
+          };
+          (&cover.image, &data[range.clone()], options)
+        })
+        .collect();
+      let stego = codec.encode_images(&items)?;
+      Ok(
+        batch
+          .into_iter()
+          .zip(stego)
+          .map(|((index, cover, _), stego)| (index, cover, stego))
+          .collect(),
+      )
+    },
+    |(index, cover, stego)| {
+      let output = outputs[index].output.as_ref().unwrap();
+      cover.write(&stego, output)?;
+      let hashed = crate::hash_output(output, args.name_by_hash)?;
+      written.lock().unwrap().insert(index, hashed);
+      Ok(())
+    },
+  )?;
+  let mut records = Vec::new();
+  for (entry, (output, hash)) in outputs.into_iter().zip(written.into_inner().unwrap().into_values()) {
+    records.push(registry::Record::new(&data, &entry.input, &output, Some(hash.clone())));
+    manifest.push(Entry {
+      output: Some(output),
+      blake3: Some(hash),
+      ..entry
+    });
+  }
+  if let Some(path) = &args.registry {
+    registry::append(path, &records)?;
+  }
+
+  let output = daemon::Output {
+    stdout: format!(
+      "{} bytes split across {count} images in {}\n",
+      data.len(),
+      output_dir.display()
+    ),
+    stderr: manifest.summary(),
+    ..Default::default()
+  };
+  manifest.write(args.manifest.as_deref())?;
+  Ok(output)
+}
+
+// A cover of `--per-image` with its own message, or why it could not be read.
+type Item = (PathBuf, Result<(Cover, PayloadType, Vec<u8>, Option<String>)>);
+
+// Hides the whole payload in every image of `--input-dir` for `--per-image`, with the variables of a `-d` template
+// expanded for each, and writes them to `--output-dir` as PNG.
+pub fn encode_each(args: &EncodeArgs, codec: &Codec, options: EncodeOptions) -> Result<daemon::Output> {
+  let (Some(input_dir), Some(output_dir)) = (&args.input_dir, &args.output_dir) else {
+    bail!("--input-dir requires --output-dir");
+  };
+  std::fs::create_dir_all(output_dir)?;
+  // A broken template fails before any image
+  crate::expand_data(args, input_dir, 0)?;
+  let read = |(index, path): (usize, PathBuf)| -> Result<Item> {
+    let item = crate::expand_data(args, &path, index + 1).and_then(|args| {
+      let (payload_type, message) = message::read(&args)?;
+      Ok((Cover::read(&path, &args)?, payload_type, message, args.data))
+    });
+    // Unreadable covers are skipped, unless --fail-fast
+    match item {
+      Err(err) if args.fail_fast => Err(err.context(format!("Failed to read {}", path.display()))),
+      item => Ok((path, item)),
+    }
+  };
+  let mut manifest = Manifest::default();
+  let written = Mutex::new(Vec::new());
+  let records = Mutex::new(Vec::new());
+  pipeline::run(
+    data::list_images(input_dir)?.into_iter().enumerate().collect(),
+    pipeline::workers(args.jobs),
+    args.batch_size,
+    read,
+    |batch| {
+      let mut covers = Vec::new();
+      for (path, item) in batch {
+        match item {
+          Ok(item) => covers.push((path, item)),
+          Err(err) => manifest.push(Entry::failed(&path, &err)),
+        }
+      }
+      let items: Vec<_> = covers
+        .iter()
+        .map(|(_, (cover, payload_type, message, _))| {
+          let options = EncodeOptions {
+            size: cover.size,
+            payload_type: *payload_type,
+            ..options
+          };
+          (&cover.image, message.as_slice(), options)
+        })
+        .collect();
+      // A cover too small for its message fails the whole batch, so those are encoded one by one to find it
+      let stego = match codec.encode_images(&items) {
+        Ok(stego) => stego.into_iter().map(Ok).collect(),
+        Err(_) if items.len() > 1 => items
+          .iter()
+          .map(|item| Ok(codec.encode_images(std::slice::from_ref(item))?.remove(0)))
+          .collect(),
+        Err(err) => vec![Err(err)],
+      };
+      let mut encoded = Vec::new();
+      for ((path, (cover, _, message, text)), stego) in covers.into_iter().zip(stego) {
+        match stego {
+          Ok(stego) => encoded.push((path, cover, stego, message, text)),
+          Err(err) if args.fail_fast => return Err(err.context(format!("Failed to encode {}", path.display()))),
+          Err(err) => manifest.push(Entry::failed(&path, &err)),
+        }
+      }
+      Ok(encoded)
+    },
+    |(path, cover, stego, message, payload)| {
+      let name = path.file_stem().context("Invalid image name")?;
+      let output = output_dir.join(name).with_extension("png");
+      let entry = cover
+        .write(&stego, &output)
+        .and_then(|()| crate::hash_output(&output, args.name_by_hash))
+        .map(|(output, hash)| {
+          if args.registry.is_some() {
+            let record = registry::Record::new(&message, &path, &output, Some(hash.clone()));
+            records.lock().unwrap().push(record);
+          }
+          Entry {
+            output: Some(output),
+            bytes: Some(message.len()),
+            blake3: Some(hash),
+            payload: payload.clone(),
+            ..Entry::new(&path, Status::Encoded)
+          }
+        });
+      let entry = match entry {
+        Err(err) if args.fail_fast => return Err(err.context(format!("Failed to write {}", output.display()))),
+        entry => entry.unwrap_or_else(|err| Entry::failed(&path, &err)),
+      };
+      written.lock().unwrap().push(entry);
+      Ok(())
+    },
+  )?;
+  written
+    .into_inner()
+    .unwrap()
+    .into_iter()
+    .for_each(|entry| manifest.push(entry));
+  if let Some(path) = &args.registry {
+    registry::append(path, &records.into_inner().unwrap())?;
+  }
+
+  let output = daemon::Output {
+    stdout: format!("{} images encoded in {}\n", manifest.succeeded, output_dir.display()),
+    stderr: manifest.summary(),
+    ..Default::default()
+  };
+  manifest.write(args.manifest.as_deref())?;
+  Ok(output)
+}
+
+// Decodes every image of `--input-dir` and writes the reassembled data to `-o`.
+pub fn decode(
+  args: &DecodeArgs,
+  codec: &Codec,
+  options: &DecodeOptions,
+  verify_key: Option<&VerifyingKey>,
+) -> Result<daemon::Output> {
+  let (Some(input_dir), Some(output_path)) = (&args.input_dir, &args.output) else {
+    bail!("--input-dir requires -o");
+  };
+  let mut output = daemon::Output::default();
+  let mut manifest = Manifest::default();
+  let mut chunks = Vec::new();
+  pipeline::run(
+    data::list_images(input_dir)?,
+    pipeline::workers(args.jobs),
+    args.batch_size,
+    |path| {
+      // Unreadable images are skipped, unless --fail-fast
+      match image::open(&path) {
+        Err(err) if args.fail_fast => {
+          Err(anyhow::Error::from(err).context(format!("Failed to read {}", path.display())))
+        }
+        image => Ok((image.map(|image| image.to_rgb8()), path)),
+      }
+    },
+    |batch| {
+      let (images, paths): (Vec<_>, Vec<_>) = batch
+        .into_iter()
+        .filter_map(|(image, path)| match image {
+          Ok(image) => Some((image, path)),
+          Err(err) => {
+            manifest.push(Entry::failed(&path, &err.into()));
+            None
+          }
+        })
+        .unzip();
+      for (payload, path) in codec.decode_images(&images, options)?.into_iter().zip(paths) {
+        let payload = match payload {
+          Ok(payload) => payload,
+          Err(err) if matches!(err.downcast_ref(), Some(SteganoError::DecodeFailed)) => {
+            output.stderr += &format!("warning: no data found in {}\n", path.display());
+            manifest.push(Entry::new(&path, Status::NoData));
+            continue;
+          }
+          Err(err) if args.fail_fast => return Err(err),
+          Err(err) => {
+            manifest.push(Entry::failed(&path, &err));
+            continue;
+          }
+        };
+        let Some(chunk) = payload.header.as_ref().and_then(|header| header.chunk) else {
+          output.stderr += &format!("warning: {} is not part of a split payload\n", path.display());
+          manifest.push(Entry::new(&path, Status::NotSplit));
+          continue;
+        };
+        if let Some(key) = verify_key {
+          if payload.verify(key) != Some(true) {
+            bail!("The signature of {} does not match --verify-key", path.display());
+          }
+        }
+        // A tampered part would corrupt the reassembled data, so it is left out like an unreadable image
+        if options.stego_key.and_then(|key| payload.authenticate(key)) == Some(false) {
+          let err = anyhow!(
+            "The MAC of {} does not match --key, it was tampered with",
+            path.display()
+          );
+          if args.fail_fast {
+            return Err(err);
+          }
+          manifest.push(Entry::failed(&path, &err));
+          continue;
+        }
+        manifest.push(Entry {
+          part: Some(chunk.index + 1),
+          bytes: Some(payload.data.len()),
+          ..Entry::new(&path, Status::Decoded)
+        });
+        manifest.payload_id.get_or_insert(format!("{:08x}", chunk.checksum));
+        chunks.push((chunk, payload.data));
+      }
+      Ok(vec![])
+    },
+    |()| Ok(()),
+  )?;
+
+  manifest.write(args.manifest.as_deref())?;
+  let data = match reassemble(chunks) {
+    Ok(data) => data,
+    Err(err) => bail!("{err}\n{}", manifest.summary().trim_end()),
+  };
+  std::fs::write(output_path, &data)?;
+  output.stdout = format!("{} bytes written to {}\n", data.len(), output_path.display());
+  if let Some(path) = &args.registry {
+    output.stderr += &registry::report(&registry::lookup(path, &data)?);
+  }
+  output.stderr += &manifest.summary();
+  Ok(output)
+}
+
+// Joins the chunks in order and checks the result against the checksum they were written with.
+fn reassemble(chunks: Vec<(Chunk, Vec<u8>)>) -> Result<Vec<u8>> {
+  let Some((first, _)) = chunks.first() else {
+    bail!("No parts of a split payload found");
+  };
+  let (count, checksum) = (first.count, first.checksum);
+  let mut parts = BTreeMap::new();
+  for (chunk, data) in chunks {
+    if chunk.count != count || chunk.checksum != checksum {
+      bail!("The images hold parts of different payloads");
+    }
+    parts.insert(chunk.index, data);
+  }
+  let missing: Vec<String> = (0..count)
+    .filter(|index| !parts.contains_key(index))
+    .map(|index| (index + 1).to_string())
+    .collect();
+  if !missing.is_empty() {
+    bail!("Missing part(s) {} of {count}", missing.join(", "));
+  }
+  let data = parts.into_values().flatten().collect::<Vec<u8>>();
+  if crc32fast::hash(&data) != checksum {
+    bail!("Checksum mismatch, the reassembled payload is corrupted");
+  }
+  Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_reassemble() {
+    let checksum = crc32fast::hash(b"hello world");
+    let chunk = |index| Chunk {
+      index,
+      count: 2,
+      checksum,
+    };
+    let data = reassemble(vec![(chunk(1), b" world".to_vec()), (chunk(0), b"hello".to_vec())]).unwrap();
+    assert_eq!(data, b"hello world");
+
+    let err = reassemble(vec![(chunk(1), b" world".to_vec())]).unwrap_err();
+    assert_eq!(err.to_string(), "Missing part(s) 1 of 2");
+    assert!(reassemble(vec![(chunk(0), b"hello".to_vec()), (chunk(1), b" there".to_vec())]).is_err());
+  }
+
+  #[test]
+  fn test_manifest() -> Result<()> {
+    let mut manifest = Manifest {
+      payload_id: Some("0d4a1185".to_string()),
+      ..Default::default()
+    };
+    assert_eq!(manifest.summary(), "");
+    manifest.push(Entry {
+      part: Some(1),
+      bytes: Some(5),
+      blake3: Some("ab".repeat(32)),
+      ..Entry::new(Path::new("b.png"), Status::Decoded)
+    });
+    manifest.push(Entry::failed(Path::new("a.png"), &anyhow::anyhow!("corrupt")));
+    manifest.push(Entry::new(Path::new("c.png"), Status::NoData));
+    assert_eq!(manifest.summary(), "1 images succeeded, 1 failed:\n  a.png: corrupt\n");
+
+    let tmp = tempfile::tempdir()?;
+    let path = tmp.path().join("manifest.json");
+    manifest.write(Some(&path))?;
+    let json: serde_json::Value = serde_json::from_slice(&std::fs::read(&path)?)?;
+    assert_eq!(json["payload_id"], "0d4a1185");
+    assert_eq!(json["succeeded"], 1);
+    assert_eq!(json["images"][0]["status"], "failed");
+    assert_eq!(json["images"][1]["part"], 1);
+    assert_eq!(json["images"][1]["blake3"], "ab".repeat(32));
+    assert_eq!(json["images"][2]["status"], "no-data");
+    assert!(json["images"][2].get("error").is_none());
+    Ok(())
+  }
+}
@@ -0,0 +1,95 @@
+use argon2::Argon2;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+use crate::payload::MAC_LEN;
+use crate::utils;
+
+// Secret shared by the sender and the receiver. It permutes which data tensor positions carry which payload bits and
+// XORs them with a keystream, so decoding with the public decoder but without the key yields noise.
+#[derive(Clone)]
+pub struct StegoKey {
+  seed: [u8; 32],
+}
+
+// Fixed rather than random, since the receiver has only the passphrase; it still keeps precomputed tables for other
+// Argon2 users from applying here.
+const SALT: &[u8] = b"steganogan-rs stego key v1";
+
+impl StegoKey {
+  // Argon2id with its default cost (19 MiB, 2 passes), so that guessing passphrases against a stolen image is slow.
+  pub fn new(passphrase: &str) -> Self {
+    let mut seed = [0; 32];
+    Argon2::default()
+      .hash_password_into(passphrase.as_bytes(), SALT, &mut seed)
+      .expect("salt and seed lengths are within Argon2 limits");
+    Self { seed }
+  }
+
+  // MAC of a header and message, keyed separately from the scrambling so that the seed is not used for both.
+  pub fn mac(&self, data: &[u8]) -> [u8; MAC_LEN] {
+    let key = utils::hmac_sha256(&self.seed, b"steganogan-rs mac");
+    utils::hmac_sha256(&key, data)[..MAC_LEN].try_into().unwrap()
+  }
+
+  // ChaCha20 rather than `StdRng`, whose algorithm may change between rand versions and break existing images.
+  fn schedule(&self, len: usize) -> (Vec<usize>, Vec<u8>) {
+    let mut rng = ChaCha20Rng::from_seed(self.seed);
+    let mut permutation: Vec<usize> = (0..len).collect();
+    permutation.shuffle(&mut rng);
+    let mask = (0..len).map(|_| rng.gen::<bool>() as u8).collect();
+    (permutation, mask)
+  }
+
+  // Moves payload bit `i` to position `permutation[i]` after whitening it.
+  pub fn scramble(&self, bits: &[u8]) -> Vec<u8> {
+    let (permutation, mask) = self.schedule(bits.len());
+    let mut scrambled = vec![0; bits.len()];
+    for (i, &position) in permutation.iter().enumerate() {
+      scrambled[position] = bits[i] ^ mask[i];
+    }
+    scrambled
+  }
+
+  pub fn unscramble(&self, bits: &[u8]) -> Vec<u8> {
+    self.unscramble_with(bits, |bit| bit ^ 1)
+  }
+
+  // Same as `unscramble` for the decoder's logits, whose sign is the bit.
+  pub fn unscramble_logits(&self, logits: &[f32]) -> Vec<f32> {
+    self.unscramble_with(logits, |logit| -logit)
+  }
+
+  fn unscramble_with<T: Copy>(&self, values: &[T], flip: impl Fn(T) -> T) -> Vec<T> {
+    let (permutation, mask) = self.schedule(values.len());
+    permutation
+      .iter()
+      .zip(mask)
+      .map(|(&position, mask)| match mask {
+        1 => flip(values[position]),
+        _ => values[position],
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::payload;
+
+  #[test]
+  fn test_scramble() {
+    let data = payload::pack(&payload::Header::default(), b"hello");
+    let bits = payload::tile(&data, 1, 64, 64).unwrap();
+    let key = StegoKey::new("secret");
+    let scrambled = key.scramble(&bits);
+    assert_ne!(scrambled, bits);
+    assert_eq!(key.unscramble(&scrambled), bits);
+    assert_eq!(payload::extract(&key.unscramble(&scrambled)).unwrap().message, "hello");
+    assert!(payload::extract(&scrambled).is_err());
+    assert!(payload::extract(&StegoKey::new("guess").unscramble(&scrambled)).is_err());
+    assert_ne!(key.seed, StegoKey::new("Secret").seed);
+  }
+}
@@ -0,0 +1,212 @@
+use lazy_static::lazy_static;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use rayon::prelude::*;
+
+// Side in pixels of the square tiles `Spread::Tiles` repeats the payload in. Every tile holds the same bits, so the
+// tiles of a cropped or shifted image only need to be found again, not its original size.
+pub const TILE: usize = 128;
+// Known bits at the same positions of every payload channel of every tile, whose correlation with the decoder's logits
+// shows where the tiles start. All channels carry them, as a model can read some channels far better than others.
+const PILOTS: usize = 256;
+// Side in pixels of the square cells of a robust tile, whose pixels all hold the same bit, so that resampling the image
+// off the pixel grid mostly blurs a bit into its own cell rather than into its neighbours.
+const CELL: usize = 2;
+// How far the best phase has to correlate above the average one, in standard deviations, to be taken for the pilots
+// rather than noise.
+const MIN_SCORE: f32 = 6.;
+
+// How the payload bits fill the data positions of a tile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tiling {
+  /// Every payload channel of a pixel holds a bit of its own
+  Plain,
+  /// All payload channels of a cell of `CELL` x `CELL` pixels hold the same bit, whose logits decoding sums, and the
+  /// bits are interleaved over the tile, so that a damaged region costs a few bits of many Reed-Solomon blocks rather
+  /// than whole blocks
+  Robust,
+}
+
+impl Tiling {
+  fn cell(self) -> usize {
+    match self {
+      Tiling::Plain => 1,
+      Tiling::Robust => CELL,
+    }
+  }
+
+  // Positions of a tile, one per cell.
+  fn positions(self) -> usize {
+    (TILE / self.cell()).pow(2)
+  }
+
+  // Position of pixel (y, x) of a tile.
+  fn position(self, (y, x): (usize, usize)) -> usize {
+    let cell = self.cell();
+    (y / cell) * (TILE / cell) + x / cell
+  }
+
+  fn slots(self) -> &'static [Slot] {
+    match self {
+      Tiling::Plain => &SLOTS,
+      Tiling::Robust => &CELL_SLOTS,
+    }
+  }
+}
+
+// What a position in every channel of a tile holds.
+#[derive(Clone, Copy)]
+enum Slot {
+  Data(usize),
+  Pilot(u8),
+}
+
+lazy_static! {
+  static ref SLOTS: Vec<Slot> = slots(TILE * TILE, TILE as u64);
+  static ref CELL_SLOTS: Vec<Slot> = slots(Tiling::Robust.positions(), TILE as u64 + 2);
+  // Stream index of every data position of a robust tile.
+  static ref INTERLEAVE: Vec<usize> = {
+    let mut order: Vec<usize> = (0..Tiling::Robust.positions() - PILOTS).collect();
+    order.shuffle(&mut ChaCha20Rng::seed_from_u64(TILE as u64 + 1));
+    order
+  };
+}
+
+// Scatters the pilots over the `positions` of a tile with ChaCha20, like `StegoKey`, so that they stay where old images
+// have them.
+fn slots(positions: usize, seed: u64) -> Vec<Slot> {
+  let mut rng = ChaCha20Rng::seed_from_u64(seed);
+  let mut slots = vec![Slot::Data(0); positions];
+  let mut placed = 0;
+  while placed < PILOTS {
+    let position = rng.gen_range(0..positions);
+    if matches!(slots[position], Slot::Data(_)) {
+      slots[position] = Slot::Pilot(rng.gen::<bool>() as u8);
+      placed += 1;
+    }
+  }
+  let mut index = 0;
+  for slot in slots.iter_mut() {
+    if let Slot::Data(i) = slot {
+      *i = index;
+      index += 1;
+    }
+  }
+  slots
+}
+
+// Payload bits one tile holds at `depth` bits per pixel.
+pub fn capacity(depth: usize, tiling: Tiling) -> usize {
+  match tiling {
+    Tiling::Plain => depth * (TILE * TILE - PILOTS),
+    Tiling::Robust => tiling.positions() - PILOTS,
+  }
+}
+
+// Index into the stream of a tile of data position `index` in `channel`.
+fn stream_index(channel: usize, index: usize, tiling: Tiling) -> usize {
+  match tiling {
+    Tiling::Plain => channel * (TILE * TILE - PILOTS) + index,
+    Tiling::Robust => INTERLEAVE[index],
+  }
+}
+
+// Payload bits of `(depth, height, width)` that repeat the `capacity(depth, tiling)` bits of `stream` and the pilots in
+// every tile.
+pub fn layout(stream: &[u8], (depth, height, width): (usize, usize, usize), tiling: Tiling) -> Vec<u8> {
+  (0..depth * height * width)
+    .into_par_iter()
+    .map(|i| {
+      let (channel, y, x) = (i / (height * width), i / width % height, i % width);
+      match tiling.slots()[tiling.position((y % TILE, x % TILE))] {
+        Slot::Data(index) => stream[stream_index(channel, index, tiling)],
+        Slot::Pilot(value) => value,
+      }
+    })
+    .collect()
+}
+
+// Sums the logits of `(depth, height, width)` payload bits of all tiles and returns them in stream order, shifted to
+// where the pilots line up. `None` if no shift matches the pilots, for images without tiles.
+pub fn realign(logits: &[f32], (depth, height, width): (usize, usize, usize), tiling: Tiling) -> Option<Vec<f32>> {
+  let mut folded = vec![0f32; depth * TILE * TILE];
+  for (i, logit) in logits.iter().enumerate() {
+    let (channel, y, x) = (i / (height * width), i / width % height, i % width);
+    folded[channel * TILE * TILE + (y % TILE) * TILE + x % TILE] += logit;
+  }
+  let summed: Vec<f32> = (0..TILE * TILE)
+    .map(|pixel| (0..depth).map(|channel| folded[channel * TILE * TILE + pixel]).sum())
+    .collect();
+  // Position in the folded logits of tile pixel (y, x) if the image starts `shift` pixels into a tile
+  let at = |(y, x): (usize, usize), (dy, dx): (usize, usize)| ((y + TILE - dy) % TILE) * TILE + (x + TILE - dx) % TILE;
+  let slot = |pixel: usize| tiling.slots()[tiling.position((pixel / TILE, pixel % TILE))];
+
+  let pilots: Vec<((usize, usize), f32)> = (0..TILE * TILE)
+    .filter_map(|pixel| match slot(pixel) {
+      Slot::Pilot(value) => Some(((pixel / TILE, pixel % TILE), value as f32 * 2. - 1.)),
+      Slot::Data(_) => None,
+    })
+    .collect();
+  let scores: Vec<f32> = (0..TILE * TILE)
+    .into_par_iter()
+    .map(|shift| {
+      let shift = (shift / TILE, shift % TILE);
+      pilots
+        .iter()
+        .map(|&(pixel, sign)| sign * summed[at(pixel, shift)])
+        .sum()
+    })
+    .collect();
+  let mean = scores.iter().sum::<f32>() / scores.len() as f32;
+  let std = (scores.iter().map(|score| (score - mean).powi(2)).sum::<f32>() / scores.len() as f32).sqrt();
+  let (best, score) = scores.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1))?;
+  if score - mean <= MIN_SCORE * std {
+    return None;
+  }
+
+  let shift = (best / TILE, best % TILE);
+  let mut stream = vec![0.; capacity(depth, tiling)];
+  for channel in 0..depth {
+    for pixel in 0..TILE * TILE {
+      if let Slot::Data(index) = slot(pixel) {
+        stream[stream_index(channel, index, tiling)] +=
+          folded[channel * TILE * TILE + at((pixel / TILE, pixel % TILE), shift)];
+      }
+    }
+  }
+  Some(stream)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_realign() {
+    let depth = 2;
+    let shape = (depth, 300, 260);
+    for tiling in [Tiling::Plain, Tiling::Robust] {
+      let stream: Vec<u8> = (0..capacity(depth, tiling)).map(|i| (i * 7 % 5 == 0) as u8).collect();
+      let bits = layout(&stream, shape, tiling);
+      let logits: Vec<f32> = bits.iter().map(|&bit| bit as f32 * 2. - 1.).collect();
+      let signs = |stream: &[f32]| -> Vec<u8> { stream.iter().map(|&logit| (logit > 0.) as u8).collect() };
+      assert_eq!(signs(&realign(&logits, shape, tiling).unwrap()), stream);
+
+      // A crop that starts inside a tile
+      let (top, left, height, width) = (37, 101, 150, 140);
+      let cropped: Vec<f32> = (0..depth)
+        .flat_map(|c| (top..top + height).flat_map(move |y| (left..left + width).map(move |x| (c, y, x))))
+        .map(|(c, y, x)| logits[(c * shape.1 + y) * shape.2 + x])
+        .collect();
+      assert_eq!(
+        signs(&realign(&cropped, (depth, height, width), tiling).unwrap()),
+        stream
+      );
+    }
+
+    let mut rng = ChaCha20Rng::seed_from_u64(0);
+    let noise: Vec<f32> = (0..depth * 200 * 200).map(|_| rng.gen::<f32>() - 0.5).collect();
+    assert!(realign(&noise, (depth, 200, 200), Tiling::Plain).is_none());
+  }
+}
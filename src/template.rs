@@ -0,0 +1,65 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+// Values of the variables in a `-d` template, for the image it goes into.
+pub struct Vars<'a> {
+  pub input: &'a Path,
+  /// 1-based position of the image in the run
+  pub index: usize,
+}
+
+// Expands `{filename}`, `{index}` and `{timestamp}` (Unix seconds) in `template`, with `{{` and `}}` for literal braces.
+pub fn expand(template: &str, vars: &Vars) -> Result<String> {
+  let mut expanded = String::new();
+  let mut rest = template;
+  while let Some(start) = rest.find(['{', '}']) {
+    expanded += &rest[..start];
+    rest = &rest[start..];
+    if rest.starts_with("{{") || rest.starts_with("}}") {
+      expanded += &rest[..1];
+      rest = &rest[2..];
+      continue;
+    }
+    let Some(end) = rest.find('}').filter(|_| rest.starts_with('{')) else {
+      bail!("Unmatched brace in the template '{template}', write {{{{ and }}}} for literal ones");
+    };
+    expanded += &match &rest[1..end] {
+      "filename" => vars
+        .input
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned(),
+      "index" => vars.index.to_string(),
+      "timestamp" => std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs()
+        .to_string(),
+      name => bail!("Unknown template variable {{{name}}}, use {{filename}}, {{index}} or {{timestamp}}"),
+    };
+    rest = &rest[end + 1..];
+  }
+  Ok(expanded + rest)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_expand() -> Result<()> {
+    let vars = Vars {
+      input: Path::new("renders/shot 01.png"),
+      index: 3,
+    };
+    assert_eq!(expand("order:{filename}:{index}", &vars)?, "order:shot 01.png:3");
+    assert_eq!(expand("{{index}} is {index}}}", &vars)?, "{index} is 3}");
+    assert!(expand("{timestamp}", &vars)?.parse::<u64>()? > 1_600_000_000);
+    assert_eq!(expand("no variables", &vars)?, "no variables");
+    assert!(expand("{name}", &vars).is_err());
+    assert!(expand("{index", &vars).is_err());
+    assert!(expand("index}", &vars).is_err());
+    Ok(())
+  }
+}
@@ -0,0 +1,97 @@
+use anyhow::Result;
+use candle_core::{Device, Tensor};
+use image::RgbImage;
+
+const RADIUS: usize = 2;
+// Scales are capped so that a few very busy pixels do not get all the payload energy.
+const MAX_SCALE: f32 = 4.;
+
+// Standard deviation of the luma over the (2 * RADIUS + 1)² window around every pixel, row-major.
+pub fn local_std(img: &RgbImage) -> Vec<f32> {
+  let (w, h) = (img.width() as usize, img.height() as usize);
+  let luma: Vec<f64> = img
+    .pixels()
+    .map(|p| 0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64)
+    .collect();
+  // Summed area tables of the luma and its square, with a leading row and column of zeros
+  let mut sum = vec![0f64; (w + 1) * (h + 1)];
+  let mut sum_sq = vec![0f64; (w + 1) * (h + 1)];
+  for y in 0..h {
+    for x in 0..w {
+      let v = luma[y * w + x];
+      let i = (y + 1) * (w + 1) + x + 1;
+      sum[i] = v + sum[i - 1] + sum[i - w - 1] - sum[i - w - 2];
+      sum_sq[i] = v * v + sum_sq[i - 1] + sum_sq[i - w - 1] - sum_sq[i - w - 2];
+    }
+  }
+  let area = |table: &[f64], (x0, y0, x1, y1): (usize, usize, usize, usize)| {
+    table[y1 * (w + 1) + x1] - table[y0 * (w + 1) + x1] - table[y1 * (w + 1) + x0] + table[y0 * (w + 1) + x0]
+  };
+  (0..h)
+    .flat_map(|y| (0..w).map(move |x| (x, y)))
+    .map(|(x, y)| {
+      let window = (
+        x.saturating_sub(RADIUS),
+        y.saturating_sub(RADIUS),
+        (x + RADIUS + 1).min(w),
+        (y + RADIUS + 1).min(h),
+      );
+      let n = ((window.2 - window.0) * (window.3 - window.1)) as f64;
+      let mean = area(&sum, window) / n;
+      (area(&sum_sq, window) / n - mean * mean).max(0.).sqrt() as f32
+    })
+    .collect()
+}
+
+// Per-pixel scales for the encoder residual, `(std / mean std)^exponent` capped at `MAX_SCALE`: above 1 in busy
+// regions, below 1 in smooth ones where changes show as banding. Returned as a (1, 1, h, w) tensor in the layout of
+// `image_io::to_tensor`.
+pub fn strength_map(img: &RgbImage, exponent: f32, device: &Device) -> Result<Tensor> {
+  let std = local_std(img);
+  let mean = std.iter().sum::<f32>() / std.len().max(1) as f32;
+  let scales: Vec<f32> = std
+    .iter()
+    .map(|&s| match mean > 0. {
+      true => ((s + 1.) / (mean + 1.)).powf(exponent).min(MAX_SCALE),
+      false => 1.,
+    })
+    .collect();
+  let (w, h) = (img.width() as usize, img.height() as usize);
+  Ok(Tensor::from_vec(scales, (1, 1, h, w), device)?)
+}
+
+#[cfg(test)]
+mod tests {
+  use image::Rgb;
+
+  use super::*;
+  use crate::image_io;
+
+  #[test]
+  fn test_strength_map() -> Result<()> {
+    // Noise on the left, flat on the right
+    let img = RgbImage::from_fn(12, 6, |x, y| match x < 6 {
+      true => Rgb([((x * 97 + y * 61) % 256) as u8; 3]),
+      false => Rgb([128; 3]),
+    });
+    let std = local_std(&img);
+    assert!(std[0] > 10. && std[11] == 0.);
+
+    let device = &Device::Cpu;
+    let scales = strength_map(&img, 1., device)?.flatten_all()?.to_vec1::<f32>()?;
+    let columns = image_io::to_tensor(&RgbImage::from_fn(12, 6, |x, _| Rgb([x as u8; 3])), device)?
+      .narrow(1, 0, 1)?
+      .flatten_all()?
+      .to_vec1::<f32>()?;
+    for (scale, column) in scales.iter().zip(columns) {
+      match column {
+        c if c < 3. => assert!(*scale > 1., "{scale} at column {c}"),
+        c if c > 8. => assert!(*scale < 0.2, "{scale} at column {c}"),
+        _ => {}
+      }
+    }
+    let flat = strength_map(&RgbImage::from_pixel(4, 4, Rgb([9; 3])), 2., device)?;
+    assert_eq!(flat.flatten_all()?.to_vec1::<f32>()?, [1.; 16]);
+    Ok(())
+  }
+}
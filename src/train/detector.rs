@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use super::optim::Adam;
+use super::{accuracy, bce_with_logits, random_payload, trainable_vars};
+use crate::data::Dataset;
+use crate::model::detector::Detector;
+use crate::model::encoder::Encoder;
+use crate::weights::{self, ModelConfig};
+use crate::{image_io, rng};
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DetectorMetrics {
+  pub bce: f32,
+  pub accuracy: f32,
+}
+
+// Trains a detector to tell covers from stego images of a fixed encoder. Its accuracy on held out images is a measure
+// of how detectable the encoder is.
+pub struct DetectorTrainer {
+  config: ModelConfig,
+  device: Device,
+  encoder: Encoder,
+  detector: Detector,
+  vars: VarMap,
+  opt: Adam,
+  rng: StdRng,
+}
+
+impl DetectorTrainer {
+  pub fn new(model: &Path, hidden_size: usize, lr: f64, seed: Option<u64>, device: &Device) -> Result<Self> {
+    let config = weights::model_config(model, "encoder")?;
+    let mut encoder_vars = VarMap::new();
+    let encoder = Encoder::from_config(&config, VarBuilder::from_varmap(&encoder_vars, DType::F32, device))?;
+    weights::load(&mut encoder_vars, model, "encoder")?;
+    let vars = VarMap::new();
+    let detector = Detector::new(hidden_size, VarBuilder::from_varmap(&vars, DType::F32, device))?;
+    let opt = Adam::new(trainable_vars(&vars, "detector"), lr, 0.9, 0.999)?;
+    Ok(Self {
+      config: ModelConfig { hidden_size, ..config },
+      device: device.clone(),
+      encoder,
+      detector,
+      vars,
+      opt,
+      rng: rng::from_seed(seed),
+    })
+  }
+
+  // Covers followed by stego images of them, labeled 0 and 1. The stego images are quantized to 8 bits like saved
+  // images, going through the host also keeps the encoder out of the backward pass.
+  fn batch(&self, cover: &Tensor, rng: &mut StdRng) -> Result<(Tensor, Tensor)> {
+    let payload = random_payload(rng, cover, self.config.data_depth)?;
+    let stego = ((self.encoder.forward(cover, &payload)? + 1.)? * 127.5)?;
+    let quantized = image_io::quantize(&stego)?;
+    let stego = ((Tensor::from_vec(quantized, stego.shape(), &self.device)?.to_dtype(DType::F32)? / 127.5)? - 1.)?;
+    let n = cover.dim(0)?;
+    let labels = Tensor::cat(
+      &[
+        Tensor::zeros(n, DType::F32, &self.device)?,
+        Tensor::ones(n, DType::F32, &self.device)?,
+      ],
+      0,
+    )?;
+    Ok((Tensor::cat(&[cover, &stego], 0)?, labels))
+  }
+
+  pub fn step(&mut self, cover: &Tensor) -> Result<DetectorMetrics> {
+    let mut rng = StdRng::from_rng(&mut self.rng)?;
+    let (images, labels) = self.batch(cover, &mut rng)?;
+    let logits = self.detector.forward_t(&images, true)?;
+    let bce = bce_with_logits(&logits, &labels)?;
+    self.opt.backward_step(&bce)?;
+    Ok(DetectorMetrics {
+      bce: bce.to_scalar()?,
+      accuracy: accuracy(&logits, &labels)?,
+    })
+  }
+
+  // Data order is drawn from the trainer's generator, so it follows `--seed` and differs between epochs.
+  pub fn train_epoch(&mut self, dataset: &Dataset) -> Result<DetectorMetrics> {
+    let mut metrics = DetectorMetrics::default();
+    let mut steps = 0;
+    let seed = self.rng.gen();
+    for cover in dataset.batches(seed, &self.device) {
+      let step = self.step(&cover?)?;
+      metrics.bce += step.bce;
+      metrics.accuracy += step.accuracy;
+      steps += 1;
+    }
+    Ok(DetectorMetrics {
+      bce: metrics.bce / steps as f32,
+      accuracy: metrics.accuracy / steps as f32,
+    })
+  }
+
+  pub fn validate(&self, dataset: &Dataset) -> Result<DetectorMetrics> {
+    let mut metrics = DetectorMetrics::default();
+    let mut steps = 0;
+    // Same payloads every time, so that epochs are comparable
+    let mut rng = StdRng::seed_from_u64(0);
+    for cover in dataset.batches(0, &self.device) {
+      let (images, labels) = self.batch(&cover?, &mut rng)?;
+      let logits = self.detector.forward_t(&images, false)?;
+      metrics.bce += bce_with_logits(&logits, &labels)?.to_scalar::<f32>()?;
+      metrics.accuracy += accuracy(&logits, &labels)?;
+      steps += 1;
+    }
+    Ok(DetectorMetrics {
+      bce: metrics.bce / steps as f32,
+      accuracy: metrics.accuracy / steps as f32,
+    })
+  }
+
+  // Writes `detector.safetensors`, whose `hidden_size` metadata is the detector's.
+  pub fn save(&self, dir: &Path, extra: &HashMap<String, String>) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    weights::save(&self.vars, &dir.join("detector.safetensors"), &self.config, extra)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_step() -> Result<()> {
+    let device = &Device::Cpu;
+    let mut trainer = DetectorTrainer::new(Path::new("pretrained"), 4, 1e-3, Some(0), device)?;
+    let cover = Tensor::rand(-1f32, 1f32, (2, 3, 16, 16), device)?;
+    let (images, labels) = trainer.batch(&cover, &mut StdRng::seed_from_u64(0))?;
+    assert_eq!(images.dims4()?, (4, 3, 16, 16));
+    assert_eq!(labels.to_vec1::<f32>()?, [0., 0., 1., 1.]);
+    let running_mean =
+      |trainer: &DetectorTrainer| trainer.vars.data().lock().unwrap()["stem.bn.running_mean"].to_vec1::<f32>();
+    assert_eq!(running_mean(&trainer)?, [0.; 4]);
+    let metrics = trainer.step(&cover)?;
+    assert!(metrics.bce.is_finite() && (0. ..=1.).contains(&metrics.accuracy));
+    // Batch norm learns the statistics of the training batches
+    assert_ne!(running_mean(&trainer)?, [0.; 4]);
+
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path().join("detector");
+    trainer.save(&dir, &HashMap::new())?;
+    assert_eq!(weights::model_config(&dir, "detector")?.hidden_size, 4);
+    Ok(())
+  }
+}
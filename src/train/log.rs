@@ -0,0 +1,217 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use candle_core::Tensor;
+
+use super::Metrics;
+use crate::image_io;
+
+const CSV_HEADER: &str = "step,epoch,encoder_mse,decoder_bce,decoder_acc,rs_bpp,psnr,cover_score,generated_score";
+
+pub struct MetricsLogger {
+  csv: BufWriter<File>,
+  events: Option<EventWriter>,
+  samples: Option<PathBuf>,
+}
+
+impl MetricsLogger {
+  // A resumed run appends to the rows of the run it continues instead of replacing them.
+  pub fn new(dir: &Path, resume: bool, tensorboard: bool, samples: bool) -> Result<Self> {
+    std::fs::create_dir_all(dir)?;
+    let file = OpenOptions::new()
+      .create(true)
+      .write(true)
+      .append(resume)
+      .truncate(!resume)
+      .open(dir.join("metrics.csv"))?;
+    let new = file.metadata()?.len() == 0;
+    let mut csv = BufWriter::new(file);
+    if new {
+      writeln!(csv, "{CSV_HEADER}")?;
+    }
+    let events = if tensorboard {
+      Some(EventWriter::new(dir)?)
+    } else {
+      None
+    };
+    let samples = if samples {
+      std::fs::create_dir_all(dir.join("samples"))?;
+      Some(dir.join("samples"))
+    } else {
+      None
+    };
+    Ok(Self { csv, events, samples })
+  }
+
+  pub fn log(&mut self, step: usize, epoch: usize, metrics: &Metrics) -> Result<()> {
+    writeln!(
+      self.csv,
+      "{step},{epoch},{},{},{},{},{},{},{}",
+      metrics.encoder_mse,
+      metrics.decoder_bce,
+      metrics.decoder_acc,
+      metrics.rs_bpp,
+      metrics.psnr,
+      metrics.cover_score,
+      metrics.generated_score
+    )?;
+    if let Some(events) = self.events.as_mut() {
+      events.write_scalars(
+        step,
+        &[
+          ("encoder_mse", metrics.encoder_mse),
+          ("decoder_bce", metrics.decoder_bce),
+          ("decoder_acc", metrics.decoder_acc),
+          ("rs_bpp", metrics.rs_bpp),
+          ("psnr", metrics.psnr),
+          ("cover_score", metrics.cover_score),
+          ("generated_score", metrics.generated_score),
+        ],
+      )?;
+    }
+    Ok(())
+  }
+
+  pub fn wants_samples(&self) -> bool {
+    self.samples.is_some()
+  }
+
+  pub fn save_samples(&mut self, epoch: usize, cover: &Tensor, generated: &Tensor) -> Result<()> {
+    let Some(dir) = &self.samples else {
+      return Ok(());
+    };
+    tensor_to_image(&cover.get(0)?)?.save(dir.join(format!("epoch{epoch:04}_cover.png")))?;
+    tensor_to_image(&generated.get(0)?)?.save(dir.join(format!("epoch{epoch:04}_stego.png")))?;
+    Ok(())
+  }
+
+  pub fn flush(&mut self) -> Result<()> {
+    self.csv.flush()?;
+    if let Some(events) = self.events.as_mut() {
+      events.file.flush()?;
+    }
+    Ok(())
+  }
+}
+
+fn tensor_to_image(x: &Tensor) -> Result<image::RgbImage> {
+  let (_, h, w) = x.dims3()?;
+  let x = ((x.permute((1, 2, 0))? + 1.)? * 127.5)?;
+  Ok(image::RgbImage::from_raw(w as u32, h as u32, image_io::quantize(&x)?).unwrap())
+}
+
+// Minimal TensorBoard event file writer: TFRecord framing of `Event` protobufs with scalar summaries.
+struct EventWriter {
+  file: BufWriter<File>,
+}
+
+impl EventWriter {
+  fn new(dir: &Path) -> Result<Self> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let file = File::create(dir.join(format!("events.out.tfevents.{now}.steganogan")))?;
+    let mut writer = Self {
+      file: BufWriter::new(file),
+    };
+    let mut event = event_header(0)?;
+    write_bytes_field(&mut event, 3, b"brain.Event:2");
+    writer.write_record(&event)?;
+    Ok(writer)
+  }
+
+  fn write_scalars(&mut self, step: usize, scalars: &[(&str, f32)]) -> Result<()> {
+    let mut summary = Vec::new();
+    for (tag, value) in scalars.iter() {
+      let mut entry = Vec::new();
+      write_bytes_field(&mut entry, 1, tag.as_bytes());
+      entry.push(2 << 3 | 5);
+      entry.extend(value.to_le_bytes());
+      write_bytes_field(&mut summary, 1, &entry);
+    }
+    let mut event = event_header(step)?;
+    write_bytes_field(&mut event, 5, &summary);
+    self.write_record(&event)
+  }
+
+  fn write_record(&mut self, data: &[u8]) -> Result<()> {
+    let len = (data.len() as u64).to_le_bytes();
+    self.file.write_all(&len)?;
+    self.file.write_all(&masked_crc32c(&len).to_le_bytes())?;
+    self.file.write_all(data)?;
+    self.file.write_all(&masked_crc32c(data).to_le_bytes())?;
+    Ok(())
+  }
+}
+
+fn event_header(step: usize) -> Result<Vec<u8>> {
+  let wall_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+  let mut event = vec![1 << 3 | 1];
+  event.extend(wall_time.to_le_bytes());
+  event.push(2 << 3);
+  write_varint(&mut event, step as u64);
+  Ok(event)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+  while value >= 0x80 {
+    out.push(value as u8 | 0x80);
+    value >>= 7;
+  }
+  out.push(value as u8);
+}
+
+fn write_bytes_field(out: &mut Vec<u8>, field: u8, data: &[u8]) {
+  out.push(field << 3 | 2);
+  write_varint(out, data.len() as u64);
+  out.extend(data);
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+  let mut crc = !0u32;
+  for byte in data.iter() {
+    crc ^= *byte as u32;
+    for _ in 0..8 {
+      crc = (crc >> 1) ^ (0x82f63b78 & (crc & 1).wrapping_neg());
+    }
+  }
+  !crc
+}
+
+fn masked_crc32c(data: &[u8]) -> u32 {
+  crc32c(data).rotate_right(15).wrapping_add(0xa282ead8)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_crc32c() {
+    assert_eq!(crc32c(b"123456789"), 0xe3069283);
+  }
+
+  #[test]
+  fn test_varint() {
+    let mut out = Vec::new();
+    write_varint(&mut out, 300);
+    assert_eq!(out, [0xac, 0x02]);
+  }
+
+  #[test]
+  fn test_resume() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path().join("logs");
+    let rows = |resume| -> Result<usize> {
+      let mut logger = MetricsLogger::new(&dir, resume, false, false)?;
+      logger.log(1, 1, &Metrics::default())?;
+      logger.flush()?;
+      Ok(std::fs::read_to_string(dir.join("metrics.csv"))?.lines().count())
+    };
+    assert_eq!(rows(false)?, 2);
+    assert_eq!(rows(true)?, 3);
+    assert_eq!(rows(false)?, 2);
+    Ok(())
+  }
+}
@@ -0,0 +1,728 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use candle_core::{DType, Device, Shape, Tensor, Var};
+use candle_nn::var_builder::SimpleBackend;
+use candle_nn::{Init, VarBuilder, VarMap};
+use clap::{Args, ValueEnum};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+pub mod detector;
+pub mod log;
+pub mod noise;
+pub mod optim;
+
+use self::noise::NoiseLayer;
+use self::optim::{Adam, LossScaler};
+use crate::data::Dataset;
+use crate::model::critic::Critic;
+use crate::model::decoder::Decoder;
+use crate::model::encoder::Encoder;
+use crate::rng;
+use crate::weights::{self, ModelConfig};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LrSchedule {
+  Constant,
+  Step,
+  Cosine,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Precision {
+  F16,
+  Bf16,
+}
+
+impl Precision {
+  fn dtype(self) -> DType {
+    match self {
+      Precision::F16 => DType::F16,
+      Precision::Bf16 => DType::BF16,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct TrainOptions {
+  #[arg(long, default_value_t = 4)]
+  pub epochs: usize,
+  #[arg(long, default_value_t = 1e-4)]
+  pub lr: f64,
+  #[arg(long, default_value_t = 0.9)]
+  pub beta1: f64,
+  #[arg(long, default_value_t = 0.999)]
+  pub beta2: f64,
+  #[arg(long, value_enum, default_value_t = LrSchedule::Constant)]
+  pub lr_schedule: LrSchedule,
+  /// Epochs between learning rate decays for the step schedule
+  #[arg(long, default_value_t = 10)]
+  pub lr_step_size: usize,
+  /// Learning rate decay factor for the step schedule
+  #[arg(long, default_value_t = 0.5)]
+  pub lr_gamma: f64,
+  /// Critic weights are clamped to [-clip, clip] after every critic step
+  #[arg(long, default_value_t = 0.1)]
+  pub critic_clip: f64,
+  /// Keep the critic weights fixed
+  #[arg(long)]
+  pub freeze_critic: bool,
+  /// Train only the encoder and decoder on reconstruction and decoding losses, without the critic
+  #[arg(long, conflicts_with = "freeze_critic")]
+  pub no_critic: bool,
+  /// Noise layers between encoder and decoder, one is picked at random for every batch
+  #[arg(long, value_enum, value_delimiter = ',')]
+  pub noise: Vec<NoiseLayer>,
+  /// Run forward and backward passes in half precision, keeping f32 master weights
+  #[arg(long, value_enum)]
+  pub amp: Option<Precision>,
+  /// Seed for training payloads, noise layers and data order, random by default
+  #[arg(long)]
+  pub seed: Option<u64>,
+}
+
+impl TrainOptions {
+  pub fn lr_at(&self, epoch: usize) -> f64 {
+    match self.lr_schedule {
+      LrSchedule::Constant => self.lr,
+      LrSchedule::Step => self.lr * self.lr_gamma.powi((epoch / self.lr_step_size.max(1)) as i32),
+      LrSchedule::Cosine => {
+        let progress = epoch as f64 / self.epochs.max(1) as f64;
+        self.lr * 0.5 * (1. + (std::f64::consts::PI * progress).cos())
+      }
+    }
+  }
+
+  pub fn metadata(&self) -> HashMap<String, String> {
+    let schedule = self.lr_schedule.to_possible_value().unwrap();
+    let noise: Vec<_> = self
+      .noise
+      .iter()
+      .map(|layer| layer.to_possible_value().unwrap().get_name().to_string())
+      .collect();
+    let amp = self.amp.map_or("none".to_string(), |amp| {
+      amp.to_possible_value().unwrap().get_name().to_string()
+    });
+    HashMap::from([
+      ("train.epochs".to_string(), self.epochs.to_string()),
+      ("train.lr".to_string(), self.lr.to_string()),
+      ("train.beta1".to_string(), self.beta1.to_string()),
+      ("train.beta2".to_string(), self.beta2.to_string()),
+      ("train.lr_schedule".to_string(), schedule.get_name().to_string()),
+      ("train.lr_step_size".to_string(), self.lr_step_size.to_string()),
+      ("train.lr_gamma".to_string(), self.lr_gamma.to_string()),
+      ("train.critic_clip".to_string(), self.critic_clip.to_string()),
+      ("train.freeze_critic".to_string(), self.freeze_critic.to_string()),
+      ("train.no_critic".to_string(), self.no_critic.to_string()),
+      ("train.noise".to_string(), noise.join(",")),
+      ("train.amp".to_string(), amp.to_string()),
+      (
+        "train.seed".to_string(),
+        self.seed.map_or("none".to_string(), |seed| seed.to_string()),
+      ),
+    ])
+  }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Metrics {
+  pub encoder_mse: f32,
+  pub decoder_bce: f32,
+  pub decoder_acc: f32,
+  pub rs_bpp: f32,
+  pub psnr: f32,
+  pub cover_score: f32,
+  pub generated_score: f32,
+}
+
+impl Metrics {
+  fn add(&mut self, other: &Metrics) {
+    self.encoder_mse += other.encoder_mse;
+    self.decoder_bce += other.decoder_bce;
+    self.decoder_acc += other.decoder_acc;
+    self.rs_bpp += other.rs_bpp;
+    self.psnr += other.psnr;
+    self.cover_score += other.cover_score;
+    self.generated_score += other.generated_score;
+  }
+
+  fn scale(&mut self, k: f32) {
+    self.encoder_mse *= k;
+    self.decoder_bce *= k;
+    self.decoder_acc *= k;
+    self.rs_bpp *= k;
+    self.psnr *= k;
+    self.cover_score *= k;
+    self.generated_score *= k;
+  }
+}
+
+pub struct Trainer {
+  config: ModelConfig,
+  options: TrainOptions,
+  device: Device,
+  epoch: usize,
+  step: usize,
+  encoder: Encoder,
+  decoder: Decoder,
+  critic: Critic,
+  encoder_vars: VarMap,
+  decoder_vars: VarMap,
+  critic_vars: VarMap,
+  critic_params: Vec<Var>,
+  coder_opt: Adam,
+  critic_opt: Option<Adam>,
+  scaler: Option<LossScaler>,
+  // ChaCha20 rather than `StdRng` so that `save` can store its position and `resume` continue the same sequence
+  rng: ChaCha20Rng,
+}
+
+impl Trainer {
+  pub fn new(config: ModelConfig, options: &TrainOptions, device: &Device) -> Result<Self> {
+    let encoder_vars = VarMap::new();
+    let decoder_vars = VarMap::new();
+    let critic_vars = VarMap::new();
+    let (encoder, decoder, critic) = build_networks(
+      &config,
+      [&encoder_vars, &decoder_vars, &critic_vars].map(|vars| VarBuilder::from_varmap(vars, DType::F32, device)),
+    )?;
+
+    let (lr, beta1, beta2) = (options.lr_at(0), options.beta1, options.beta2);
+    let coder_vars = [
+      trainable_vars(&encoder_vars, "encoder"),
+      trainable_vars(&decoder_vars, "decoder"),
+    ]
+    .concat();
+    let coder_opt = Adam::new(coder_vars, lr, beta1, beta2)?;
+    let critic_vars_named = trainable_vars(&critic_vars, "critic");
+    let critic_params = critic_vars_named.iter().map(|(_, var)| var.clone()).collect();
+    let critic_opt = if options.freeze_critic || options.no_critic {
+      None
+    } else {
+      Some(Adam::new(critic_vars_named, lr, beta1, beta2)?)
+    };
+
+    Ok(Self {
+      config,
+      options: options.clone(),
+      device: device.clone(),
+      epoch: 0,
+      step: 0,
+      encoder,
+      decoder,
+      critic,
+      encoder_vars,
+      decoder_vars,
+      critic_vars,
+      critic_params,
+      coder_opt,
+      critic_opt,
+      scaler: (options.amp == Some(Precision::F16)).then(LossScaler::default),
+      rng: rng::seeded(options.seed),
+    })
+  }
+
+  pub fn load(&mut self, model: &Path) -> Result<()> {
+    weights::load(&mut self.encoder_vars, model, "encoder")?;
+    weights::load(&mut self.decoder_vars, model, "decoder")?;
+    weights::load(&mut self.critic_vars, model, "critic")?;
+    Ok(())
+  }
+
+  // Starts from the variables of `model` that fit these networks by name and shape, for a model of another
+  // architecture or with other blocks. The others keep their fresh initialization, their count is returned.
+  pub fn load_matching(&mut self, model: &Path) -> Result<usize> {
+    let mut source = [VarMap::new(), VarMap::new(), VarMap::new()];
+    build_networks(
+      &weights::model_config(model, "encoder")?,
+      source
+        .each_ref()
+        .map(|vars| VarBuilder::from_varmap(vars, DType::F32, &self.device)),
+    )?;
+    let mut fresh = 0;
+    let targets = [&self.encoder_vars, &self.decoder_vars, &self.critic_vars];
+    for ((source, target), component) in source.iter_mut().zip(targets).zip(["encoder", "decoder", "critic"]) {
+      weights::load(source, model, component)?;
+      let source = source.data().lock().unwrap();
+      for (name, var) in target.data().lock().unwrap().iter() {
+        match source.get(name).filter(|source| source.dims() == var.dims()) {
+          Some(source) => var.set(source.as_tensor())?,
+          None => fresh += 1,
+        }
+      }
+    }
+    Ok(fresh)
+  }
+
+  pub fn save(&self, dir: &Path, extra: &HashMap<String, String>) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let mut metadata = self.options.metadata();
+    metadata.extend(extra.iter().map(|(k, v)| (k.clone(), v.clone())));
+    for (varmap, component) in [
+      (&self.encoder_vars, "encoder"),
+      (&self.decoder_vars, "decoder"),
+      (&self.critic_vars, "critic"),
+    ] {
+      weights::save(
+        varmap,
+        &dir.join(format!("{component}.safetensors")),
+        &self.config,
+        &metadata,
+      )?;
+    }
+
+    let mut state = self.coder_opt.state("coder")?;
+    if let Some(critic_opt) = self.critic_opt.as_ref() {
+      state.extend(critic_opt.state("critic")?);
+    }
+    state.insert("epoch".to_string(), Tensor::new(&[self.epoch as u32], &self.device)?);
+    state.insert("step".to_string(), Tensor::new(&[self.step as u32], &self.device)?);
+    state.insert("rng_seed".to_string(), Tensor::new(&self.rng.get_seed(), &self.device)?);
+    let position = self.rng.get_word_pos().to_le_bytes();
+    state.insert("rng_position".to_string(), Tensor::new(&position, &self.device)?);
+    candle_core::safetensors::save(&state, dir.join("optimizer.safetensors"))?;
+    Ok(())
+  }
+
+  pub fn resume(&mut self, dir: &Path) -> Result<()> {
+    self.load(dir)?;
+    let state = candle_core::safetensors::load(dir.join("optimizer.safetensors"), &self.device)?;
+    self.coder_opt.load_state("coder", &state)?;
+    if let Some(critic_opt) = self.critic_opt.as_mut() {
+      critic_opt.load_state("critic", &state)?;
+    }
+    let counter = |key: &str| -> Result<usize> {
+      let value = state
+        .get(key)
+        .ok_or_else(|| anyhow!("Missing '{key}' in optimizer state"))?;
+      Ok(value.to_vec1::<u32>()?[0] as usize)
+    };
+    self.epoch = counter("epoch")?;
+    self.step = counter("step")?;
+    // Checkpoints from before the generator was saved keep the fresh one
+    if let (Some(seed), Some(position)) = (state.get("rng_seed"), state.get("rng_position")) {
+      let seed: Vec<u8> = seed.to_vec1()?;
+      let position: Vec<u8> = position.to_vec1()?;
+      self.rng = ChaCha20Rng::from_seed(
+        seed
+          .try_into()
+          .map_err(|_| anyhow!("Invalid 'rng_seed' in optimizer state"))?,
+      );
+      self.rng.set_word_pos(u128::from_le_bytes(
+        position
+          .try_into()
+          .map_err(|_| anyhow!("Invalid 'rng_position' in optimizer state"))?,
+      ));
+    }
+    Ok(())
+  }
+
+  pub fn epoch(&self) -> usize {
+    self.epoch
+  }
+
+  pub fn critic_step(&mut self, cover: &Tensor) -> Result<Metrics> {
+    // A frozen critic or none at all has no step, nor networks to build for it
+    if self.critic_opt.is_none() {
+      return Ok(Metrics::default());
+    }
+    let half = self.half_networks()?;
+    let (encoder, _, critic) = match &half {
+      Some((encoder, decoder, critic)) => (encoder, decoder, critic),
+      None => (&self.encoder, &self.decoder, &self.critic),
+    };
+    let critic_opt = self.critic_opt.as_mut().unwrap();
+    let dtype = self.options.amp.map_or(DType::F32, Precision::dtype);
+    let cover = cover.to_dtype(dtype)?;
+    let payload = random_payload(&mut self.rng, &cover, self.config.data_depth)?;
+    let generated = encoder.forward_t(&cover, &payload, true)?;
+    let cover_score = critic.forward_t(&cover, true)?.mean_all()?.to_dtype(DType::F32)?;
+    let generated_score = critic.forward_t(&generated, true)?.mean_all()?.to_dtype(DType::F32)?;
+    backward_step(critic_opt, &mut self.scaler, &(&cover_score - &generated_score)?)?;
+    let clip = self.options.critic_clip;
+    for var in self.critic_params.iter() {
+      var.set(&var.clamp(-clip, clip)?)?;
+    }
+    Ok(Metrics {
+      cover_score: cover_score.to_scalar()?,
+      generated_score: generated_score.to_scalar()?,
+      ..Default::default()
+    })
+  }
+
+  pub fn coder_step(&mut self, cover: &Tensor) -> Result<Metrics> {
+    let half = self.half_networks()?;
+    let (encoder, decoder, critic) = match &half {
+      Some((encoder, decoder, critic)) => (encoder, decoder, critic),
+      None => (&self.encoder, &self.decoder, &self.critic),
+    };
+    let dtype = self.options.amp.map_or(DType::F32, Precision::dtype);
+    let payload = random_payload(&mut self.rng, cover, self.config.data_depth)?;
+    let generated = encoder
+      .forward_t(&cover.to_dtype(dtype)?, &payload.to_dtype(dtype)?, true)?
+      .to_dtype(DType::F32)?;
+    let (noised, payload) = noise::apply(&self.options.noise, &generated, cover, &payload, &mut self.rng)?;
+    let noised = self.config.preprocess.encoded_to_decoder(&noised)?;
+    let decoded = decoder
+      .forward_t(&noised.to_dtype(dtype)?, true)?
+      .to_dtype(DType::F32)?;
+    let encoder_mse = (&generated - cover)?.sqr()?.mean_all()?;
+    let decoder_bce = bce_with_logits(&decoded, &payload)?;
+    let mut loss = ((&encoder_mse * 100.)? + &decoder_bce)?;
+    let mut generated_score = 0.;
+    if !self.options.no_critic {
+      let score = critic
+        .forward_t(&generated.to_dtype(dtype)?, true)?
+        .mean_all()?
+        .to_dtype(DType::F32)?;
+      loss = (loss + &score)?;
+      generated_score = score.to_scalar()?;
+    }
+    backward_step(&mut self.coder_opt, &mut self.scaler, &loss)?;
+    let encoder_mse = encoder_mse.to_scalar::<f32>()?;
+    let decoder_acc = accuracy(&decoded, &payload)?;
+    Ok(Metrics {
+      encoder_mse,
+      psnr: 10. * (4. / encoder_mse).log10(),
+      decoder_bce: decoder_bce.to_scalar()?,
+      decoder_acc,
+      rs_bpp: rs_bpp(decoder_acc, self.config.data_depth),
+      generated_score,
+      ..Default::default()
+    })
+  }
+
+  // Half precision copies of the networks for mixed precision steps, see `MixedPrecision`.
+  fn half_networks(&self) -> Result<Option<(Encoder, Decoder, Critic)>> {
+    let Some(amp) = self.options.amp else {
+      return Ok(None);
+    };
+    let vbs = [&self.encoder_vars, &self.decoder_vars, &self.critic_vars]
+      .map(|vars| MixedPrecision::var_builder(vars, amp.dtype(), &self.device));
+    Ok(Some(build_networks(&self.config, vbs)?))
+  }
+
+  // Metrics on the validation set, which also calibrates the decision thresholds `save` stores with the model.
+  pub fn validate(&mut self, dataset: &Dataset) -> Result<Metrics> {
+    let mut metrics = Metrics::default();
+    let mut calibration = weights::Calibration::new(self.config.data_depth);
+    let mut steps = 0;
+    // Same payloads every time, so that epochs are comparable
+    let mut rng = StdRng::seed_from_u64(0);
+    for cover in dataset.batches(0, &self.device) {
+      let cover = cover?;
+      let payload = random_payload(&mut rng, &cover, self.config.data_depth)?;
+      let generated = self.encoder.forward(&cover, &payload)?;
+      let decoded = self
+        .decoder
+        .forward(&self.config.preprocess.encoded_to_decoder(&generated)?)?;
+      let encoder_mse = (&generated - &cover)?.sqr()?.mean_all()?.to_scalar::<f32>()?;
+      let decoder_acc = accuracy(&decoded, &payload)?;
+      calibration.add(&decoded, &payload)?;
+      metrics.add(&Metrics {
+        encoder_mse,
+        psnr: 10. * (4. / encoder_mse).log10(),
+        decoder_bce: bce_with_logits(&decoded, &payload)?.to_scalar()?,
+        decoder_acc,
+        rs_bpp: rs_bpp(decoder_acc, self.config.data_depth),
+        cover_score: self.critic.forward(&cover)?.mean_all()?.to_scalar()?,
+        generated_score: self.critic.forward(&generated)?.mean_all()?.to_scalar()?,
+      });
+      steps += 1;
+    }
+    metrics.scale(1. / steps as f32);
+    self.config.thresholds = calibration.thresholds();
+    Ok(metrics)
+  }
+
+  pub fn generate(&self, cover: &Tensor) -> Result<Tensor> {
+    let payload = random_payload(&mut StdRng::seed_from_u64(0), cover, self.config.data_depth)?;
+    Ok(self.encoder.forward(cover, &payload)?)
+  }
+
+  pub fn train_epoch(
+    &mut self,
+    dataset: &Dataset,
+    mut on_step: impl FnMut(usize, &Metrics) -> Result<()>,
+  ) -> Result<Metrics> {
+    let lr = self.options.lr_at(self.epoch);
+    self.coder_opt.set_learning_rate(lr);
+    if let Some(critic_opt) = self.critic_opt.as_mut() {
+      critic_opt.set_learning_rate(lr);
+    }
+    self.epoch += 1;
+    let mut metrics = Metrics::default();
+    let mut steps = 0;
+    // From the generator, so that the order follows `--seed` and a resumed run continues it
+    let seed = self.rng.gen();
+    for cover in dataset.batches(seed, &self.device) {
+      let cover = cover?;
+      let critic = self.critic_step(&cover)?;
+      let coder = self.coder_step(&cover)?;
+      let step_metrics = Metrics {
+        cover_score: critic.cover_score,
+        ..coder
+      };
+      self.step += 1;
+      on_step(self.step, &step_metrics)?;
+      metrics.add(&step_metrics);
+      steps += 1;
+    }
+    metrics.scale(1. / steps as f32);
+    Ok(metrics)
+  }
+}
+
+fn build_networks(
+  config: &ModelConfig,
+  [encoder_vb, decoder_vb, critic_vb]: [VarBuilder; 3],
+) -> Result<(Encoder, Decoder, Critic)> {
+  let encoder = Encoder::from_config(config, encoder_vb)?;
+  let decoder = Decoder::from_config(config, decoder_vb)?;
+  let critic = Critic::new(config.hidden_size, critic_vb)?;
+  Ok((encoder, decoder, critic))
+}
+
+// Builds the networks from casts of the variables rather than the variables themselves, so that gradients flow back to
+// the f32 master weights.
+pub(crate) fn cast_var_builder(varmap: &VarMap, dtype: DType, device: &Device) -> Result<VarBuilder<'static>> {
+  let vars = varmap.data().lock().unwrap();
+  let tensors = vars
+    .iter()
+    .map(|(name, var)| Ok((name.clone(), var.as_tensor().to_dtype(dtype)?)))
+    .collect::<Result<HashMap<_, _>>>()?;
+  Ok(VarBuilder::from_tensors(tensors, dtype, device))
+}
+
+// Casts of the variables like `cast_var_builder`, except for the running statistics of batch norm: those are the f32
+// variables themselves, which batch norm updates in f32 whatever the precision of its input, so that mixed precision
+// steps update the statistics of the model.
+struct MixedPrecision(HashMap<String, Tensor>);
+
+impl MixedPrecision {
+  fn var_builder(varmap: &VarMap, dtype: DType, device: &Device) -> VarBuilder<'static> {
+    let vars = varmap.data().lock().unwrap();
+    let tensors = vars.iter().map(|(name, var)| (name.clone(), var.as_tensor().clone()));
+    VarBuilder::from_backend(Box::new(Self(tensors.collect())), dtype, device.clone())
+  }
+}
+
+impl SimpleBackend for MixedPrecision {
+  fn get(&self, shape: Shape, name: &str, _: Init, dtype: DType, device: &Device) -> candle_core::Result<Tensor> {
+    let tensor = self.get_unchecked(name, dtype, device)?;
+    if tensor.shape() != &shape {
+      candle_core::bail!(
+        "shape mismatch for {name}: expected {shape:?}, got {:?}",
+        tensor.shape()
+      );
+    }
+    Ok(tensor)
+  }
+
+  fn get_unchecked(&self, name: &str, dtype: DType, device: &Device) -> candle_core::Result<Tensor> {
+    let tensor = self
+      .0
+      .get(name)
+      .ok_or_else(|| candle_core::Error::CannotFindTensor { path: name.to_string() })?;
+    match is_running_stat(name) {
+      true => Ok(tensor.clone()),
+      false => tensor.to_device(device)?.to_dtype(dtype),
+    }
+  }
+
+  fn contains_tensor(&self, name: &str) -> bool {
+    self.0.contains_key(name)
+  }
+}
+
+fn is_running_stat(name: &str) -> bool {
+  name.ends_with("running_mean") || name.ends_with("running_var")
+}
+
+fn backward_step(opt: &mut Adam, scaler: &mut Option<LossScaler>, loss: &Tensor) -> Result<()> {
+  match scaler {
+    Some(scaler) => {
+      scaler.backward_step(opt, loss)?;
+    }
+    None => opt.backward_step(loss)?,
+  }
+  Ok(())
+}
+
+fn random_payload(rng: &mut impl Rng, cover: &Tensor, data_depth: usize) -> Result<Tensor> {
+  let (n, _, h, w) = cover.dims4()?;
+  let payload = rng::uniform_tensor(rng, (n, data_depth, h, w), cover.device())?;
+  Ok(payload.ge(0.5)?.to_dtype(cover.dtype())?)
+}
+
+fn trainable_vars(varmap: &VarMap, prefix: &str) -> Vec<(String, Var)> {
+  let vars = varmap.data().lock().unwrap();
+  let mut vars: Vec<_> = vars
+    .iter()
+    .filter(|(name, _)| !is_running_stat(name))
+    .map(|(name, var)| (format!("{prefix}.{name}"), var.clone()))
+    .collect();
+  vars.sort_by(|(a, _), (b, _)| a.cmp(b));
+  vars
+}
+
+fn bce_with_logits(logits: &Tensor, target: &Tensor) -> Result<Tensor> {
+  let loss = ((logits.relu()? - (logits * target)?)? + (logits.abs()?.neg()?.exp()? + 1.)?.log()?)?;
+  Ok(loss.mean_all()?)
+}
+
+fn accuracy(logits: &Tensor, target: &Tensor) -> Result<f32> {
+  let predicted = logits.ge(0.)?.to_dtype(DType::F32)?;
+  Ok(predicted.eq(target)?.to_dtype(DType::F32)?.mean_all()?.to_scalar()?)
+}
+
+// Reed-Solomon bits per pixel: the payload rate left after error correction at the given bit accuracy.
+fn rs_bpp(accuracy: f32, data_depth: usize) -> f32 {
+  data_depth as f32 * (2. * accuracy - 1.)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::attention::Attention;
+  use crate::model::Arch;
+  use crate::preprocess::Preprocess;
+
+  #[test]
+  fn test_bce_with_logits() -> Result<()> {
+    let device = &Device::Cpu;
+    let logits = Tensor::new(&[0f32, 2., -2.], device)?;
+    let target = Tensor::new(&[1f32, 1., 0.], device)?;
+    let loss = bce_with_logits(&logits, &target)?;
+    assert_eq!(candle_core::test_utils::to_vec0_round(&loss, 4)?, 0.3157);
+    Ok(())
+  }
+
+  #[test]
+  fn test_steps() -> Result<()> {
+    let device = &Device::Cpu;
+    let config = ModelConfig {
+      arch: Arch::Dense,
+      data_depth: 2,
+      hidden_size: 4,
+      preprocess: Preprocess::default(),
+      attention: Attention::None,
+      num_blocks: 2,
+      separable: false,
+      thresholds: Vec::new(),
+    };
+    let options = TrainOptions {
+      epochs: 1,
+      lr: 1e-3,
+      beta1: 0.9,
+      beta2: 0.999,
+      lr_schedule: LrSchedule::Constant,
+      lr_step_size: 1,
+      lr_gamma: 1.,
+      critic_clip: 0.1,
+      freeze_critic: false,
+      no_critic: false,
+      noise: vec![],
+      amp: None,
+      seed: None,
+    };
+    let mut trainer = Trainer::new(config.clone(), &options, device)?;
+    let cover = Tensor::rand(-1f32, 1f32, (2, 3, 16, 16), device)?;
+    trainer.critic_step(&cover)?;
+    for var in trainer.critic_params.iter() {
+      assert!(var.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()? <= 0.1);
+    }
+    let metrics = trainer.coder_step(&cover)?;
+    assert!(metrics.encoder_mse.is_finite() && metrics.decoder_bce.is_finite());
+    assert!((0. ..=1.).contains(&metrics.decoder_acc));
+
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path().join("checkpoint");
+    trainer.epoch = 3;
+    trainer.save(&dir, &HashMap::new())?;
+    let mut resumed = Trainer::new(config.clone(), &options, device)?;
+    resumed.resume(&dir)?;
+    assert_eq!((resumed.epoch(), resumed.step), (3, trainer.step));
+    assert_eq!(
+      resumed.coder_opt.state("coder")?.len(),
+      trainer.coder_opt.state("coder")?.len()
+    );
+    assert_eq!(resumed.rng.gen::<u64>(), trainer.rng.gen::<u64>());
+    let config = ModelConfig {
+      attention: Attention::Se,
+      ..config
+    };
+    // Only the two convolutions of the attention blocks of the three conv blocks of each network are new
+    assert_eq!(
+      Trainer::new(config, &options, device)?.load_matching(&dir)?,
+      2 * 3 * 2 * 2
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn test_no_critic() -> Result<()> {
+    let device = &Device::Cpu;
+    let config = ModelConfig {
+      arch: Arch::Dense,
+      data_depth: 1,
+      hidden_size: 4,
+      preprocess: Preprocess::default(),
+      attention: Attention::None,
+      num_blocks: 2,
+      separable: false,
+      thresholds: Vec::new(),
+    };
+    #[derive(clap::Parser)]
+    struct Cli {
+      #[command(flatten)]
+      train: TrainOptions,
+    }
+    let options = <Cli as clap::Parser>::parse_from(["train", "--no-critic", "--amp", "f16"]).train;
+    let mut trainer = Trainer::new(config, &options, device)?;
+    assert!(trainer.critic_opt.is_none());
+    let cover = Tensor::rand(-1f32, 1f32, (1, 3, 16, 16), device)?;
+    let metrics = trainer.coder_step(&cover)?;
+    assert_eq!(metrics.generated_score, 0.);
+
+    // The half precision step updated the running statistics of the model
+    let vars = trainer.encoder_vars.data().lock().unwrap();
+    let (_, mean) = vars.iter().find(|(name, _)| name.ends_with("running_mean")).unwrap();
+    assert_eq!(mean.dtype(), DType::F32);
+    assert!(mean.abs()?.sum_all()?.to_scalar::<f32>()? > 0.);
+    Ok(())
+  }
+
+  #[test]
+  fn test_rs_bpp() {
+    assert_eq!(rs_bpp(1., 4), 4.);
+    assert_eq!(rs_bpp(0.75, 4), 2.);
+    assert_eq!(rs_bpp(0.5, 4), 0.);
+  }
+
+  #[test]
+  fn test_lr_schedule() {
+    let mut options = TrainOptions {
+      epochs: 10,
+      lr: 1.,
+      beta1: 0.9,
+      beta2: 0.999,
+      lr_schedule: LrSchedule::Step,
+      lr_step_size: 4,
+      lr_gamma: 0.5,
+      critic_clip: 0.1,
+      freeze_critic: false,
+      no_critic: false,
+      noise: vec![],
+      amp: None,
+      seed: None,
+    };
+    assert_eq!([0, 3, 4, 8].map(|epoch| options.lr_at(epoch)), [1., 1., 0.5, 0.25]);
+    options.lr_schedule = LrSchedule::Cosine;
+    assert_eq!(options.lr_at(0), 1.);
+    assert!((options.lr_at(5) - 0.5).abs() < 1e-9);
+  }
+}
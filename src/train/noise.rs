@@ -0,0 +1,191 @@
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use clap::ValueEnum;
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::rng;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NoiseLayer {
+  /// Additive Gaussian noise
+  Gaussian,
+  /// Gaussian blur
+  Blur,
+  /// Replace a random subset of stego pixels with cover pixels
+  Dropout,
+  /// Random crop (the payload is cropped along with the image)
+  Crop,
+  /// Downscale and upscale back
+  Resize,
+  /// Differentiable JPEG approximation that drops high DCT frequencies
+  Jpeg,
+}
+
+const GAUSSIAN_STD: f64 = 0.1;
+const BLUR_SIGMA: f64 = 1.;
+const BLUR_SIZE: usize = 5;
+const DROPOUT_KEEP: f64 = 0.7;
+const CROP_MIN: f64 = 0.5;
+const JPEG_KEEP_LUMA: usize = 5;
+const JPEG_KEEP_CHROMA: usize = 3;
+
+// Applies one randomly picked noise layer to a batch of stego images, HiDDeN-style.
+// Returns the noised images and the payload they should still decode to.
+pub fn apply(
+  layers: &[NoiseLayer],
+  generated: &Tensor,
+  cover: &Tensor,
+  payload: &Tensor,
+  rng: &mut impl Rng,
+) -> Result<(Tensor, Tensor)> {
+  let Some(layer) = layers.choose(rng) else {
+    return Ok((generated.clone(), payload.clone()));
+  };
+  let (_, _, h, w) = generated.dims4()?;
+  let noised = match layer {
+    NoiseLayer::Gaussian => {
+      (generated + (rng::normal_tensor(rng, generated.shape(), generated.device())? * GAUSSIAN_STD)?)?
+    }
+    NoiseLayer::Blur => blur(generated, BLUR_SIZE, BLUR_SIGMA)?,
+    NoiseLayer::Dropout => {
+      let mask = rng::uniform_tensor(rng, (1, 1, h, w), generated.device())?
+        .lt(DROPOUT_KEEP)?
+        .to_dtype(DType::F32)?;
+      (generated.broadcast_mul(&mask)? + cover.broadcast_mul(&(1. - mask)?)?)?
+    }
+    NoiseLayer::Crop => {
+      let (ch, cw) = (
+        rng.gen_range((h as f64 * CROP_MIN) as usize..=h),
+        rng.gen_range((w as f64 * CROP_MIN) as usize..=w),
+      );
+      let (y, x) = (rng.gen_range(0..=h - ch), rng.gen_range(0..=w - cw));
+      let crop = |t: &Tensor| t.narrow(2, y, ch)?.narrow(3, x, cw);
+      return Ok((crop(generated)?, crop(payload)?));
+    }
+    NoiseLayer::Resize => generated.avg_pool2d(2)?.upsample_nearest2d(h, w)?,
+    NoiseLayer::Jpeg => jpeg_mask(generated, JPEG_KEEP_LUMA, JPEG_KEEP_CHROMA)?,
+  };
+  Ok((noised, payload.clone()))
+}
+
+fn blur(x: &Tensor, size: usize, sigma: f64) -> Result<Tensor> {
+  let (n, c, h, w) = x.dims4()?;
+  let half = (size / 2) as f64;
+  let weights: Vec<f32> = (0..size * size)
+    .map(|i| {
+      let (dy, dx) = ((i / size) as f64 - half, (i % size) as f64 - half);
+      (-(dx * dx + dy * dy) / (2. * sigma * sigma)).exp() as f32
+    })
+    .collect();
+  let sum: f32 = weights.iter().sum();
+  let kernel = (Tensor::from_vec(weights, (1, 1, size, size), x.device())? / sum as f64)?;
+  let blurred = x.reshape((n * c, 1, h, w))?.conv2d(&kernel, size / 2, 1, 1, 1)?;
+  Ok(blurred.reshape((n, c, h, w))?)
+}
+
+// JPEG-Mask: convert to YCbCr, take the 8x8 block DCT, zero all but the lowest `keep x keep` frequencies of each
+// channel and transform back. Sides that are not a multiple of 8 are padded by repeating the last row and column, as
+// JPEG encoders do for partial blocks, and cropped back afterwards.
+fn jpeg_mask(x: &Tensor, keep_luma: usize, keep_chroma: usize) -> Result<Tensor> {
+  let (_, _, height, width) = x.dims4()?;
+  let x = x
+    .pad_with_same(2, 0, height.next_multiple_of(8) - height)?
+    .pad_with_same(3, 0, width.next_multiple_of(8) - width)?;
+  let (n, c, h, w) = x.dims4()?;
+  let device = x.device();
+  let to_ycbcr = Tensor::new(
+    &[
+      [0.299f32, 0.587, 0.114],
+      [-0.168736, -0.331264, 0.5],
+      [0.5, -0.418688, -0.081312],
+    ],
+    device,
+  )?;
+  let to_rgb = Tensor::new(
+    &[[1f32, 0., 1.402], [1., -0.344136, -0.714136], [1., 1.772, 0.]],
+    device,
+  )?;
+  let basis = dct_basis(device)?;
+  let mask = Tensor::stack(
+    &[
+      frequency_mask(keep_luma, device)?,
+      frequency_mask(keep_chroma, device)?,
+      frequency_mask(keep_chroma, device)?,
+    ],
+    0,
+  )?;
+
+  let (bh, bw) = (h / 8, w / 8);
+  let ycbcr = mix_channels(&x, &to_ycbcr)?;
+  let blocks = ycbcr
+    .reshape((n, c, bh, 8, bw, 8))?
+    .permute((0, 2, 4, 1, 3, 5))?
+    .reshape((n * bh * bw, c, 64))?;
+  let coefficients = blocks.broadcast_matmul(&basis.t()?)?.broadcast_mul(&mask)?;
+  let blocks = coefficients.broadcast_matmul(&basis)?;
+  let ycbcr = blocks
+    .reshape((n, bh, bw, c, 8, 8))?
+    .permute((0, 3, 1, 4, 2, 5))?
+    .reshape((n, c, h, w))?;
+  Ok(
+    mix_channels(&ycbcr, &to_rgb)?
+      .narrow(2, 0, height)?
+      .narrow(3, 0, width)?,
+  )
+}
+
+fn mix_channels(x: &Tensor, matrix: &Tensor) -> Result<Tensor> {
+  let (n, c, h, w) = x.dims4()?;
+  let mixed = matrix.broadcast_matmul(&x.reshape((n, c, h * w))?)?;
+  Ok(mixed.reshape((n, c, h, w))?)
+}
+
+// Orthonormal 8x8 DCT-II basis, one flattened 8x8 pattern per row.
+fn dct_basis(device: &Device) -> Result<Tensor> {
+  let scale = |k: usize| if k == 0 { (1f64 / 8.).sqrt() } else { 0.5 };
+  let cos = |k: usize, i: usize| ((2 * i + 1) as f64 * k as f64 * std::f64::consts::PI / 16.).cos();
+  let basis: Vec<f32> = (0..64 * 64)
+    .map(|idx| {
+      let (freq, pos) = (idx / 64, idx % 64);
+      let (u, v, y, x) = (freq / 8, freq % 8, pos / 8, pos % 8);
+      (scale(u) * scale(v) * cos(u, y) * cos(v, x)) as f32
+    })
+    .collect();
+  Ok(Tensor::from_vec(basis, (64, 64), device)?)
+}
+
+fn frequency_mask(keep: usize, device: &Device) -> Result<Tensor> {
+  let mask: Vec<f32> = (0..64).map(|i| (i / 8 < keep && i % 8 < keep) as u8 as f32).collect();
+  Ok(Tensor::from_vec(mask, 64, device)?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_jpeg_mask() -> Result<()> {
+    let device = &Device::Cpu;
+    let x = Tensor::rand(-1f32, 1f32, (2, 3, 16, 8), device)?;
+    let max_diff = |a: &Tensor, b: &Tensor| -> Result<f32> { Ok((a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar()?) };
+    assert!(max_diff(&jpeg_mask(&x, 8, 8)?, &x)? < 1e-4);
+    assert!(max_diff(&jpeg_mask(&x, 2, 1)?, &x)? > 0.1);
+    let x = x.narrow(2, 0, 13)?.narrow(3, 0, 6)?;
+    assert!(max_diff(&jpeg_mask(&x, 8, 8)?, &x)? < 1e-4);
+    Ok(())
+  }
+
+  #[test]
+  fn test_apply() -> Result<()> {
+    let device = &Device::Cpu;
+    let generated = Tensor::rand(-1f32, 1f32, (2, 3, 16, 16), device)?;
+    let cover = Tensor::rand(-1f32, 1f32, (2, 3, 16, 16), device)?;
+    let payload = Tensor::zeros((2, 4, 16, 16), DType::F32, device)?;
+    for layer in NoiseLayer::value_variants() {
+      let (noised, target) = apply(&[*layer], &generated, &cover, &payload, &mut rng::from_seed(Some(0)))?;
+      assert_eq!(noised.dims()[2..], target.dims()[2..]);
+    }
+    Ok(())
+  }
+}
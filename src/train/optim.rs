@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use candle_core::backprop::GradStore;
+use candle_core::{DType, Tensor, Var};
+
+struct AdamParam {
+  name: String,
+  var: Var,
+  m: Var,
+  v: Var,
+}
+
+// Adam with named parameters, so that its moments can be saved to and restored from a checkpoint.
+pub struct Adam {
+  params: Vec<AdamParam>,
+  lr: f64,
+  beta1: f64,
+  beta2: f64,
+  eps: f64,
+  step: usize,
+}
+
+impl Adam {
+  pub fn new(vars: Vec<(String, Var)>, lr: f64, beta1: f64, beta2: f64) -> Result<Self> {
+    let params = vars
+      .into_iter()
+      .map(|(name, var)| {
+        Ok(AdamParam {
+          name,
+          m: Var::zeros(var.shape(), var.dtype(), var.device())?,
+          v: Var::zeros(var.shape(), var.dtype(), var.device())?,
+          var,
+        })
+      })
+      .collect::<Result<Vec<_>>>()?;
+    Ok(Self {
+      params,
+      lr,
+      beta1,
+      beta2,
+      eps: 1e-8,
+      step: 0,
+    })
+  }
+
+  pub fn set_learning_rate(&mut self, lr: f64) {
+    self.lr = lr;
+  }
+
+  pub fn backward_step(&mut self, loss: &Tensor) -> Result<()> {
+    let grads = loss.backward()?;
+    self.step(&grads)
+  }
+
+  pub fn step(&mut self, grads: &GradStore) -> Result<()> {
+    self.step += 1;
+    let scale_m = 1. / (1. - self.beta1.powi(self.step as i32));
+    let scale_v = 1. / (1. - self.beta2.powi(self.step as i32));
+    for param in self.params.iter() {
+      let Some(grad) = grads.get(&param.var) else {
+        continue;
+      };
+      let m = ((param.m.as_tensor() * self.beta1)? + (grad * (1. - self.beta1))?)?;
+      let v = ((param.v.as_tensor() * self.beta2)? + (grad.sqr()? * (1. - self.beta2))?)?;
+      let update = ((&m * scale_m)? / ((&v * scale_v)?.sqrt()? + self.eps)?)?;
+      param.var.set(&(param.var.as_tensor() - (update * self.lr)?)?)?;
+      param.m.set(&m)?;
+      param.v.set(&v)?;
+    }
+    Ok(())
+  }
+
+  pub fn state(&self, prefix: &str) -> Result<HashMap<String, Tensor>> {
+    let mut state = HashMap::new();
+    for param in self.params.iter() {
+      state.insert(format!("{prefix}.{}.m", param.name), param.m.as_tensor().clone());
+      state.insert(format!("{prefix}.{}.v", param.name), param.v.as_tensor().clone());
+    }
+    let step = Tensor::new(&[self.step as u32], self.params[0].var.device())?;
+    state.insert(format!("{prefix}.step"), step);
+    Ok(state)
+  }
+
+  pub fn load_state(&mut self, prefix: &str, state: &HashMap<String, Tensor>) -> Result<()> {
+    let get = |key: String| {
+      state
+        .get(&key)
+        .ok_or_else(|| anyhow!("Missing '{key}' in optimizer state"))
+    };
+    for param in self.params.iter() {
+      param
+        .m
+        .set(&get(format!("{prefix}.{}.m", param.name))?.to_device(param.var.device())?)?;
+      param
+        .v
+        .set(&get(format!("{prefix}.{}.v", param.name))?.to_device(param.var.device())?)?;
+    }
+    self.step = get(format!("{prefix}.step"))?.to_vec1::<u32>()?[0] as usize;
+    Ok(())
+  }
+}
+
+const SCALE_GROWTH_INTERVAL: usize = 2000;
+
+// Dynamic loss scaling for half precision: the loss is scaled up before backpropagation so that small gradients do
+// not underflow, steps with overflowing gradients are skipped and the scale is lowered.
+pub struct LossScaler {
+  scale: f64,
+  good_steps: usize,
+}
+
+impl Default for LossScaler {
+  fn default() -> Self {
+    Self {
+      scale: 65536.,
+      good_steps: 0,
+    }
+  }
+}
+
+impl LossScaler {
+  // Returns false if the step was skipped because of non-finite gradients.
+  pub fn backward_step(&mut self, opt: &mut Adam, loss: &Tensor) -> Result<bool> {
+    let mut grads = (loss.to_dtype(DType::F32)? * self.scale)?.backward()?;
+    let mut finite = true;
+    for param in opt.params.iter() {
+      let Some(grad) = grads.remove(&param.var) else {
+        continue;
+      };
+      let grad = (grad.to_dtype(DType::F32)? / self.scale)?;
+      finite &= grad.sum_all()?.to_scalar::<f32>()?.is_finite();
+      grads.insert(&param.var, grad);
+    }
+    if !finite {
+      self.scale /= 2.;
+      self.good_steps = 0;
+      return Ok(false);
+    }
+    opt.step(&grads)?;
+    self.good_steps += 1;
+    if self.good_steps == SCALE_GROWTH_INTERVAL {
+      self.scale *= 2.;
+      self.good_steps = 0;
+    }
+    Ok(true)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use candle_core::Device;
+
+  use super::*;
+
+  #[test]
+  fn test_minimize() -> Result<()> {
+    let x = Var::new(&[3f32, -2.], &Device::Cpu)?;
+    let mut adam = Adam::new(vec![("x".to_string(), x.clone())], 0.1, 0.9, 0.999)?;
+    for _ in 0..200 {
+      adam.backward_step(&x.as_tensor().sqr()?.sum_all()?)?;
+    }
+    assert!(x.as_tensor().abs()?.max(0)?.to_scalar::<f32>()? < 0.1);
+
+    let state = adam.state("opt")?;
+    let mut restored = Adam::new(vec![("x".to_string(), x.clone())], 0.1, 0.9, 0.999)?;
+    restored.load_state("opt", &state)?;
+    assert_eq!(restored.step, 200);
+    Ok(())
+  }
+
+  #[test]
+  fn test_loss_scaler() -> Result<()> {
+    let x = Var::new(&[1f32, -1.], &Device::Cpu)?;
+    let mut adam = Adam::new(vec![("x".to_string(), x.clone())], 0.1, 0.9, 0.999)?;
+    let mut scaler = LossScaler::default();
+    let loss = x.as_tensor().to_dtype(DType::F16)?.sqr()?.sum_all()?;
+    assert!(!scaler.backward_step(&mut adam, &loss)?);
+    assert_eq!(scaler.scale, 32768.);
+    assert_eq!(x.as_tensor().to_vec1::<f32>()?, [1., -1.]);
+
+    let mut scaler = LossScaler {
+      scale: 1024.,
+      good_steps: 0,
+    };
+    assert!(scaler.backward_step(&mut adam, &loss)?);
+    assert!(x.as_tensor().abs()?.max(0)?.to_scalar::<f32>()? < 1.);
+    Ok(())
+  }
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+
+use image::imageops::{self, FilterType};
+use image::{Rgb, RgbImage};
+
+// Light edit a shared image may have gone through, which `Codec::decode_search` undoes before decoding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transform {
+  Identity,
+  FlipHorizontal,
+  FlipVertical,
+  /// Rotated clockwise by this many degrees, keeping the size
+  Rotate(f32),
+  /// Resized by this factor
+  Scale(f32),
+}
+
+impl fmt::Display for Transform {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Transform::Identity => write!(f, "no edit"),
+      Transform::FlipHorizontal => write!(f, "horizontal flip"),
+      Transform::FlipVertical => write!(f, "vertical flip"),
+      Transform::Rotate(degrees) => write!(f, "rotation by {degrees:+}°"),
+      Transform::Scale(scale) => write!(f, "scaling to {}%", (scale * 100.).round()),
+    }
+  }
+}
+
+impl Transform {
+  // Edits `--search-transforms` tries, the unedited image first.
+  pub fn search() -> Vec<Self> {
+    let mut transforms = vec![Transform::Identity, Transform::FlipHorizontal, Transform::FlipVertical];
+    transforms.extend([-3., -2., -1., 1., 2., 3.].map(Transform::Rotate));
+    transforms.extend([0.9, 0.95, 1.05, 1.1].map(Transform::Scale));
+    transforms
+  }
+
+  pub fn apply(&self, img: &RgbImage) -> RgbImage {
+    match *self {
+      Transform::Identity => img.clone(),
+      Transform::FlipHorizontal => imageops::flip_horizontal(img),
+      Transform::FlipVertical => imageops::flip_vertical(img),
+      Transform::Rotate(degrees) => rotate(img, degrees),
+      Transform::Scale(scale) => {
+        let (w, h) = img.dimensions();
+        let size = |v: u32| ((v as f32 * scale).round() as u32).max(1);
+        imageops::resize(img, size(w), size(h), FilterType::Triangle)
+      }
+    }
+  }
+
+  // Maps an image that went through this edit back to how it was, as far as the edit can be undone.
+  pub fn undo(&self, img: &RgbImage) -> RgbImage {
+    match *self {
+      Transform::Rotate(degrees) => rotate(img, -degrees),
+      Transform::Scale(scale) => Transform::Scale(1. / scale).apply(img),
+      // Flips are their own inverse
+      transform => transform.apply(img),
+    }
+  }
+}
+
+// Rotates clockwise around the center with bilinear sampling. The size stays the same, corners that come from outside
+// the image repeat its edge pixels.
+fn rotate(img: &RgbImage, degrees: f32) -> RgbImage {
+  let (w, h) = img.dimensions();
+  let (sin, cos) = degrees.to_radians().sin_cos();
+  let (cx, cy) = ((w as f32 - 1.) / 2., (h as f32 - 1.) / 2.);
+  let pixel = |x: i64, y: i64| img.get_pixel(x.clamp(0, w as i64 - 1) as u32, y.clamp(0, h as i64 - 1) as u32);
+  RgbImage::from_fn(w, h, |x, y| {
+    let (dx, dy) = (x as f32 - cx, y as f32 - cy);
+    // Source position: the output position rotated back
+    let (sx, sy) = (cx + dx * cos + dy * sin, cy - dx * sin + dy * cos);
+    let (x0, y0) = (sx.floor(), sy.floor());
+    let (fx, fy) = (sx - x0, sy - y0);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let mut out = [0; 3];
+    for (c, value) in out.iter_mut().enumerate() {
+      let top = pixel(x0, y0)[c] as f32 * (1. - fx) + pixel(x0 + 1, y0)[c] as f32 * fx;
+      let bottom = pixel(x0, y0 + 1)[c] as f32 * (1. - fx) + pixel(x0 + 1, y0 + 1)[c] as f32 * fx;
+      *value = (top * (1. - fy) + bottom * fy).round().clamp(0., 255.) as u8;
+    }
+    Rgb(out)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_undo() {
+    let img = RgbImage::from_fn(60, 40, |x, y| Rgb([(x * 4) as u8, (y * 6) as u8, ((x + y) * 2) as u8]));
+    for transform in Transform::search() {
+      let restored = transform.undo(&transform.apply(&img));
+      assert_eq!(restored.dimensions(), img.dimensions(), "{transform}");
+      // Away from the edges, where rotation repeats pixels, the edits undo up to interpolation
+      let error = (10..50)
+        .flat_map(|x| (10..30).map(move |y| (x, y)))
+        .map(|(x, y)| {
+          let (a, b) = (img.get_pixel(x, y), restored.get_pixel(x, y));
+          (0..3).map(|c| (a[c] as i32 - b[c] as i32).abs()).max().unwrap()
+        })
+        .max()
+        .unwrap();
+      assert!(error <= 3, "{transform}: {error}");
+    }
+    assert_eq!(Transform::Rotate(-2.).to_string(), "rotation by -2°");
+  }
+}
@@ -1,78 +1,85 @@
 use std::collections::BTreeMap;
 
-use anyhow::Result;
+use bitvec::prelude::*;
 use candle_nn::VarMap;
-use lazy_static::lazy_static;
-
-const CHUNK_SIZE: usize = 5;
-const ENCODED_SIZE: usize = 30;
-lazy_static! {
-  static ref RS_ENC: reed_solomon::Encoder = reed_solomon::Encoder::new(ENCODED_SIZE - CHUNK_SIZE);
-  static ref RS_DEC: reed_solomon::Decoder = reed_solomon::Decoder::new(ENCODED_SIZE - CHUNK_SIZE);
-}
-
-pub fn bytes_to_bits(data: &[u8]) -> Vec<u8> {
-  data
-    .iter()
-    .flat_map(|byte| {
-      let mut byte = *byte;
-      let mut bits = Vec::new();
-      for _ in 0..8 {
-        bits.push(byte & 1);
-        byte >>= 1;
-      }
-      bits
-    })
-    .collect()
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::ecc::{self, Correction};
+use crate::error::Result;
+
+pub(crate) const CHUNK_SIZE: usize = 5;
+pub(crate) const ENCODED_SIZE: usize = 30;
+
+// Packed bits in the order they are embedded: bytes in order, least significant bit first.
+pub type Bits = BitVec<u8, Lsb0>;
+
+pub fn bytes_to_bits(data: &[u8]) -> Bits {
+  Bits::from_slice(data)
 }
 
-pub fn bytes_to_encoded_bits(data: &[u8]) -> Vec<u8> {
-  let compressed =
-    miniz_oxide::deflate::compress_to_vec(data, miniz_oxide::deflate::CompressionLevel::DefaultLevel as u8);
-  compressed
-    .chunks(CHUNK_SIZE)
-    .flat_map(|chunk| RS_ENC.encode(chunk).to_vec())
-    .flat_map(|mut byte| {
-      let mut bits = Vec::new();
-      for _ in 0..8 {
-        bits.push(byte & 1);
-        byte >>= 1;
-      }
-      bits
-    })
-    .collect()
+// Reed-Solomon codes the data in chunks with the standard code, compression is up to the caller (see `payload::pack`).
+pub fn bytes_to_encoded_bits(data: &[u8]) -> Bits {
+  Bits::from_vec(ecc::encode(&*ecc::STANDARD, data))
+}
+
+// Endlessly repeats the bits as 0/1 values, the layout of the payload tensor, so it can be filled without building
+// the repeated bit vector first.
+pub fn cycle_bits(bits: &BitSlice<u8, Lsb0>) -> impl Iterator<Item = u8> + '_ {
+  bits.iter().cycle().map(|bit| *bit as u8)
 }
 
 pub fn encoded_bytes_to_data(bytes: &[u8]) -> Result<Vec<u8>> {
-  let mut decoded = Vec::with_capacity(bytes.len() / ENCODED_SIZE * CHUNK_SIZE);
-  for chunk in bytes.chunks(ENCODED_SIZE) {
-    let decoded_chunk: Vec<u8> = match RS_DEC.correct(chunk, None) {
-      Ok(decoded_chunk) => decoded_chunk.iter().take(CHUNK_SIZE).copied().collect(),
-      Err(_) => chunk.iter().take(CHUNK_SIZE).copied().collect(),
-    };
-    decoded.extend(decoded_chunk);
-  }
+  Ok(correct(bytes).0)
+}
 
-  match miniz_oxide::inflate::decompress_to_vec(&decoded) {
-    Ok(decompressed) => Ok(decompressed),
-    Err(err) => Ok(err.output),
+pub fn correct(bytes: &[u8]) -> (Vec<u8>, Correction) {
+  ecc::correct(&*ecc::STANDARD, bytes)
+}
+
+// HMAC-SHA256 (RFC 2104) on top of sha2.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+  let mut block = [0u8; 64];
+  if key.len() > block.len() {
+    block[..32].copy_from_slice(&Sha256::digest(key));
+  } else {
+    block[..key.len()].copy_from_slice(key);
   }
+  let inner = Sha256::new()
+    .chain_update(block.map(|byte| byte ^ 0x36))
+    .chain_update(data)
+    .finalize();
+  Sha256::new()
+    .chain_update(block.map(|byte| byte ^ 0x5c))
+    .chain_update(inner)
+    .finalize()
+    .into()
+}
+
+// Lowercase hex, as hashes are printed.
+pub fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{byte:02x}")).collect()
 }
 
 pub fn bits_to_bytes(bits: &[u8]) -> Vec<u8> {
   bits
-    .chunks(8)
+    .par_chunks(8)
     .map(|byte| byte.iter().enumerate().map(|(i, bit)| bit << i).sum())
     .collect()
 }
 
-pub fn split_bytes<'a>(bytes: &'a [u8], delimeter: &[u8]) -> Vec<&'a [u8]> {
-  let idxs: Vec<usize> = bytes
-    .windows(4)
+// Start indices of every occurrence of the 4 byte delimiter, overlapping ones included.
+pub fn find_delimiters(bytes: &[u8], delimeter: &[u8]) -> Vec<usize> {
+  bytes
+    .par_windows(4)
     .enumerate()
     .filter(|(_, window)| *window == delimeter)
     .map(|(idx, _)| idx)
-    .collect();
+    .collect()
+}
+
+pub fn split_bytes<'a>(bytes: &'a [u8], delimeter: &[u8]) -> Vec<&'a [u8]> {
+  let idxs = find_delimiters(bytes, delimeter);
   let mut parts = Vec::new();
   let mut cur = bytes;
   for idx in idxs.iter().rev() {
@@ -118,12 +125,21 @@ fn tree_to_string(tree: &BTreeMap<String, TreeNode>, indent: usize, s: &mut Stri
 
 pub fn varmap_to_string(varmap: &VarMap) -> String {
   let varmap = varmap.data().lock().unwrap();
-  let mut vars: Vec<_> = varmap.iter().collect();
+  tensor_tree(
+    varmap
+      .iter()
+      .map(|(path, var)| (path.as_str(), format!("{:?}", var.shape()))),
+  )
+}
+
+// Indented tree of dotted tensor names, one level per name part, with `description` after the last part.
+pub fn tensor_tree<'a>(tensors: impl Iterator<Item = (&'a str, String)>) -> String {
+  let mut vars: Vec<_> = tensors.collect();
   vars.sort_by_key(|(prefix, _)| *prefix);
 
   let mut tree: BTreeMap<String, TreeNode> = BTreeMap::new();
 
-  for (path, var) in vars.iter() {
+  for (path, description) in vars {
     let path_parts: Vec<&str> = path.split('.').collect();
     let mut current = &mut tree;
     for part in path_parts.iter() {
@@ -133,10 +149,7 @@ pub fn varmap_to_string(varmap: &VarMap) -> String {
         .as_branch_mut()
         .unwrap();
     }
-    current.insert(
-      path_parts.last().unwrap().to_string(),
-      TreeNode::Leaf(format!("{:?}", var.shape())),
-    );
+    current.insert(path_parts.last().unwrap().to_string(), TreeNode::Leaf(description));
   }
 
   let mut s = String::new();
@@ -152,7 +165,26 @@ mod tests {
   fn test() -> Result<()> {
     let data = vec![1, 2, 3, 4, 5, 6];
     let bits = bytes_to_encoded_bits(&data);
-    assert_eq!(data, encoded_bytes_to_data(&bits_to_bytes(&bits))?);
+    assert_eq!(data, encoded_bytes_to_data(bits.as_raw_slice())?);
     Ok(())
   }
+
+  #[test]
+  fn test_bits() {
+    let bits = bytes_to_bits(&[0b1011, 0xff]);
+    assert_eq!(bits.len(), 16);
+    let unpacked: Vec<u8> = cycle_bits(&bits).take(20).collect();
+    assert_eq!(unpacked[..4], [1, 1, 0, 1]);
+    assert_eq!(unpacked[16..], [1, 1, 0, 1]);
+    assert_eq!(bits_to_bytes(&unpacked[..16]), [0b1011, 0xff]);
+  }
+
+  #[test]
+  fn test_hmac() {
+    // Test case 2 of RFC 4231
+    assert_eq!(
+      to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+      "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+  }
 }
@@ -0,0 +1,127 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::{bail, ensure, Result};
+use steganogan_rs::codec::{Codec, DecodeOptions, EncodeOptions};
+use steganogan_rs::payload::Payload;
+use steganogan_rs::{y4m, SteganoError};
+
+use crate::{daemon, DecodeArgs, EncodeArgs};
+
+// Videos are streamed frame by frame as Y4M instead of being read as images.
+pub fn is_video(path: &Path) -> bool {
+  path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("y4m"))
+}
+
+// Hides the payload in every frame, with the frame index in its header, and writes a 4:4:4 Y4M stream.
+pub fn encode(
+  args: &EncodeArgs,
+  codec: &Codec,
+  message: &[u8],
+  options: EncodeOptions,
+  input: &Path,
+  output: &Path,
+) -> Result<daemon::Output> {
+  if args.resize.is_some() || args.max_dim.is_some() {
+    bail!("--resize and --max-dim are not supported for videos");
+  }
+  ensure!(is_video(output), "Videos are written as Y4M, use a .y4m output");
+  let mut reader = y4m::Reader::new(BufReader::new(File::open(input)?))?;
+  let mut writer = y4m::Writer::new(BufWriter::new(File::create(output)?), &reader.params)?;
+  let mut frames = 0;
+  while let Some(frame) = reader.next_frame()? {
+    let options = EncodeOptions {
+      frame: Some(frames),
+      ..options
+    };
+    writer.write_frame(&codec.encode_with(&frame, message, &options)?)?;
+    frames += 1;
+  }
+  writer.finish()?;
+  Ok(daemon::Output {
+    stdout: format!("done, {frames} frames\n"),
+    ..Default::default()
+  })
+}
+
+// Decodes every frame on its own and returns the payload most of them agree on, with the frame size. Frames that
+// were re-encoded too lossily simply drop out of the vote.
+pub fn decode(
+  args: &DecodeArgs,
+  codec: &Codec,
+  input: &Path,
+  options: &DecodeOptions,
+  output: &mut daemon::Output,
+) -> Result<(Result<Payload>, (u32, u32))> {
+  ensure!(!args.all_candidates, "--all-candidates is not supported for videos");
+  let mut reader = y4m::Reader::new(BufReader::new(File::open(input)?))?;
+  let mut recovered = Vec::new();
+  let mut frames = 0;
+  while let Some(frame) = reader.next_frame()? {
+    frames += 1;
+    match codec.decode_with(&frame, options) {
+      Ok(payload) => recovered.push(payload),
+      Err(err) if matches!(err.downcast_ref(), Some(SteganoError::DecodeFailed)) => {}
+      Err(err) => return Err(err),
+    }
+  }
+  let size = (reader.params.width, reader.params.height);
+  let Some((payload, votes)) = vote(recovered) else {
+    return Ok((Err(SteganoError::DecodeFailed.into()), size));
+  };
+  output.stderr += &format!("recovered from {votes} of {frames} frames\n");
+  // The highest frame index tells how long the video was when it was encoded
+  if let Some(encoded) = payload.header.as_ref().and_then(|header| header.frame) {
+    if encoded >= frames {
+      output.stderr += &format!(
+        "warning: the payload was embedded into frame {} but the video has only {frames}, frames were dropped\n",
+        encoded + 1
+      );
+    }
+  }
+  Ok((Ok(payload), size))
+}
+
+// The most frequent message and how many frames hold it. The payload returned is the one with the highest frame
+// index.
+fn vote(recovered: Vec<Payload>) -> Option<(Payload, usize)> {
+  let mut groups: Vec<(Payload, usize)> = Vec::new();
+  for payload in recovered {
+    let frame = |payload: &Payload| payload.header.as_ref().and_then(|header| header.frame);
+    match groups.iter_mut().find(|(group, _)| group.data == payload.data) {
+      Some((group, votes)) => {
+        *votes += 1;
+        if frame(&payload) > frame(group) {
+          *group = payload;
+        }
+      }
+      None => groups.push((payload, 1)),
+    }
+  }
+  groups.into_iter().max_by_key(|(_, votes)| *votes)
+}
+
+#[cfg(test)]
+mod tests {
+  use steganogan_rs::payload::Header;
+
+  use super::*;
+
+  #[test]
+  fn test_vote() {
+    let payload = |message: &str, frame| Payload {
+      header: Some(Header {
+        frame: Some(frame),
+        ..Default::default()
+      }),
+      message: message.to_string(),
+      data: message.as_bytes().to_vec(),
+    };
+    let recovered = vec![payload("hello", 0), payload("hellp", 1), payload("hello", 4)];
+    let (winner, votes) = vote(recovered).unwrap();
+    assert_eq!((winner.message.as_str(), votes), ("hello", 2));
+    assert_eq!(winner.header.unwrap().frame, Some(4));
+    assert!(vote(Vec::new()).is_none());
+  }
+}
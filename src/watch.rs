@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use image::ImageFormat;
+
+use crate::daemon;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Size and modification time of a file, which stop changing once it is completely written.
+type Stamp = (u64, SystemTime);
+
+// Images that appear in or are replaced in a directory. The directory is polled rather than subscribed to, which
+// works the same on every platform and on network shares.
+pub struct Watcher {
+  dir: PathBuf,
+  /// Images seen changed on the last poll, returned once they stay the same for another one
+  pending: HashMap<PathBuf, Stamp>,
+  done: HashMap<PathBuf, Stamp>,
+}
+
+impl Watcher {
+  // Images already in `dir` are left alone.
+  pub fn new(dir: &Path) -> Result<Self> {
+    Ok(Self {
+      dir: dir.to_path_buf(),
+      pending: HashMap::new(),
+      done: images(dir)?.into_iter().collect(),
+    })
+  }
+
+  // Images that changed since the previous poll but not since, so that files still being copied in wait for the next.
+  pub fn poll(&mut self) -> Result<Vec<PathBuf>> {
+    let images = images(&self.dir)?;
+    let mut ready = Vec::new();
+    let mut pending = HashMap::new();
+    for (path, stamp) in &images {
+      if self.done.get(path) == Some(stamp) {
+        continue;
+      }
+      if self.pending.get(path) == Some(stamp) {
+        ready.push(path.clone());
+      } else {
+        pending.insert(path.clone(), *stamp);
+      }
+    }
+    self.pending = pending;
+    // Deleted images are forgotten, so that one of the same name counts as new
+    self.done = images
+      .into_iter()
+      .filter(|(path, _)| !self.pending.contains_key(path))
+      .collect();
+    Ok(ready)
+  }
+}
+
+fn images(dir: &Path) -> Result<Vec<(PathBuf, Stamp)>> {
+  let mut images = Vec::new();
+  for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to watch {}", dir.display()))? {
+    let path = entry?.path();
+    if ImageFormat::from_path(&path).is_err() {
+      continue;
+    }
+    // Files can vanish between listing and reading them
+    let Ok(metadata) = std::fs::metadata(&path) else {
+      continue;
+    };
+    if metadata.is_file() {
+      images.push((path, (metadata.len(), metadata.modified()?)));
+    }
+  }
+  images.sort();
+  Ok(images)
+}
+
+// Runs `process` on every image that appears in `dir` until interrupted, printing its output. A failure is printed
+// and the watch goes on.
+pub fn run(dir: &Path, mut process: impl FnMut(&Path) -> Result<daemon::Output>) -> Result<()> {
+  let mut watcher = Watcher::new(dir)?;
+  eprintln!("watching {} for new images", dir.display());
+  loop {
+    for path in watcher.poll()? {
+      eprintln!("{}:", path.display());
+      match process(&path) {
+        Ok(output) => output.print(),
+        Err(err) => eprintln!("error: {err:#}"),
+      }
+    }
+    std::thread::sleep(POLL_INTERVAL);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_watcher() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    std::fs::write(dir.join("old.png"), b"old")?;
+    let mut watcher = Watcher::new(dir)?;
+    assert!(watcher.poll()?.is_empty());
+
+    std::fs::write(dir.join("new.png"), b"new")?;
+    std::fs::write(dir.join("notes.txt"), b"not an image")?;
+    assert!(watcher.poll()?.is_empty());
+    assert_eq!(watcher.poll()?, vec![dir.join("new.png")]);
+    assert!(watcher.poll()?.is_empty());
+
+    // Still being written on the first poll
+    std::fs::write(dir.join("old.png"), b"replaced")?;
+    assert!(watcher.poll()?.is_empty());
+    std::fs::write(dir.join("old.png"), b"replaced, done")?;
+    assert!(watcher.poll()?.is_empty());
+    assert_eq!(watcher.poll()?, vec![dir.join("old.png")]);
+    Ok(())
+  }
+}
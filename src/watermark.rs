@@ -0,0 +1,142 @@
+use anyhow::{anyhow, Result};
+use candle_core::Tensor;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+use crate::engine::SteganoGan;
+use crate::error::SteganoError;
+use crate::signing::from_hex;
+use crate::utils::to_hex;
+
+const PREFIX: &str = "wm1";
+
+// Provenance record hidden in an image as `wm1:<creator>:<timestamp>:<signature>`, where the Ed25519 signature
+// covers everything before it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watermark {
+  pub creator: String,
+  /// Seconds since the Unix epoch
+  pub timestamp: u64,
+}
+
+impl Watermark {
+  pub fn now(creator: &str) -> Self {
+    let timestamp = std::time::SystemTime::now()
+      .duration_since(std::time::UNIX_EPOCH)
+      .map_or(0, |elapsed| elapsed.as_secs());
+    Self {
+      creator: creator.to_string(),
+      timestamp,
+    }
+  }
+
+  pub fn sign(&self, key: &SigningKey) -> String {
+    let signed = format!("{PREFIX}:{}:{}", self.creator, self.timestamp);
+    let signature = key.sign(signed.as_bytes());
+    format!("{signed}:{}", to_hex(&signature.to_bytes()))
+  }
+
+  // Parses a decoded message and checks its signature, failing if it is not a watermark or was not signed by `key`.
+  pub fn verify(message: &str, key: &VerifyingKey) -> Result<Self> {
+    let parse = || {
+      let (signed, signature) = message.rsplit_once(':')?;
+      let (rest, timestamp) = signed.rsplit_once(':')?;
+      let creator = rest.strip_prefix(PREFIX)?.strip_prefix(':')?;
+      let signature = Signature::from_bytes(&from_hex(signature)?.try_into().ok()?);
+      Some((signed, signature, creator, timestamp.parse().ok()?))
+    };
+    let (signed, signature, creator, timestamp) = parse().ok_or_else(|| anyhow!("The message is not a watermark"))?;
+    key
+      .verify(signed.as_bytes(), &signature)
+      .map_err(|_| anyhow!("Invalid watermark signature for creator '{creator}'"))?;
+    Ok(Self {
+      creator: creator.to_string(),
+      timestamp,
+    })
+  }
+}
+
+// Post-generation watermarking for image generation services: hides an ID in every image of a generated batch and
+// reads them back for audits, each in a single forward pass over the batch.
+pub struct Watermarker {
+  engine: SteganoGan,
+}
+
+impl Watermarker {
+  pub fn new(engine: SteganoGan) -> Self {
+    Self { engine }
+  }
+
+  // `images` is an (n, 3, h, w) tensor with values in [0, 1], like the output of a diffusion model, and `ids` has one
+  // ID per image. The watermarked batch keeps the shape, dtype and device of `images`.
+  pub fn embed(&self, images: &Tensor, ids: &[impl AsRef<str>]) -> Result<Tensor> {
+    let messages: Vec<&[u8]> = ids.iter().map(|id| id.as_ref().as_bytes()).collect();
+    self.engine.encode_batch(images, &messages)
+  }
+
+  // The ID of every image of the batch, `None` for images without a readable one.
+  pub fn audit(&self, images: &Tensor) -> Result<Vec<Option<String>>> {
+    self
+      .engine
+      .decode_batch(images)?
+      .into_iter()
+      .map(|payload| match payload {
+        Ok(payload) => Ok(Some(payload.message)),
+        Err(err) if matches!(err.downcast_ref(), Some(SteganoError::DecodeFailed)) => Ok(None),
+        Err(err) => Err(err),
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::signing::generate_key;
+
+  #[test]
+  fn test_sign_verify() -> Result<()> {
+    let mut rng = crate::rng::from_seed(Some(0));
+    let key = generate_key(&mut rng);
+    let watermark = Watermark {
+      creator: "studio:alice".to_string(),
+      timestamp: 1700000000,
+    };
+    let message = watermark.sign(&key);
+    assert_eq!(Watermark::verify(&message, &key.verifying_key())?, watermark);
+
+    let forged = message.replace("alice", "mallory");
+    assert!(Watermark::verify(&forged, &key.verifying_key()).is_err());
+    assert!(Watermark::verify(&message, &generate_key(&mut rng).verifying_key()).is_err());
+    assert!(Watermark::verify("hello", &key.verifying_key()).is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn test_watermarker() -> Result<()> {
+    let engine = SteganoGan::builder().build()?;
+    let images = Tensor::arange(0f32, 2. * 3. * 48. * 64., &candle_core::Device::Cpu)?
+      .reshape((2, 3, 48, 64))?
+      .sin()?
+      .affine(0.4, 0.5)?;
+    let ids = ["gen-0001", "gen-0002"];
+    let watermarker = Watermarker::new(engine);
+    let marked = watermarker.embed(&images, &ids)?;
+    assert_eq!(marked.dims(), images.dims());
+    assert!(watermarker.embed(&images, &ids[..1]).is_err());
+
+    // The batch is watermarked and audited like each image on its own
+    let audit = watermarker.audit(&marked)?;
+    for (i, id) in ids.iter().enumerate() {
+      let single = watermarker.engine.encode_tensor(&images.get(i)?, id.as_bytes())?;
+      let diff = (&single - marked.get(i)?)?.abs()?.max_all()?.to_scalar::<f32>()?;
+      assert!(diff < 1e-4);
+      let decoded = watermarker
+        .engine
+        .decode_tensor(&single)
+        .ok()
+        .map(|payload| payload.message);
+      assert_eq!(audit[i], decoded);
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,846 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use candle_core::quantized::gguf_file;
+use candle_core::Tensor;
+use candle_nn::VarMap;
+use clap::ValueEnum;
+use safetensors::tensor::TensorView;
+
+use crate::error::{self, SteganoError};
+use crate::model::attention::Attention;
+use crate::model::Arch;
+use crate::preprocess::Preprocess;
+
+#[cfg(feature = "embedded-weights")]
+pub const EMBEDDED_ENCODER: &[u8] =
+  include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/pretrained/encoder.safetensors"));
+#[cfg(feature = "embedded-weights")]
+pub const EMBEDDED_DECODER: &[u8] =
+  include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/pretrained/decoder.safetensors"));
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelConfig {
+  pub arch: Arch,
+  pub data_depth: usize,
+  pub hidden_size: usize,
+  pub preprocess: Preprocess,
+  pub attention: Attention,
+  /// Conv blocks between the first block and the output, or U-Net levels
+  pub num_blocks: usize,
+  /// Depthwise-separable convolutions in the conv blocks, for fast CPU inference
+  pub separable: bool,
+  /// Calibrated decision threshold of every data depth channel, zero for all when empty
+  pub thresholds: Vec<f32>,
+}
+
+impl Default for ModelConfig {
+  fn default() -> Self {
+    Self {
+      arch: Arch::Dense,
+      data_depth: 8,
+      hidden_size: 32,
+      preprocess: Preprocess::default(),
+      attention: Attention::None,
+      num_blocks: 2,
+      separable: false,
+      thresholds: Vec::new(),
+    }
+  }
+}
+
+impl ModelConfig {
+  pub(crate) fn to_metadata(&self) -> HashMap<String, String> {
+    let arch = self.arch.to_possible_value().unwrap();
+    let attention = self.attention.to_possible_value().unwrap();
+    let mut metadata = HashMap::from([
+      ("arch".to_string(), arch.get_name().to_string()),
+      ("data_depth".to_string(), self.data_depth.to_string()),
+      ("hidden_size".to_string(), self.hidden_size.to_string()),
+      self.preprocess.to_metadata(),
+      ("attention".to_string(), attention.get_name().to_string()),
+      ("num_blocks".to_string(), self.num_blocks.to_string()),
+      ("separable".to_string(), self.separable.to_string()),
+    ]);
+    if !self.thresholds.is_empty() {
+      metadata.insert("thresholds".to_string(), thresholds_metadata(&self.thresholds));
+    }
+    metadata
+  }
+
+  fn from_metadata(metadata: &HashMap<String, String>) -> Result<Self> {
+    let get = |key: &str| {
+      metadata
+        .get(key)
+        .ok_or_else(|| anyhow!("Missing '{key}' in model metadata"))
+    };
+    let thresholds = match metadata.get("thresholds") {
+      Some(thresholds) => thresholds.split(',').map(str::parse).collect::<Result<Vec<f32>, _>>()?,
+      None => Vec::new(),
+    };
+    let data_depth = get("data_depth")?.parse()?;
+    if !thresholds.is_empty() && thresholds.len() != data_depth {
+      bail!(
+        "{} thresholds in model metadata for data depth {data_depth}",
+        thresholds.len()
+      );
+    }
+    Ok(Self {
+      arch: Arch::from_str(get("arch")?, true).map_err(|err| anyhow!(err))?,
+      data_depth,
+      hidden_size: get("hidden_size")?.parse()?,
+      preprocess: Preprocess::from_metadata(metadata.get("decode_range"))?,
+      attention: match metadata.get("attention") {
+        Some(attention) => Attention::from_str(attention, true).map_err(|err| anyhow!(err))?,
+        None => Attention::None,
+      },
+      num_blocks: metadata.get("num_blocks").map_or(Ok(2), |blocks| blocks.parse())?,
+      separable: metadata
+        .get("separable")
+        .map_or(Ok(false), |separable| separable.parse())?,
+      thresholds,
+    })
+  }
+
+  // Floats a forward pass holds per pixel at its peak, an upper estimate from the widest layer, the last one of the
+  // encoder: its concatenated input, the copy `Tensor::cat` makes of it and the 3x3 patches a convolution unfolds it
+  // into, next to its output.
+  pub fn activations_per_pixel(&self) -> usize {
+    let widest = (self.num_blocks + 1) * self.hidden_size + self.data_depth;
+    widest * 11 + self.hidden_size
+  }
+
+  // Context a tile needs on every side for a forward pass over it to match one over the whole image, and the multiple
+  // of pixels tiles start at, so that the pooling of a U-Net decoder lines up with that of the whole image. Every
+  // 3x3 convolution sees one pixel further, at the scale of its level for the U-Net. Attention averages over the whole
+  // input, so with it tiles only come close.
+  pub fn tile_margin(&self) -> (usize, usize) {
+    let dense = self.num_blocks + 2;
+    let (radius, alignment) = match self.arch {
+      Arch::Dense => (dense, 1),
+      Arch::Unet => {
+        let levels: usize = (0..self.num_blocks).map(|level| 3 << level).sum();
+        // The bottleneck convolution and the pooling into it
+        (dense.max(levels + (2 << self.num_blocks) + 1), 1 << self.num_blocks)
+      }
+    };
+    (radius.div_ceil(alignment) * alignment, alignment)
+  }
+
+  // Moves the decision thresholds of the `data_depth` channels of one decoder output to zero, so that the sign of a
+  // logit is its bit.
+  pub fn center_logits(&self, logits: &mut [f32]) {
+    if self.thresholds.is_empty() {
+      return;
+    }
+    let plane = logits.len() / self.data_depth;
+    for (channel, threshold) in logits.chunks_mut(plane).zip(&self.thresholds) {
+      channel.iter_mut().for_each(|logit| *logit -= threshold);
+    }
+  }
+}
+
+// Decoder logits split by data depth channel and embedded bit, from which `thresholds` calibrates the decision
+// threshold of every channel.
+pub struct Calibration {
+  sums: Vec<[f64; 2]>,
+  counts: Vec<[usize; 2]>,
+}
+
+impl Calibration {
+  pub fn new(data_depth: usize) -> Self {
+    Self {
+      sums: vec![[0.; 2]; data_depth],
+      counts: vec![[0; 2]; data_depth],
+    }
+  }
+
+  // Adds decoder logits of shape `(n, data_depth, h, w)` with the bits that were embedded.
+  pub fn add(&mut self, logits: &Tensor, bits: &Tensor) -> Result<()> {
+    let (_, depth, h, w) = logits.dims4()?;
+    let logits = logits.flatten_all()?.to_vec1::<f32>()?;
+    let bits = bits.flatten_all()?.to_dtype(candle_core::DType::U8)?.to_vec1::<u8>()?;
+    for (i, (&logit, &bit)) in logits.iter().zip(&bits).enumerate() {
+      let channel = i / (h * w) % depth;
+      self.sums[channel][bit as usize] += logit as f64;
+      self.counts[channel][bit as usize] += 1;
+    }
+    Ok(())
+  }
+
+  // Midpoint between the mean logits of zeros and ones in every channel, zero for channels that did not see both.
+  pub fn thresholds(&self) -> Vec<f32> {
+    self
+      .sums
+      .iter()
+      .zip(&self.counts)
+      .map(|(sums, counts)| match counts {
+        [0, _] | [_, 0] => 0.,
+        _ => ((sums[0] / counts[0] as f64 + sums[1] / counts[1] as f64) / 2.) as f32,
+      })
+      .collect()
+  }
+}
+
+pub fn model_config(model: &Path, component: &str) -> Result<ModelConfig> {
+  let path = match model.is_dir() {
+    true => component_file(model, component),
+    false => model.to_path_buf(),
+  };
+  let metadata = match path.extension().and_then(|ext| ext.to_str()) {
+    Some("safetensors") if path.exists() => read_metadata(&path)?.unwrap_or_default(),
+    Some("gguf") => gguf_metadata(&path)?,
+    #[cfg(feature = "onnx")]
+    Some("onnx") => crate::onnx::read(&path)?.metadata,
+    _ => HashMap::new(),
+  };
+  // PyTorch checkpoints and exports from other tools do not record a model config, their shapes tell the data depth
+  // and hidden size
+  if metadata.contains_key("arch") {
+    return ModelConfig::from_metadata(&metadata);
+  }
+  let default = ModelConfig::default();
+  let detected = tensor_shapes(&path, component)
+    .ok()
+    .map(|shapes| hyperparameters(&shapes, component))
+    .unwrap_or_default();
+  Ok(ModelConfig {
+    data_depth: detected.data_depth.unwrap_or(default.data_depth),
+    hidden_size: detected.hidden_size.unwrap_or(default.hidden_size),
+    ..default
+  })
+}
+
+// Shapes of the tensors of a component in a weights file, named like the variables of this crate.
+fn tensor_shapes(path: &Path, component: &str) -> Result<HashMap<String, Vec<usize>>> {
+  let shapes: Vec<(String, Vec<usize>)> = match path.extension().and_then(|ext| ext.to_str()) {
+    Some("safetensors") => safetensors::SafeTensors::deserialize(&std::fs::read(path)?)?
+      .tensors()
+      .into_iter()
+      .map(|(name, view)| (name, view.shape().to_vec()))
+      .collect(),
+    Some("gguf") => gguf_file::Content::read(&mut std::fs::File::open(path)?)?
+      .tensor_infos
+      .into_iter()
+      .map(|(name, info)| (name, info.shape.dims().to_vec()))
+      .collect(),
+    #[cfg(feature = "onnx")]
+    Some("onnx") => crate::onnx::read(path)?
+      .tensors
+      .into_iter()
+      .map(|(name, tensor)| (name, tensor.dims().to_vec()))
+      .collect(),
+    _ => read_pytorch(path)?
+      .into_iter()
+      .map(|(name, tensor)| (name, tensor.dims().to_vec()))
+      .collect(),
+  };
+  Ok(component_tensors(shapes, component).into_iter().collect())
+}
+
+// The tensors of a component, without the `component.` prefix of whole-model checkpoints and renamed with `map_name`.
+fn component_tensors<T>(tensors: Vec<(String, T)>, component: &str) -> Vec<(String, T)> {
+  let prefix = format!("{component}.");
+  let prefixed = tensors.iter().any(|(name, _)| name.starts_with(&prefix));
+  tensors
+    .into_iter()
+    .filter_map(|(name, tensor)| {
+      let name = match (prefixed, name.strip_prefix(&prefix)) {
+        (true, Some(name)) => name,
+        (true, None) => return None,
+        (false, _) => name.as_str(),
+      };
+      Some((map_name(name)?, tensor))
+    })
+    .collect()
+}
+
+// Weights of a component in a model directory: safetensors, or an ONNX or GGUF export.
+fn component_file(model: &Path, component: &str) -> PathBuf {
+  let files = ["safetensors", "onnx", "gguf"].map(|ext| model.join(format!("{component}.{ext}")));
+  files.iter().find(|file| file.exists()).unwrap_or(&files[0]).clone()
+}
+
+pub fn load(varmap: &mut VarMap, model: &Path, component: &str) -> error::Result<()> {
+  let path = match model.is_dir() {
+    true => component_file(model, component),
+    false => model.to_path_buf(),
+  };
+  let result = match path.extension().and_then(|ext| ext.to_str()) {
+    Some("safetensors") if model.is_dir() => load_safetensors(varmap, &path, component),
+    Some("safetensors") => load_safetensors_file(varmap, &path, component),
+    Some("gguf") => load_gguf(varmap, &path, component),
+    #[cfg(feature = "onnx")]
+    Some("onnx") => load_onnx(varmap, &path, component),
+    #[cfg(not(feature = "onnx"))]
+    Some("onnx") => Err(anyhow!("Loading ONNX weights requires the `onnx` feature")),
+    _ => load_pytorch(varmap, &path, component),
+  };
+  result.map_err(|err| SteganoError::WeightLoad {
+    path,
+    reason: format!("{err:#}"),
+  })
+}
+
+// Loads the safetensors file of a component, checking the tensor shapes first for an error that names the
+// hyperparameter to change rather than the tensor.
+fn load_safetensors(varmap: &mut VarMap, path: &Path, component: &str) -> Result<()> {
+  let buffer = std::fs::read(path)?;
+  let shapes = safetensors::SafeTensors::deserialize(&buffer)?
+    .tensors()
+    .into_iter()
+    .map(|(name, view)| (name, view.shape().to_vec()))
+    .collect();
+  check_shapes(varmap, &shapes, component)?;
+  Ok(varmap.load(path)?)
+}
+
+// Hyperparameters that tensors of a component were built with, as far as their names and shapes show them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Hyperparameters {
+  pub data_depth: Option<usize>,
+  pub hidden_size: Option<usize>,
+}
+
+// Reads the hidden size from the batch norm of the first block, and the data depth from the output convolution of
+// the decoder, `conv{num_blocks + 2}.0`, or the data channels the last convolution of the encoder takes on top of the
+// outputs of its blocks.
+pub fn hyperparameters(shapes: &HashMap<String, Vec<usize>>, component: &str) -> Hyperparameters {
+  let hidden_size = ["conv1.2.weight", "down0.0.2.weight", "layers.2.weight"]
+    .iter()
+    .find_map(|name| shapes.get(*name))
+    .and_then(|shape| shape.first().copied());
+  // The last convolution is the one without a batch norm after it
+  let out = (2..64)
+    .filter(|i| shapes.contains_key(&format!("conv{i}.0.weight")) && !shapes.contains_key(&format!("conv{i}.2.weight")))
+    .max()
+    .map(|i| (i, &shapes[&format!("conv{i}.0.weight")]));
+  let data_depth = match (component, out, hidden_size) {
+    ("decoder", Some((_, shape)), _) => shape.first().copied(),
+    ("encoder", Some((i, shape)), Some(hidden_size)) => {
+      shape.get(1).and_then(|&c| c.checked_sub((i - 1) * hidden_size))
+    }
+    _ => None,
+  };
+  Hyperparameters {
+    data_depth,
+    hidden_size,
+  }
+}
+
+// Fails with the hyperparameters to change when tensors that the model has come with other shapes.
+fn check_shapes(varmap: &VarMap, shapes: &HashMap<String, Vec<usize>>, component: &str) -> Result<()> {
+  let vars = varmap.data().lock().unwrap();
+  let mut mismatches: Vec<(&String, &[usize], &Vec<usize>)> = vars
+    .iter()
+    .filter_map(|(name, var)| {
+      let shape = shapes.get(name)?;
+      (var.dims() != shape.as_slice()).then_some((name, var.dims(), shape))
+    })
+    .collect();
+  if mismatches.is_empty() {
+    return Ok(());
+  }
+  mismatches.sort();
+  let expected: HashMap<String, Vec<usize>> = vars
+    .iter()
+    .map(|(name, var)| (name.clone(), var.dims().to_vec()))
+    .collect();
+  let (found, expected) = (
+    hyperparameters(shapes, component),
+    hyperparameters(&expected, component),
+  );
+  let mut hints = Vec::new();
+  for (hyperparameter, found, expected) in [
+    ("data_depth", found.data_depth, expected.data_depth),
+    ("hidden_size", found.hidden_size, expected.hidden_size),
+  ] {
+    if let Some((found, expected)) = found.zip(expected).filter(|(found, expected)| found != expected) {
+      let flag = hyperparameter.replace('_', "-");
+      hints.push(format!(
+        "the weights have {hyperparameter} {found} where the model has {expected}, set --{flag} {found}"
+      ));
+    }
+  }
+  if hints.is_empty() {
+    hints.push("the architecture differs, check --arch, --num-blocks, --attention and --separable".to_string());
+  }
+  let (name, expected, found) = mismatches[0];
+  Err(
+    SteganoError::ShapeMismatch(format!(
+      "{} {component} tensors have other shapes than the model expects, '{name}' is {found:?} instead of {expected:?}: {}",
+      mismatches.len(),
+      hints.join(", ")
+    ))
+    .into(),
+  )
+}
+
+// Loads safetensors weights from memory, for builds that embed or ship them without a model directory.
+pub fn load_buffer(varmap: &mut VarMap, buffer: &[u8], component: &str) -> Result<()> {
+  let load = || -> Result<()> {
+    let tensors = candle_core::safetensors::load_buffer(buffer, &candle_core::Device::Cpu)?;
+    let vars = varmap.data().lock().unwrap();
+    for (name, var) in vars.iter() {
+      let tensor = tensors.get(name).ok_or_else(|| anyhow!("Missing tensor '{name}'"))?;
+      var.set(&tensor.to_device(var.device())?.to_dtype(var.dtype())?)?;
+    }
+    Ok(())
+  };
+  load().map_err(|err| {
+    SteganoError::WeightLoad {
+      path: PathBuf::from(format!("<{component} buffer>")),
+      reason: format!("{err:#}"),
+    }
+    .into()
+  })
+}
+
+// Loads a safetensors file given on its own rather than in a model directory: the weights of a single component, or
+// of the whole model with `encoder.`/`decoder.`/`critic.` prefixes.
+fn load_safetensors_file(varmap: &mut VarMap, path: &Path, component: &str) -> Result<()> {
+  let tensors = candle_core::safetensors::load(path, &candle_core::Device::Cpu)?;
+  set_tensors(varmap, tensors.into_iter().collect(), path, component)
+}
+
+// Loads a PyTorch state dict checkpoint, either of a single module or of the whole SteganoGAN
+// with `encoder.`/`decoder.`/`critic.` prefixes.
+pub fn load_pytorch(varmap: &mut VarMap, path: &Path, component: &str) -> Result<()> {
+  set_tensors(varmap, read_pytorch(path)?, path, component)
+}
+
+// Tensors of a PyTorch state dict. The `.steg` and `.p` files of the original SteganoGAN pickle the whole Python
+// object instead, which only Python with its classes can restore, so those get an error that says how to convert them.
+pub(crate) fn read_pytorch(path: &Path) -> Result<Vec<(String, Tensor)>> {
+  let tensors = match candle_core::pickle::read_all(path) {
+    Ok(tensors) if !tensors.is_empty() => return Ok(tensors),
+    tensors => tensors,
+  };
+  let data = std::fs::read(path)?;
+  let pickles_class = |module: &[u8]| data.windows(module.len()).any(|window| window == module);
+  if pickles_class(b"steganogan.") || pickles_class(b"torch.nn.modules.") {
+    bail!(
+      "{} pickles a whole model object rather than a state dict; save the state dicts of its networks with \
+       `torch.save({{**m.encoder.state_dict(prefix='encoder.'), **m.decoder.state_dict(prefix='decoder.'), \
+       **m.critic.state_dict(prefix='critic.')}}, 'model.pt')` and load that",
+      path.display()
+    );
+  }
+  Ok(tensors?)
+}
+
+// Loads a GGUF file with the tensor names of a PyTorch checkpoint or of this crate, dequantizing quantized tensors.
+pub fn load_gguf(varmap: &mut VarMap, path: &Path, component: &str) -> Result<()> {
+  let device = &candle_core::Device::Cpu;
+  let mut file = std::fs::File::open(path)?;
+  let content = gguf_file::Content::read(&mut file)?;
+  let tensors = content
+    .tensor_infos
+    .keys()
+    .map(|name| {
+      Ok((
+        name.clone(),
+        content.tensor(&mut file, name, device)?.dequantize(device)?,
+      ))
+    })
+    .collect::<Result<_>>()?;
+  set_tensors(varmap, tensors, path, component)
+}
+
+// Loads the initializers of an ONNX model, like one written by `onnx::export` or by `torch.onnx.export` without
+// constant folding, which would merge batch norm into the convolutions.
+#[cfg(feature = "onnx")]
+pub fn load_onnx(varmap: &mut VarMap, path: &Path, component: &str) -> Result<()> {
+  set_tensors(varmap, crate::onnx::read(path)?.tensors, path, component)
+}
+
+// String metadata of a GGUF file, where the model config keys are the same as in safetensors.
+pub(crate) fn gguf_metadata(path: &Path) -> Result<HashMap<String, String>> {
+  let content = gguf_file::Content::read(&mut std::fs::File::open(path)?)?;
+  Ok(
+    content
+      .metadata
+      .iter()
+      .filter_map(|(key, value)| Some((key.clone(), value.to_string().ok()?.clone())))
+      .collect(),
+  )
+}
+
+// Sets every variable from `tensors`, which are named like a PyTorch state dict.
+fn set_tensors(varmap: &mut VarMap, tensors: Vec<(String, Tensor)>, path: &Path, component: &str) -> Result<()> {
+  let tensors = component_tensors(tensors, component);
+  let shapes = tensors
+    .iter()
+    .map(|(name, tensor)| (name.clone(), tensor.dims().to_vec()))
+    .collect();
+  check_shapes(varmap, &shapes, component)?;
+
+  let vars = varmap.data().lock().unwrap();
+  let mut loaded = 0;
+  for (name, tensor) in tensors.iter() {
+    let Some(var) = vars.get(name) else {
+      bail!("Unexpected tensor '{name}' in {}", path.display());
+    };
+    var.set(&tensor.to_device(var.device())?.to_dtype(var.dtype())?)?;
+    loaded += 1;
+  }
+  if loaded != vars.len() {
+    bail!(
+      "{} contains {loaded} of {} {component} tensors",
+      path.display(),
+      vars.len()
+    );
+  }
+  Ok(())
+}
+
+pub fn save(varmap: &VarMap, path: &Path, config: &ModelConfig, extra: &HashMap<String, String>) -> Result<()> {
+  let vars = varmap.data().lock().unwrap();
+  let mut tensors = Vec::with_capacity(vars.len());
+  for (name, var) in vars.iter() {
+    let data: Vec<u8> = var
+      .to_dtype(candle_core::DType::F32)?
+      .flatten_all()?
+      .to_vec1::<f32>()?
+      .iter()
+      .flat_map(|x| x.to_le_bytes())
+      .collect();
+    tensors.push((name.clone(), var.dims().to_vec(), data));
+  }
+  let views = tensors
+    .iter()
+    .map(|(name, shape, data)| Ok((name, TensorView::new(safetensors::Dtype::F32, shape.clone(), data)?)))
+    .collect::<Result<Vec<_>>>()?;
+  let mut metadata = config.to_metadata();
+  metadata.extend(extra.iter().map(|(k, v)| (k.clone(), v.clone())));
+  safetensors::serialize_to_file(views, &Some(metadata), path)?;
+  Ok(())
+}
+
+fn thresholds_metadata(thresholds: &[f32]) -> String {
+  thresholds.iter().map(f32::to_string).collect::<Vec<_>>().join(",")
+}
+
+// Stores calibrated thresholds in the model config of every component of a safetensors model directory.
+pub fn store_thresholds(model: &Path, thresholds: &[f32]) -> Result<()> {
+  let files: Vec<PathBuf> = ["encoder", "decoder", "critic"]
+    .iter()
+    .map(|component| model.join(format!("{component}.safetensors")))
+    .filter(|file| file.exists())
+    .collect();
+  if files.is_empty() {
+    bail!("Calibrated thresholds can only be stored in a model directory with safetensors weights");
+  }
+  let entries = HashMap::from([("thresholds".to_string(), thresholds_metadata(thresholds))]);
+  files.iter().try_for_each(|file| update_metadata(file, &entries))
+}
+
+// Rewrites the metadata of a safetensors file with `entries` added or replaced, keeping its tensors.
+pub fn update_metadata(path: &Path, entries: &HashMap<String, String>) -> Result<()> {
+  let buffer = std::fs::read(path)?;
+  let mut metadata = buffer_metadata(&buffer)?.unwrap_or_default();
+  metadata.extend(entries.iter().map(|(k, v)| (k.clone(), v.clone())));
+  let tensors = safetensors::SafeTensors::deserialize(&buffer)?;
+  safetensors::serialize_to_file(tensors.tensors(), &Some(metadata), path)?;
+  Ok(())
+}
+
+pub fn read_config(path: &Path) -> Result<Option<ModelConfig>> {
+  read_metadata(path)?
+    .as_ref()
+    .map(ModelConfig::from_metadata)
+    .transpose()
+}
+
+pub fn buffer_config(buffer: &[u8]) -> Result<Option<ModelConfig>> {
+  buffer_metadata(buffer)?
+    .as_ref()
+    .map(ModelConfig::from_metadata)
+    .transpose()
+}
+
+pub fn read_metadata(path: &Path) -> Result<Option<HashMap<String, String>>> {
+  buffer_metadata(&std::fs::read(path)?)
+}
+
+fn buffer_metadata(buffer: &[u8]) -> Result<Option<HashMap<String, String>>> {
+  let (_, metadata) = safetensors::SafeTensors::read_metadata(buffer)?;
+  Ok(metadata.metadata().clone())
+}
+
+fn map_name(name: &str) -> Option<String> {
+  if name.ends_with(".num_batches_tracked") {
+    return None;
+  }
+  match name.strip_prefix("_models.") {
+    Some(rest) => Some(format!("layers.{rest}")),
+    None => Some(name.to_string()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use candle_nn::VarBuilder;
+
+  use super::*;
+  use crate::model::decoder::Decoder;
+  use crate::model::encoder::Encoder;
+
+  #[test]
+  fn test_map_name() {
+    assert_eq!(map_name("conv4.0.weight").as_deref(), Some("conv4.0.weight"));
+    assert_eq!(map_name("_models.9.bias").as_deref(), Some("layers.9.bias"));
+    assert_eq!(map_name("conv1.2.num_batches_tracked"), None);
+  }
+
+  #[test]
+  fn test_pickled_model() -> Result<()> {
+    // What `torch.save(steganogan)` starts with: the class of the object rather than a dict of tensors
+    let tmp = tempfile::tempdir()?;
+    let path = tmp.path().join("model.steg");
+    std::fs::write(&path, b"\x80\x02csteganogan.models\nSteganoGAN\nq\x00)\x81q\x01.")?;
+    let err = read_pytorch(&path).unwrap_err().to_string();
+    assert!(err.contains("whole model object"));
+    Ok(())
+  }
+
+  #[test]
+  fn test_shape_mismatch() -> Result<()> {
+    let device = &candle_core::Device::Cpu;
+    let decoder = |config: &ModelConfig| -> Result<VarMap> {
+      let varmap = VarMap::new();
+      Decoder::from_config(
+        config,
+        VarBuilder::from_varmap(&varmap, candle_core::DType::F32, device),
+      )?;
+      Ok(varmap)
+    };
+    let config = ModelConfig {
+      data_depth: 4,
+      hidden_size: 8,
+      ..Default::default()
+    };
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    save(
+      &decoder(&config)?,
+      &dir.join("decoder.safetensors"),
+      &config,
+      &HashMap::new(),
+    )?;
+
+    let mut other = decoder(&ModelConfig {
+      data_depth: 1,
+      ..config.clone()
+    })?;
+    let err = load(&mut other, dir, "decoder").unwrap_err().to_string();
+    assert!(
+      err.contains("data_depth 4 where the model has 1, set --data-depth 4"),
+      "{err}"
+    );
+    let mut other = decoder(&ModelConfig {
+      hidden_size: 16,
+      ..config.clone()
+    })?;
+    let err = load(&mut other, dir, "decoder").unwrap_err().to_string();
+    assert!(
+      err.contains("set --hidden-size 8") && !err.contains("data-depth"),
+      "{err}"
+    );
+    load(&mut decoder(&config)?, dir, "decoder")?;
+
+    let encoder = VarMap::new();
+    Encoder::from_config(
+      &config,
+      VarBuilder::from_varmap(&encoder, candle_core::DType::F32, device),
+    )?;
+    let shapes = encoder
+      .data()
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|(name, var)| (name.clone(), var.dims().to_vec()))
+      .collect();
+    assert_eq!(
+      hyperparameters(&shapes, "encoder"),
+      Hyperparameters {
+        data_depth: Some(4),
+        hidden_size: Some(8)
+      }
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn test_detect_config() -> Result<()> {
+    let device = &candle_core::Device::Cpu;
+    let config = ModelConfig {
+      data_depth: 3,
+      hidden_size: 12,
+      ..Default::default()
+    };
+    // Weights without a config in their metadata, like those written by other tools
+    let varmap = VarMap::new();
+    Encoder::from_config(
+      &config,
+      VarBuilder::from_varmap(&varmap, candle_core::DType::F32, device),
+    )?;
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    varmap.save(dir.join("encoder.safetensors"))?;
+    assert_eq!(model_config(dir, "encoder")?, config);
+    Ok(())
+  }
+
+  #[test]
+  fn test_calibration() -> Result<()> {
+    let device = &candle_core::Device::Cpu;
+    // Two images with two channels, whose logits for a one are 2 and -1 above those for a zero
+    let bits = Tensor::new(&[[[[0f32, 1.]], [[1., 0.]]], [[[1., 0.]], [[0., 1.]]]], device)?;
+    let offsets = Tensor::new(&[1f32, -3.], device)?.reshape((1, 2, 1, 1))?;
+    let scales = Tensor::new(&[2f32, 1.], device)?.reshape((1, 2, 1, 1))?;
+    let logits = bits.broadcast_mul(&scales)?.broadcast_add(&offsets)?;
+    let mut calibration = Calibration::new(2);
+    calibration.add(&logits, &bits)?;
+    let config = ModelConfig {
+      data_depth: 2,
+      thresholds: calibration.thresholds(),
+      ..Default::default()
+    };
+    assert_eq!(config.thresholds, [2., -2.5]);
+
+    let mut logits = logits.get(0)?.flatten_all()?.to_vec1::<f32>()?;
+    config.center_logits(&mut logits);
+    assert_eq!(logits, [-1., 1., 0.5, -0.5]);
+    Ok(())
+  }
+
+  #[cfg(feature = "embedded-weights")]
+  #[test]
+  fn test_embedded() -> Result<()> {
+    let codec = crate::codec::Codec::from_buffers(EMBEDDED_ENCODER, EMBEDDED_DECODER, &candle_core::Device::Cpu)?;
+    assert_eq!(codec.config(), &ModelConfig::default());
+    Ok(())
+  }
+
+  #[test]
+  fn test_save_with_config() -> Result<()> {
+    let device = &candle_core::Device::Cpu;
+    let config = ModelConfig {
+      arch: Arch::Dense,
+      data_depth: 8,
+      hidden_size: 32,
+      preprocess: crate::preprocess::Profile::Symmetric.preprocess(),
+      attention: Attention::None,
+      num_blocks: 2,
+      separable: false,
+      thresholds: vec![0.5, -0.25, 0., 0., 0., 0., 0., 1.],
+    };
+    let mut varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, candle_core::DType::F32, device);
+    let decoder = Decoder::from_config(&config, vb)?;
+    load(&mut varmap, Path::new("pretrained"), "decoder")?;
+
+    let tmp = tempfile::tempdir()?;
+    let path = tmp.path().join("model.safetensors");
+    save(&varmap, &path, &config, &HashMap::new())?;
+    assert_eq!(read_config(&path)?.as_ref(), Some(&config));
+    varmap.load(&path)?;
+    assert_eq!(
+      decoder
+        .forward(&candle_core::Tensor::zeros(
+          (1, 3, 8, 8),
+          candle_core::DType::F32,
+          device
+        )?)?
+        .dims(),
+      [1, 8, 8, 8]
+    );
+
+    // The file on its own, like a model directory of a single component
+    let mut loaded = VarMap::new();
+    Decoder::from_config(
+      &config,
+      VarBuilder::from_varmap(&loaded, candle_core::DType::F32, device),
+    )?;
+    load(&mut loaded, &path, "decoder")?;
+    let loaded = loaded.data().lock().unwrap();
+    for (name, var) in varmap.data().lock().unwrap().iter() {
+      assert_eq!(
+        var.flatten_all()?.to_vec1::<f32>()?,
+        loaded[name].flatten_all()?.to_vec1::<f32>()?
+      );
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_load_gguf() -> Result<()> {
+    use candle_core::quantized::{GgmlDType, QTensor};
+
+    let device = &candle_core::Device::Cpu;
+    let config = ModelConfig {
+      num_blocks: 3,
+      ..Default::default()
+    };
+    let varmap = VarMap::new();
+    Decoder::from_config(
+      &config,
+      VarBuilder::from_varmap(&varmap, candle_core::DType::F32, device),
+    )?;
+    varmap
+      .data()
+      .lock()
+      .unwrap()
+      .values()
+      .try_for_each(|var| var.set(&Tensor::randn(0f32, 1., var.dims(), device)?))?;
+    // A whole model in one file, with the `decoder.` prefix
+    let tensors = varmap
+      .data()
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|(name, var)| {
+        Ok((
+          format!("decoder.{name}"),
+          QTensor::quantize(var.as_tensor(), GgmlDType::F32)?,
+        ))
+      })
+      .collect::<Result<Vec<_>>>()?;
+    let metadata: Vec<_> = config
+      .to_metadata()
+      .into_iter()
+      .map(|(key, value)| (key, gguf_file::Value::String(value)))
+      .collect();
+    let tmp = tempfile::tempdir()?;
+    let path = tmp.path().join("model.gguf");
+    gguf_file::write(
+      &mut std::fs::File::create(&path)?,
+      &metadata
+        .iter()
+        .map(|(key, value)| (key.as_str(), value))
+        .collect::<Vec<_>>(),
+      &tensors
+        .iter()
+        .map(|(name, tensor)| (name.as_str(), tensor))
+        .collect::<Vec<_>>(),
+    )?;
+
+    assert_eq!(model_config(&path, "decoder")?, config);
+    let mut loaded = VarMap::new();
+    Decoder::from_config(
+      &config,
+      VarBuilder::from_varmap(&loaded, candle_core::DType::F32, device),
+    )?;
+    load(&mut loaded, &path, "decoder")?;
+    let loaded = loaded.data().lock().unwrap();
+    for (name, var) in varmap.data().lock().unwrap().iter() {
+      assert_eq!(
+        var.flatten_all()?.to_vec1::<f32>()?,
+        loaded[name].flatten_all()?.to_vec1::<f32>()?
+      );
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,226 @@
+use std::io::{BufRead, Write};
+
+use anyhow::{bail, ensure, Context, Result};
+use image::{Rgb, RgbImage};
+
+const MAGIC: &str = "YUV4MPEG2";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Chroma {
+  Mono,
+  C420,
+  C422,
+  C444,
+}
+
+impl Chroma {
+  fn parse(tag: &str) -> Result<Self> {
+    Ok(match tag {
+      "mono" => Self::Mono,
+      "420" | "420jpeg" | "420paldv" | "420mpeg2" => Self::C420,
+      "422" => Self::C422,
+      "444" => Self::C444,
+      _ => bail!("Unsupported Y4M colorspace C{tag}, convert the video to yuv444p"),
+    })
+  }
+
+  // Horizontal and vertical subsampling of the chroma planes.
+  fn subsampling(self) -> (u32, u32) {
+    match self {
+      Self::Mono | Self::C444 => (1, 1),
+      Self::C420 => (2, 2),
+      Self::C422 => (2, 1),
+    }
+  }
+}
+
+// Stream parameters shared by the reader and the writer.
+#[derive(Debug, Clone)]
+pub struct Params {
+  pub width: u32,
+  pub height: u32,
+  chroma: Chroma,
+  full_range: bool,
+  /// The other header fields (frame rate, interlacing, aspect ratio...), written back unchanged
+  rest: Vec<String>,
+}
+
+// Reads the frames of a YUV4MPEG2 stream, like the output of `ffmpeg -f yuv4mpegpipe`, as RGB images.
+pub struct Reader<R> {
+  input: R,
+  pub params: Params,
+}
+
+impl<R: BufRead> Reader<R> {
+  pub fn new(mut input: R) -> Result<Self> {
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    let mut fields = line.split_ascii_whitespace();
+    ensure!(fields.next() == Some(MAGIC), "Not a Y4M stream");
+    let (mut width, mut height) = (None, None);
+    let (mut chroma, mut full_range) = (Chroma::C420, false);
+    let mut rest = Vec::new();
+    for field in fields {
+      let (tag, value) = field.split_at(1);
+      match tag {
+        "W" => width = Some(value.parse().context("Invalid Y4M width")?),
+        "H" => height = Some(value.parse().context("Invalid Y4M height")?),
+        "C" => chroma = Chroma::parse(value)?,
+        "X" if value.starts_with("YSCSS=") => {}
+        "X" if value.starts_with("COLORRANGE=") => full_range = value == "COLORRANGE=FULL",
+        _ => rest.push(field.to_string()),
+      }
+    }
+    let (Some(width), Some(height)) = (width, height) else {
+      bail!("The Y4M header has no size");
+    };
+    Ok(Self {
+      input,
+      params: Params {
+        width,
+        height,
+        chroma,
+        full_range,
+        rest,
+      },
+    })
+  }
+
+  // The next frame, `None` at the end of the stream.
+  pub fn next_frame(&mut self) -> Result<Option<RgbImage>> {
+    let mut line = String::new();
+    if self.input.read_line(&mut line)? == 0 {
+      return Ok(None);
+    }
+    ensure!(line.starts_with("FRAME"), "Invalid Y4M frame header");
+    let Params {
+      width,
+      height,
+      chroma,
+      full_range,
+      ..
+    } = self.params;
+    let (sx, sy) = chroma.subsampling();
+    let (cw, ch) = (width.div_ceil(sx), height.div_ceil(sy));
+    let mut luma = vec![0; (width * height) as usize];
+    self.input.read_exact(&mut luma)?;
+    let mut planes = vec![128; 2 * (cw * ch) as usize];
+    if chroma != Chroma::Mono {
+      self.input.read_exact(&mut planes)?;
+    }
+    let (u, v) = planes.split_at((cw * ch) as usize);
+    Ok(Some(RgbImage::from_fn(width, height, |x, y| {
+      let c = ((y / sy) * cw + x / sx) as usize;
+      to_rgb([luma[(y * width + x) as usize], u[c], v[c]], full_range)
+    })))
+  }
+}
+
+// Writes RGB frames as a 4:4:4 YUV4MPEG2 stream: subsampling the chroma would destroy most of the payload.
+pub struct Writer<W> {
+  output: W,
+  params: Params,
+}
+
+impl<W: Write> Writer<W> {
+  // Keeps the frame rate, color range and other properties of `params`.
+  pub fn new(mut output: W, params: &Params) -> Result<Self> {
+    let params = Params {
+      chroma: Chroma::C444,
+      ..params.clone()
+    };
+    write!(output, "{MAGIC} W{} H{} C444", params.width, params.height)?;
+    for field in &params.rest {
+      write!(output, " {field}")?;
+    }
+    if params.full_range {
+      write!(output, " XCOLORRANGE=FULL")?;
+    }
+    writeln!(output)?;
+    Ok(Self { output, params })
+  }
+
+  pub fn write_frame(&mut self, frame: &RgbImage) -> Result<()> {
+    ensure!(
+      frame.dimensions() == (self.params.width, self.params.height),
+      "Frame size differs from the stream"
+    );
+    let yuv: Vec<[u8; 3]> = frame.pixels().map(|p| to_yuv(p, self.params.full_range)).collect();
+    writeln!(self.output, "FRAME")?;
+    for plane in 0..3 {
+      self
+        .output
+        .write_all(&yuv.iter().map(|p| p[plane]).collect::<Vec<_>>())?;
+    }
+    Ok(())
+  }
+
+  pub fn finish(mut self) -> Result<W> {
+    self.output.flush()?;
+    Ok(self.output)
+  }
+}
+
+// BT.601, the default of ffmpeg for standard definition, in limited (16-235) or full range.
+fn to_yuv(p: &Rgb<u8>, full_range: bool) -> [u8; 3] {
+  let [r, g, b] = p.0.map(|c| c as f32);
+  let y = 0.299 * r + 0.587 * g + 0.114 * b;
+  let u = -0.168736 * r - 0.331264 * g + 0.5 * b;
+  let v = 0.5 * r - 0.418688 * g - 0.081312 * b;
+  let (y, u, v) = match full_range {
+    true => (y, u + 128., v + 128.),
+    false => (16. + y * 219. / 255., 128. + u * 224. / 255., 128. + v * 224. / 255.),
+  };
+  [y, u, v].map(|c| c.round().clamp(0., 255.) as u8)
+}
+
+fn to_rgb([y, u, v]: [u8; 3], full_range: bool) -> Rgb<u8> {
+  let (y, u, v) = (y as f32, u as f32 - 128., v as f32 - 128.);
+  let (y, u, v) = match full_range {
+    true => (y, u, v),
+    false => ((y - 16.) * 255. / 219., u * 255. / 224., v * 255. / 224.),
+  };
+  let rgb = [y + 1.402 * v, y - 0.344136 * u - 0.714136 * v, y + 1.772 * u];
+  Rgb(rgb.map(|c| c.round().clamp(0., 255.) as u8))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_roundtrip() -> Result<()> {
+    let frames: Vec<_> = (0..2u32)
+      .map(|i| RgbImage::from_fn(5, 3, |x, y| Rgb([(x * 50) as u8, (y * 90) as u8, (i * 200) as u8])))
+      .collect();
+    // Gray 4:2:0 frames, whose chroma planes are 3x2
+    let frame = [b"FRAME\n".to_vec(), vec![100; 15], vec![128; 2 * 3 * 2]].concat();
+    let input = [
+      b"YUV4MPEG2 W5 H3 F25:1 Ip C420jpeg XYSCSS=420JPEG\n".to_vec(),
+      frame.clone(),
+      frame,
+    ]
+    .concat();
+    let mut reader = Reader::new(&input[..])?;
+    assert_eq!(
+      reader.next_frame()?,
+      Some(RgbImage::from_pixel(5, 3, to_rgb([100, 128, 128], false)))
+    );
+    assert!(reader.next_frame()?.is_some() && reader.next_frame()?.is_none());
+
+    let mut writer = Writer::new(Vec::new(), &reader.params)?;
+    for frame in &frames {
+      writer.write_frame(frame)?;
+    }
+    let output = writer.finish()?;
+    assert!(output.starts_with(b"YUV4MPEG2 W5 H3 C444 F25:1 Ip\nFRAME\n"));
+    let mut reader = Reader::new(&output[..])?;
+    for frame in &frames {
+      let decoded = reader.next_frame()?.unwrap();
+      // Limited range loses a little precision
+      assert!(decoded.iter().zip(frame.iter()).all(|(a, b)| a.abs_diff(*b) <= 2));
+    }
+    assert!(reader.next_frame()?.is_none());
+    Ok(())
+  }
+}
@@ -0,0 +1,99 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+pub const WEIGHT_FILES: [&str; 3] = ["encoder.safetensors", "decoder.safetensors", "critic.safetensors"];
+
+pub struct ModelInfo {
+  pub name: &'static str,
+  pub description: &'static str,
+  pub url: &'static str,
+  pub data_depth: usize,
+  pub hidden_size: usize,
+}
+
+pub const MODELS: &[ModelInfo] = &[ModelInfo {
+  name: "dense",
+  description: "SteganoGAN dense architecture, trained on DIV2K",
+  url: "https://github.com/vvh413/steganogan-rs/raw/main/pretrained",
+  data_depth: 8,
+  hidden_size: 32,
+}];
+
+pub fn find(name: &str) -> Option<&'static ModelInfo> {
+  MODELS.iter().find(|model| model.name == name)
+}
+
+pub fn cache_dir() -> Result<PathBuf> {
+  if let Some(dir) = std::env::var_os("STEGANOGAN_CACHE") {
+    return Ok(PathBuf::from(dir));
+  }
+  dirs::cache_dir()
+    .map(|dir| dir.join("steganogan-rs"))
+    .ok_or_else(|| anyhow!("Cannot determine cache directory, set STEGANOGAN_CACHE"))
+}
+
+pub fn is_cached(model: &ModelInfo) -> Result<bool> {
+  let dir = cache_dir()?.join(model.name);
+  Ok(WEIGHT_FILES.iter().all(|file| dir.join(file).is_file()))
+}
+
+#[cfg(feature = "download")]
+pub fn pull(model: &ModelInfo) -> Result<PathBuf> {
+  use std::io::Read;
+
+  use anyhow::Context;
+
+  let dir = cache_dir()?.join(model.name);
+  std::fs::create_dir_all(&dir)?;
+  for file in WEIGHT_FILES {
+    let url = format!("{}/{file}", model.url);
+    let mut bytes = Vec::new();
+    ureq::get(&url)
+      .call()
+      .with_context(|| format!("Failed to download {url}"))?
+      .into_reader()
+      .read_to_end(&mut bytes)?;
+    let tmp = dir.join(format!("{file}.part"));
+    std::fs::write(&tmp, bytes)?;
+    std::fs::rename(tmp, dir.join(file))?;
+  }
+  Ok(dir)
+}
+
+#[cfg(not(feature = "download"))]
+pub fn pull(model: &ModelInfo) -> Result<PathBuf> {
+  Err(anyhow!("Downloading '{}' requires the `download` feature", model.name))
+}
+
+// Name of the model built into the binary with the `embedded-weights` feature.
+pub const EMBEDDED: &str = "pretrained";
+
+// Whether `model` refers to the built-in weights, which a `pretrained` directory in the working directory overrides.
+pub fn is_embedded(model: &str) -> bool {
+  cfg!(feature = "embedded-weights") && model == EMBEDDED && !Path::new(model).exists()
+}
+
+pub fn resolve(model: &str) -> Result<PathBuf> {
+  let path = Path::new(model);
+  if path.exists() {
+    return Ok(path.to_path_buf());
+  }
+  let info = find(model).ok_or_else(|| anyhow!("Unknown model '{model}': not a path or a known model name"))?;
+  if !is_cached(info)? {
+    return Err(anyhow!("Model '{model}' is not downloaded, run `models pull {model}`"));
+  }
+  Ok(cache_dir()?.join(info.name))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_resolve() -> Result<()> {
+    assert_eq!(resolve("pretrained")?, PathBuf::from("pretrained"));
+    assert!(resolve("no-such-model").is_err());
+    Ok(())
+  }
+}
@@ -0,0 +1,90 @@
+// Decodes `--profile screenshot` stego images after what taking a screenshot of a displayed image does to them: a
+// resampling off the pixel grid or through another resolution, a crop that starts anywhere and 8 bit rounding.
+
+use std::path::Path;
+
+use candle_core::Device;
+use image::imageops::{self, FilterType};
+use image::{ImageFormat, RgbImage};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use steganogan_rs::codec::{Codec, DecodeOptions, EncodeOptions};
+use steganogan_rs::ecc::Ecc;
+use steganogan_rs::payload::Spread;
+use steganogan_rs::{image_io, sync};
+
+const SIZE: u32 = 320;
+
+fn cover() -> RgbImage {
+  let mut rng = ChaCha20Rng::seed_from_u64(7);
+  RgbImage::from_fn(SIZE, SIZE, |x, y| {
+    image::Rgb([(x * 3 + y) as u8, (y * 2) as u8, rng.gen_range(100..160)])
+  })
+}
+
+// Bilinear resampling of the image moved `shift` pixels right and down, the edge pixels repeated.
+fn shift(img: &RgbImage, shift: f32) -> RgbImage {
+  let pixel = |x: u32, y: u32| img.get_pixel(x.min(img.width() - 1), y.min(img.height() - 1)).0;
+  RgbImage::from_fn(img.width(), img.height(), |x, y| {
+    let (a, b, c, d) = (pixel(x, y), pixel(x + 1, y), pixel(x, y + 1), pixel(x + 1, y + 1));
+    image::Rgb(std::array::from_fn(|i| {
+      let top = (1. - shift) * a[i] as f32 + shift * b[i] as f32;
+      let bottom = (1. - shift) * c[i] as f32 + shift * d[i] as f32;
+      ((1. - shift) * top + shift * bottom).round() as u8
+    }))
+  })
+}
+
+// Scaled up by `factor` and back down, as a screenshot of the image zoomed in and shrunk again.
+fn rescale(img: &RgbImage, factor: f32) -> RgbImage {
+  let (w, h) = img.dimensions();
+  let up = imageops::resize(
+    img,
+    (w as f32 * factor) as u32,
+    (h as f32 * factor) as u32,
+    FilterType::Triangle,
+  );
+  imageops::resize(&up, w, h, FilterType::Triangle)
+}
+
+// Decodes a crop that starts inside a tile from the damaged stego image.
+fn screenshot(codec: &Codec, stego: &RgbImage, damage: impl Fn(&RgbImage) -> RgbImage, tiling: sync::Tiling) -> bool {
+  let png = image_io::encode_image(&damage(stego), ImageFormat::Png).unwrap();
+  let png = image::load_from_memory(&png).unwrap().to_rgb8();
+  let cropped = imageops::crop_imm(&png, 45, 77, 230, 230).to_image();
+  let options = DecodeOptions {
+    resync: Some(tiling),
+    ..Default::default()
+  };
+  codec
+    .decode_with(&cropped, &options)
+    .is_ok_and(|payload| payload.message == "screenshot")
+}
+
+fn encode(codec: &Codec, spread: Spread, ecc: Ecc) -> RgbImage {
+  let options = EncodeOptions {
+    spread,
+    ecc,
+    ..Default::default()
+  };
+  codec.encode_with(&cover(), b"screenshot", &options).unwrap()
+}
+
+#[test]
+fn test_screenshot_profile() {
+  let codec = Codec::load(Path::new("pretrained"), &Device::Cpu).unwrap();
+  let stego = encode(&codec, Spread::RobustTiles, Ecc::Strong);
+  assert!(screenshot(&codec, &stego, |img| shift(img, 0.25), sync::Tiling::Robust));
+  assert!(screenshot(&codec, &stego, |img| shift(img, 0.5), sync::Tiling::Robust));
+  assert!(screenshot(
+    &codec,
+    &stego,
+    |img| rescale(img, 1.5),
+    sync::Tiling::Robust
+  ));
+
+  // Plain tiles, which hold a different bit in every channel of every pixel, do not survive the same damage
+  let stego = encode(&codec, Spread::Tiles, Ecc::Standard);
+  assert!(screenshot(&codec, &stego, |img| img.clone(), sync::Tiling::Plain));
+  assert!(!screenshot(&codec, &stego, |img| shift(img, 0.5), sync::Tiling::Plain));
+}